@@ -0,0 +1,544 @@
+use std::fmt;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::Formats;
+
+/// Value of one or two registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    /// coil or discrete input
+    Bool(bool),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(v) => write!(f, "{}", *v as u8),
+            Value::U16(v) => write!(f, "{}", v),
+            Value::I16(v) => write!(f, "{}", v),
+            Value::U32(v) => write!(f, "{}", v),
+            Value::I32(v) => write!(f, "{}", v),
+            Value::F32(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl Value {
+    /// Parse a value given on the command line, unsigned formats also take 0x.. and 0b..
+    pub fn parse(s: &str, format: &Formats) -> Result<Value> {
+        let invalid = || anyhow!("Write value {} does not fit {:?}", s, format);
+        let radix = |s: &str| {
+            if let Some(hex) = s.strip_prefix("0x") {
+                (hex.to_string(), 16)
+            } else if let Some(bin) = s.strip_prefix("0b") {
+                (bin.to_string(), 2)
+            } else {
+                (s.to_string(), 10)
+            }
+        };
+        let value = match kind(format)? {
+            Kind::U16 => {
+                let (digits, radix) = radix(s);
+                Value::U16(u16::from_str_radix(&digits, radix).map_err(|_| invalid())?)
+            }
+            Kind::I16 => Value::I16(s.parse().map_err(|_| invalid())?),
+            Kind::U32 => {
+                let (digits, radix) = radix(s);
+                Value::U32(u32::from_str_radix(&digits, radix).map_err(|_| invalid())?)
+            }
+            Kind::I32 => Value::I32(s.parse().map_err(|_| invalid())?),
+            Kind::F32 => Value::F32(s.parse().map_err(|_| invalid())?),
+        };
+        Ok(value)
+    }
+}
+
+/// Binary counter reading of IEC104, u32 also as 0x.. and 0b.., or a negative i32
+pub fn parse_counter(s: &str) -> Result<i32> {
+    match Value::parse(s, &Formats::U32).or_else(|_| Value::parse(s, &Formats::I32))? {
+        Value::U32(v) => Ok(v as i32),
+        Value::I32(v) => Ok(v),
+        _ => unreachable!(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+fn kind(format: &Formats) -> Result<Kind> {
+    let kind = match format {
+        Formats::U16 | Formats::Hex16 | Formats::Bin16 => Kind::U16,
+        Formats::I16 => Kind::I16,
+        Formats::U32
+        | Formats::U32abcd
+        | Formats::U32badc
+        | Formats::U32cdab
+        | Formats::U32dcba
+        | Formats::Hex32
+        | Formats::Bin32 => Kind::U32,
+        Formats::I32
+        | Formats::I32abcd
+        | Formats::I32badc
+        | Formats::I32cdab
+        | Formats::I32dcba => Kind::I32,
+        Formats::F32
+        | Formats::F32abcd
+        | Formats::F32badc
+        | Formats::F32cdab
+        | Formats::F32dcba => Kind::F32,
+        Formats::String | Formats::Unkonwn => bail!("{:?} has no register encoding", format),
+    };
+    Ok(kind)
+}
+
+/// Registers a value of `format` takes
+pub fn word_count(format: &Formats) -> usize {
+    match kind(format) {
+        Ok(Kind::U32 | Kind::I32 | Kind::F32) => 2,
+        _ => 1,
+    }
+}
+
+/// Positions of the big endian bytes ABCD of a 32-bit value in the two registers.
+///
+/// `little_endian` (`-L`) swaps the words of the formats without an explicit order.
+fn byte_order(format: &Formats, little_endian: bool) -> [usize; 4] {
+    match format {
+        Formats::I32 | Formats::U32 | Formats::F32 | Formats::Hex32 | Formats::Bin32
+            if little_endian =>
+        {
+            [2, 3, 0, 1]
+        }
+        Formats::I32badc | Formats::U32badc | Formats::F32badc => [1, 0, 3, 2],
+        Formats::I32cdab | Formats::U32cdab | Formats::F32cdab => [2, 3, 0, 1],
+        Formats::I32dcba | Formats::U32dcba | Formats::F32dcba => [3, 2, 1, 0],
+        _ => [0, 1, 2, 3],
+    }
+}
+
+/// Registers of `value` in `format`, the value must be of the type of the format
+pub fn encode(value: Value, format: &Formats, little_endian: bool) -> Result<Vec<u16>> {
+    let bits = match (kind(format)?, value) {
+        (Kind::U16, Value::Bool(v)) => return Ok(vec![v as u16]),
+        (Kind::U16, Value::U16(v)) => return Ok(vec![v]),
+        (Kind::I16, Value::I16(v)) => return Ok(vec![v as u16]),
+        (Kind::U32, Value::U32(v)) => v,
+        (Kind::I32, Value::I32(v)) => v as u32,
+        (Kind::F32, Value::F32(v)) => v.to_bits(),
+        _ => bail!("{:?} can not be encoded as {:?}", value, format),
+    };
+    let abcd = bits.to_be_bytes();
+    let mut bytes = [0u8; 4];
+    for (i, pos) in byte_order(format, little_endian).into_iter().enumerate() {
+        bytes[pos] = abcd[i];
+    }
+    Ok(vec![
+        u16::from_be_bytes([bytes[0], bytes[1]]),
+        u16::from_be_bytes([bytes[2], bytes[3]]),
+    ])
+}
+
+/// Value of `format` in the first registers of `words`
+pub fn decode(words: &[u16], format: &Formats, little_endian: bool) -> Result<Value> {
+    let kind = kind(format)?;
+    if words.len() < word_count(format) {
+        bail!(
+            "{:?} needs {} registers, got {}",
+            format,
+            word_count(format),
+            words.len()
+        );
+    }
+    let bits = match kind {
+        Kind::U16 => return Ok(Value::U16(words[0])),
+        Kind::I16 => return Ok(Value::I16(words[0] as i16)),
+        _ => {
+            let bytes = [words[0].to_be_bytes(), words[1].to_be_bytes()].concat();
+            let order = byte_order(format, little_endian);
+            u32::from_be_bytes([
+                bytes[order[0]],
+                bytes[order[1]],
+                bytes[order[2]],
+                bytes[order[3]],
+            ])
+        }
+    };
+    Ok(match kind {
+        Kind::I32 => Value::I32(bits as i32),
+        Kind::F32 => Value::F32(f32::from_bits(bits)),
+        _ => Value::U32(bits),
+    })
+}
+
+/// Values of consecutive registers, each taking the registers of `format`
+pub fn decode_all(words: &[u16], format: &Formats, little_endian: bool) -> Result<Vec<Value>> {
+    words
+        .chunks(word_count(format))
+        .map(|w| decode(w, format, little_endian))
+        .collect()
+}
+
+/// Text of `value` in `format`, negative 16/32-bit values are followed by their signed value
+pub fn format_value(value: &Value, format: &Formats) -> String {
+    match (format, *value) {
+        (Formats::Hex16, Value::U16(v)) => format!("{:#04X}", v),
+        (Formats::Hex32, Value::U32(v)) => format!("{:#010X}", v),
+        (Formats::Bin16, Value::U16(v)) => format!("{:016b}", v),
+        (Formats::Bin32, Value::U32(v)) => format!("{:032b}", v),
+        (Formats::U16, Value::U16(v)) if v & 0x8000 != 0 => format!("{} ({})", v, v as i16),
+        (_, Value::U32(v)) if v & 0x8000_0000 != 0 => format!("{} ({})", v, v as i32),
+        (_, v) => v.to_string(),
+    }
+}
+
+/// One line per value, the first one at `addr`
+pub fn format_values(mut addr: u16, format: &Formats, values: &[Value]) -> String {
+    let mut out = String::new();
+    for value in values {
+        out += &format!(
+            "[{}({:#04X})]: \t{}\n",
+            addr,
+            addr,
+            format_value(value, format)
+        );
+        let words = match value {
+            Value::Bool(_) => 1,
+            _ => word_count(format),
+        };
+        addr = addr.wrapping_add(words as u16);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Formats; 23] = [
+        Formats::U16,
+        Formats::I16,
+        Formats::I32,
+        Formats::I32abcd,
+        Formats::I32badc,
+        Formats::I32cdab,
+        Formats::I32dcba,
+        Formats::U32,
+        Formats::U32abcd,
+        Formats::U32badc,
+        Formats::U32cdab,
+        Formats::U32dcba,
+        Formats::F32,
+        Formats::F32abcd,
+        Formats::F32badc,
+        Formats::F32cdab,
+        Formats::F32dcba,
+        Formats::Hex16,
+        Formats::Hex32,
+        Formats::Bin16,
+        Formats::Bin32,
+        Formats::String,
+        Formats::Unkonwn,
+    ];
+
+    fn sample(format: &Formats) -> Option<Value> {
+        match kind(format).ok()? {
+            Kind::U16 => Some(Value::U16(0xBEEF)),
+            Kind::I16 => Some(Value::I16(-12345)),
+            Kind::U32 => Some(Value::U32(0x1122_3344)),
+            Kind::I32 => Some(Value::I32(-0x1122_3344)),
+            Kind::F32 => Some(Value::F32(-1234.5)),
+        }
+    }
+
+    #[test]
+    fn round_trip_every_format() {
+        for format in ALL.iter() {
+            let Some(value) = sample(format) else {
+                continue;
+            };
+            for little_endian in [false, true] {
+                let words = encode(value, format, little_endian).unwrap();
+                assert_eq!(words.len(), word_count(format), "{:?}", format);
+                assert_eq!(
+                    decode(&words, format, little_endian).unwrap(),
+                    value,
+                    "{:?} -L {}",
+                    format,
+                    little_endian
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn word_counts() {
+        for format in ALL.iter() {
+            let expected = match format {
+                Formats::U16
+                | Formats::I16
+                | Formats::Hex16
+                | Formats::Bin16
+                | Formats::String
+                | Formats::Unkonwn => 1,
+                _ => 2,
+            };
+            assert_eq!(word_count(format), expected, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn u32_byte_orders() {
+        let cases = [
+            (Formats::U32, [0x1122, 0x3344]),
+            (Formats::U32abcd, [0x1122, 0x3344]),
+            (Formats::U32badc, [0x2211, 0x4433]),
+            (Formats::U32cdab, [0x3344, 0x1122]),
+            (Formats::U32dcba, [0x4433, 0x2211]),
+            (Formats::Hex32, [0x1122, 0x3344]),
+            (Formats::Bin32, [0x1122, 0x3344]),
+        ];
+        for (format, words) in cases {
+            assert_eq!(
+                encode(Value::U32(0x1122_3344), &format, false).unwrap(),
+                words,
+                "{:?}",
+                format
+            );
+            assert_eq!(
+                decode(&words, &format, false).unwrap(),
+                Value::U32(0x1122_3344),
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn i32_byte_orders() {
+        // -2 is FF FF FF FE
+        let cases = [
+            (Formats::I32, [0xFFFF, 0xFFFE]),
+            (Formats::I32abcd, [0xFFFF, 0xFFFE]),
+            (Formats::I32badc, [0xFFFF, 0xFEFF]),
+            (Formats::I32cdab, [0xFFFE, 0xFFFF]),
+            (Formats::I32dcba, [0xFEFF, 0xFFFF]),
+        ];
+        for (format, words) in cases {
+            assert_eq!(encode(Value::I32(-2), &format, false).unwrap(), words);
+            assert_eq!(decode(&words, &format, false).unwrap(), Value::I32(-2));
+        }
+    }
+
+    #[test]
+    fn f32_byte_orders() {
+        // 25.0 is 41 C8 00 00
+        let cases = [
+            (Formats::F32, [0x41C8, 0x0000]),
+            (Formats::F32abcd, [0x41C8, 0x0000]),
+            (Formats::F32badc, [0xC841, 0x0000]),
+            (Formats::F32cdab, [0x0000, 0x41C8]),
+            (Formats::F32dcba, [0x0000, 0xC841]),
+        ];
+        for (format, words) in cases {
+            assert_eq!(encode(Value::F32(25.0), &format, false).unwrap(), words);
+            assert_eq!(decode(&words, &format, false).unwrap(), Value::F32(25.0));
+        }
+    }
+
+    #[test]
+    fn little_endian_swaps_words_of_unordered_formats() {
+        for format in [
+            Formats::I32,
+            Formats::U32,
+            Formats::F32,
+            Formats::Hex32,
+            Formats::Bin32,
+        ] {
+            let value = sample(&format).unwrap();
+            let mut big = encode(value, &format, false).unwrap();
+            big.reverse();
+            assert_eq!(encode(value, &format, true).unwrap(), big, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn little_endian_ignored_by_ordered_formats() {
+        for format in ALL.iter().filter(|f| {
+            !matches!(
+                f,
+                Formats::I32 | Formats::U32 | Formats::F32 | Formats::Hex32 | Formats::Bin32
+            )
+        }) {
+            let Some(value) = sample(format) else {
+                continue;
+            };
+            assert_eq!(
+                encode(value, format, true).unwrap(),
+                encode(value, format, false).unwrap(),
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_formats() {
+        assert_eq!(
+            encode(Value::I16(-1), &Formats::I16, false).unwrap(),
+            [0xFFFF]
+        );
+        assert_eq!(
+            decode(&[0x8000], &Formats::I16, false).unwrap(),
+            Value::I16(i16::MIN)
+        );
+        assert_eq!(
+            decode(&[0x8000], &Formats::U16, false).unwrap(),
+            Value::U16(0x8000)
+        );
+        assert_eq!(
+            encode(Value::Bool(true), &Formats::Bin16, false).unwrap(),
+            [1]
+        );
+        assert_eq!(
+            encode(Value::Bool(false), &Formats::Bin16, false).unwrap(),
+            [0]
+        );
+    }
+
+    #[test]
+    fn decode_uses_first_registers() {
+        assert_eq!(
+            decode(&[0x0001, 0x0002, 0x0003], &Formats::U32, false).unwrap(),
+            Value::U32(0x0001_0002)
+        );
+        assert_eq!(
+            decode(&[7, 8], &Formats::U16, false).unwrap(),
+            Value::U16(7)
+        );
+    }
+
+    #[test]
+    fn decode_too_few_registers() {
+        assert!(decode(&[0x1122], &Formats::U32, false).is_err());
+        assert!(decode(&[0x1122], &Formats::F32dcba, false).is_err());
+        assert!(decode(&[], &Formats::U16, false).is_err());
+    }
+
+    #[test]
+    fn mismatched_value_and_format() {
+        assert!(encode(Value::I32(1), &Formats::U32, false).is_err());
+        assert!(encode(Value::F32(1.0), &Formats::I32, false).is_err());
+        assert!(encode(Value::U16(1), &Formats::I16, false).is_err());
+        assert!(encode(Value::Bool(true), &Formats::U32, false).is_err());
+    }
+
+    #[test]
+    fn string_and_unknown_have_no_encoding() {
+        for format in [Formats::String, Formats::Unkonwn] {
+            assert!(encode(Value::U16(1), &format, false).is_err());
+            assert!(decode(&[1, 2], &format, false).is_err());
+            assert!(Value::parse("1", &format).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_values() {
+        assert_eq!(Value::parse("17", &Formats::U16).unwrap(), Value::U16(17));
+        assert_eq!(
+            Value::parse("0x11", &Formats::Hex16).unwrap(),
+            Value::U16(0x11)
+        );
+        assert_eq!(
+            Value::parse("0b11", &Formats::Bin16).unwrap(),
+            Value::U16(3)
+        );
+        assert_eq!(Value::parse("-5", &Formats::I16).unwrap(), Value::I16(-5));
+        assert_eq!(
+            Value::parse("0xDEADBEEF", &Formats::U32dcba).unwrap(),
+            Value::U32(0xDEAD_BEEF)
+        );
+        assert_eq!(
+            Value::parse("-70000", &Formats::I32cdab).unwrap(),
+            Value::I32(-70000)
+        );
+        assert_eq!(Value::parse("1.5", &Formats::F32).unwrap(), Value::F32(1.5));
+        assert!(Value::parse("-1", &Formats::U16).is_err());
+        assert!(Value::parse("70000", &Formats::U16).is_err());
+        assert!(Value::parse("0x", &Formats::U32).is_err());
+        assert!(Value::parse("1.5", &Formats::I32).is_err());
+    }
+
+    #[test]
+    fn nan_survives_every_order() {
+        for format in [
+            Formats::F32,
+            Formats::F32abcd,
+            Formats::F32badc,
+            Formats::F32cdab,
+            Formats::F32dcba,
+        ] {
+            let words = encode(Value::F32(f32::NAN), &format, false).unwrap();
+            match decode(&words, &format, false).unwrap() {
+                Value::F32(v) => assert!(v.is_nan(), "{:?}", format),
+                v => panic!("{:?}", v),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_consecutive_values() {
+        assert_eq!(
+            decode_all(&[1, 2, 3, 4], &Formats::U32, false).unwrap(),
+            [Value::U32(0x0001_0002), Value::U32(0x0003_0004)]
+        );
+        assert_eq!(
+            decode_all(&[0xFFFF, 2], &Formats::I16, false).unwrap(),
+            [Value::I16(-1), Value::I16(2)]
+        );
+        assert!(decode_all(&[1, 2, 3], &Formats::F32, false).is_err());
+    }
+
+    #[test]
+    fn format_lines() {
+        assert_eq!(format_value(&Value::U16(0x1F), &Formats::Hex16), "0x1F");
+        assert_eq!(
+            format_value(&Value::U32(0x1F), &Formats::Hex32),
+            "0x0000001F"
+        );
+        assert_eq!(
+            format_value(&Value::U16(5), &Formats::Bin16),
+            "0000000000000101"
+        );
+        assert_eq!(
+            format_value(&Value::U16(0xFFFF), &Formats::U16),
+            "65535 (-1)"
+        );
+        assert_eq!(
+            format_value(&Value::U32(0xFFFF_FFFE), &Formats::U32cdab),
+            "4294967294 (-2)"
+        );
+        assert_eq!(format_value(&Value::Bool(true), &Formats::Bin16), "1");
+        assert_eq!(format_value(&Value::F32(-1.5), &Formats::F32), "-1.5");
+
+        assert_eq!(
+            format_values(10, &Formats::F32, &[Value::F32(1.0), Value::F32(2.0)]),
+            "[10(0x0A)]: \t1\n[12(0x0C)]: \t2\n"
+        );
+        assert_eq!(
+            format_values(7, &Formats::U32, &[Value::Bool(false), Value::Bool(true)]),
+            "[7(0x07)]: \t0\n[8(0x08)]: \t1\n"
+        );
+    }
+}
@@ -0,0 +1,392 @@
+use std::{collections::HashMap, fmt, fs::File, io::BufReader};
+
+use anyhow::{Context, Result};
+use clap::CommandFactory;
+
+use crate::{parse_host, Args, Device, DeviceList, Mode, Remote};
+
+/// A mistake in `device_list.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// name of the device, empty if it has none
+    pub device: String,
+    /// JSON path of the offending value, for example `$.device[3].remote.port`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.device.is_empty() {
+            write!(f, "{}: {}", self.path, self.message)
+        } else {
+            write!(f, "{} ({}): {}", self.path, self.device, self.message)
+        }
+    }
+}
+
+pub fn load(path: &str) -> Result<DeviceList> {
+    let conf = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    serde_json::from_reader(BufReader::new(conf)).with_context(|| format!("Invalid {}", path))
+}
+
+/// Mode of a device, `None` for an unknown protocol or mode
+pub fn mode(remote: &Remote) -> Option<Mode> {
+    match remote.protocol.to_lowercase().as_str() {
+        // the mode of IEC104 entries is not used
+        "iec104" => Some(Mode::IEC104),
+        "iec101" => Some(Mode::IEC101),
+        "modbus" => match remote.mode.to_lowercase().as_str() {
+            "tcp" => Some(Mode::Tcp),
+            "tls" => Some(Mode::Tls),
+            "udp" => Some(Mode::Udp),
+            "rtu" => Some(Mode::Rtu),
+            "ascii" => Some(Mode::Ascii),
+            "rtu_in_tcp" => Some(Mode::RtuInTcp),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_serial(mode: Mode) -> bool {
+    matches!(mode, Mode::Rtu | Mode::Ascii | Mode::IEC101)
+}
+
+/// IP address or host name, with or without port
+fn is_host(host: &str) -> bool {
+    parse_host(host).is_some() || parse_host(&format!("{}:0", host)).is_some()
+}
+
+/// `host:port` or the serial port and its settings, for example `/dev/ttyS1 9600 8N1`
+pub fn endpoint(remote: &Remote) -> String {
+    match mode(remote) {
+        Some(mode) if is_serial(mode) => format!(
+            "{} {} {}{}{}",
+            remote.device.as_deref().unwrap_or("-"),
+            remote.baud.unwrap_or(9600),
+            remote
+                .data_bit
+                .unwrap_or(if mode == Mode::Ascii { 7 } else { 8 }),
+            match remote.parity.as_deref().map(str::to_lowercase).as_deref() {
+                Some("even") => 'E',
+                Some("odd") => 'O',
+                None if mode == Mode::Ascii => 'E',
+                _ => 'N',
+            },
+            remote.stop_bit.unwrap_or(1)
+        ),
+        mode => {
+            let host = remote.host.as_deref().unwrap_or("-");
+            let port = remote.port.clone().unwrap_or_else(|| {
+                if mode == Some(Mode::Tls) {
+                    "802"
+                } else {
+                    "502"
+                }
+                .to_string()
+            });
+            if host.contains(':') {
+                format!("[{}]:{}", host, port)
+            } else {
+                format!("{}:{}", host, port)
+            }
+        }
+    }
+}
+
+/// `dpoll <name>` runs the subcommand, a device of that name can not be polled
+fn is_subcommand(name: &str) -> bool {
+    name == "help"
+        || Args::command()
+            .get_subcommands()
+            .any(|c| c.get_name() == name)
+}
+
+/// Everything wrong with the devices of `list`
+pub fn validate(list: &DeviceList) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut names: HashMap<&str, usize> = HashMap::new();
+    for (i, device) in list.devices.iter().enumerate() {
+        let name = device.signature.name.as_str();
+        let mut problem = |field: &str, message: String| {
+            problems.push(Problem {
+                device: name.to_string(),
+                path: format!("$.device[{}].{}", i, field),
+                message,
+            })
+        };
+
+        if name.is_empty() {
+            problem("signature.name", "missing name".to_string());
+        } else if let Some(first) = names.get(name) {
+            problem(
+                "signature.name",
+                format!("duplicate name, also $.device[{}]", first),
+            );
+        } else {
+            names.insert(name, i);
+        }
+        if is_subcommand(name) {
+            problem(
+                "signature.name",
+                format!("name shadowed by `dpoll {}`, rename the device", name),
+            );
+        }
+        check_remote(device, &mut problem);
+    }
+    problems
+}
+
+fn check_remote(device: &Device, problem: &mut impl FnMut(&str, String)) {
+    let remote = &device.remote;
+    if let Some(period) = remote.period_ms {
+        if period <= 10 {
+            problem("remote.period_ms", format!("period {} ms not > 10", period));
+        }
+    }
+    if let Some(timeout) = remote.timeout_ms {
+        // the range of -o, 0.01-10 s
+        if !(10..=10000).contains(&timeout) {
+            problem(
+                "remote.timeout_ms",
+                format!("time-out {} ms not in 10-10000", timeout),
+            );
+        }
+    }
+    let Some(mode) = mode(remote) else {
+        match remote.protocol.to_lowercase().as_str() {
+            "modbus" => problem("remote.mode", format!("unknown mode {:?}", remote.mode)),
+            _ => problem(
+                "remote.protocol",
+                format!("unknown protocol {:?}", remote.protocol),
+            ),
+        }
+        return;
+    };
+
+    if is_serial(mode) {
+        if remote.device.as_deref().unwrap_or_default().is_empty() {
+            problem("remote.device", "missing serial port".to_string());
+        }
+        if let Some(baud) = remote.baud {
+            if !(1200..=921600).contains(&baud) {
+                problem(
+                    "remote.baud",
+                    format!("baudrate {} not in 1200-921600", baud),
+                );
+            }
+        }
+        if let Some(bits) = remote.data_bit {
+            if !(7..=8).contains(&bits) {
+                problem("remote.data_bit", format!("data bits {} not 7 or 8", bits));
+            }
+        }
+        if let Some(bits) = remote.stop_bit {
+            if !(1..=2).contains(&bits) {
+                problem("remote.stop_bit", format!("stop bits {} not 1 or 2", bits));
+            }
+        }
+        if let Some(parity) = &remote.parity {
+            if !matches!(parity.to_lowercase().as_str(), "none" | "even" | "odd") {
+                problem(
+                    "remote.parity",
+                    format!("parity {:?} not none, even or odd", parity),
+                );
+            }
+        }
+        return;
+    }
+
+    match remote.host.as_deref() {
+        None | Some("") => problem("remote.host", "missing host".to_string()),
+        Some(host) if !is_host(host) => problem("remote.host", format!("bad host {:?}", host)),
+        _ => {}
+    }
+    if let Some(port) = &remote.port {
+        if !matches!(port.parse::<u16>(), Ok(1..)) {
+            problem("remote.port", format!("bad port {:?}", port));
+        }
+    }
+    for (i, backup) in remote.backup.iter().flatten().enumerate() {
+        if !is_host(backup) {
+            problem(
+                &format!("remote.backup[{}]", i),
+                format!("bad host {:?}", backup),
+            );
+        }
+    }
+    if mode == Mode::Tls && (remote.ca.is_none() || remote.cert.is_none() || remote.key.is_none()) {
+        problem("remote", "tls needs ca, cert and key".to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(json: &str) -> Vec<(String, String)> {
+        let list: DeviceList = serde_json::from_str(json).unwrap();
+        validate(&list)
+            .into_iter()
+            .map(|p| (p.path, p.message))
+            .collect()
+    }
+
+    fn paths(json: &str) -> Vec<String> {
+        problems(json).into_iter().map(|(path, _)| path).collect()
+    }
+
+    #[test]
+    fn valid_devices() {
+        let json = r#"{"device": [
+            {"signature": {"name": "em1"}, "remote": {"host": "192.168.10.13", "port": "502",
+                "timeout_ms": 10000}},
+            {"signature": {"name": "em2"}, "remote": {"mode": "rtu", "device": "/dev/ttyS1",
+                "baud": 19200, "data_bit": 8, "stop_bit": 2, "parity": "Even"}},
+            {"signature": {"name": "rtu1"}, "remote": {"protocol": "IEC104", "host": "fe80::1",
+                "backup": ["[fe80::2]:2404"], "period_ms": 1000}},
+            {"signature": {"name": "tls1"}, "remote": {"mode": "tls", "host": "plc-01.site.local",
+                "ca": "ca.pem", "cert": "client.pem", "key": "client.key"}}
+        ]}"#;
+        assert_eq!(problems(json), vec![]);
+    }
+
+    #[test]
+    fn names() {
+        let json = r#"{"device": [
+            {"signature": {"name": "em1"}, "remote": {"host": "10.0.0.1"}},
+            {"signature": {"name": ""}, "remote": {"host": "10.0.0.2"}},
+            {"signature": {"name": "em1"}, "remote": {"host": "10.0.0.3"}},
+            {"signature": {"name": "replay"}, "remote": {"host": "10.0.0.4"}},
+            {"signature": {"name": "config"}, "remote": {"host": "10.0.0.5"}}
+        ]}"#;
+        let found = problems(json);
+        assert_eq!(found.len(), 4);
+        assert_eq!(found[0].0, "$.device[1].signature.name");
+        assert_eq!(
+            found[1],
+            (
+                "$.device[2].signature.name".to_string(),
+                "duplicate name, also $.device[0]".to_string()
+            )
+        );
+        assert_eq!(found[2].0, "$.device[3].signature.name");
+        assert!(found[2].1.contains("dpoll replay"));
+        assert_eq!(found[3].0, "$.device[4].signature.name");
+    }
+
+    #[test]
+    fn hosts_and_ports() {
+        let json = r#"{"device": [
+            {"signature": {"name": "a"}, "remote": {"port": "502"}},
+            {"signature": {"name": "b"}, "remote": {"host": "10.0.0.1", "port": "0"}},
+            {"signature": {"name": "c"}, "remote": {"host": "10.0.0.1", "port": "65536"}},
+            {"signature": {"name": "d"}, "remote": {"host": "10.0.0.1", "port": "x"}},
+            {"signature": {"name": "e"}, "remote": {"host": "bad host"}},
+            {"signature": {"name": "f"}, "remote": {"protocol": "iec104", "host": "10.0.0.1",
+                "backup": ["10.0.0.2", "bad host"]}}
+        ]}"#;
+        assert_eq!(
+            paths(json),
+            vec![
+                "$.device[0].remote.host",
+                "$.device[1].remote.port",
+                "$.device[2].remote.port",
+                "$.device[3].remote.port",
+                "$.device[4].remote.host",
+                "$.device[5].remote.backup[1]",
+            ]
+        );
+    }
+
+    #[test]
+    fn protocols_and_modes() {
+        let json = r#"{"device": [
+            {"signature": {"name": "a"}, "remote": {"protocol": "bacnet", "host": "10.0.0.1"}},
+            {"signature": {"name": "b"}, "remote": {"mode": "rtu_over_udp", "host": "10.0.0.1"}},
+            {"signature": {"name": "c"}, "remote": {"protocol": "Modbus", "mode": "RTU_IN_TCP",
+                "host": "10.0.0.1"}}
+        ]}"#;
+        assert_eq!(
+            problems(json),
+            vec![
+                (
+                    "$.device[0].remote.protocol".to_string(),
+                    "unknown protocol \"bacnet\"".to_string()
+                ),
+                (
+                    "$.device[1].remote.mode".to_string(),
+                    "unknown mode \"rtu_over_udp\"".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn serial_settings() {
+        let json = r#"{"device": [
+            {"signature": {"name": "a"}, "remote": {"mode": "rtu"}},
+            {"signature": {"name": "b"}, "remote": {"mode": "ascii", "device": "COM1", "baud": 300,
+                "data_bit": 9, "stop_bit": 0, "parity": "mark"}},
+            {"signature": {"name": "c"}, "remote": {"protocol": "iec101", "device": "/dev/ttyS0",
+                "baud": 921601}}
+        ]}"#;
+        assert_eq!(
+            paths(json),
+            vec![
+                "$.device[0].remote.device",
+                "$.device[1].remote.baud",
+                "$.device[1].remote.data_bit",
+                "$.device[1].remote.stop_bit",
+                "$.device[1].remote.parity",
+                "$.device[2].remote.baud",
+            ]
+        );
+    }
+
+    #[test]
+    fn period_and_tls() {
+        let json = r#"{"device": [
+            {"signature": {"name": "a"}, "remote": {"host": "10.0.0.1", "period_ms": 10}},
+            {"signature": {"name": "b"}, "remote": {"mode": "tls", "host": "10.0.0.1",
+                "ca": "ca.pem", "cert": "client.pem"}}
+        ]}"#;
+        assert_eq!(
+            problems(json),
+            vec![
+                (
+                    "$.device[0].remote.period_ms".to_string(),
+                    "period 10 ms not > 10".to_string()
+                ),
+                (
+                    "$.device[1].remote".to_string(),
+                    "tls needs ca, cert and key".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn timeouts() {
+        let json = r#"{"device": [
+            {"signature": {"name": "a"}, "remote": {"host": "10.0.0.1", "timeout_ms": 9}},
+            {"signature": {"name": "b"}, "remote": {"host": "10.0.0.1", "timeout_ms": 10}},
+            {"signature": {"name": "c"}, "remote": {"mode": "rtu", "device": "/dev/ttyS1",
+                "timeout_ms": 10001}}
+        ]}"#;
+        assert_eq!(
+            problems(json),
+            vec![
+                (
+                    "$.device[0].remote.timeout_ms".to_string(),
+                    "time-out 9 ms not in 10-10000".to_string()
+                ),
+                (
+                    "$.device[2].remote.timeout_ms".to_string(),
+                    "time-out 10001 ms not in 10-10000".to_string()
+                ),
+            ]
+        );
+    }
+}
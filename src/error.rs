@@ -0,0 +1,244 @@
+use std::{fmt, io};
+
+use tokio_modbus::ExceptionCode;
+
+use crate::{iec104_client::CommandPhase, modbus_pdu};
+
+/// Why a request failed, returned by [`Session`](crate::session::Session) calls
+#[derive(Debug)]
+pub enum Error {
+    /// connecting failed or the connection broke
+    Transport(io::Error),
+    /// no response in time
+    Timeout,
+    /// exception response of a Modbus device
+    Exception(ExceptionCode),
+    /// negative confirmation (ActCon-) of an IEC104 command
+    Rejected { addr: u16, phase: CommandPhase },
+}
+
+impl Error {
+    /// Request that can not be sent, e.g. to a table the protocol doesn't have
+    pub fn invalid_input(msg: impl Into<String>) -> Self {
+        Error::Transport(io::Error::new(io::ErrorKind::InvalidInput, msg.into()))
+    }
+
+    /// Exit code of the process when a request failed with this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Transport(_) => 3,
+            Error::Timeout => 4,
+            Error::Rejected { .. } => 5,
+            Error::Exception(code) => match modbus_pdu::exception_value(*code) {
+                code @ 0x01..=0x0B => 10 + code as i32,
+                _ => 30,
+            },
+        }
+    }
+}
+
+/// 1 unless `e` is an [`Error`]
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    e.downcast_ref::<Error>().map_or(1, Error::exit_code)
+}
+
+pub fn exception_name(code: ExceptionCode) -> &'static str {
+    match code {
+        ExceptionCode::IllegalFunction => "Illegal Function",
+        ExceptionCode::IllegalDataAddress => "Illegal Data Address",
+        ExceptionCode::IllegalDataValue => "Illegal Data Value",
+        ExceptionCode::ServerDeviceFailure => "Server Device Failure",
+        ExceptionCode::Acknowledge => "Acknowledge",
+        ExceptionCode::ServerDeviceBusy => "Server Device Busy",
+        ExceptionCode::MemoryParityError => "Memory Parity Error",
+        ExceptionCode::GatewayPathUnavailable => "Gateway Path Unavailable",
+        ExceptionCode::GatewayTargetDevice => "Gateway Target Device Failed to Respond",
+        ExceptionCode::Custom(_) => "Unknown Exception",
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "{}", e),
+            Error::Timeout => write!(f, "timeout"),
+            Error::Exception(code) => write!(
+                f,
+                "exception {:#04X} {}",
+                modbus_pdu::exception_value(*code),
+                exception_name(*code)
+            ),
+            Error::Rejected { addr, phase } => {
+                write!(f, "command {}: {:?} ActCon- (rejected)", addr, phase)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Wrong checksum of a received frame, the source of its [`io::ErrorKind::InvalidData`] error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumError {
+    Crc { expected: u16, actual: u16 },
+    Lrc { expected: u8, actual: u8 },
+}
+
+impl ChecksumError {
+    /// Whether `e` was caused by a wrong checksum
+    pub fn is_source_of(e: &io::Error) -> bool {
+        e.kind() == io::ErrorKind::InvalidData
+            && e.get_ref().is_some_and(|e| e.is::<ChecksumError>())
+    }
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumError::Crc { expected, actual } => write!(
+                f,
+                "CRC mismatch: expected {:#06X}, got {:#06X}",
+                expected, actual
+            ),
+            ChecksumError::Lrc { expected, actual } => write!(
+                f,
+                "LRC mismatch: expected {:#04X}, got {:#04X}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChecksumError {}
+
+impl From<ChecksumError> for io::Error {
+    fn from(e: ChecksumError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Transport(e)
+    }
+}
+
+impl From<tokio_modbus::Error> for Error {
+    fn from(e: tokio_modbus::Error) -> Self {
+        match e {
+            // e.g. UDP after the last retry
+            tokio_modbus::Error::Transport(e) if e.kind() == io::ErrorKind::TimedOut => {
+                Error::Timeout
+            }
+            tokio_modbus::Error::Transport(e) => Error::Transport(e),
+            e => Error::Transport(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        }
+    }
+}
+
+impl From<tokio_iecp5::Error> for Error {
+    fn from(e: tokio_iecp5::Error) -> Self {
+        match is_timeout(&e) {
+            true => Error::Timeout,
+            false => Error::Transport(io::Error::other(e.to_string())),
+        }
+    }
+}
+
+/// Whether `e` or one of its sources is an elapsed timer or an I/O timeout
+fn is_timeout(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        let timed_out = e
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut);
+        if timed_out || e.is::<tokio::time::error::Elapsed>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(Error::Transport(io::Error::other("closed")).exit_code(), 3);
+        assert_eq!(Error::Timeout.exit_code(), 4);
+        let rejected = Error::Rejected {
+            addr: 1,
+            phase: CommandPhase::Select,
+        };
+        assert_eq!(rejected.exit_code(), 5);
+        assert_eq!(
+            Error::Exception(ExceptionCode::IllegalFunction).exit_code(),
+            11
+        );
+        assert_eq!(
+            Error::Exception(ExceptionCode::GatewayTargetDevice).exit_code(),
+            21
+        );
+        assert_eq!(
+            Error::Exception(ExceptionCode::Custom(0x42)).exit_code(),
+            30
+        );
+
+        assert_eq!(exit_code(&Error::Timeout.into()), 4);
+        let wrapped = anyhow::Error::from(Error::Timeout).context("Read 1");
+        assert_eq!(exit_code(&wrapped), 4);
+        assert_eq!(exit_code(&anyhow::anyhow!("bad argument")), 1);
+    }
+
+    #[test]
+    fn modbus_errors() {
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "no response");
+        assert!(matches!(
+            Error::from(tokio_modbus::Error::Transport(timed_out)),
+            Error::Timeout
+        ));
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        match Error::from(tokio_modbus::Error::Transport(reset)) {
+            Error::Transport(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+            e => panic!("{:?}", e),
+        }
+        // the source of a checksum error is kept
+        let checksum = ChecksumError::Crc {
+            expected: 1,
+            actual: 2,
+        };
+        match Error::from(tokio_modbus::Error::Transport(checksum.into())) {
+            Error::Transport(e) => assert!(ChecksumError::is_source_of(&e)),
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[derive(Debug)]
+    struct Wrapped(io::Error);
+
+    impl fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "link: {}", self.0)
+        }
+    }
+
+    impl std::error::Error for Wrapped {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn timeouts_in_the_source_chain() {
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "t1");
+        assert!(is_timeout(&Wrapped(timed_out)));
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        assert!(!is_timeout(&Wrapped(refused)));
+        let elapsed = tokio::time::timeout(std::time::Duration::ZERO, std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        assert!(is_timeout(&elapsed));
+    }
+}
@@ -0,0 +1,860 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use log::error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    time::{sleep, timeout_at, Instant},
+};
+use tokio_iecp5::{
+    asdu::{Asdu, TypeID},
+    ClientHandler,
+};
+use tokio_serial::SerialPortBuilder;
+
+use crate::{
+    error::Error,
+    iec104_client::{
+        no_command, CommandOption, CommandOutcome, IEC104ClientHandler, Point, PointSpace,
+    },
+};
+
+const START_FIXED: u8 = 0x10;
+const START_VARIABLE: u8 = 0x68;
+const END: u8 = 0x16;
+const SINGLE_ACK: u8 = 0xE5;
+
+// control field
+const DIR: u8 = 0x80;
+const PRM: u8 = 0x40;
+/// frame count bit (primary) / access demand, class 1 data available (secondary)
+const FCB: u8 = 0x20;
+const ACD: u8 = 0x20;
+const FCV: u8 = 0x10;
+
+// function codes of primary frames
+const FC_RESET_LINK: u8 = 0;
+const FC_TEST_LINK: u8 = 2;
+const FC_USER_DATA_CONFIRMED: u8 = 3;
+const FC_USER_DATA_NO_REPLY: u8 = 4;
+const FC_REQUEST_STATUS: u8 = 9;
+const FC_REQUEST_CLASS1: u8 = 10;
+const FC_REQUEST_CLASS2: u8 = 11;
+
+// function codes of secondary frames
+const FC_ACK: u8 = 0;
+const FC_STATUS: u8 = 11;
+const FC_NOT_IMPLEMENTED: u8 = 15;
+
+const C_SC_NA_1: u8 = 45;
+const C_DC_NA_1: u8 = 46;
+const C_SE_NA_1: u8 = 48;
+const C_SE_NB_1: u8 = 49;
+const C_SE_NC_1: u8 = 50;
+const C_BO_NA_1: u8 = 51;
+const C_IC_NA_1: u8 = 100;
+const C_CI_NA_1: u8 = 101;
+const COT_ACTIVATION: u8 = 6;
+/// select bit of the command qualifiers (S/E)
+const SELECT: u8 = 0x80;
+
+/// transmissions of a primary frame before the link is considered lost
+const RETRIES: usize = 3;
+const INTERROGATION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Link transmission procedure
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkMode {
+    /// the outstation only answers, class 1/class 2 data is polled
+    #[default]
+    Unbalanced,
+    /// both stations initiate transmissions
+    Balanced,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IEC101Option {
+    pub link_mode: LinkMode,
+    pub link_addr: u16,
+    /// octets of the link address, 0 is only allowed in balanced mode
+    pub link_addr_size: usize,
+    pub cot_size: usize,
+    pub ca_size: usize,
+    pub ioa_size: usize,
+    /// common address of ASDU, the station address used by the application layer
+    pub common_addr: u16,
+    /// time the secondary station has to answer a primary frame
+    pub timeout: Duration,
+    /// interval between class 2 requests when the outstation has no class 1 data
+    pub poll_interval: Duration,
+}
+
+impl Default for IEC101Option {
+    fn default() -> Self {
+        IEC101Option {
+            link_mode: LinkMode::Unbalanced,
+            link_addr: 1,
+            link_addr_size: 1,
+            cot_size: 1,
+            ca_size: 1,
+            ioa_size: 2,
+            common_addr: 1,
+            timeout: Duration::from_secs(1),
+            poll_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+/// IEC 60870-5-101 controlling station, the received ASDUs are handled like IEC104 ones
+pub struct IEC101Client {
+    option: IEC101Option,
+    command_option: CommandOption,
+    inner: Arc<IEC104ClientHandler>,
+    commands_tx: mpsc::Sender<Vec<u8>>,
+    commands_rx: Mutex<Option<mpsc::Receiver<Vec<u8>>>>,
+    serial: Option<SerialPortBuilder>,
+}
+
+impl IEC101Client {
+    pub fn new(option: IEC101Option) -> Self {
+        let (commands_tx, commands_rx) = mpsc::channel(16);
+        IEC101Client {
+            option,
+            command_option: CommandOption::default(),
+            inner: Arc::new(IEC104ClientHandler::new()),
+            commands_tx,
+            commands_rx: Mutex::new(Some(commands_rx)),
+            serial: None,
+        }
+    }
+
+    pub fn set_command_option(&mut self, op: CommandOption) {
+        self.command_option = op;
+    }
+
+    /// Serial port a [`Session`](crate::session::Session) connects to
+    pub fn set_serial(&mut self, builder: SerialPortBuilder) {
+        self.serial = Some(builder);
+    }
+
+    pub fn serial(&self) -> Option<&SerialPortBuilder> {
+        self.serial.as_ref()
+    }
+
+    /// False until the link layer was started, it runs only once
+    pub fn is_started(&self) -> bool {
+        self.commands_rx.lock().unwrap().is_none()
+    }
+
+    /// Run the link layer on `port` in the background until the port fails
+    pub fn start<T>(&self, port: T)
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let Some(commands) = self.commands_rx.lock().unwrap().take() else {
+            return;
+        };
+        let link = Link {
+            port,
+            option: self.option,
+            handler: self.inner.clone(),
+            commands,
+            buf: Vec::new(),
+            fcb: false,
+            remote_fcb: None,
+            class1: false,
+        };
+        tokio::spawn(link.run());
+    }
+
+    pub async fn write_siq(&self, addr: u16, v: bool) -> Result<CommandOutcome, Error> {
+        let qu = self.command_option.qualifier.value() << 2;
+        self.command(TypeID::C_SC_NA_1, C_SC_NA_1, addr, true, |select| {
+            vec![v as u8 | qu | if select { SELECT } else { 0 }]
+        })
+        .await
+    }
+
+    pub async fn write_diq(&self, addr: u16, v: u8) -> Result<CommandOutcome, Error> {
+        let qu = self.command_option.qualifier.value() << 2;
+        self.command(TypeID::C_DC_NA_1, C_DC_NA_1, addr, true, |select| {
+            vec![(v % 4) | qu | if select { SELECT } else { 0 }]
+        })
+        .await
+    }
+
+    pub async fn write_nva(&self, addr: u16, v: i16) -> Result<CommandOutcome, Error> {
+        self.command(TypeID::C_SE_NA_1, C_SE_NA_1, addr, true, |select| {
+            setpoint(&v.to_le_bytes(), select)
+        })
+        .await
+    }
+
+    pub async fn write_sva(&self, addr: u16, v: i16) -> Result<CommandOutcome, Error> {
+        self.command(TypeID::C_SE_NB_1, C_SE_NB_1, addr, true, |select| {
+            setpoint(&v.to_le_bytes(), select)
+        })
+        .await
+    }
+
+    pub async fn write_r(&self, addr: u16, v: f32) -> Result<CommandOutcome, Error> {
+        self.command(TypeID::C_SE_NC_1, C_SE_NC_1, addr, true, |select| {
+            setpoint(&v.to_le_bytes(), select)
+        })
+        .await
+    }
+
+    pub async fn write_bcr(&self, addr: u16, v: i32) -> Result<CommandOutcome, Error> {
+        // bitstring commands have no S/E bit, always direct execute
+        self.command(TypeID::C_BO_NA_1, C_BO_NA_1, addr, false, |_| {
+            v.to_le_bytes().to_vec()
+        })
+        .await
+    }
+
+    /// Send the command of the type of `point`
+    pub async fn write_point(&self, addr: u16, point: Point) -> Result<CommandOutcome, Error> {
+        match point {
+            Point::Siq(v) => self.write_siq(addr, v).await,
+            Point::Diq(v) => self.write_diq(addr, v).await,
+            Point::Nva(v) => self.write_nva(addr, v).await,
+            Point::Sva(v) => self.write_sva(addr, v).await,
+            Point::R(v) => self.write_r(addr, v).await,
+            Point::Bcr(v) => self.write_bcr(addr, v).await,
+            p => Err(no_command(p)),
+        }
+    }
+
+    /// Queue the command ASDUs for the link and wait for the confirmations, `element` is the
+    /// information element of the select (true) or execute (false) command
+    async fn command(
+        &self,
+        type_id: TypeID,
+        code: u8,
+        addr: u16,
+        selectable: bool,
+        element: impl Fn(bool) -> Vec<u8>,
+    ) -> Result<CommandOutcome, Error> {
+        if self.option.ioa_size < 3 && addr as u32 >= 1 << (8 * self.option.ioa_size) {
+            return Err(Error::Transport(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "IOA {} does not fit in {} octets",
+                    addr, self.option.ioa_size
+                ),
+            )));
+        }
+        let option = self.command_option;
+        self.inner
+            .command(&option, type_id, addr, selectable, |select| {
+                let asdu = self.option.asdu(code, addr, &element(select));
+                async move {
+                    self.commands_tx.send(asdu).await.map_err(|_| {
+                        Error::Transport(io::Error::new(
+                            io::ErrorKind::NotConnected,
+                            "IEC101 link stopped",
+                        ))
+                    })
+                }
+            })
+            .await
+    }
+}
+
+/// Information element of a setpoint command, the value followed by the QOS
+fn setpoint(value: &[u8], select: bool) -> Vec<u8> {
+    let mut element = value.to_vec();
+    element.push(if select { SELECT } else { 0 });
+    element
+}
+
+impl PointSpace for IEC101Client {
+    fn handler(&self) -> &IEC104ClientHandler {
+        &self.inner
+    }
+}
+
+/// FT 1.2 frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Frame {
+    /// single character acknowledgement
+    Ack,
+    Fixed {
+        control: u8,
+        addr: u16,
+    },
+    Variable {
+        control: u8,
+        addr: u16,
+        asdu: Vec<u8>,
+    },
+}
+
+impl Frame {
+    fn control(&self) -> u8 {
+        match self {
+            Frame::Ack => FC_ACK,
+            Frame::Fixed { control, .. } | Frame::Variable { control, .. } => *control,
+        }
+    }
+
+    fn function(&self) -> u8 {
+        self.control() & 0x0F
+    }
+
+    fn is_primary(&self) -> bool {
+        self.control() & PRM != 0
+    }
+
+    fn encode(&self, addr_size: usize) -> Vec<u8> {
+        match self {
+            Frame::Ack => vec![SINGLE_ACK],
+            Frame::Fixed { control, addr } => {
+                let mut buf = vec![START_FIXED, *control];
+                buf.extend_from_slice(&addr.to_le_bytes()[..addr_size]);
+                buf.push(checksum(&buf[1..]));
+                buf.push(END);
+                buf
+            }
+            Frame::Variable {
+                control,
+                addr,
+                asdu,
+            } => {
+                let len = (1 + addr_size + asdu.len()) as u8;
+                let mut buf = vec![START_VARIABLE, len, len, START_VARIABLE, *control];
+                buf.extend_from_slice(&addr.to_le_bytes()[..addr_size]);
+                buf.extend_from_slice(asdu);
+                buf.push(checksum(&buf[4..]));
+                buf.push(END);
+                buf
+            }
+        }
+    }
+
+    /// Take the next frame out of `buf`, `None` until it is complete. Bytes that do not start a
+    /// valid frame are dropped.
+    fn decode(buf: &mut Vec<u8>, addr_size: usize) -> Option<Frame> {
+        loop {
+            let start = *buf.first()?;
+            match start {
+                SINGLE_ACK => {
+                    buf.drain(..1);
+                    return Some(Frame::Ack);
+                }
+                START_FIXED => {
+                    let len = 4 + addr_size;
+                    if buf.len() < len {
+                        return None;
+                    }
+                    if buf[len - 1] == END && checksum(&buf[1..len - 2]) == buf[len - 2] {
+                        let frame = Frame::Fixed {
+                            control: buf[1],
+                            addr: le_addr(&buf[2..2 + addr_size]),
+                        };
+                        buf.drain(..len);
+                        return Some(frame);
+                    }
+                }
+                START_VARIABLE => {
+                    if buf.len() < 4 {
+                        return None;
+                    }
+                    let l = buf[1] as usize;
+                    if buf[2] == buf[1] && buf[3] == START_VARIABLE && l > addr_size {
+                        let len = l + 6;
+                        if buf.len() < len {
+                            return None;
+                        }
+                        if buf[len - 1] == END && checksum(&buf[4..len - 2]) == buf[len - 2] {
+                            let frame = Frame::Variable {
+                                control: buf[4],
+                                addr: le_addr(&buf[5..5 + addr_size]),
+                                asdu: buf[5 + addr_size..len - 2].to_vec(),
+                            };
+                            buf.drain(..len);
+                            return Some(frame);
+                        }
+                    }
+                }
+                _ => {}
+            }
+            log::warn!("IEC101 invalid frame, byte {:#04X} dropped", start);
+            buf.drain(..1);
+        }
+    }
+}
+
+impl IEC101Option {
+    /// ASDU of a single activation to `ioa`, sized as configured
+    fn asdu(&self, type_id: u8, ioa: u16, element: &[u8]) -> Vec<u8> {
+        let mut asdu = vec![type_id, 0x01, COT_ACTIVATION];
+        if self.cot_size == 2 {
+            asdu.push(0);
+        }
+        asdu.extend_from_slice(&self.common_addr.to_le_bytes()[..self.ca_size]);
+        let ioa = (ioa as u32).to_le_bytes();
+        asdu.extend_from_slice(&ioa[..self.ioa_size]);
+        asdu.extend_from_slice(element);
+        asdu
+    }
+
+    /// Widen COT and common address to 2 octets and IOA to 3 octets, the IEC104 layout
+    fn normalize(&self, asdu: &[u8]) -> Option<Vec<u8>> {
+        let header = 2 + self.cot_size + self.ca_size;
+        if asdu.len() < header {
+            return None;
+        }
+        let size = element_size(asdu[0])?;
+        let n = (asdu[1] & 0x7F) as usize;
+        // SQ = 1: one IOA followed by n elements
+        let (objects, elements) = if asdu[1] & 0x80 != 0 { (1, n) } else { (n, 1) };
+
+        let mut out = Vec::with_capacity(asdu.len() + 2 + n * 3);
+        out.extend_from_slice(&asdu[..3]);
+        // originator address
+        out.push(if self.cot_size == 2 { asdu[3] } else { 0 });
+        out.push(asdu[2 + self.cot_size]);
+        out.push(if self.ca_size == 2 {
+            asdu[3 + self.cot_size]
+        } else {
+            0
+        });
+        let mut pos = header;
+        for _ in 0..objects {
+            out.extend_from_slice(asdu.get(pos..pos + self.ioa_size)?);
+            out.resize(out.len() + 3 - self.ioa_size, 0);
+            pos += self.ioa_size;
+            out.extend_from_slice(asdu.get(pos..pos + size * elements)?);
+            pos += size * elements;
+        }
+        Some(out)
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+fn le_addr(data: &[u8]) -> u16 {
+    data.iter().rev().fold(0, |addr, b| addr << 8 | *b as u16)
+}
+
+/// Size of one information element (without IOA) per type identification
+fn element_size(type_id: u8) -> Option<usize> {
+    let size = match type_id {
+        1 | 3 => 1,                    // M_SP_NA_1, M_DP_NA_1
+        2 | 4 => 4,                    // M_SP_TA_1, M_DP_TA_1
+        5 => 2,                        // M_ST_NA_1
+        6 => 5,                        // M_ST_TA_1
+        7 | 13 | 15 | 20 => 5,         // M_BO_NA_1, M_ME_NC_1, M_IT_NA_1, M_PS_NA_1
+        8 | 14 | 16 => 8,              // M_BO_TA_1, M_ME_TC_1, M_IT_TA_1
+        9 | 11 => 3,                   // M_ME_NA_1, M_ME_NB_1
+        10 | 12 | 17 => 6,             // M_ME_TA_1, M_ME_TB_1, M_EP_TA_1
+        18 | 19 => 7,                  // M_EP_TB_1, M_EP_TC_1
+        21 => 2,                       // M_ME_ND_1
+        30 | 31 => 8,                  // M_SP_TB_1, M_DP_TB_1
+        32 => 9,                       // M_ST_TB_1
+        33 | 36 | 37 => 12,            // M_BO_TB_1, M_ME_TF_1, M_IT_TB_1
+        34 | 35 | 38 => 10,            // M_ME_TD_1, M_ME_TE_1, M_EP_TD_1
+        39 | 40 => 11,                 // M_EP_TE_1, M_EP_TF_1
+        45..=47 | 70 | 100 | 101 => 1, // C_SC/C_DC/C_RC_NA_1, M_EI_NA_1, C_IC/C_CI_NA_1
+        48 | 49 => 3,                  // C_SE_NA_1, C_SE_NB_1
+        50 => 5,                       // C_SE_NC_1
+        51 => 4,                       // C_BO_NA_1
+        102 => 0,                      // C_RD_NA_1
+        103 => 7,                      // C_CS_NA_1
+        _ => return None,
+    };
+    Some(size)
+}
+
+struct Link<T> {
+    port: T,
+    option: IEC101Option,
+    handler: Arc<IEC104ClientHandler>,
+    /// command ASDUs to send
+    commands: mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    /// FCB of the next primary frame we send
+    fcb: bool,
+    /// FCB of the last primary frame received (balanced), to detect repetitions
+    remote_fcb: Option<bool>,
+    /// the outstation has class 1 data (unbalanced)
+    class1: bool,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Link<T> {
+    async fn run(mut self) {
+        loop {
+            if let Err(e) = self.session().await {
+                error!("IEC101 port error: {}", e);
+                return;
+            }
+            sleep(self.option.timeout).await;
+        }
+    }
+
+    /// Initialize the link and keep it polled, returns when the link is lost
+    async fn session(&mut self) -> io::Result<()> {
+        if !self.init().await? {
+            return Ok(());
+        }
+        let mut interrogated: Option<Instant> = None;
+        loop {
+            // commands go out first and between polls, in balanced mode after at most one receive timeout
+            while let Ok(asdu) = self.commands.try_recv() {
+                if !self.send_asdu(asdu).await? {
+                    return Ok(());
+                }
+            }
+            let due = match interrogated {
+                Some(t) => t.elapsed() >= INTERROGATION_INTERVAL,
+                None => true,
+            };
+            if due {
+                if !self.send_asdu(self.system_command(C_CI_NA_1, 0x05)).await? {
+                    return Ok(());
+                }
+                log::info!("IEC101 TRIGGER: Interrogation CUM");
+                if !self.send_asdu(self.system_command(C_IC_NA_1, 20)).await? {
+                    return Ok(());
+                }
+                log::info!("IEC101 TRIGGER: Interrogation ALL");
+                interrogated = Some(Instant::now());
+            }
+
+            match self.option.link_mode {
+                LinkMode::Unbalanced => {
+                    let fc = if self.class1 {
+                        FC_REQUEST_CLASS1
+                    } else {
+                        FC_REQUEST_CLASS2
+                    };
+                    match self.request(fc, None).await? {
+                        Some(reply) => self.on_secondary(reply).await,
+                        None => {
+                            log::warn!("IEC101 link {} lost", self.option.link_addr);
+                            return Ok(());
+                        }
+                    }
+                    if !self.class1 {
+                        sleep(self.option.poll_interval).await;
+                    }
+                }
+                LinkMode::Balanced => {
+                    if let Some(frame) = self.receive().await? {
+                        if frame.is_primary() {
+                            self.on_primary(frame).await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Request the link status and reset the link of the outstation
+    async fn init(&mut self) -> io::Result<bool> {
+        match self.request(FC_REQUEST_STATUS, None).await? {
+            Some(reply) if reply.function() == FC_STATUS => {}
+            _ => return Ok(false),
+        }
+        match self.request(FC_RESET_LINK, None).await? {
+            Some(reply) if reply.function() == FC_ACK => {}
+            _ => return Ok(false),
+        }
+        // the first frame after a reset has FCB set
+        self.fcb = true;
+        self.class1 = false;
+        log::info!("IEC101 TRIGGER: link {} reset", self.option.link_addr);
+        Ok(true)
+    }
+
+    async fn send_asdu(&mut self, asdu: Vec<u8>) -> io::Result<bool> {
+        match self.request(FC_USER_DATA_CONFIRMED, Some(asdu)).await? {
+            Some(reply) => {
+                let confirmed = reply.function() == FC_ACK;
+                self.on_secondary(reply).await;
+                Ok(confirmed)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Send a primary frame and wait for the answer of the outstation. Frames with FCV are
+    /// repeated with the same FCB, which toggles once the frame is answered.
+    async fn request(&mut self, fc: u8, asdu: Option<Vec<u8>>) -> io::Result<Option<Frame>> {
+        let fcv = matches!(
+            fc,
+            FC_TEST_LINK | FC_USER_DATA_CONFIRMED | FC_REQUEST_CLASS1 | FC_REQUEST_CLASS2
+        );
+        let mut control = self.dir() | PRM | fc;
+        if fcv {
+            control |= FCV;
+            if self.fcb {
+                control |= FCB;
+            }
+        }
+        let addr = self.option.link_addr;
+        let frame = match asdu {
+            Some(asdu) => Frame::Variable {
+                control,
+                addr,
+                asdu,
+            },
+            None => Frame::Fixed { control, addr },
+        };
+
+        for _ in 0..RETRIES {
+            self.send(&frame).await?;
+            while let Some(reply) = self.receive().await? {
+                if reply.is_primary() {
+                    // balanced, the outstation sent something on its own
+                    self.on_primary(reply).await?;
+                    continue;
+                }
+                if fcv {
+                    self.fcb = !self.fcb;
+                }
+                return Ok(Some(reply));
+            }
+            log::warn!("IEC101 no answer to function {}, repeat", fc);
+        }
+        Ok(None)
+    }
+
+    /// Answer a frame initiated by the outstation, balanced mode only
+    async fn on_primary(&mut self, frame: Frame) -> io::Result<()> {
+        let control = frame.control();
+        let answer = match frame.function() {
+            FC_RESET_LINK => {
+                self.remote_fcb = None;
+                Some(FC_ACK)
+            }
+            FC_TEST_LINK | FC_USER_DATA_CONFIRMED => Some(FC_ACK),
+            FC_USER_DATA_NO_REPLY => None,
+            FC_REQUEST_STATUS => Some(FC_STATUS),
+            _ => Some(FC_NOT_IMPLEMENTED),
+        };
+        // a repetition has the same FCB as the previous frame, only acknowledge it again
+        let mut repeated = false;
+        if control & FCV != 0 {
+            let fcb = control & FCB != 0;
+            repeated = self.remote_fcb == Some(fcb);
+            self.remote_fcb = Some(fcb);
+        }
+        if let Some(fc) = answer {
+            let reply = Frame::Fixed {
+                control: self.dir() | fc,
+                addr: self.option.link_addr,
+            };
+            self.send(&reply).await?;
+        }
+        if let Frame::Variable { asdu, .. } = frame {
+            if !repeated {
+                self.on_asdu(&asdu).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_secondary(&mut self, frame: Frame) {
+        if self.option.link_mode == LinkMode::Unbalanced {
+            self.class1 = frame != Frame::Ack && frame.control() & ACD != 0;
+        }
+        if let Frame::Variable { asdu, .. } = frame {
+            self.on_asdu(&asdu).await;
+        }
+    }
+
+    async fn on_asdu(&mut self, data: &[u8]) {
+        let Some(data) = self.option.normalize(data) else {
+            log::warn!("IEC101 unsupported ASDU {:02X?}", data);
+            return;
+        };
+        match Asdu::try_from(Bytes::from(data)) {
+            Ok(asdu) => {
+                if let Err(e) = self.handler.call(asdu).await {
+                    error!("Error while processing IEC101 ASDU: {}", e);
+                }
+            }
+            Err(e) => error!("IEC101 invalid ASDU: {}", e),
+        }
+    }
+
+    /// Activation of a station wide command with IOA 0 and the qualifier
+    fn system_command(&self, type_id: u8, qualifier: u8) -> Vec<u8> {
+        self.option.asdu(type_id, 0, &[qualifier])
+    }
+
+    fn dir(&self) -> u8 {
+        match self.option.link_mode {
+            LinkMode::Balanced => DIR,
+            LinkMode::Unbalanced => 0,
+        }
+    }
+
+    async fn send(&mut self, frame: &Frame) -> io::Result<()> {
+        let data = frame.encode(self.option.link_addr_size);
+        log::debug!("IEC101 TX {:02X?}", data);
+        self.port.write_all(&data).await
+    }
+
+    /// Next frame from the outstation, `None` on timeout
+    async fn receive(&mut self) -> io::Result<Option<Frame>> {
+        let deadline = Instant::now() + self.option.timeout;
+        loop {
+            if let Some(frame) = Frame::decode(&mut self.buf, self.option.link_addr_size) {
+                log::debug!("IEC101 RX {:?}", frame);
+                return Ok(Some(frame));
+            }
+            let mut chunk = [0u8; 256];
+            match timeout_at(deadline, self.port.read(&mut chunk)).await {
+                Ok(Ok(0)) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(Ok(n)) => self.buf.extend_from_slice(&chunk[..n]),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_wraps() {
+        assert_eq!(checksum(&[]), 0);
+        assert_eq!(checksum(&[0x53, 0x01]), 0x54);
+        assert_eq!(checksum(&[0xFF, 0x02]), 0x01);
+    }
+
+    #[test]
+    fn fixed_frame() {
+        let frame = Frame::Fixed {
+            control: PRM | FC_REQUEST_STATUS,
+            addr: 1,
+        };
+        let data = frame.encode(1);
+        assert_eq!(data, [0x10, 0x49, 0x01, 0x4A, 0x16]);
+        let mut buf = data.clone();
+        assert_eq!(Frame::decode(&mut buf, 1), Some(frame));
+        assert!(buf.is_empty());
+
+        let frame = Frame::Fixed {
+            control: FC_ACK,
+            addr: 0x0102,
+        };
+        let mut buf = frame.encode(2);
+        assert_eq!(buf, [0x10, 0x00, 0x02, 0x01, 0x03, 0x16]);
+        assert_eq!(Frame::decode(&mut buf, 2), Some(frame));
+    }
+
+    #[test]
+    fn variable_frame() {
+        let frame = Frame::Variable {
+            control: PRM | FCV | FC_USER_DATA_CONFIRMED,
+            addr: 1,
+            asdu: vec![100, 1, 6, 1, 0, 0, 20],
+        };
+        let data = frame.encode(1);
+        assert_eq!(
+            data,
+            [0x68, 0x09, 0x09, 0x68, 0x53, 0x01, 100, 1, 6, 1, 0, 0, 20, 0xD4, 0x16]
+        );
+        // split over two reads
+        let mut buf = data[..7].to_vec();
+        assert_eq!(Frame::decode(&mut buf, 1), None);
+        buf.extend_from_slice(&data[7..]);
+        assert_eq!(Frame::decode(&mut buf, 1), Some(frame));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_drops_garbage() {
+        let mut buf = vec![0x00, 0x10, 0x49, 0x01, 0x00, 0x16, SINGLE_ACK];
+        // bad checksum, the bytes are dropped up to the single character ack
+        assert_eq!(Frame::decode(&mut buf, 1), Some(Frame::Ack));
+        assert!(buf.is_empty());
+
+        // L repeated wrong, the last start byte may begin a frame
+        let mut buf = vec![0x68, 0x09, 0x08, 0x68];
+        assert_eq!(Frame::decode(&mut buf, 1), None);
+        assert_eq!(buf, [0x68]);
+    }
+
+    #[test]
+    fn element_sizes() {
+        assert_eq!(element_size(1), Some(1));
+        assert_eq!(element_size(13), Some(5));
+        assert_eq!(element_size(36), Some(12));
+        assert_eq!(element_size(50), Some(5));
+        assert_eq!(element_size(102), Some(0));
+        assert_eq!(element_size(200), None);
+    }
+
+    #[test]
+    fn normalize_widens_fields() {
+        let op = IEC101Option::default();
+        // M_ME_NB_1, 2 objects, spontaneous, CA 1, IOA 1 and 2
+        let asdu = [
+            11, 0x02, 3, 1, 0x01, 0x00, 0x10, 0x00, 0x00, 0x02, 0x00, 0x20, 0x00, 0x00,
+        ];
+        assert_eq!(
+            op.normalize(&asdu).unwrap(),
+            [
+                11, 0x02, 3, 0, 1, 0, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x02, 0x00, 0x00, 0x20,
+                0x00, 0x00
+            ]
+        );
+
+        // SQ = 1, one IOA for two elements
+        let asdu = [1, 0x82, 20, 1, 0x05, 0x00, 0x01, 0x00];
+        assert_eq!(
+            op.normalize(&asdu).unwrap(),
+            [1, 0x82, 20, 0, 1, 0, 0x05, 0x00, 0x00, 0x01, 0x00]
+        );
+
+        let op = IEC101Option {
+            cot_size: 2,
+            ca_size: 2,
+            ioa_size: 3,
+            ..IEC101Option::default()
+        };
+        let asdu = [1, 0x01, 3, 7, 0x34, 0x12, 0x01, 0x02, 0x03, 0x01];
+        assert_eq!(op.normalize(&asdu).unwrap(), asdu);
+
+        // truncated or unknown type
+        assert_eq!(op.normalize(&asdu[..9]), None);
+        assert_eq!(op.normalize(&[200, 0x01, 3, 0, 1, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn command_asdu() {
+        let op = IEC101Option {
+            common_addr: 3,
+            ..IEC101Option::default()
+        };
+        assert_eq!(
+            op.asdu(C_SC_NA_1, 2000, &[0x81]),
+            [45, 0x01, 6, 3, 0xD0, 0x07, 0x81]
+        );
+        let op = IEC101Option {
+            cot_size: 2,
+            ca_size: 2,
+            ioa_size: 3,
+            ..op
+        };
+        assert_eq!(
+            op.asdu(C_SE_NB_1, 1, &setpoint(&(-2i16).to_le_bytes(), false)),
+            [49, 0x01, 6, 0, 3, 0, 0x01, 0x00, 0x00, 0xFE, 0xFF, 0x00]
+        );
+    }
+}
@@ -1,13 +1,24 @@
 use std::{
-    future,
+    fmt, future,
+    future::Future,
+    io,
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
 use anyhow::Result;
+use async_trait::async_trait;
+use bit_struct::u5;
+use clap::ValueEnum;
 use log::error;
-use tokio::{sync::oneshot, time::sleep};
+use tokio::{
+    sync::{broadcast, oneshot},
+    time::{sleep, timeout},
+};
 use tokio_iecp5::{
     asdu::{Asdu, Cause, CauseOfTransmission, CommonAddr, TypeID},
     cproc::{
@@ -18,6 +29,12 @@ use tokio_iecp5::{
     Client, ClientHandler, ClientOption, Error,
 };
 
+use crate::{
+    iec104_link::{LinkMonitor, LinkStats},
+    record::Recorder,
+    Functions,
+};
+
 #[allow(dead_code)]
 enum IEC104DateType {
     Siq,
@@ -26,34 +43,408 @@ enum IEC104DateType {
     Sva,
     R,
     Bcr,
+    St,
+    Bo,
+    Ep,
+    Eps,
+    Epo,
+    Ps,
+}
+
+/// Phase of a control command in which the outstation answered (or failed to)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandPhase {
+    Select,
+    Execute,
+    Termination,
+}
+
+/// What the outstation actually answered to a control command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandOutcome {
+    /// positive ActCon followed by ActTerm
+    Executed,
+    /// positive ActCon, but no ActTerm before the timeout
+    Confirmed,
+    /// negative ActCon (P/N = 1)
+    Rejected(CommandPhase),
+    /// no answer before the timeout
+    Timeout(CommandPhase),
+}
+
+impl CommandOutcome {
+    pub fn is_success(&self) -> bool {
+        matches!(self, CommandOutcome::Executed | CommandOutcome::Confirmed)
+    }
+
+    /// Error of an unsuccessful command to `addr`
+    pub fn error(&self, addr: u16) -> Option<crate::error::Error> {
+        match *self {
+            CommandOutcome::Executed | CommandOutcome::Confirmed => None,
+            CommandOutcome::Rejected(phase) => Some(crate::error::Error::Rejected { addr, phase }),
+            CommandOutcome::Timeout(_) => Some(crate::error::Error::Timeout),
+        }
+    }
+}
+
+impl fmt::Display for CommandOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandOutcome::Executed => write!(f, "ActCon+, ActTerm"),
+            CommandOutcome::Confirmed => write!(f, "ActCon+, no ActTerm"),
+            CommandOutcome::Rejected(phase) => write!(f, "{:?} ActCon- (rejected)", phase),
+            CommandOutcome::Timeout(phase) => write!(f, "{:?} timeout", phase),
+        }
+    }
+}
+
+fn already_started() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "IEC104 client already started")
+}
+
+/// Error for a point type without a control command
+pub(crate) fn no_command(point: Point) -> crate::error::Error {
+    crate::error::Error::invalid_input(format!("No command for {:?}", point))
+}
+
+/// Qualifier of command (QU) for single and double commands
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Qualifier {
+    /// no additional definition
+    #[default]
+    None,
+    ShortPulse,
+    LongPulse,
+    Persistent,
+}
+
+impl Qualifier {
+    pub(crate) fn value(&self) -> u8 {
+        match self {
+            Qualifier::None => 0,
+            Qualifier::ShortPulse => 1,
+            Qualifier::LongPulse => 2,
+            Qualifier::Persistent => 3,
+        }
+    }
+}
+
+/// Single command (C_SC_NA_1), `select` is the S/E bit
+fn single_cmd(addr: u16, v: bool, select: bool, qu: Qualifier) -> SingleCommandInfo {
+    let mut cmd = SingleCommandInfo::new(addr, v, select);
+    cmd.qoc.qu().set(u5::new(qu.value()).unwrap());
+    cmd
+}
+
+/// Double command (C_DC_NA_1), `select` is the S/E bit
+fn double_cmd(addr: u16, v: u8, select: bool, qu: Qualifier) -> DoubleCommandInfo {
+    let mut cmd = DoubleCommandInfo::new(addr, v, select);
+    cmd.qoc.qu().set(u5::new(qu.value()).unwrap());
+    cmd
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CommandOption {
+    /// select-before-operate, otherwise direct execute
+    pub select: bool,
+    pub qualifier: Qualifier,
+    /// timeout for each of ActCon (select), ActCon (execute), ActTerm and read replies
+    pub timeout: Duration,
+}
+
+impl Default for CommandOption {
+    fn default() -> Self {
+        CommandOption {
+            select: true,
+            qualifier: Qualifier::None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Step position (VTI), value -64..63 with transient state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepPosition {
+    pub value: i8,
+    pub transient: bool,
+}
+
+/// Event of protection equipment, `value` is the event state, start events or output circuit
+/// information depending on the type, `msec` the elapsed/relay time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtectionEvent {
+    pub value: u8,
+    pub msec: u16,
+}
+
+/// Packed single points with status change detection (SCD)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackedSinglePoint {
+    /// status of the 16 single points
+    pub stn: u16,
+    /// change detection of the 16 single points
+    pub cdn: u16,
+}
+
+/// Value of an information object of any type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Point {
+    Siq(bool),
+    Diq(u8),
+    Nva(i16),
+    Sva(i16),
+    R(f32),
+    Bcr(i32),
+    St(StepPosition),
+    Bo(u32),
+    Ep(ProtectionEvent),
+    Eps(ProtectionEvent),
+    Epo(ProtectionEvent),
+    Ps(PackedSinglePoint),
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Point::Siq(v) => write!(f, "{}", v),
+            Point::Diq(v) => write!(f, "{}", v),
+            Point::Nva(v) | Point::Sva(v) => write!(f, "{}", v),
+            Point::R(v) => write!(f, "{}", v),
+            Point::Bcr(v) => write!(f, "{}", v),
+            Point::St(v) => write!(
+                f,
+                "{}{}",
+                v.value,
+                if v.transient { " (transient)" } else { "" }
+            ),
+            Point::Bo(v) => write!(f, "{:#010X}", v),
+            Point::Ep(v) => write!(f, "{} ({} ms)", v.value, v.msec),
+            Point::Eps(v) | Point::Epo(v) => write!(f, "{:08b} ({} ms)", v.value, v.msec),
+            Point::Ps(v) => write!(f, "{:016b} (cd {:016b})", v.stn, v.cdn),
+        }
+    }
+}
+
+/// Confirmation of a control command sent back by the outstation
+#[derive(Debug, Clone)]
+struct CommandResponse {
+    type_id: TypeID,
+    ioa: u16,
+    cause: Cause,
+    negative: bool,
+}
+
+/// Reply of the outstation to a read command
+#[derive(Debug, Clone)]
+struct ReadResponse {
+    ioa: u16,
+    positive: bool,
+}
+
+/// What the outstation answered to a read command (C_RD_NA_1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOutcome {
+    /// the value arrived with cause request, it can be read from the storage space
+    Replied,
+    /// negative confirmation, e.g. unknown information object address
+    Rejected,
+    /// no answer before the timeout
+    Timeout,
+}
+
+/// Connection of a redundancy group
+#[async_trait]
+trait Link: Send + Sync {
+    async fn is_connected(&self) -> bool;
+    /// in data transfer (STARTDT)
+    async fn is_active(&self) -> bool;
+    async fn send_start_dt(&self) -> Result<(), Error>;
+    async fn send_stop_dt(&self) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl Link for Client<Arc<IEC104ClientHandler>> {
+    async fn is_connected(&self) -> bool {
+        Client::is_connected(self).await
+    }
+
+    async fn is_active(&self) -> bool {
+        Client::is_active(self).await
+    }
+
+    async fn send_start_dt(&self) -> Result<(), Error> {
+        Client::send_start_dt(self).await
+    }
+
+    async fn send_stop_dt(&self) -> Result<(), Error> {
+        Client::send_stop_dt(self).await
+    }
+}
+
+/// Redundancy group of connections to the same outstation, only one of them is in data transfer
+/// (STARTDT), the others are kept in standby (STOPDT) and tested with TESTFR by the link layer
+struct Redundancy<L = Client<Arc<IEC104ClientHandler>>> {
+    endpoints: Vec<SocketAddr>,
+    clients: Vec<Arc<L>>,
+    active: AtomicUsize,
+    link_stats: Arc<Mutex<LinkStats>>,
+}
+
+impl<L: Link> Redundancy<L> {
+    fn active(&self) -> Arc<L> {
+        self.clients[self.active.load(Ordering::Relaxed)].clone()
+    }
+
+    /// Keep the active connection in data transfer and the others in standby, switch over when
+    /// the active connection is lost. Returns the active client if it is connected.
+    async fn supervise(&self) -> Option<Arc<L>> {
+        let active = self.active.load(Ordering::Relaxed);
+        if !self.clients[active].is_connected().await {
+            for (i, client) in self.clients.iter().enumerate() {
+                if i != active && client.is_connected().await {
+                    self.active.store(i, Ordering::Relaxed);
+                    self.link_stats.lock().unwrap().switchovers += 1;
+                    log::warn!(
+                        "IEC104 switchover: {} -> {}",
+                        self.endpoints[active],
+                        self.endpoints[i]
+                    );
+                    break;
+                }
+            }
+        }
+
+        let active = self.active.load(Ordering::Relaxed);
+        for (i, client) in self.clients.iter().enumerate() {
+            if i != active && client.is_active().await && client.send_stop_dt().await.is_ok() {
+                log::info!("IEC104 TRIGGER: STOPDT {} (standby)", self.endpoints[i]);
+            }
+        }
+
+        let client = self.clients[active].clone();
+        if !client.is_connected().await {
+            // client 会自动连接
+            return None;
+        }
+        if !client.is_active().await {
+            client.send_start_dt().await.ok()?;
+            log::info!("IEC104 TRIGGER: STARTDT {}", self.endpoints[active]);
+        }
+        Some(client)
+    }
 }
 
 pub struct IEC104Client {
     remote_addr: CommonAddr,
-    // TODO: change to mutex
-    client: Arc<Client<Arc<IEC104ClientHandler>>>,
+    command_option: CommandOption,
+    read_on_demand: bool,
+    group: Arc<Redundancy>,
     inner: Arc<IEC104ClientHandler>,
+    monitors: Vec<LinkMonitor>,
     shutdown_tx: Option<oneshot::Sender<()>>,
     terminated_rx: Option<oneshot::Receiver<()>>,
 }
 
 impl IEC104Client {
-    pub fn new(socket_addr: SocketAddr, remote_addr: CommonAddr) -> Self {
-        let op = ClientOption::new(socket_addr, true);
+    pub fn new(socket_addr: SocketAddr, remote_addr: CommonAddr) -> io::Result<Self> {
+        Self::with_endpoints(&[socket_addr], remote_addr)
+    }
+
+    /// Client of a redundancy group, the first endpoint is the primary one
+    pub fn with_endpoints(endpoints: &[SocketAddr], remote_addr: CommonAddr) -> io::Result<Self> {
+        let link_stats = Arc::new(Mutex::new(LinkStats::default()));
         let inner = Arc::new(IEC104ClientHandler::new());
-        let client = Arc::new(Client::new(inner.clone(), op));
+        let mut clients = Vec::new();
+        let mut monitors = Vec::new();
+        for &socket_addr in endpoints {
+            // the client connects through the link monitor, which relays to the outstation
+            let monitor = LinkMonitor::bind(socket_addr, link_stats.clone())?;
+            let op = ClientOption::new(monitor.local_addr()?, true);
+            clients.push(Arc::new(Client::new(inner.clone(), op)));
+            monitors.push(monitor);
+        }
 
-        IEC104Client {
+        Ok(IEC104Client {
             remote_addr,
-            client,
+            command_option: CommandOption::default(),
+            read_on_demand: false,
+            group: Arc::new(Redundancy {
+                endpoints: endpoints.to_vec(),
+                clients,
+                active: AtomicUsize::new(0),
+                link_stats,
+            }),
             inner,
+            monitors,
             shutdown_tx: None,
             terminated_rx: None,
+        })
+    }
+
+    /// Shared link statistics, updated for as long as the client runs
+    pub fn link_stats(&self) -> Arc<Mutex<LinkStats>> {
+        self.group.link_stats.clone()
+    }
+
+    /// Collect the link statistics into `stats` instead, fails once started
+    pub fn set_link_stats(&mut self, stats: Arc<Mutex<LinkStats>>) -> io::Result<()> {
+        self.check_not_started()?;
+        let group = Arc::get_mut(&mut self.group).ok_or_else(already_started)?;
+        group.link_stats = stats.clone();
+        for monitor in self.monitors.iter_mut() {
+            monitor.set_stats(stats.clone());
+        }
+        Ok(())
+    }
+
+    /// Record the ASDUs sent and received, fails once started
+    pub fn set_recorder(&mut self, recorder: Arc<Recorder>) -> io::Result<()> {
+        self.check_not_started()?;
+        for monitor in self.monitors.iter_mut() {
+            monitor.set_recorder(recorder.clone());
+        }
+        Ok(())
+    }
+
+    /// The link monitors are spawned on start, they can not be changed afterwards
+    fn check_not_started(&self) -> io::Result<()> {
+        if self.monitors.len() < self.group.endpoints.len() {
+            return Err(already_started());
+        }
+        Ok(())
+    }
+
+    /// Endpoint currently in data transfer
+    pub fn active_endpoint(&self) -> SocketAddr {
+        self.group.endpoints[self.group.active.load(Ordering::Relaxed)]
+    }
+
+    async fn start_clients(&mut self) -> Result<(), Error> {
+        for monitor in self.monitors.drain(..) {
+            monitor.spawn();
         }
+        for client in self.group.clients.iter() {
+            client.start().await?;
+        }
+        Ok(())
+    }
+
+    pub fn set_command_option(&mut self, op: CommandOption) {
+        self.command_option = op;
+    }
+
+    /// Read the points of a [`Session`](crate::session::Session) with C_RD_NA_1 instead of a
+    /// general interrogation
+    pub fn set_read_on_demand(&mut self, on: bool) {
+        self.read_on_demand = on;
+    }
+
+    pub fn read_on_demand(&self) -> bool {
+        self.read_on_demand
     }
 
     pub async fn start(&mut self) -> Result<(), Error> {
-        self.client.start().await?;
+        self.start_clients().await?;
 
         if self.shutdown_tx.is_some() {
             return Ok(());
@@ -64,7 +455,7 @@ impl IEC104Client {
         self.shutdown_tx = Some(shutdown_tx);
         self.terminated_rx = Some(terminated_rx);
 
-        let client = self.client.clone();
+        let group = self.group.clone();
 
         tokio::spawn(async move {
             loop {
@@ -72,17 +463,7 @@ impl IEC104Client {
                     terminated_tx.send(()).unwrap();
                     break;
                 }
-                if !client.is_connected().await {
-                    // client 会自动连接
-                    // client.start().await;
-                    continue;
-                }
-                if !client.is_active().await {
-                    if client.send_start_dt().await.is_err() {
-                        continue;
-                    }
-                    log::info!("IEC104 TRIGGER: STARTDT");
-                }
+                group.supervise().await;
 
                 sleep(Duration::from_secs(1)).await;
             }
@@ -92,7 +473,7 @@ impl IEC104Client {
     }
 
     pub async fn start_interrogation(&mut self) -> Result<(), Error> {
-        self.client.start().await?;
+        self.start_clients().await?;
 
         if self.shutdown_tx.is_some() {
             return Ok(());
@@ -103,7 +484,7 @@ impl IEC104Client {
         self.shutdown_tx = Some(shutdown_tx);
         self.terminated_rx = Some(terminated_rx);
 
-        let client = self.client.clone();
+        let group = self.group.clone();
         let remote_addr = self.remote_addr;
 
         tokio::spawn(async move {
@@ -112,17 +493,10 @@ impl IEC104Client {
                     terminated_tx.send(()).unwrap();
                     break;
                 }
-                if !client.is_connected().await {
-                    // client 会自动连接
-                    // client.start().await;
+                let Some(client) = group.supervise().await else {
+                    sleep(Duration::from_secs(1)).await;
                     continue;
-                }
-                if !client.is_active().await {
-                    if client.send_start_dt().await.is_err() {
-                        continue;
-                    }
-                    log::info!("IEC104 TRIGGER: STARTDT");
-                }
+                };
 
                 sleep(Duration::from_secs(1)).await;
 
@@ -204,50 +578,193 @@ impl IEC104Client {
         }
     }
 
-    pub fn read_siq(&self, addr: u16) -> Option<bool> {
-        self.inner.siq_space.lock().unwrap()[addr as usize]
+    pub async fn write_siq(&self, addr: u16, v: bool) -> Result<CommandOutcome, Error> {
+        let qu = self.command_option.qualifier;
+        let client = self.group.active();
+        self.command(TypeID::C_SC_NA_1, addr, true, |select| {
+            client.single_cmd(
+                TypeID::C_SC_NA_1,
+                CauseOfTransmission::new(false, false, Cause::Activation),
+                self.remote_addr,
+                single_cmd(addr, v, select, qu),
+            )
+        })
+        .await
     }
 
-    pub fn extract_all_siq(&self) -> Vec<(u16, bool)> {
-        self.inner
-            .siq_space
-            .lock()
-            .unwrap()
-            .iter()
-            .enumerate()
-            .filter_map(|(i, v)| v.map(|v| (i as u16, v)))
-            .collect()
+    pub async fn write_diq(&self, addr: u16, v: u8) -> Result<CommandOutcome, Error> {
+        let v = v % 4;
+        let qu = self.command_option.qualifier;
+        let client = self.group.active();
+        self.command(TypeID::C_DC_NA_1, addr, true, |select| {
+            client.double_cmd(
+                TypeID::C_DC_NA_1,
+                CauseOfTransmission::new(false, false, Cause::Activation),
+                self.remote_addr,
+                double_cmd(addr, v, select, qu),
+            )
+        })
+        .await
     }
 
-    pub async fn write_siq(&self, addr: u16, v: bool) -> Result<(), Error> {
-        let cmd = SingleCommandInfo::new(addr, v, true);
-        self.client
-            .single_cmd(
-                TypeID::C_SC_NA_1,
+    pub async fn write_nva(&self, addr: u16, v: i16) -> Result<CommandOutcome, Error> {
+        let client = self.group.active();
+        self.command(TypeID::C_SE_NA_1, addr, true, |select| {
+            let mut cmd = SetpointCommandNormalInfo::new(addr, v);
+            cmd.qos.se().set(select);
+            client.set_point_cmd_normal(
+                TypeID::C_SE_NA_1,
                 CauseOfTransmission::new(false, false, Cause::Activation),
                 self.remote_addr,
                 cmd,
             )
-            .await?;
+        })
+        .await
+    }
 
-        let cmd = SingleCommandInfo::new(addr, v, false);
-        self.client
-            .single_cmd(
-                TypeID::C_SC_NA_1,
+    pub async fn write_sva(&self, addr: u16, v: i16) -> Result<CommandOutcome, Error> {
+        let client = self.group.active();
+        self.command(TypeID::C_SE_NB_1, addr, true, |select| {
+            let mut cmd = SetpointCommandScaledInfo::new(addr, v);
+            cmd.qos.se().set(select);
+            client.set_point_cmd_scaled(
+                TypeID::C_SE_NB_1,
                 CauseOfTransmission::new(false, false, Cause::Activation),
                 self.remote_addr,
                 cmd,
             )
-            .await
+        })
+        .await
+    }
+
+    pub async fn write_r(&self, addr: u16, v: f32) -> Result<CommandOutcome, Error> {
+        let client = self.group.active();
+        self.command(TypeID::C_SE_NC_1, addr, true, |select| {
+            let mut cmd = SetpointCommandFloatInfo::new(addr, v);
+            cmd.qos.se().set(select);
+            client.set_point_cmd_float(
+                TypeID::C_SE_NC_1,
+                CauseOfTransmission::new(false, false, Cause::Activation),
+                self.remote_addr,
+                cmd,
+            )
+        })
+        .await
+    }
+
+    pub async fn write_bcr(&self, addr: u16, v: i32) -> Result<CommandOutcome, Error> {
+        // bitstring commands have no S/E bit, always direct execute
+        let client = self.group.active();
+        self.command(TypeID::C_BO_NA_1, addr, false, |_| {
+            let cmd = BitsString32CommandInfo::new(addr, v);
+            client.bits_string32_cmd(
+                TypeID::C_BO_NA_1,
+                CauseOfTransmission::new(false, false, Cause::Activation),
+                self.remote_addr,
+                cmd,
+            )
+        })
+        .await
+    }
+
+    /// Send the command of the type of `point`
+    pub async fn write_point(
+        &self,
+        addr: u16,
+        point: Point,
+    ) -> Result<CommandOutcome, crate::error::Error> {
+        let outcome = match point {
+            Point::Siq(v) => self.write_siq(addr, v).await,
+            Point::Diq(v) => self.write_diq(addr, v).await,
+            Point::Nva(v) => self.write_nva(addr, v).await,
+            Point::Sva(v) => self.write_sva(addr, v).await,
+            Point::R(v) => self.write_r(addr, v).await,
+            Point::Bcr(v) => self.write_bcr(addr, v).await,
+            p => return Err(no_command(p)),
+        };
+        Ok(outcome?)
     }
 
-    pub fn read_diq(&self, addr: u16) -> Option<u8> {
-        self.inner.diq_space.lock().unwrap()[addr as usize]
+    /// Read a single information object on demand with C_RD_NA_1, the value is stored in the
+    /// storage space of its type, like interrogated or spontaneous data
+    pub async fn read_point(&self, addr: u16) -> Result<ReadOutcome, Error> {
+        let mut rx = self.inner.read_tx.subscribe();
+        let client = self.group.active();
+        client
+            .read_cmd(
+                CauseOfTransmission::new(false, false, Cause::Request),
+                self.remote_addr,
+                addr,
+            )
+            .await?;
+
+        let outcome = timeout(self.command_option.timeout, async {
+            loop {
+                match rx.recv().await {
+                    Ok(r) if r.ioa == addr => {
+                        return if r.positive {
+                            ReadOutcome::Replied
+                        } else {
+                            ReadOutcome::Rejected
+                        };
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return ReadOutcome::Timeout,
+                }
+            }
+        })
+        .await
+        .unwrap_or(ReadOutcome::Timeout);
+        Ok(outcome)
     }
 
-    pub fn extract_all_diq(&self) -> Vec<(u16, u8)> {
+    async fn command<F, Fut>(
+        &self,
+        type_id: TypeID,
+        addr: u16,
+        selectable: bool,
+        send: F,
+    ) -> Result<CommandOutcome, Error>
+    where
+        F: Fn(bool) -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
         self.inner
-            .diq_space
+            .command(&self.command_option, type_id, addr, selectable, send)
+            .await
+    }
+}
+
+/// Storage spaces of the information objects received from an outstation
+pub trait PointSpace {
+    fn handler(&self) -> &IEC104ClientHandler;
+
+    /// Value of the point of type `function`, none until it was received
+    fn point(&self, function: &Functions, addr: u16) -> Option<Point> {
+        match function {
+            Functions::Siq => self.read_siq(addr).map(Point::Siq),
+            Functions::Diq => self.read_diq(addr).map(Point::Diq),
+            Functions::Nva => self.read_nva(addr).map(Point::Nva),
+            Functions::Sva => self.read_sva(addr).map(Point::Sva),
+            Functions::R => self.read_r(addr).map(Point::R),
+            Functions::Bcr => self.read_bcr(addr).map(Point::Bcr),
+            Functions::St => self.read_st(addr).map(Point::St),
+            Functions::Bo => self.read_bo(addr).map(Point::Bo),
+            Functions::Ep => self.read_ep(addr).map(Point::Ep),
+            Functions::Eps => self.read_eps(addr).map(Point::Eps),
+            Functions::Epo => self.read_epo(addr).map(Point::Epo),
+            Functions::Ps => self.read_ps(addr).map(Point::Ps),
+            _ => None,
+        }
+    }
+
+    fn read_siq(&self, addr: u16) -> Option<bool> {
+        self.handler().siq_space.lock().unwrap()[addr as usize]
+    }
+
+    fn extract_all_siq(&self) -> Vec<(u16, bool)> {
+        self.handler()
+            .siq_space
             .lock()
             .unwrap()
             .iter()
@@ -256,39 +773,27 @@ impl IEC104Client {
             .collect()
     }
 
-    pub async fn write_diq(&self, addr: u16, v: u8) -> Result<(), Error> {
-        let v = v % 4;
-        {
-            let cmd = DoubleCommandInfo::new(addr, v, true);
-            self.client
-                .double_cmd(
-                    TypeID::C_DC_NA_1,
-                    CauseOfTransmission::new(false, false, Cause::Activation),
-                    self.remote_addr,
-                    cmd,
-                )
-                .await?;
-        }
+    fn read_diq(&self, addr: u16) -> Option<u8> {
+        self.handler().diq_space.lock().unwrap()[addr as usize]
+    }
 
-        {
-            let cmd = DoubleCommandInfo::new(addr, v, false);
-            self.client
-                .double_cmd(
-                    TypeID::C_DC_NA_1,
-                    CauseOfTransmission::new(false, false, Cause::Activation),
-                    self.remote_addr,
-                    cmd,
-                )
-                .await
-        }
+    fn extract_all_diq(&self) -> Vec<(u16, u8)> {
+        self.handler()
+            .diq_space
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| v.map(|v| (i as u16, v)))
+            .collect()
     }
 
-    pub fn read_nva(&self, addr: u16) -> Option<i16> {
-        self.inner.nva_space.lock().unwrap()[addr as usize]
+    fn read_nva(&self, addr: u16) -> Option<i16> {
+        self.handler().nva_space.lock().unwrap()[addr as usize]
     }
 
-    pub fn extract_all_nva(&self) -> Vec<(u16, i16)> {
-        self.inner
+    fn extract_all_nva(&self) -> Vec<(u16, i16)> {
+        self.handler()
             .nva_space
             .lock()
             .unwrap()
@@ -298,25 +803,28 @@ impl IEC104Client {
             .collect()
     }
 
-    pub async fn write_nva(&self, addr: u16, v: i16) -> Result<(), Error> {
-        let cmd = SetpointCommandNormalInfo::new(addr, v);
-        self.client
-            .set_point_cmd_normal(
-                TypeID::C_SE_NA_1,
-                CauseOfTransmission::new(false, false, Cause::Activation),
-                self.remote_addr,
-                cmd,
-            )
-            .await
+    fn read_sva(&self, addr: u16) -> Option<i16> {
+        self.handler().sva_space.lock().unwrap()[addr as usize]
     }
 
-    pub fn read_sva(&self, addr: u16) -> Option<i16> {
-        self.inner.sva_space.lock().unwrap()[addr as usize]
+    fn extract_all_sva(&self) -> Vec<(u16, i16)> {
+        self.handler()
+            .sva_space
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| v.map(|v| (i as u16, v)))
+            .collect()
     }
 
-    pub fn extract_all_sva(&self) -> Vec<(u16, i16)> {
-        self.inner
-            .sva_space
+    fn read_r(&self, addr: u16) -> Option<f32> {
+        self.handler().r_space.lock().unwrap()[addr as usize]
+    }
+
+    fn extract_all_r(&self) -> Vec<(u16, f32)> {
+        self.handler()
+            .r_space
             .lock()
             .unwrap()
             .iter()
@@ -325,25 +833,28 @@ impl IEC104Client {
             .collect()
     }
 
-    pub async fn write_sva(&self, addr: u16, v: i16) -> Result<(), Error> {
-        let cmd = SetpointCommandScaledInfo::new(addr, v);
-        self.client
-            .set_point_cmd_scaled(
-                TypeID::C_SE_NB_1,
-                CauseOfTransmission::new(false, false, Cause::Activation),
-                self.remote_addr,
-                cmd,
-            )
-            .await
+    fn read_bcr(&self, addr: u16) -> Option<i32> {
+        self.handler().bcr_space.lock().unwrap()[addr as usize]
     }
 
-    pub fn read_r(&self, addr: u16) -> Option<f32> {
-        self.inner.r_space.lock().unwrap()[addr as usize]
+    fn extract_all_bcr(&self) -> Vec<(u16, i32)> {
+        self.handler()
+            .bcr_space
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| v.map(|v| (i as u16, v)))
+            .collect()
     }
 
-    pub fn extract_all_r(&self) -> Vec<(u16, f32)> {
-        self.inner
-            .r_space
+    fn read_st(&self, addr: u16) -> Option<StepPosition> {
+        self.handler().st_space.lock().unwrap()[addr as usize]
+    }
+
+    fn extract_all_st(&self) -> Vec<(u16, StepPosition)> {
+        self.handler()
+            .st_space
             .lock()
             .unwrap()
             .iter()
@@ -352,25 +863,28 @@ impl IEC104Client {
             .collect()
     }
 
-    pub async fn write_r(&self, addr: u16, v: f32) -> Result<(), Error> {
-        let cmd = SetpointCommandFloatInfo::new(addr, v);
-        self.client
-            .set_point_cmd_float(
-                TypeID::C_SE_NC_1,
-                CauseOfTransmission::new(false, false, Cause::Activation),
-                self.remote_addr,
-                cmd,
-            )
-            .await
+    fn read_bo(&self, addr: u16) -> Option<u32> {
+        self.handler().bo_space.lock().unwrap()[addr as usize]
+    }
+
+    fn extract_all_bo(&self) -> Vec<(u16, u32)> {
+        self.handler()
+            .bo_space
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| v.map(|v| (i as u16, v)))
+            .collect()
     }
 
-    pub fn read_bcr(&self, addr: u16) -> Option<i32> {
-        self.inner.bcr_space.lock().unwrap()[addr as usize]
+    fn read_ep(&self, addr: u16) -> Option<ProtectionEvent> {
+        self.handler().ep_space.lock().unwrap()[addr as usize]
     }
 
-    pub fn extract_all_bcr(&self) -> Vec<(u16, i32)> {
-        self.inner
-            .bcr_space
+    fn extract_all_ep(&self) -> Vec<(u16, ProtectionEvent)> {
+        self.handler()
+            .ep_space
             .lock()
             .unwrap()
             .iter()
@@ -379,38 +893,239 @@ impl IEC104Client {
             .collect()
     }
 
-    pub async fn write_bcr(&self, addr: u16, v: i32) -> Result<(), Error> {
-        let cmd = BitsString32CommandInfo::new(addr, v);
-        self.client
-            .bits_string32_cmd(
-                TypeID::C_BO_NA_1,
-                CauseOfTransmission::new(false, false, Cause::Activation),
-                self.remote_addr,
-                cmd,
-            )
-            .await
+    fn read_eps(&self, addr: u16) -> Option<ProtectionEvent> {
+        self.handler().eps_space.lock().unwrap()[addr as usize]
+    }
+
+    fn extract_all_eps(&self) -> Vec<(u16, ProtectionEvent)> {
+        self.handler()
+            .eps_space
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| v.map(|v| (i as u16, v)))
+            .collect()
+    }
+
+    fn read_epo(&self, addr: u16) -> Option<ProtectionEvent> {
+        self.handler().epo_space.lock().unwrap()[addr as usize]
+    }
+
+    fn extract_all_epo(&self) -> Vec<(u16, ProtectionEvent)> {
+        self.handler()
+            .epo_space
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| v.map(|v| (i as u16, v)))
+            .collect()
+    }
+
+    fn read_ps(&self, addr: u16) -> Option<PackedSinglePoint> {
+        self.handler().ps_space.lock().unwrap()[addr as usize]
+    }
+
+    fn extract_all_ps(&self) -> Vec<(u16, PackedSinglePoint)> {
+        self.handler()
+            .ps_space
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| v.map(|v| (i as u16, v)))
+            .collect()
+    }
+}
+
+impl PointSpace for IEC104Client {
+    fn handler(&self) -> &IEC104ClientHandler {
+        &self.inner
     }
 }
 
+/// Last value of every information object address, on the heap as it is too large for the stack
+type Space<T> = Arc<Mutex<Box<[Option<T>]>>>;
+
+fn space<T: Clone>() -> Space<T> {
+    Arc::new(Mutex::new(vec![None; 65536].into_boxed_slice()))
+}
+
 #[derive(Debug, Clone)]
-struct IEC104ClientHandler {
-    siq_space: Arc<Mutex<[Option<bool>; 65536]>>,
-    diq_space: Arc<Mutex<[Option<u8>; 65536]>>,
-    nva_space: Arc<Mutex<[Option<i16>; 65536]>>,
-    sva_space: Arc<Mutex<[Option<i16>; 65536]>>,
-    r_space: Arc<Mutex<[Option<f32>; 65536]>>,
-    bcr_space: Arc<Mutex<[Option<i32>; 65536]>>,
+pub struct IEC104ClientHandler {
+    siq_space: Space<bool>,
+    diq_space: Space<u8>,
+    nva_space: Space<i16>,
+    sva_space: Space<i16>,
+    r_space: Space<f32>,
+    bcr_space: Space<i32>,
+    st_space: Space<StepPosition>,
+    bo_space: Space<u32>,
+    ep_space: Space<ProtectionEvent>,
+    eps_space: Space<ProtectionEvent>,
+    epo_space: Space<ProtectionEvent>,
+    ps_space: Space<PackedSinglePoint>,
+    command_tx: broadcast::Sender<CommandResponse>,
+    read_tx: broadcast::Sender<ReadResponse>,
+}
+
+impl Default for IEC104ClientHandler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IEC104ClientHandler {
     pub fn new() -> Self {
         IEC104ClientHandler {
-            siq_space: Arc::new(Mutex::new([None; 65536])),
-            diq_space: Arc::new(Mutex::new([None; 65536])),
-            nva_space: Arc::new(Mutex::new([None; 65536])),
-            sva_space: Arc::new(Mutex::new([None; 65536])),
-            r_space: Arc::new(Mutex::new([None; 65536])),
-            bcr_space: Arc::new(Mutex::new([None; 65536])),
+            siq_space: space(),
+            diq_space: space(),
+            nva_space: space(),
+            sva_space: space(),
+            r_space: space(),
+            bcr_space: space(),
+            st_space: space(),
+            bo_space: space(),
+            ep_space: space(),
+            eps_space: space(),
+            epo_space: space(),
+            ps_space: space(),
+            command_tx: broadcast::channel(64).0,
+            read_tx: broadcast::channel(64).0,
+        }
+    }
+
+    /// Run a control command through select (optional), execute and termination.
+    ///
+    /// `send(true)` must send the select command, `send(false)` the execute command.
+    pub(crate) async fn command<F, Fut, E>(
+        &self,
+        option: &CommandOption,
+        type_id: TypeID,
+        addr: u16,
+        selectable: bool,
+        send: F,
+    ) -> Result<CommandOutcome, E>
+    where
+        F: Fn(bool) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+    {
+        // subscribe before sending, so that a fast confirmation is not lost
+        let mut rx = self.command_tx.subscribe();
+
+        if selectable && option.select {
+            send(true).await?;
+            match self
+                .wait_response(&mut rx, option.timeout, type_id, addr, Cause::ActivationCon)
+                .await
+            {
+                Some(true) => {}
+                Some(false) => return Ok(CommandOutcome::Rejected(CommandPhase::Select)),
+                None => return Ok(CommandOutcome::Timeout(CommandPhase::Select)),
+            }
+        }
+
+        send(false).await?;
+        match self
+            .wait_response(&mut rx, option.timeout, type_id, addr, Cause::ActivationCon)
+            .await
+        {
+            Some(true) => {}
+            Some(false) => return Ok(CommandOutcome::Rejected(CommandPhase::Execute)),
+            None => return Ok(CommandOutcome::Timeout(CommandPhase::Execute)),
+        }
+
+        match self
+            .wait_response(
+                &mut rx,
+                option.timeout,
+                type_id,
+                addr,
+                Cause::ActivationTerm,
+            )
+            .await
+        {
+            Some(true) => Ok(CommandOutcome::Executed),
+            Some(false) => Ok(CommandOutcome::Rejected(CommandPhase::Termination)),
+            None => Ok(CommandOutcome::Confirmed),
+        }
+    }
+
+    /// Wait for a response with the given cause, returns `Some(positive)`, or `None` on timeout
+    async fn wait_response(
+        &self,
+        rx: &mut broadcast::Receiver<CommandResponse>,
+        wait: Duration,
+        type_id: TypeID,
+        addr: u16,
+        cause: Cause,
+    ) -> Option<bool> {
+        timeout(wait, async {
+            loop {
+                match rx.recv().await {
+                    Ok(r) if r.type_id == type_id && r.ioa == addr && r.cause == cause => {
+                        return Some(!r.negative);
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .await
+        .unwrap_or(None)
+    }
+
+    fn store<T>(&self, space: &Mutex<Box<[Option<T>]>>, ioa: u16, v: T, cause: Cause) {
+        space.lock().unwrap()[ioa as usize] = Some(v);
+        if cause == Cause::Request {
+            // no one is waiting if nobody subscribed, ignore the error
+            let _ = self.read_tx.send(ReadResponse {
+                ioa,
+                positive: true,
+            });
+        }
+    }
+
+    fn on_command_response(&self, asdu: &mut Asdu) {
+        let type_id = asdu.identifier.type_id;
+        let cause = asdu.identifier.cot.cause().get();
+        let negative = asdu.identifier.cot.is_negative().get();
+        let ioa = match type_id {
+            TypeID::C_SC_NA_1 => asdu.get_single_cmd().map(|mut c| c.ioa.addr().get()),
+            TypeID::C_DC_NA_1 => asdu.get_double_cmd().map(|mut c| c.ioa.addr().get()),
+            TypeID::C_SE_NA_1 => asdu
+                .get_set_point_cmd_normal()
+                .map(|mut c| c.ioa.addr().get()),
+            TypeID::C_SE_NB_1 => asdu
+                .get_set_point_cmd_scaled()
+                .map(|mut c| c.ioa.addr().get()),
+            TypeID::C_SE_NC_1 => asdu
+                .get_set_point_cmd_float()
+                .map(|mut c| c.ioa.addr().get()),
+            TypeID::C_BO_NA_1 => asdu.get_bits_string32_cmd().map(|mut c| c.ioa.addr().get()),
+            _ => return,
+        };
+        match ioa {
+            Ok(ioa) => {
+                log::info!(
+                    "IEC104 {:?} ioa {} {:?}{}",
+                    type_id,
+                    ioa,
+                    cause,
+                    if negative { " negative" } else { "" }
+                );
+                // no one is waiting if nobody subscribed, ignore the error
+                let _ = self.command_tx.send(CommandResponse {
+                    type_id,
+                    ioa,
+                    cause,
+                    negative,
+                });
+            }
+            Err(e) => {
+                error!("Error while processing command confirmation: {}", e);
+            }
         }
     }
 }
@@ -420,14 +1135,53 @@ impl ClientHandler for IEC104ClientHandler {
 
     fn call(&self, asdu: Asdu) -> Self::Future {
         let mut asdu = asdu;
+        let cause = asdu.identifier.cot.cause().get();
         match asdu.identifier.type_id {
             TypeID::C_IC_NA_1 => future::ready(Ok(vec![])),
+            TypeID::C_RD_NA_1 => {
+                // a positive confirmation is followed by the value with cause request, only a
+                // negative one answers the read
+                let negative = asdu.identifier.cot.is_negative().get();
+                match asdu.get_read_cmd() {
+                    Ok(mut ioa) => {
+                        log::info!(
+                            "IEC104 C_RD_NA_1 ioa {} {:?}{}",
+                            ioa.addr().get(),
+                            cause,
+                            if negative { " negative" } else { "" }
+                        );
+                        if negative {
+                            let _ = self.read_tx.send(ReadResponse {
+                                ioa: ioa.addr().get(),
+                                positive: false,
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error while processing read command confirmation: {}", e);
+                    }
+                }
+                future::ready(Ok(vec![]))
+            }
+            TypeID::C_SC_NA_1
+            | TypeID::C_DC_NA_1
+            | TypeID::C_SE_NA_1
+            | TypeID::C_SE_NB_1
+            | TypeID::C_SE_NC_1
+            | TypeID::C_BO_NA_1 => {
+                self.on_command_response(&mut asdu);
+                future::ready(Ok(vec![]))
+            }
             TypeID::M_SP_NA_1 | TypeID::M_SP_TA_1 | TypeID::M_SP_TB_1 => {
                 match asdu.get_single_point() {
                     Ok(sgs) => {
                         for mut sg in sgs {
-                            self.siq_space.lock().unwrap()[sg.ioa.addr().get() as usize] =
-                                Some(sg.siq.spi().get());
+                            self.store(
+                                &self.siq_space,
+                                sg.ioa.addr().get(),
+                                sg.siq.spi().get(),
+                                cause,
+                            );
                         }
                     }
                     Err(e) => {
@@ -440,8 +1194,12 @@ impl ClientHandler for IEC104ClientHandler {
                 match asdu.get_double_point() {
                     Ok(dbs) => {
                         for mut db in dbs {
-                            self.diq_space.lock().unwrap()[db.ioa.addr().get() as usize] =
-                                Some(db.diq.spi().get().value());
+                            self.store(
+                                &self.diq_space,
+                                db.ioa.addr().get(),
+                                db.diq.spi().get().value(),
+                                cause,
+                            );
                         }
                     }
                     Err(e) => {
@@ -455,8 +1213,7 @@ impl ClientHandler for IEC104ClientHandler {
                 match asdu.get_measured_value_normal() {
                     Ok(nvas) => {
                         for mut v in nvas {
-                            self.nva_space.lock().unwrap()[v.ioa.addr().get() as usize] =
-                                Some(v.nva);
+                            self.store(&self.nva_space, v.ioa.addr().get(), v.nva, cause);
                         }
                     }
                     Err(e) => {
@@ -469,8 +1226,7 @@ impl ClientHandler for IEC104ClientHandler {
                 match asdu.get_measured_value_scaled() {
                     Ok(svas) => {
                         for mut v in svas {
-                            self.sva_space.lock().unwrap()[v.ioa.addr().get() as usize] =
-                                Some(v.sva);
+                            self.store(&self.sva_space, v.ioa.addr().get(), v.sva, cause);
                         }
                     }
                     Err(e) => {
@@ -483,7 +1239,7 @@ impl ClientHandler for IEC104ClientHandler {
                 match asdu.get_measured_value_float() {
                     Ok(rs) => {
                         for mut v in rs {
-                            self.r_space.lock().unwrap()[v.ioa.addr().get() as usize] = Some(v.r);
+                            self.store(&self.r_space, v.ioa.addr().get(), v.r, cause);
                         }
                     }
                     Err(e) => {
@@ -496,8 +1252,7 @@ impl ClientHandler for IEC104ClientHandler {
                 match asdu.get_integrated_totals() {
                     Ok(bcrs) => {
                         for mut v in bcrs {
-                            self.bcr_space.lock().unwrap()[v.ioa.addr().get() as usize] =
-                                Some(v.bcr.value);
+                            self.store(&self.bcr_space, v.ioa.addr().get(), v.bcr.value, cause);
                         }
                     }
                     Err(e) => {
@@ -506,8 +1261,565 @@ impl ClientHandler for IEC104ClientHandler {
                 }
                 future::ready(Ok(vec![]))
             }
+            TypeID::M_ST_NA_1 | TypeID::M_ST_TA_1 | TypeID::M_ST_TB_1 => {
+                match asdu.get_step_position() {
+                    Ok(sts) => {
+                        for mut v in sts {
+                            self.store(
+                                &self.st_space,
+                                v.ioa.addr().get(),
+                                StepPosition {
+                                    value: v.vti.value().get(),
+                                    transient: v.vti.transient().get(),
+                                },
+                                cause,
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error while processing step position message: {}", e);
+                    }
+                }
+                future::ready(Ok(vec![]))
+            }
+            TypeID::M_BO_NA_1 | TypeID::M_BO_TA_1 | TypeID::M_BO_TB_1 => {
+                match asdu.get_bit_string32() {
+                    Ok(bos) => {
+                        for mut v in bos {
+                            self.store(&self.bo_space, v.ioa.addr().get(), v.bsi, cause);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error while processing bitstring32 message: {}", e);
+                    }
+                }
+                future::ready(Ok(vec![]))
+            }
+            TypeID::M_EP_TA_1 | TypeID::M_EP_TD_1 => {
+                match asdu.get_event_of_protection_equipment() {
+                    Ok(eps) => {
+                        for mut v in eps {
+                            self.store(
+                                &self.ep_space,
+                                v.ioa.addr().get(),
+                                ProtectionEvent {
+                                    value: v.event.es().get().value(),
+                                    msec: v.msec,
+                                },
+                                cause,
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error while processing protection event message: {}", e);
+                    }
+                }
+                future::ready(Ok(vec![]))
+            }
+            TypeID::M_EP_TB_1 | TypeID::M_EP_TE_1 => {
+                match asdu.get_packed_start_events_of_protection_equipment() {
+                    Ok(eps) => {
+                        for mut v in eps {
+                            self.store(
+                                &self.eps_space,
+                                v.ioa.addr().get(),
+                                ProtectionEvent {
+                                    value: v.event,
+                                    msec: v.msec,
+                                },
+                                cause,
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error while processing packed start events message: {}", e);
+                    }
+                }
+                future::ready(Ok(vec![]))
+            }
+            TypeID::M_EP_TC_1 | TypeID::M_EP_TF_1 => {
+                match asdu.get_packed_output_circuit_info() {
+                    Ok(epos) => {
+                        for mut v in epos {
+                            self.store(
+                                &self.epo_space,
+                                v.ioa.addr().get(),
+                                ProtectionEvent {
+                                    value: v.oci,
+                                    msec: v.msec,
+                                },
+                                cause,
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        error!(
+                            "Error while processing packed output circuit message: {}",
+                            e
+                        );
+                    }
+                }
+                future::ready(Ok(vec![]))
+            }
+            TypeID::M_PS_NA_1 => {
+                match asdu.get_packed_single_point_with_scd() {
+                    Ok(pss) => {
+                        for mut v in pss {
+                            self.store(
+                                &self.ps_space,
+                                v.ioa.addr().get(),
+                                PackedSinglePoint {
+                                    stn: (v.scd & 0xFFFF) as u16,
+                                    cdn: (v.scd >> 16) as u16,
+                                },
+                                cause,
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error while processing packed single point message: {}", e);
+                    }
+                }
+                future::ready(Ok(vec![]))
+            }
 
             _ => future::ready(Ok(vec![])),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: u16 = 4001;
+
+    const OPTION: CommandOption = CommandOption {
+        select: true,
+        qualifier: Qualifier::None,
+        timeout: Duration::from_millis(50),
+    };
+
+    /// Run a single command against a fake outstation, which answers the select (`true`) or
+    /// execute (`false`) command with `answer`. Returns the outcome and the S/E bits sent.
+    async fn run(
+        option: CommandOption,
+        selectable: bool,
+        answer: impl Fn(bool) -> Vec<(Cause, bool)>,
+    ) -> (CommandOutcome, Vec<bool>) {
+        let handler = IEC104ClientHandler::new();
+        let sent = Mutex::new(Vec::new());
+        let outcome = handler
+            .command(&option, TypeID::C_SC_NA_1, ADDR, selectable, |select| {
+                sent.lock().unwrap().push(select);
+                for (cause, negative) in answer(select) {
+                    handler
+                        .command_tx
+                        .send(CommandResponse {
+                            type_id: TypeID::C_SC_NA_1,
+                            ioa: ADDR,
+                            cause,
+                            negative,
+                        })
+                        .unwrap();
+                }
+                future::ready(Ok::<_, io::Error>(()))
+            })
+            .await
+            .unwrap();
+        (outcome, sent.into_inner().unwrap())
+    }
+
+    const CON: (Cause, bool) = (Cause::ActivationCon, false);
+    const CON_NEGATIVE: (Cause, bool) = (Cause::ActivationCon, true);
+    const TERM: (Cause, bool) = (Cause::ActivationTerm, false);
+
+    #[tokio::test]
+    async fn select_before_operate() {
+        let (outcome, sent) = run(OPTION, true, |select| {
+            if select {
+                vec![CON]
+            } else {
+                vec![CON, TERM]
+            }
+        })
+        .await;
+        assert_eq!(outcome, CommandOutcome::Executed);
+        assert_eq!(sent, [true, false]);
+        assert!(outcome.is_success());
+        assert!(outcome.error(ADDR).is_none());
+    }
+
+    #[tokio::test]
+    async fn rejected() {
+        let (outcome, sent) = run(OPTION, true, |_| vec![CON_NEGATIVE]).await;
+        assert_eq!(outcome, CommandOutcome::Rejected(CommandPhase::Select));
+        assert_eq!(sent, [true]);
+        assert!(matches!(
+            outcome.error(ADDR),
+            Some(crate::error::Error::Rejected {
+                addr: ADDR,
+                phase: CommandPhase::Select
+            })
+        ));
+
+        let (outcome, sent) = run(OPTION, true, |select| {
+            if select {
+                vec![CON]
+            } else {
+                vec![CON_NEGATIVE]
+            }
+        })
+        .await;
+        assert_eq!(outcome, CommandOutcome::Rejected(CommandPhase::Execute));
+        assert_eq!(sent, [true, false]);
+        assert!(matches!(
+            outcome.error(ADDR),
+            Some(crate::error::Error::Rejected {
+                addr: ADDR,
+                phase: CommandPhase::Execute
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn timeouts() {
+        let (outcome, sent) = run(OPTION, true, |_| vec![]).await;
+        assert_eq!(outcome, CommandOutcome::Timeout(CommandPhase::Select));
+        assert_eq!(sent, [true]);
+        assert!(matches!(
+            outcome.error(ADDR),
+            Some(crate::error::Error::Timeout)
+        ));
+
+        let (outcome, _) = run(
+            OPTION,
+            true,
+            |select| if select { vec![CON] } else { vec![] },
+        )
+        .await;
+        assert_eq!(outcome, CommandOutcome::Timeout(CommandPhase::Execute));
+
+        // confirmed but never terminated still counts as success
+        let (outcome, sent) = run(OPTION, true, |_| vec![CON]).await;
+        assert_eq!(outcome, CommandOutcome::Confirmed);
+        assert_eq!(sent, [true, false]);
+        assert!(outcome.is_success());
+    }
+
+    #[tokio::test]
+    async fn responses_of_other_objects() {
+        let handler = IEC104ClientHandler::new();
+        let outcome = handler
+            .command(&OPTION, TypeID::C_SC_NA_1, ADDR, false, |_| {
+                for (type_id, ioa) in [(TypeID::C_DC_NA_1, ADDR), (TypeID::C_SC_NA_1, ADDR + 1)] {
+                    handler
+                        .command_tx
+                        .send(CommandResponse {
+                            type_id,
+                            ioa,
+                            cause: Cause::ActivationCon,
+                            negative: false,
+                        })
+                        .unwrap();
+                }
+                future::ready(Ok::<_, io::Error>(()))
+            })
+            .await
+            .unwrap();
+        assert_eq!(outcome, CommandOutcome::Timeout(CommandPhase::Execute));
+    }
+
+    #[tokio::test]
+    async fn direct_execute() {
+        let direct = CommandOption {
+            select: false,
+            ..OPTION
+        };
+        let (outcome, sent) = run(direct, true, |_| vec![CON, TERM]).await;
+        assert_eq!(outcome, CommandOutcome::Executed);
+        assert_eq!(sent, [false]);
+
+        // commands without S/E bit are never selected
+        let (outcome, sent) = run(OPTION, false, |_| vec![CON, TERM]).await;
+        assert_eq!(outcome, CommandOutcome::Executed);
+        assert_eq!(sent, [false]);
+    }
+
+    #[tokio::test]
+    async fn send_errors() {
+        let handler = IEC104ClientHandler::new();
+        let e = handler
+            .command(&OPTION, TypeID::C_SC_NA_1, ADDR, true, |_| {
+                future::ready(Err::<(), _>(io::Error::from(io::ErrorKind::BrokenPipe)))
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn qualifiers() {
+        for (qu, value) in [
+            (Qualifier::None, 0),
+            (Qualifier::ShortPulse, 1),
+            (Qualifier::LongPulse, 2),
+            (Qualifier::Persistent, 3),
+        ] {
+            for select in [true, false] {
+                let mut cmd = single_cmd(ADDR, true, select, qu);
+                assert_eq!(cmd.ioa.addr().get(), ADDR);
+                assert_eq!(cmd.qoc.qu().get().value(), value);
+                assert_eq!(cmd.qoc.se().get(), select);
+
+                let mut cmd = double_cmd(ADDR, 2, select, qu);
+                assert_eq!(cmd.ioa.addr().get(), ADDR);
+                assert_eq!(cmd.qoc.qu().get().value(), value);
+                assert_eq!(cmd.qoc.se().get(), select);
+            }
+        }
+    }
+
+    /// Link over a plain TCP connection, writes the U frames of STARTDT and STOPDT act
+    struct FakeLink {
+        stream: std::net::TcpStream,
+        active: std::sync::atomic::AtomicBool,
+    }
+
+    const STARTDT: [u8; 6] = [0x68, 0x04, 0x07, 0x00, 0x00, 0x00];
+    const STOPDT: [u8; 6] = [0x68, 0x04, 0x13, 0x00, 0x00, 0x00];
+
+    impl FakeLink {
+        fn send(&self, frame: &[u8], active: bool) -> Result<(), Error> {
+            use std::io::Write;
+            (&self.stream).write_all(frame).unwrap();
+            self.active.store(active, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl Link for FakeLink {
+        async fn is_connected(&self) -> bool {
+            // nothing is sent by the outstation, a read of 0 bytes is the end of the connection
+            !matches!(self.stream.peek(&mut [0]), Ok(0))
+        }
+
+        async fn is_active(&self) -> bool {
+            self.active.load(Ordering::Relaxed)
+        }
+
+        async fn send_start_dt(&self) -> Result<(), Error> {
+            self.send(&STARTDT, true)
+        }
+
+        async fn send_stop_dt(&self) -> Result<(), Error> {
+            self.send(&STOPDT, false)
+        }
+    }
+
+    async fn frame(outstation: &mut tokio::net::TcpStream) -> [u8; 6] {
+        use tokio::io::AsyncReadExt;
+        let mut frame = [0; 6];
+        timeout(Duration::from_secs(1), outstation.read_exact(&mut frame))
+            .await
+            .unwrap()
+            .unwrap();
+        frame
+    }
+
+    #[tokio::test]
+    async fn switchover() {
+        let mut endpoints = Vec::new();
+        let mut clients = Vec::new();
+        let mut outstations = Vec::new();
+        for active in [false, true] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            stream.set_nonblocking(true).unwrap();
+            outstations.push(listener.accept().await.unwrap().0);
+            endpoints.push(addr);
+            clients.push(Arc::new(FakeLink {
+                stream,
+                active: active.into(),
+            }));
+        }
+        let group = Redundancy {
+            endpoints,
+            clients,
+            active: AtomicUsize::new(0),
+            link_stats: Arc::new(Mutex::new(LinkStats::default())),
+        };
+        let mut backup = outstations.pop().unwrap();
+        let mut primary = outstations.pop().unwrap();
+
+        // the backup left in data transfer is stopped, the primary started
+        let client = group.supervise().await.unwrap();
+        assert!(Arc::ptr_eq(&client, &group.clients[0]));
+        assert_eq!(frame(&mut backup).await, STOPDT);
+        assert_eq!(frame(&mut primary).await, STARTDT);
+        assert_eq!(group.link_stats.lock().unwrap().switchovers, 0);
+
+        drop(primary);
+        sleep(Duration::from_millis(50)).await;
+        let client = group.supervise().await.unwrap();
+        assert!(Arc::ptr_eq(&client, &group.clients[1]));
+        assert_eq!(group.active.load(Ordering::Relaxed), 1);
+        assert_eq!(frame(&mut backup).await, STARTDT);
+        assert_eq!(group.link_stats.lock().unwrap().switchovers, 1);
+
+        // nothing to do while the backup stays connected
+        let client = group.supervise().await.unwrap();
+        assert!(Arc::ptr_eq(&client, &group.clients[1]));
+        assert_eq!(group.link_stats.lock().unwrap().switchovers, 1);
+        let mut buf = [0; 1];
+        assert!(timeout(Duration::from_millis(50), backup.peek(&mut buf))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn link_settings_after_start() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = IEC104Client::new(listener.local_addr().unwrap(), 1).unwrap();
+        let stats = Arc::new(Mutex::new(LinkStats::default()));
+        client.set_link_stats(stats.clone()).unwrap();
+        assert!(Arc::ptr_eq(&client.link_stats(), &stats));
+
+        client.start().await.unwrap();
+        let e = client
+            .set_link_stats(Arc::new(Mutex::new(LinkStats::default())))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(Arc::ptr_eq(&client.link_stats(), &stats));
+    }
+
+    /// CP56Time2a of 2024-06-01 12:30:10.000
+    const CP56: [u8; 7] = [0x10, 0x27, 30, 12, 1, 6, 24];
+
+    /// Spontaneous ASDU to common address 1 with `objects` of IOA and element
+    fn asdu(type_id: u8, objects: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut asdu = vec![type_id, objects.len() as u8, 3, 0, 1, 0];
+        for (ioa, element) in objects {
+            asdu.extend_from_slice(&ioa.to_le_bytes());
+            asdu.push(0);
+            asdu.extend_from_slice(element);
+        }
+        asdu
+    }
+
+    fn with_time(element: &[u8]) -> Vec<u8> {
+        [element, &CP56].concat()
+    }
+
+    #[test]
+    fn step_positions_and_bitstrings() {
+        let replay = crate::record::Iec104Replay::default();
+        // VTI 5 transient, VTI 63 with QDS invalid
+        replay
+            .feed(&asdu(5, &[(0x10, &[0x85, 0x00]), (0x11, &[0x3F, 0x80])]))
+            .unwrap();
+        replay
+            .feed(&asdu(32, &[(0x12, &with_time(&[0x03, 0x00]))]))
+            .unwrap();
+        assert_eq!(
+            replay.read_st(0x10),
+            Some(StepPosition {
+                value: 5,
+                transient: true
+            })
+        );
+        assert_eq!(
+            replay.read_st(0x11),
+            Some(StepPosition {
+                value: 63,
+                transient: false
+            })
+        );
+        assert_eq!(replay.point(&Functions::St, 0x12).unwrap().to_string(), "3");
+        assert_eq!(replay.read_st(0x13), None);
+
+        replay
+            .feed(&asdu(7, &[(0x20, &[0x78, 0x56, 0x34, 0x12, 0x80])]))
+            .unwrap();
+        replay
+            .feed(&asdu(33, &[(0x21, &with_time(&[0x01, 0, 0, 0x80, 0x00]))]))
+            .unwrap();
+        assert_eq!(replay.read_bo(0x20), Some(0x12345678));
+        assert_eq!(
+            replay.point(&Functions::Bo, 0x21).unwrap().to_string(),
+            "0x80000001"
+        );
+        assert_eq!(
+            replay.extract_all_bo(),
+            [(0x20, 0x12345678), (0x21, 0x80000001)]
+        );
+    }
+
+    #[test]
+    fn protection_events() {
+        let replay = crate::record::Iec104Replay::default();
+        // SEP: event state on with EI, elapsed time 1000 ms
+        replay
+            .feed(&asdu(38, &[(0x30, &with_time(&[0x0A, 0xE8, 0x03]))]))
+            .unwrap();
+        // SPE: general start and start L2, QDP invalid, relay duration 100 ms
+        replay
+            .feed(&asdu(39, &[(0x31, &with_time(&[0x05, 0x80, 0x64, 0x00]))]))
+            .unwrap();
+        // OCI: general command and command L1, relay operating time 10 ms
+        replay
+            .feed(&asdu(40, &[(0x32, &with_time(&[0x03, 0x00, 0x0A, 0x00]))]))
+            .unwrap();
+        assert_eq!(
+            replay.read_ep(0x30),
+            Some(ProtectionEvent {
+                value: 2,
+                msec: 1000
+            })
+        );
+        assert_eq!(
+            replay.read_eps(0x31),
+            Some(ProtectionEvent {
+                value: 0x05,
+                msec: 100
+            })
+        );
+        assert_eq!(
+            replay.read_epo(0x32),
+            Some(ProtectionEvent {
+                value: 0x03,
+                msec: 10
+            })
+        );
+        assert_eq!(
+            replay.point(&Functions::Eps, 0x31).unwrap().to_string(),
+            "00000101 (100 ms)"
+        );
+        // each type has its own space
+        assert_eq!(replay.read_ep(0x31), None);
+        assert_eq!(replay.read_eps(0x30), None);
+    }
+
+    #[test]
+    fn packed_single_points() {
+        let replay = crate::record::Iec104Replay::default();
+        // ST = 0x8005, CD = 0x0001, QDS 0
+        replay
+            .feed(&asdu(20, &[(0x40, &[0x05, 0x80, 0x01, 0x00, 0x00])]))
+            .unwrap();
+        assert_eq!(
+            replay.read_ps(0x40),
+            Some(PackedSinglePoint {
+                stn: 0x8005,
+                cdn: 0x0001
+            })
+        );
+        assert_eq!(
+            replay.point(&Functions::Ps, 0x40).unwrap().to_string(),
+            "1000000000000101 (cd 0000000000000001)"
+        );
+        assert_eq!(replay.extract_all_ps().len(), 1);
+    }
+}
@@ -0,0 +1,578 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    time::Instant,
+};
+
+use crate::record::{self, Record, Recorder};
+
+/// t1, time-out of send or test APDUs
+const T1: Duration = Duration::from_secs(15);
+/// t3, time-out for sending test frames in case of a long idle state
+const T3: Duration = Duration::from_secs(20);
+
+const START_BYTE: u8 = 0x68;
+const STARTDT_ACT: u8 = 0x07;
+const STARTDT_CON: u8 = 0x0B;
+const STOPDT_ACT: u8 = 0x13;
+const STOPDT_CON: u8 = 0x23;
+const TESTFR_ACT: u8 = 0x43;
+const TESTFR_CON: u8 = 0x83;
+
+/// Link layer statistics of an IEC104 connection
+#[derive(Debug, Clone, Default)]
+pub struct LinkStats {
+    pub connections: u32,
+    pub reconnects: u32,
+    /// data transfer moved to another connection of the redundancy group
+    pub switchovers: u32,
+    pub connect_errors: u32,
+    pub i_sent: u64,
+    pub i_received: u64,
+    pub s_sent: u64,
+    pub s_received: u64,
+    pub u_sent: u64,
+    pub u_received: u64,
+    pub testfr_act_sent: u64,
+    pub testfr_con_received: u64,
+    /// TESTFR act sent by the outstation, i.e. its t3 expired
+    pub testfr_act_received: u64,
+    pub testfr_con_sent: u64,
+    pub t1_timeouts: u32,
+    /// TESTFR act sent by us after nothing was received for t3
+    pub t3_timeouts: u32,
+    pub first_startdt_con: Option<Duration>,
+    pub last_startdt_con: Option<Duration>,
+    /// N(S) gaps of received I frames
+    pub seq_gaps: u32,
+    /// ASDU count per type identification
+    pub asdu_types: BTreeMap<u8, u64>,
+}
+
+impl fmt::Display for LinkStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} connections, {} reconnects, {} switchovers, {} connect errors",
+            self.connections, self.reconnects, self.switchovers, self.connect_errors
+        )?;
+        writeln!(
+            f,
+            "I frames {} sent, {} received; S frames {} sent, {} received; U frames {} sent, {} received",
+            self.i_sent, self.i_received, self.s_sent, self.s_received, self.u_sent, self.u_received
+        )?;
+        writeln!(
+            f,
+            "TESTFR {} act sent/{} con received, {} act received/{} con sent",
+            self.testfr_act_sent,
+            self.testfr_con_received,
+            self.testfr_act_received,
+            self.testfr_con_sent
+        )?;
+        writeln!(
+            f,
+            "{} t1 timeouts, {} t3 timeouts, {} sequence gaps",
+            self.t1_timeouts, self.t3_timeouts, self.seq_gaps
+        )?;
+        match (self.first_startdt_con, self.last_startdt_con) {
+            (Some(first), Some(last)) => writeln!(
+                f,
+                "STARTDT con after {} ms (first), {} ms (last)",
+                first.as_millis(),
+                last.as_millis()
+            )?,
+            _ => writeln!(f, "STARTDT con not received")?,
+        }
+        for (t, n) in self.asdu_types.iter() {
+            writeln!(f, "  {:<10} ({:>3}): {}", type_name(*t), t, n)?;
+        }
+        Ok(())
+    }
+}
+
+/// Transparent relay between the IEC104 client and the outstation, which decodes the APCI of
+/// every frame passing through to collect [`LinkStats`].
+pub struct LinkMonitor {
+    listener: TcpListener,
+    remote: SocketAddr,
+    stats: Arc<Mutex<LinkStats>>,
+    recorder: Option<Arc<Recorder>>,
+}
+
+impl LinkMonitor {
+    /// Bind a local port for the client to connect to instead of `remote`, must be called
+    /// within the tokio runtime
+    pub fn bind(remote: SocketAddr, stats: Arc<Mutex<LinkStats>>) -> io::Result<Self> {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        Ok(LinkMonitor {
+            listener,
+            remote,
+            stats,
+            recorder: None,
+        })
+    }
+
+    pub fn set_stats(&mut self, stats: Arc<Mutex<LinkStats>>) {
+        self.stats = stats;
+    }
+
+    /// Write the ASDU of every I frame to `recorder`
+    pub fn set_recorder(&mut self, recorder: Arc<Recorder>) {
+        self.recorder = Some(recorder);
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn spawn(self) {
+        let listener = self.listener;
+        let remote = self.remote;
+        let stats = self.stats;
+        let recorder = self.recorder;
+        tokio::spawn(async move {
+            let mut connections = 0;
+            loop {
+                let local = match listener.accept().await {
+                    Ok((local, _)) => local,
+                    Err(e) => {
+                        // e.g. out of file descriptors, don't spin on it
+                        log::warn!("IEC104 link monitor accept error: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                match TcpStream::connect(remote).await {
+                    Ok(upstream) => {
+                        connections += 1;
+                        {
+                            let mut s = stats.lock().unwrap();
+                            s.connections += 1;
+                            if connections > 1 {
+                                s.reconnects += 1;
+                                log::warn!("IEC104 reconnected to {} ({})", remote, s.reconnects);
+                            }
+                        }
+                        relay(local, upstream, stats.clone(), recorder.clone()).await;
+                    }
+                    Err(e) => {
+                        stats.lock().unwrap().connect_errors += 1;
+                        log::warn!("IEC104 connect {} error: {}", remote, e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+/// Per connection state to detect timeouts and sequence gaps
+struct ConnState {
+    connected_at: Instant,
+    startdt_con: bool,
+    /// U act frames sent, waiting for con
+    pending_u: Option<Instant>,
+    /// N(S) and time of I frames sent, waiting for ack
+    unacked: VecDeque<(u16, Instant)>,
+    next_ns_received: Option<u16>,
+    last_received: Instant,
+    /// send time of the frame whose t1 timeout was counted
+    t1_counted: Option<Instant>,
+}
+
+impl ConnState {
+    fn new() -> Self {
+        let now = Instant::now();
+        ConnState {
+            connected_at: now,
+            startdt_con: false,
+            pending_u: None,
+            unacked: VecDeque::new(),
+            next_ns_received: None,
+            last_received: now,
+            t1_counted: None,
+        }
+    }
+
+    /// Send time of the oldest frame still waiting for a confirmation
+    fn oldest_pending(&self) -> Option<Instant> {
+        let unacked = self.unacked.front().map(|(_, t)| *t);
+        match (self.pending_u, unacked) {
+            (Some(u), Some(i)) => Some(u.min(i)),
+            (u, i) => u.or(i),
+        }
+    }
+}
+
+async fn relay(
+    local: TcpStream,
+    upstream: TcpStream,
+    stats: Arc<Mutex<LinkStats>>,
+    recorder: Option<Arc<Recorder>>,
+) {
+    let state = Mutex::new(ConnState::new());
+    let (local_r, local_w) = local.into_split();
+    let (upstream_r, upstream_w) = upstream.into_split();
+
+    let recorder = recorder.as_deref();
+    let watch = async {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            check_t1(&state, &stats);
+        }
+    };
+    tokio::select! {
+        _ = pipe(local_r, upstream_w, Direction::Sent, &state, &stats, recorder) => {}
+        _ = pipe(upstream_r, local_w, Direction::Received, &state, &stats, recorder) => {}
+        _ = watch => {}
+    }
+    check_t1(&state, &stats);
+}
+
+/// Count a t1 timeout once for the oldest frame not confirmed within t1
+fn check_t1(state: &Mutex<ConnState>, stats: &Mutex<LinkStats>) {
+    let mut state = state.lock().unwrap();
+    let Some(sent) = state.oldest_pending() else {
+        return;
+    };
+    if sent.elapsed() >= T1 && state.t1_counted != Some(sent) {
+        state.t1_counted = Some(sent);
+        stats.lock().unwrap().t1_timeouts += 1;
+        log::warn!("IEC104 t1 timeout");
+    }
+}
+
+async fn pipe(
+    mut r: OwnedReadHalf,
+    mut w: OwnedWriteHalf,
+    dir: Direction,
+    state: &Mutex<ConnState>,
+    stats: &Mutex<LinkStats>,
+    recorder: Option<&Recorder>,
+) -> io::Result<()> {
+    let mut buf = [0u8; 1024];
+    let mut frame = Vec::new();
+    loop {
+        let n = r.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        w.write_all(&buf[..n]).await?;
+
+        frame.extend_from_slice(&buf[..n]);
+        while let Some(apdu) = next_apdu(&mut frame) {
+            on_apdu(&apdu, dir, state, stats);
+            if let Some(recorder) = recorder {
+                record_apdu(recorder, &apdu, dir);
+            }
+        }
+    }
+}
+
+/// Take the next complete APDU out of `frame`, skipping bytes up to a start byte
+fn next_apdu(frame: &mut Vec<u8>) -> Option<Vec<u8>> {
+    while frame.len() >= 2 {
+        if frame[0] != START_BYTE {
+            // lost sync, drop until the next start byte
+            let skip = frame
+                .iter()
+                .position(|b| *b == START_BYTE)
+                .unwrap_or(frame.len());
+            frame.drain(..skip);
+            continue;
+        }
+        let len = frame[1] as usize + 2;
+        if frame.len() < len {
+            return None;
+        }
+        let apdu: Vec<u8> = frame.drain(..len).collect();
+        if len >= 6 {
+            return Some(apdu);
+        }
+    }
+    None
+}
+
+fn record_apdu(recorder: &Recorder, apdu: &[u8], dir: Direction) {
+    if apdu[2] & 0x01 == 0 && apdu.len() > 6 {
+        recorder.write(&Record::Iec104 {
+            time: record::now(),
+            sent: dir == Direction::Sent,
+            asdu: record::to_hex(&apdu[6..], ""),
+        });
+    }
+}
+
+fn on_apdu(apdu: &[u8], dir: Direction, state: &Mutex<ConnState>, stats: &Mutex<LinkStats>) {
+    let mut state = state.lock().unwrap();
+    let mut stats = stats.lock().unwrap();
+    let c = &apdu[2..6];
+    let idle = state.last_received.elapsed();
+    if dir == Direction::Received {
+        state.last_received = Instant::now();
+    }
+    if c[0] & 0x01 == 0 {
+        // I frame
+        let ns = u16::from_le_bytes([c[0], c[1]]) >> 1;
+        let nr = u16::from_le_bytes([c[2], c[3]]) >> 1;
+        match dir {
+            Direction::Sent => {
+                stats.i_sent += 1;
+                state.unacked.push_back((ns, Instant::now()));
+            }
+            Direction::Received => {
+                stats.i_received += 1;
+                ack(&mut state, nr);
+                if let Some(expected) = state.next_ns_received {
+                    if ns != expected {
+                        stats.seq_gaps += 1;
+                        log::warn!(
+                            "IEC104 sequence gap: expected N(S) {}, got {}",
+                            expected,
+                            ns
+                        );
+                    }
+                }
+                state.next_ns_received = Some((ns + 1) % 32768);
+                if apdu.len() > 6 {
+                    *stats.asdu_types.entry(apdu[6]).or_insert(0) += 1;
+                }
+            }
+        }
+    } else if c[0] & 0x03 == 0x01 {
+        // S frame
+        let nr = u16::from_le_bytes([c[2], c[3]]) >> 1;
+        match dir {
+            Direction::Sent => stats.s_sent += 1,
+            Direction::Received => {
+                stats.s_received += 1;
+                ack(&mut state, nr);
+            }
+        }
+    } else {
+        // U frame
+        match dir {
+            Direction::Sent => stats.u_sent += 1,
+            Direction::Received => stats.u_received += 1,
+        }
+        match (c[0], dir) {
+            (STARTDT_ACT | STOPDT_ACT | TESTFR_ACT, Direction::Sent) => {
+                if c[0] == TESTFR_ACT {
+                    stats.testfr_act_sent += 1;
+                    if idle >= T3 {
+                        stats.t3_timeouts += 1;
+                    }
+                }
+                state.pending_u = Some(Instant::now());
+            }
+            (STARTDT_CON | STOPDT_CON | TESTFR_CON, Direction::Received) => {
+                state.pending_u = None;
+                if c[0] == TESTFR_CON {
+                    stats.testfr_con_received += 1;
+                }
+                if c[0] == STARTDT_CON && !state.startdt_con {
+                    state.startdt_con = true;
+                    let elapsed = state.connected_at.elapsed();
+                    stats.first_startdt_con.get_or_insert(elapsed);
+                    stats.last_startdt_con = Some(elapsed);
+                }
+            }
+            (TESTFR_ACT, Direction::Received) => stats.testfr_act_received += 1,
+            (TESTFR_CON, Direction::Sent) => stats.testfr_con_sent += 1,
+            _ => {}
+        }
+    }
+}
+
+/// Drop the sent I frames acknowledged by N(R)
+fn ack(state: &mut ConnState, nr: u16) {
+    while let Some((ns, _)) = state.unacked.front() {
+        // N(R) is the next expected N(S), everything before it is acknowledged
+        let distance = (nr + 32768 - ns) % 32768;
+        if distance == 0 || distance > 16384 {
+            break;
+        }
+        state.unacked.pop_front();
+    }
+}
+
+fn type_name(t: u8) -> &'static str {
+    match t {
+        1 => "M_SP_NA_1",
+        3 => "M_DP_NA_1",
+        5 => "M_ST_NA_1",
+        7 => "M_BO_NA_1",
+        9 => "M_ME_NA_1",
+        11 => "M_ME_NB_1",
+        13 => "M_ME_NC_1",
+        15 => "M_IT_NA_1",
+        20 => "M_PS_NA_1",
+        21 => "M_ME_ND_1",
+        30 => "M_SP_TB_1",
+        31 => "M_DP_TB_1",
+        32 => "M_ST_TB_1",
+        33 => "M_BO_TB_1",
+        34 => "M_ME_TD_1",
+        35 => "M_ME_TE_1",
+        36 => "M_ME_TF_1",
+        37 => "M_IT_TB_1",
+        38 => "M_EP_TD_1",
+        39 => "M_EP_TE_1",
+        40 => "M_EP_TF_1",
+        45 => "C_SC_NA_1",
+        46 => "C_DC_NA_1",
+        47 => "C_RC_NA_1",
+        48 => "C_SE_NA_1",
+        49 => "C_SE_NB_1",
+        50 => "C_SE_NC_1",
+        51 => "C_BO_NA_1",
+        70 => "M_EI_NA_1",
+        100 => "C_IC_NA_1",
+        101 => "C_CI_NA_1",
+        102 => "C_RD_NA_1",
+        103 => "C_CS_NA_1",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn i_frame(ns: u16, nr: u16) -> Vec<u8> {
+        let ns = (ns << 1).to_le_bytes();
+        let nr = (nr << 1).to_le_bytes();
+        vec![START_BYTE, 6, ns[0], ns[1], nr[0], nr[1], 13, 1]
+    }
+
+    fn s_frame(nr: u16) -> Vec<u8> {
+        let nr = (nr << 1).to_le_bytes();
+        vec![START_BYTE, 4, 0x01, 0x00, nr[0], nr[1]]
+    }
+
+    fn u_frame(c: u8) -> Vec<u8> {
+        vec![START_BYTE, 4, c, 0x00, 0x00, 0x00]
+    }
+
+    fn feed(frames: &[(Vec<u8>, Direction)]) -> (ConnState, LinkStats) {
+        let state = Mutex::new(ConnState::new());
+        let stats = Mutex::new(LinkStats::default());
+        for (apdu, dir) in frames {
+            on_apdu(apdu, *dir, &state, &stats);
+        }
+        (state.into_inner().unwrap(), stats.into_inner().unwrap())
+    }
+
+    #[test]
+    fn next_apdu_splits_frames() {
+        let mut frame = vec![0x00, 0xFF];
+        frame.extend(u_frame(STARTDT_ACT));
+        frame.extend(&i_frame(1, 2)[..5]);
+        assert_eq!(next_apdu(&mut frame), Some(u_frame(STARTDT_ACT)));
+        assert_eq!(next_apdu(&mut frame), None);
+        frame.extend(&i_frame(1, 2)[5..]);
+        assert_eq!(next_apdu(&mut frame), Some(i_frame(1, 2)));
+        assert!(frame.is_empty());
+
+        // too short for an APCI
+        let mut frame = vec![START_BYTE, 2, 0x01, 0x00];
+        assert_eq!(next_apdu(&mut frame), None);
+        assert!(frame.is_empty());
+    }
+
+    #[test]
+    fn on_apdu_counts_frames() {
+        let (state, stats) = feed(&[
+            (u_frame(STARTDT_ACT), Direction::Sent),
+            (u_frame(STARTDT_CON), Direction::Received),
+            (i_frame(0, 0), Direction::Sent),
+            (i_frame(1, 0), Direction::Sent),
+            (i_frame(0, 1), Direction::Received),
+            (s_frame(1), Direction::Sent),
+            (s_frame(2), Direction::Received),
+            (u_frame(TESTFR_ACT), Direction::Received),
+            (u_frame(TESTFR_CON), Direction::Sent),
+        ]);
+        assert_eq!((stats.i_sent, stats.i_received), (2, 1));
+        assert_eq!((stats.s_sent, stats.s_received), (1, 1));
+        assert_eq!((stats.u_sent, stats.u_received), (2, 2));
+        assert_eq!((stats.testfr_act_received, stats.testfr_con_sent), (1, 1));
+        assert_eq!(stats.asdu_types.get(&13), Some(&1));
+        assert!(stats.first_startdt_con.is_some());
+        assert_eq!(stats.seq_gaps, 0);
+        assert!(state.pending_u.is_none());
+        assert!(state.unacked.is_empty());
+    }
+
+    #[test]
+    fn on_apdu_counts_sequence_gaps() {
+        let (_, stats) = feed(&[
+            (i_frame(0, 0), Direction::Received),
+            (i_frame(1, 0), Direction::Received),
+            (i_frame(3, 0), Direction::Received),
+            (i_frame(4, 0), Direction::Received),
+        ]);
+        assert_eq!(stats.seq_gaps, 1);
+    }
+
+    #[test]
+    fn ack_drops_acknowledged_frames() {
+        let (state, _) = feed(&[
+            (i_frame(32766, 0), Direction::Sent),
+            (i_frame(32767, 0), Direction::Sent),
+            (i_frame(0, 0), Direction::Sent),
+            (s_frame(0), Direction::Received),
+        ]);
+        assert_eq!(
+            state.unacked.iter().map(|(ns, _)| *ns).collect::<Vec<_>>(),
+            [0]
+        );
+    }
+
+    #[test]
+    fn testfr_act_counts_t3_only_when_idle() {
+        let state = Mutex::new(ConnState::new());
+        let stats = Mutex::new(LinkStats::default());
+        on_apdu(&u_frame(TESTFR_ACT), Direction::Sent, &state, &stats);
+        on_apdu(&u_frame(TESTFR_CON), Direction::Received, &state, &stats);
+        state.lock().unwrap().last_received = Instant::now().checked_sub(T3).unwrap();
+        on_apdu(&u_frame(TESTFR_ACT), Direction::Sent, &state, &stats);
+        let stats = stats.into_inner().unwrap();
+        assert_eq!(stats.testfr_act_sent, 2);
+        assert_eq!(stats.testfr_con_received, 1);
+        assert_eq!(stats.t3_timeouts, 1);
+    }
+
+    #[test]
+    fn t1_timeout_is_counted_once() {
+        let state = Mutex::new(ConnState::new());
+        let stats = Mutex::new(LinkStats::default());
+        on_apdu(&i_frame(0, 0), Direction::Sent, &state, &stats);
+        check_t1(&state, &stats);
+        assert_eq!(stats.lock().unwrap().t1_timeouts, 0);
+
+        state.lock().unwrap().unacked[0].1 = Instant::now().checked_sub(T1).unwrap();
+        check_t1(&state, &stats);
+        check_t1(&state, &stats);
+        assert_eq!(stats.lock().unwrap().t1_timeouts, 1);
+    }
+}
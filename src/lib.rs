@@ -1,16 +1,41 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr},
+    collections::BTreeMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     str::FromStr,
     time::Duration,
 };
 
 use anyhow::Result;
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_verbosity_flag::Verbosity;
+use iec101_client::LinkMode;
+use iec104_client::Qualifier;
+use modbus_proxy::{DropRule, RewriteRule};
 use serde::{Deserialize, Serialize};
+use settings::Source;
 
+pub mod codec;
+pub mod config;
+pub mod error;
+pub mod iec101_client;
 pub mod iec104_client;
+pub mod iec104_link;
+pub mod modbus_ascii;
+pub mod modbus_gateway;
+pub mod modbus_pdu;
+pub mod modbus_proxy;
+pub mod modbus_sniffer;
+pub mod modbus_tls;
+pub mod modbus_udp;
+pub mod record;
+pub mod rs485;
+pub mod rtu_crc;
+pub mod rtu_timing;
+pub mod session;
+pub mod settings;
+pub mod stats;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeviceType {
     Device,
     Host,
@@ -19,15 +44,23 @@ pub enum DeviceType {
 
 #[derive(Debug, Clone, Parser)]
 #[command(author, version, about="Modbus/IEC104 Client Simulator", long_about = None)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// DEVICE: Serial port when using ModBus RTU protocol.
-    /// HOST: Host name or dotted IP address when using ModBus/TCP or IEC104
+    /// HOST: Host name, dotted IP or IPv6 address when using ModBus/TCP or IEC104
     /// NAME: Name of the device in the configuration file
     ///
     /// DEVICE: COM1, COM2 ... on Windows. /dev/ttyS0, /dev/ttyS1 ...  on Linux. /dev/ser1, /dev/ser2 ...    on QNX
-    /// HOST: 192.168.10.13. 192.168.10.13:502. 192.168.10.13:501, After the IP address, you can specify the port number separated by a colon.
+    /// HOST: 192.168.10.13. 192.168.10.13:502. fe80::1. [fe80::1]:502. plc-01.site.local:502, After the address, you can specify the port number separated by a colon.
+    ///       A host name without port is taken as a NAME unless --host is given.
     /// NAME: The name of the device in the configuration file. The configuration file is specified by the -conf option. for example: dpoll em2_0 -r 1 -c 10 -t 4
+    ///       Devices named replay, config or help are shadowed by the subcommands, `dpoll config check` reports them.
     #[clap(name = "DEVICE|HOST|NAME", verbatim_doc_comment)]
+    #[arg(required_unless_present = "print_effective_config")]
+    #[arg(default_value = "", hide_default_value = true)]
     #[arg(next_line_help = true)]
     pub device: String,
 
@@ -39,7 +72,7 @@ pub struct Args {
     #[arg(group = "input", verbatim_doc_comment)]
     pub writevalues: Option<Vec<String>>,
 
-    /// mode (tcp, rtu, rtu-in-tcp, iec104)
+    /// mode (tcp, tls, udp, rtu, ascii, sniff, gateway, proxy, rtu-in-tcp, iec104, iec101)
     #[clap(short, long, default_value = "tcp")]
     pub mode: Option<Mode>,
 
@@ -48,6 +81,10 @@ pub struct Args {
     #[arg(value_parser = parse_reference)]
     pub reference: Vec<u16>,
 
+    /// Whether `-r` was given on the command line rather than defaulted
+    #[clap(skip)]
+    pub reference_given: bool,
+
     /// Slave address (1-255 for rtu, 0-255 for tcp) for reading,
     ///
     /// it is possible to give an address list separated by commas or colons, for example : -a 32,33,34,36:40 read [32,33,34,36,37,38,39,40]
@@ -105,20 +142,26 @@ pub struct Args {
     /// -t sva        IEC104 Measured Value Scaled Info 测量值,标度化值
     /// -t r          IEC104 Measured Value Float Info 测量值,短浮点数
     /// -t bcr        IEC104 Binary Counter Reading Info 累计量
+    /// -t st         IEC104 Step Position Info 步位置信息
+    /// -t bo         IEC104 Bitstring of 32 bit 32比特串
+    /// -t ep         IEC104 Event of Protection Equipment 继电保护设备事件
+    /// -t eps        IEC104 Packed Start Events of Protection Equipment 继电保护设备成组启动事件
+    /// -t epo        IEC104 Packed Output Circuit Info of Protection Equipment 继电保护设备成组输出电路信息
+    /// -t ps         IEC104 Packed Single Point with Status Change Detection 带变位检出的成组单点信息
     /// -t all        IEC104 总召唤所有数据
-    #[clap(short, long, default_value = "3", verbatim_doc_comment)]
+    #[clap(short, long, default_value = "3", verbatim_doc_comment, global = true)]
     #[arg(value_parser = parse_type)]
     pub r#type: Option<Type>,
 
     /// Little endian word order for 32-bit integer and float [default = Big endian]
-    #[clap(short = 'L')]
+    #[clap(short = 'L', global = true)]
     pub little_endian: bool,
 
     /// Poll only once only, otherwise every poll rate interval
     #[clap(short = '1')]
     pub once: bool,
 
-    /// Poll rate in ms, ( > 10)
+    /// Poll rate in ms, ( > 10), `period_ms` of the device in the configuration file if not given
     #[clap(short = 'l', default_value = "1000")]
     pub poll_rate: Option<u64>,
 
@@ -127,15 +170,44 @@ pub struct Args {
     #[arg(value_parser = parse_timeout)]
     pub timeout: Option<Duration>,
 
-    /// TCP port number (502 is default)
+    /// TCP port number (502 is default, 802 for tls)
     #[clap(short, long, default_value = "502")]
     pub port: Option<u16>,
 
+    /// DEVICE|HOST|NAME is a host name looked up in DNS, not a NAME in the configuration file
+    #[clap(long)]
+    pub host: bool,
+
+    /// CA certificate (PEM) to verify the server certificate with (tls)
+    #[clap(long)]
+    pub ca: Option<String>,
+
+    /// Client certificate (PEM) (tls)
+    #[clap(long)]
+    pub cert: Option<String>,
+
+    /// Client private key (PEM) (tls)
+    #[clap(long)]
+    pub key: Option<String>,
+
+    /// Name the server certificate must be issued for, HOST by default (tls)
+    #[clap(long)]
+    pub server_name: Option<String>,
+
+    /// Retries of a request without reply (udp), the time-out applies to each attempt
+    #[clap(long, default_value = "2")]
+    pub retries: Option<usize>,
+
+    /// Slaves polled at the same time (tcp, tls, udp), N opens N connections to the device
+    /// as requests are not pipelined, 1 polls all slaves over a single connection
+    #[clap(long, default_value = "1")]
+    pub parallel: Option<usize>,
+
     /// Baudrate (1200-921600)
     #[clap(short, default_value = "9600")]
     pub baudrate: Option<u32>,
 
-    /// Databits (7 or 8, 8 for RTU)
+    /// Databits (7 or 8, 8 for RTU, 7 for ASCII if not given)
     #[clap(short, default_value = "8")]
     pub databits: Option<u8>,
 
@@ -143,17 +215,150 @@ pub struct Args {
     #[clap(short, default_value = "1")]
     pub stopbits: Option<u8>,
 
-    /// Parity (none, even, odd)
+    /// Parity (none, even, odd), even for ASCII if not given
     #[clap(short = 'P', default_value = "none")]
     pub parity: Option<String>,
 
+    /// Serial delay in ms between the end of a response and the next request,
+    /// 3.5 characters (1.75 ms above 19200 baud) for RTU and 0 for ASCII if not given,
+    /// sniff mode: silence ending a frame, 3.5 characters but at least 20 ms if not given
+    #[clap(long)]
+    pub request_delay: Option<f32>,
+
+    /// Serial inter-character timeout in ms within a response,
+    /// 1.5 characters (0.75 ms above 19200 baud) for RTU and 1000 for ASCII if not given
+    #[clap(long)]
+    pub char_timeout: Option<f32>,
+
+    /// Serial turnaround delay in ms after a broadcast (slave 0) request
+    #[clap(long, default_value = "100")]
+    pub turnaround: Option<u64>,
+
+    /// Sniff/proxy/replay, output format of a range of registers instead of -t,
+    /// START[-END]=TYPE, can be given multiple times, for example : --format-range 100-103=3:f32
+    #[clap(long, global = true)]
+    #[arg(value_parser = parse_format_range)]
+    pub format_range: Vec<FormatRange>,
+
+    /// Proxy mode, answer reads of a register with a fixed value, ADDR=TYPE=VALUE,
+    /// can be given multiple times, for example : --rewrite 100=3=0x8000 --rewrite 200=4:f32=-1.5
+    #[clap(long)]
+    #[arg(value_parser = parse_rewrite)]
+    pub rewrite: Vec<RewriteRule>,
+
+    /// Proxy mode, neither forward nor answer requests touching the registers, TABLE:START[-END],
+    /// can be given multiple times, for example : --drop 3:200-209
+    #[clap(long)]
+    #[arg(value_parser = parse_drop)]
+    pub drop: Vec<DropRule>,
+
+    /// Gateway/proxy mode, address to accept Modbus/TCP clients on
+    #[clap(long, default_value = "0.0.0.0:502")]
+    pub listen: Option<String>,
+
+    /// Save every request and response to this file, for `dpoll replay`.
+    /// Modbus PDUs are encoded again from the decoded response, so only responses dpoll
+    /// can decode are kept byte for byte, the others are saved as the error they caused
+    #[clap(long)]
+    pub record: Option<String>,
+
+    /// Sniff mode, also save every frame to this file, one line per frame: unix time and hex bytes
+    #[clap(long)]
+    pub capture: Option<String>,
+
+    /// RS-485 mode (/RTS on (0) after sending)
+    #[clap(short = 'R', conflicts_with = "rts_on_send")]
+    pub rts_after_send: bool,
+
+    /// RS-485 mode (/RTS on (0) when sending)
+    #[clap(short = 'F')]
+    pub rts_on_send: bool,
+
+    /// RS-485 delay in ms between switching RTS and the first character sent
+    #[clap(long, default_value = "0")]
+    pub rts_delay_before: Option<u64>,
+
+    /// RS-485 delay in ms between the last character sent and switching RTS back
+    #[clap(long, default_value = "0")]
+    pub rts_delay_after: Option<u64>,
+
+    /// RS-485 direction control by the kernel driver (TIOCSRS485) instead of toggling RTS, Linux only
+    #[clap(long)]
+    pub rs485_kernel: bool,
+
+    /// IEC104 redundancy group, backup HOST of the same outstation, the DEVICE|HOST is the primary.
+    /// can be given multiple times, for example : --backup 192.168.10.14 --backup 192.168.10.15:2405
+    #[clap(long)]
+    pub backup: Vec<String>,
+
+    /// Print IEC104 link statistics after every poll, they are printed on exit anyway
+    #[clap(long)]
+    pub stats: bool,
+
+    /// IEC104 direct execute for siq/diq/nva/sva/r commands, otherwise select-before-operate
+    #[clap(long)]
+    pub direct: bool,
+
+    /// IEC104 qualifier of command for siq/diq commands
+    #[clap(long, default_value = "none")]
+    pub qu: Option<Qualifier>,
+
+    /// IEC101 link transmission procedure
+    #[clap(long, default_value = "unbalanced")]
+    pub link_mode: Option<LinkMode>,
+
+    /// IEC101 link address size in octets (0-2, 0 only for balanced)
+    #[clap(long, default_value = "1")]
+    pub link_addr_size: Option<usize>,
+
+    /// IEC101 cause of transmission size in octets (1-2)
+    #[clap(long, default_value = "1")]
+    pub cot_size: Option<usize>,
+
+    /// IEC101 common address size in octets (1-2)
+    #[clap(long, default_value = "1")]
+    pub ca_size: Option<usize>,
+
+    /// IEC101 information object address size in octets (1-3)
+    #[clap(long, default_value = "2")]
+    pub ioa_size: Option<usize>,
+
+    /// IEC101 class 2 polling interval in ms (unbalanced), class 1 is polled at once when available
+    #[clap(long, default_value = "100")]
+    pub link_poll: Option<u64>,
+
+    /// IEC101 common address of ASDU, the link address (--slave) if not given
+    #[clap(long)]
+    pub common_addr: Option<u16>,
+
     /// Verbose mode.  Causes dpoll to print debugging messages about
     #[command(flatten)]
     pub verbose: Verbosity,
 
     /// The path to the configuration file
-    #[clap(long, default_value = "/home/work/deploy/device/conf/device_list.json")]
+    #[clap(
+        long,
+        default_value = "/home/work/deploy/device/conf/device_list.json",
+        global = true
+    )]
     pub conf: Option<String>,
+
+    /// Poll a device even if it is disabled (`"enable": false`) in the configuration file
+    #[clap(long)]
+    pub allow_disabled: bool,
+
+    /// Signature of the NAME in the configuration file
+    #[clap(skip)]
+    pub signature: Option<Signature>,
+
+    /// Print the settings with the layer each value came from: default, config file
+    /// (~/.config/dpoll/config.toml), environment (DPOLL_*), device or command line
+    #[clap(long)]
+    pub print_effective_config: bool,
+
+    /// Layer of each setting not from the built-in default
+    #[clap(skip)]
+    pub sources: BTreeMap<&'static str, Source>,
     // // TODO
     // /// Read the description of the type, the current status, and other information specific to a remote device (RTU only)
     // #[clap(short = 'u')]
@@ -161,25 +366,490 @@ pub struct Args {
 
     // DEPRECATED
     // -0            First reference is 0 (PDU addressing) instead 1
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Print a session saved with --record again, Modbus values in the format of -t, -L and
+    /// --format-range
+    Replay(ReplayArgs),
+    /// Check or list the devices of the configuration file (--conf)
+    Config(ConfigArgs),
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ReplayArgs {
+    /// File written by --record
+    pub file: String,
 
-    // TODO
-    // -R            RS-485 mode (/RTS on (0) after sending)
-    // -F            RS-485 mode (/RTS on (0) when sending)
+    /// Act as the recorded Modbus device on this address instead, for example : --serve 0.0.0.0:502,
+    /// IEC104 sessions can not be served
+    #[clap(long)]
+    pub serve: Option<String>,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ConfigCommand {
+    /// Report every mistake with the device name and JSON path, fails if there is any
+    Check,
+    /// Print a table of the devices with protocol, endpoint and enabled flag
+    List,
 }
 
 impl Args {
-    pub fn device_type(&mut self) -> DeviceType {
-        let d = self.device.to_lowercase();
-        if (d.contains("com") || d.contains("tty")) || d.contains("ser") {
-            DeviceType::Device
-        } else if d.parse::<Ipv4Addr>().is_ok() || d.parse::<SocketAddr>().is_ok() {
+    pub fn source(&self, setting: &str) -> Source {
+        self.sources.get(setting).copied().unwrap_or_default()
+    }
+
+    /// Whether `setting` was given by any layer rather than defaulted
+    pub fn given(&self, setting: &str) -> bool {
+        self.source(setting) > Source::Default
+    }
+
+    /// A HOST with `--host` or for an address or `host:port`, a serial port by its path, a NAME
+    /// otherwise
+    pub fn device_type(&self) -> DeviceType {
+        if self.host || parse_host(&self.device).is_some() {
             DeviceType::Host
+        } else if is_serial_port(&self.device) {
+            DeviceType::Device
         } else {
             DeviceType::Name
         }
     }
 }
 
+/// A path like `/dev/ttyS0`, or the Windows `COM1`, `\\.\COM10`
+fn is_serial_port(d: &str) -> bool {
+    let d = d.to_uppercase();
+    let com = d.strip_prefix(r"\\.\").unwrap_or(&d).strip_prefix("COM");
+    d.starts_with('/')
+        || com.is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Host and port of an IP address or `host:port`, for example `192.168.10.13`,
+/// `192.168.10.13:502`, `fe80::1`, `[fe80::1]:502` or `plc-01.site.local:502`
+pub fn parse_host(d: &str) -> Option<(String, Option<u16>)> {
+    if let Ok(ip) = d.parse::<IpAddr>() {
+        return Some((ip.to_string(), None));
+    }
+    if let Ok(sd) = d.parse::<SocketAddr>() {
+        return Some((sd.ip().to_string(), Some(sd.port())));
+    }
+    if let Some(ip) = d.strip_prefix('[').and_then(|d| d.strip_suffix(']')) {
+        return ip.parse::<Ipv6Addr>().ok().map(|ip| (ip.to_string(), None));
+    }
+    let (host, port) = d.rsplit_once(':')?;
+    let port = port.parse::<u16>().ok()?;
+    let valid = host
+        .split('.')
+        .all(|l| !l.is_empty() && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+    valid.then(|| (host.to_string(), Some(port)))
+}
+
+/// Check the arguments and complete them from the configuration of a device NAME and the
+/// defaults of the mode
+pub fn check_args(args: &mut Args) -> Result<()> {
+    let writevalues = args.writevalues.clone();
+    let tp = args.r#type.clone().unwrap();
+    let func = tp.function;
+    let format = tp.format;
+    if writevalues.is_some() {
+        let writevalues = writevalues.unwrap();
+        if args.slave.len() > 1 {
+            Err(anyhow::anyhow!("Only one slave can write"))?;
+        }
+
+        match func {
+            Functions::DiscreteInput
+            | Functions::InputRegister
+            | Functions::St
+            | Functions::Bo
+            | Functions::Ep
+            | Functions::Eps
+            | Functions::Epo
+            | Functions::Ps => {
+                Err(anyhow::anyhow!("Unable to write read-only element"))?;
+            }
+            Functions::HoldingRegister if matches!(format, Formats::String) => {
+                Err(anyhow::anyhow!("You can use string format only for output"))?
+            }
+            Functions::All if !writevalues.is_empty() => {
+                Err(anyhow::anyhow!("Write value not allowed"))?;
+            }
+            _ => {}
+        }
+        for v in writevalues.iter() {
+            match func {
+                Functions::Coil | Functions::Siq => {
+                    if v.parse::<bool>().is_err() {
+                        Err(anyhow::anyhow!("Write value {} must be bool", v))?;
+                    }
+                }
+                Functions::Diq => {
+                    if !matches!(v.parse::<u8>(), Ok(0..=3)) {
+                        Err(anyhow::anyhow!("Write value {} must be 0/1/2/3", v))?;
+                    }
+                }
+                Functions::Bcr => {
+                    codec::parse_counter(v)?;
+                }
+                _ => {
+                    codec::Value::parse(v, &format)?;
+                }
+            }
+        }
+
+        args.once = true;
+        args.count = Some(args.writevalues.clone().unwrap().len() as u16);
+    }
+
+    match args.device_type() {
+        DeviceType::Device => match args.mode.unwrap() {
+            Mode::IEC101 | Mode::Sniff | Mode::Gateway => {}
+            Mode::Proxy => Err(anyhow::anyhow!("Proxy needs the HOST of the device"))?,
+            Mode::Ascii => ascii_defaults(args),
+            _ => args.mode = Some(Mode::Rtu),
+        },
+        DeviceType::Host => {
+            // the port is split off below, the rest may look like a NAME or serial port
+            args.host = true;
+            if args.mode.unwrap() == Mode::Rtu {
+                args.mode = Some(Mode::Tcp);
+            }
+            tls_defaults(args);
+            if matches!(
+                args.mode.unwrap(),
+                Mode::IEC101 | Mode::Ascii | Mode::Sniff | Mode::Gateway
+            ) {
+                Err(anyhow::anyhow!(
+                    "{:?} needs a serial port",
+                    args.mode.unwrap()
+                ))?;
+            }
+            if let Some((host, port)) = parse_host(&args.device) {
+                args.device = host;
+                if port.is_some() {
+                    args.port = port;
+                }
+            }
+        }
+        DeviceType::Name => {
+            let conf = args.conf.clone().unwrap();
+            let device_list = config::load(&conf)?;
+            let problems = config::validate(&device_list)
+                .into_iter()
+                .filter(|p| p.device == args.device)
+                .collect::<Vec<_>>();
+            if !problems.is_empty() {
+                for p in problems.iter() {
+                    eprintln!("{}", p);
+                }
+                Err(anyhow::anyhow!(
+                    "Device {} is misconfigured in {}, see dpoll config check",
+                    args.device,
+                    conf
+                ))?;
+            }
+
+            let d = device_list
+                .devices
+                .iter()
+                .filter(|d| d.signature.name == args.device)
+                .collect::<Vec<&Device>>();
+
+            if d.is_empty() {
+                Err(anyhow::anyhow!(
+                    "No device {} found, use --host if it is a host name",
+                    args.device
+                ))?;
+            }
+            if d.len() > 1 {
+                Err(anyhow::anyhow!("Multiple devices found: {:?}", d))?;
+            }
+
+            let device = d[0];
+            if !device.enable && !args.allow_disabled {
+                Err(anyhow::anyhow!(
+                    "Device {} is disabled, use --allow-disabled to poll it anyway",
+                    args.device
+                ))?;
+            }
+            args.signature = Some(device.signature.clone());
+            settings::device(
+                &mut args.sources,
+                "poll_rate",
+                &mut args.poll_rate,
+                device.remote.period_ms,
+            );
+            settings::device(
+                &mut args.sources,
+                "timeout",
+                &mut args.timeout,
+                device
+                    .remote
+                    .timeout_ms
+                    .map(|ms| Duration::from_secs_f32(ms as f32 / 1000.0)),
+            );
+            settings::device(
+                &mut args.sources,
+                "port",
+                &mut args.port,
+                device
+                    .remote
+                    .port
+                    .as_ref()
+                    .map(|p| p.parse::<u16>().unwrap()),
+            );
+            settings::device(
+                &mut args.sources,
+                "baudrate",
+                &mut args.baudrate,
+                device.remote.baud,
+            );
+            settings::device(
+                &mut args.sources,
+                "databits",
+                &mut args.databits,
+                device.remote.data_bit,
+            );
+            settings::device(
+                &mut args.sources,
+                "stopbits",
+                &mut args.stopbits,
+                device.remote.stop_bit,
+            );
+            settings::device(
+                &mut args.sources,
+                "parity",
+                &mut args.parity,
+                device.remote.parity.clone(),
+            );
+            let mode = config::mode(&device.remote);
+            match mode {
+                Some(Mode::Rtu | Mode::Ascii) => {
+                    if mode == Some(Mode::Ascii) {
+                        args.mode = Some(Mode::Ascii);
+                        ascii_defaults(args);
+                    } else if !matches!(args.mode.unwrap(), Mode::Sniff | Mode::Gateway) {
+                        args.mode = Some(Mode::Rtu);
+                    }
+                    args.device = device.remote.device.clone().unwrap();
+                    if device.remote.slave_id.is_some() {
+                        args.slave.clear();
+                        args.slave.push(device.remote.slave_id.unwrap());
+                    }
+                }
+                Some(Mode::Tcp | Mode::Tls | Mode::Udp) => {
+                    if args.mode != Some(Mode::Proxy) {
+                        args.mode = mode;
+                    }
+                    tls_defaults(args);
+                    if device.remote.ca.is_some() {
+                        args.ca = device.remote.ca.clone();
+                    }
+                    if device.remote.cert.is_some() {
+                        args.cert = device.remote.cert.clone();
+                    }
+                    if device.remote.key.is_some() {
+                        args.key = device.remote.key.clone();
+                    }
+                    if device.remote.server_name.is_some() {
+                        args.server_name = device.remote.server_name.clone();
+                    }
+                    args.device = device.remote.host.clone().unwrap();
+                    args.host = true;
+                    if device.remote.slave_id.is_some() {
+                        args.slave.clear();
+                        args.slave.push(device.remote.slave_id.unwrap());
+                    }
+                }
+                Some(Mode::RtuInTcp) => {
+                    args.mode = Some(Mode::RtuInTcp);
+                    args.device = device.remote.host.clone().unwrap();
+                    args.host = true;
+                    if device.remote.slave_id.is_some() {
+                        args.slave.clear();
+                        args.slave.push(device.remote.slave_id.unwrap());
+                    }
+                }
+                Some(Mode::IEC104) => {
+                    args.mode = Some(Mode::IEC104);
+                    args.device = device.remote.host.clone().unwrap();
+                    args.host = true;
+                    if let Some(backup) = device.remote.backup.clone() {
+                        args.backup = backup;
+                    }
+                    if device.remote.slave_id.is_some() {
+                        args.slave.clear();
+                        args.slave.push(device.remote.slave_id.unwrap());
+                    }
+                }
+                Some(Mode::IEC101) => {
+                    args.mode = Some(Mode::IEC101);
+                    args.device = device.remote.device.clone().unwrap();
+                    if device.remote.slave_id.is_some() {
+                        args.slave.clear();
+                        args.slave.push(device.remote.slave_id.unwrap());
+                    }
+                    if args.common_addr.is_none() {
+                        args.common_addr = device.remote.common_addr;
+                    }
+                }
+                _ => Err(anyhow::anyhow!(
+                    "Unsupported protocol:{} mode:{}",
+                    device.remote.protocol,
+                    device.remote.mode
+                ))?,
+            }
+        }
+    }
+
+    if args.mode.unwrap() == Mode::Tls
+        && (args.ca.is_none() || args.cert.is_none() || args.key.is_none())
+    {
+        Err(anyhow::anyhow!("TLS needs --ca, --cert and --key"))?;
+    }
+
+    if (args.rts_on_send || args.rts_after_send)
+        && !matches!(args.mode.unwrap(), Mode::Rtu | Mode::Ascii | Mode::Gateway)
+    {
+        Err(anyhow::anyhow!(
+            "RS-485 mode (-R/-F) is only for RTU, ASCII or gateway mode, sniff mode never sends"
+        ))?;
+    }
+    if args
+        .request_delay
+        .is_some_and(|ms| !(0.0..=60000.0).contains(&ms))
+        || args
+            .char_timeout
+            .is_some_and(|ms| !(0.0..=60000.0).contains(&ms))
+    {
+        Err(anyhow::anyhow!(
+            "Request delay and char timeout must be 0-60000 ms"
+        ))?;
+    }
+    if args.rs485_kernel && !(args.rts_on_send || args.rts_after_send) {
+        Err(anyhow::anyhow!("--rs485-kernel needs -R or -F"))?;
+    }
+
+    if matches!(
+        args.mode.unwrap(),
+        Mode::Sniff | Mode::Gateway | Mode::Proxy
+    ) && args.writevalues.is_some()
+    {
+        Err(anyhow::anyhow!(
+            "{:?} mode does not take write values",
+            args.mode.unwrap()
+        ))?;
+    }
+    if (!args.rewrite.is_empty() || !args.drop.is_empty()) && args.mode.unwrap() != Mode::Proxy {
+        Err(anyhow::anyhow!(
+            "--rewrite and --drop are only for proxy mode"
+        ))?;
+    }
+    if args.capture.is_some() && args.mode.unwrap() != Mode::Sniff {
+        Err(anyhow::anyhow!("--capture is only for sniff mode"))?;
+    }
+    if args.record.is_some()
+        && matches!(
+            args.mode.unwrap(),
+            Mode::Sniff | Mode::Gateway | Mode::Proxy | Mode::IEC101
+        )
+    {
+        Err(anyhow::anyhow!(
+            "--record is only for Modbus client or IEC104 mode, not {:?}",
+            args.mode.unwrap()
+        ))?;
+    }
+
+    if args.parallel.unwrap() == 0 {
+        Err(anyhow::anyhow!("--parallel must be at least 1"))?;
+    }
+    if args.parallel.unwrap() > 1
+        && !matches!(args.mode.unwrap(), Mode::Tcp | Mode::Tls | Mode::Udp)
+    {
+        Err(anyhow::anyhow!(
+            "--parallel is only for tcp, tls or udp, not {:?}",
+            args.mode.unwrap()
+        ))?;
+    }
+
+    if matches!(args.mode.unwrap(), Mode::IEC104 | Mode::IEC101)
+        && args.writevalues.is_some()
+        && !matches!(
+            func,
+            Functions::Siq
+                | Functions::Diq
+                | Functions::Nva
+                | Functions::Sva
+                | Functions::R
+                | Functions::Bcr
+        )
+    {
+        Err(anyhow::anyhow!(
+            "IEC104/IEC101 can only write siq, diq, nva, sva, r or bcr"
+        ))?;
+    }
+
+    if !matches!(args.mode.unwrap(), Mode::IEC104 | Mode::IEC101)
+        && !matches!(
+            func,
+            Functions::Coil
+                | Functions::DiscreteInput
+                | Functions::InputRegister
+                | Functions::HoldingRegister
+        )
+    {
+        Err(anyhow::anyhow!(
+            "Type {:?} is only for IEC104/IEC101, not {:?}",
+            func,
+            args.mode.unwrap()
+        ))?;
+    }
+
+    if args.mode.unwrap() == Mode::IEC101 {
+        if args.link_addr_size.unwrap() > 2
+            || (args.link_addr_size.unwrap() == 0 && args.link_mode.unwrap() != LinkMode::Balanced)
+        {
+            Err(anyhow::anyhow!(
+                "Link address size must be 1-2 (0-2 for balanced)"
+            ))?;
+        }
+        if !(1..=2).contains(&args.cot_size.unwrap()) || !(1..=2).contains(&args.ca_size.unwrap()) {
+            Err(anyhow::anyhow!("COT and common address size must be 1-2"))?;
+        }
+        if !(1..=3).contains(&args.ioa_size.unwrap()) {
+            Err(anyhow::anyhow!("IOA size must be 1-3"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Modbus/TCP Security listens on port 802
+fn tls_defaults(args: &mut Args) {
+    if args.mode.unwrap() == Mode::Tls && !args.given("port") {
+        args.port = Some(802);
+    }
+}
+
+/// Modbus ASCII uses 7 data bits and even parity unless given otherwise
+fn ascii_defaults(args: &mut Args) {
+    if !args.given("databits") {
+        args.databits = Some(7);
+    }
+    if !args.given("parity") {
+        args.parity = Some("even".to_string());
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Functions {
     Coil,
@@ -192,6 +862,12 @@ pub enum Functions {
     Sva,
     R,
     Bcr,
+    St,
+    Bo,
+    Ep,
+    Eps,
+    Epo,
+    Ps,
     All,
 }
 
@@ -299,6 +975,30 @@ impl FromStr for Type {
                     format = Formats::I32;
                     Functions::Bcr
                 }
+                "st" => {
+                    format = Formats::I16;
+                    Functions::St
+                }
+                "bo" => {
+                    format = Formats::Hex32;
+                    Functions::Bo
+                }
+                "ep" => {
+                    format = Formats::U16;
+                    Functions::Ep
+                }
+                "eps" => {
+                    format = Formats::Bin16;
+                    Functions::Eps
+                }
+                "epo" => {
+                    format = Formats::Bin16;
+                    Functions::Epo
+                }
+                "ps" => {
+                    format = Formats::Bin32;
+                    Functions::Ps
+                }
                 "all" => {
                     format = Formats::Unkonwn;
                     Functions::All
@@ -347,6 +1047,30 @@ impl FromStr for Type {
                     format = Formats::I32;
                     Functions::Bcr
                 }
+                "st" => {
+                    format = Formats::I16;
+                    Functions::St
+                }
+                "bo" => {
+                    format = Formats::Hex32;
+                    Functions::Bo
+                }
+                "ep" => {
+                    format = Formats::U16;
+                    Functions::Ep
+                }
+                "eps" => {
+                    format = Formats::Bin16;
+                    Functions::Eps
+                }
+                "epo" => {
+                    format = Formats::Bin16;
+                    Functions::Epo
+                }
+                "ps" => {
+                    format = Formats::Bin32;
+                    Functions::Ps
+                }
                 "all" => {
                     format = Formats::Unkonwn;
                     Functions::All
@@ -366,12 +1090,12 @@ fn parse_type(s: &str) -> Result<Type> {
     s.parse()
 }
 
-fn parse_timeout(s: &str) -> Result<Duration> {
+pub(crate) fn parse_timeout(s: &str) -> Result<Duration> {
     let f = s.parse::<f32>()?;
     Ok(Duration::from_secs_f32(f))
 }
 
-fn parse_reference(s: &str) -> Result<u16> {
+pub(crate) fn parse_reference(s: &str) -> Result<u16> {
     if s.parse::<u16>().is_ok() {
         s.parse::<u16>().map_err(|e| e.into())
     } else if s.starts_with("0x") && u16::from_str_radix(s.trim_start_matches("0x"), 16).is_ok() {
@@ -383,9 +1107,62 @@ fn parse_reference(s: &str) -> Result<u16> {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// Registers START[-END]
+pub(crate) fn parse_range(s: &str) -> Result<(u16, u16)> {
+    let (start, end) = match s.split_once('-') {
+        Some((start, end)) => (parse_reference(start)?, parse_reference(end)?),
+        None => (parse_reference(s)?, parse_reference(s)?),
+    };
+    if end < start {
+        Err(anyhow::anyhow!("Range end {} before start {}", end, start))?;
+    }
+    Ok((start, end))
+}
+
+/// Output format of a range of registers, START[-END]=TYPE, for example 100-103=3:f32
+#[derive(Debug, Clone)]
+pub struct FormatRange {
+    pub start: u16,
+    pub end: u16,
+    pub r#type: Type,
+}
+
+impl FromStr for FormatRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, tp) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Format range must be START[-END]=TYPE"))?;
+        let (start, end) = parse_range(range)?;
+        Ok(FormatRange {
+            start,
+            end,
+            r#type: tp.parse()?,
+        })
+    }
+}
+
+fn parse_format_range(s: &str) -> Result<FormatRange> {
+    s.parse()
+}
+
+fn parse_rewrite(s: &str) -> Result<RewriteRule> {
+    s.parse()
+}
+
+fn parse_drop(s: &str) -> Result<DropRule> {
+    s.parse()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Signature {
+    #[serde(rename = "type")]
+    pub r#type: Option<String>,
+    pub id: Option<u32>,
     pub name: String,
+    pub model: Option<String>,
+    pub comment: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -403,6 +1180,20 @@ pub struct Remote {
     pub data_bit: Option<u8>,
     pub stop_bit: Option<u8>,
     pub timeout_ms: Option<u32>,
+    /// Default poll rate
+    pub period_ms: Option<u64>,
+    pub nspace: Option<u32>,
+    pub idle_before_segment: Option<u32>,
+    pub lock: Option<String>,
+    /// IEC104 redundancy group, other `host:port` of the same outstation
+    pub backup: Option<Vec<String>>,
+    /// Modbus/TCP Security CA certificate, client certificate and key (PEM)
+    pub ca: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub server_name: Option<String>,
+    /// IEC101 common address of ASDU, `slave_id` is the link address
+    pub common_addr: Option<u16>,
 }
 
 fn default_protocol() -> String {
@@ -417,7 +1208,7 @@ impl Default for Remote {
     fn default() -> Self {
         Remote {
             protocol: default_protocol(),
-            mode: "tcp".to_string(),
+            mode: default_mode(),
             host: None,
             port: None,
             parity: None,
@@ -427,14 +1218,59 @@ impl Default for Remote {
             data_bit: None,
             stop_bit: None,
             timeout_ms: None,
+            period_ms: None,
+            nspace: None,
+            idle_before_segment: None,
+            lock: None,
+            backup: None,
+            ca: None,
+            cert: None,
+            key: None,
+            server_name: None,
+            common_addr: None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Device {
+    #[serde(default = "default_enable")]
+    pub enable: bool,
     pub signature: Signature,
     pub remote: Remote,
+    pub options: Option<Options>,
+    /// Layout of the device, depends on its type
+    pub deploy: Option<serde_json::Value>,
+    pub upstream: Option<serde_json::Value>,
+}
+
+/// What the data collector does with the device, not used by dpoll
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Options {
+    pub sync_time: Option<bool>,
+    pub sync_data: Option<bool>,
+    pub store_fields: Option<bool>,
+    pub store_alarms: Option<bool>,
+    pub store_segments: Option<bool>,
+    pub commands: Option<bool>,
+    pub debug_log: Option<bool>,
+}
+
+fn default_enable() -> bool {
+    true
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        Device {
+            enable: default_enable(),
+            signature: Signature::default(),
+            remote: Remote::default(),
+            options: None,
+            deploy: None,
+            upstream: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -446,11 +1282,138 @@ pub struct DeviceList {
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Mode {
     Tcp,
+    Tls,
+    Udp,
     Rtu,
+    Ascii,
+    // 只监听 RTU 总线
+    Sniff,
+    // Modbus/TCP 转 RTU
+    Gateway,
+    // Modbus/TCP 中间人代理
+    Proxy,
     // 透传
     RtuInTcp,
     IEC104,
+    IEC101,
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    fn parse(argv: &[&str]) -> Args {
+        Args::parse_from(["dpoll"].iter().chain(argv))
+    }
+
+    #[test]
+    fn device_types() {
+        for host in [
+            "192.168.10.13",
+            "192.168.10.13:502",
+            "fe80::1",
+            "[fe80::1]",
+            "[fe80::1]:502",
+            "plc.example.com:502",
+            "server01.local:1502",
+        ] {
+            assert_eq!(parse(&[host]).device_type(), DeviceType::Host, "{}", host);
+        }
+        for port in [
+            "COM1",
+            "com12",
+            r"\\.\COM10",
+            "/dev/ttyUSB0",
+            "/dev/pts/0",
+            "/dev/ser1",
+        ] {
+            assert_eq!(parse(&[port]).device_type(), DeviceType::Device, "{}", port);
+        }
+        // host names without a port are NAMEs unless --host is given
+        for name in [
+            "plc.example.com",
+            "server01.local",
+            "em2_0",
+            "COM",
+            "computer1",
+        ] {
+            assert_eq!(parse(&[name]).device_type(), DeviceType::Name, "{}", name);
+            assert_eq!(
+                parse(&["--host", name]).device_type(),
+                DeviceType::Host,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn host_stays_a_host_without_its_port() {
+        for (device, host, port) in [
+            ("plc.example.com:1502", "plc.example.com", 1502),
+            ("server01.local:502", "server01.local", 502),
+            ("[fe80::1]:503", "fe80::1", 503),
+        ] {
+            let mut args = parse(&[device]);
+            check_args(&mut args).unwrap();
+            assert_eq!(args.device, host);
+            assert_eq!(args.port, Some(port));
+            assert_eq!(args.mode, Some(Mode::Tcp));
+            assert_eq!(args.device_type(), DeviceType::Host);
+        }
+
+        let mut args = parse(&["--host", "server01.local", "-m", "rtu"]);
+        check_args(&mut args).unwrap();
+        assert_eq!(args.mode, Some(Mode::Tcp));
+
+        let mut args = parse(&["/dev/pts/0"]);
+        check_args(&mut args).unwrap();
+        assert_eq!(args.mode, Some(Mode::Rtu));
+    }
+
+    #[test]
+    fn write_values() {
+        let bad: [&[&str]; 8] = [
+            &["10.0.0.1", "-t", "3", "--", "abc"],
+            &["10.0.0.1", "-t", "3:u32", "--", "abc"],
+            &["10.0.0.1", "-t", "3:hex16", "--", "0xg"],
+            &["10.0.0.1", "-t", "3", "--", "70000"],
+            &["10.0.0.1", "-t", "1", "--", "2"],
+            &["10.0.0.1", "-m", "iec104", "-t", "diq", "--", "4"],
+            &["10.0.0.1", "-m", "iec104", "-t", "bcr", "--", "abc"],
+            &["10.0.0.1", "-m", "iec104", "-t", "nva", "--", "40000"],
+        ];
+        for argv in bad {
+            assert!(check_args(&mut parse(argv)).is_err(), "{:?}", argv);
+        }
+        let good: [&[&str]; 6] = [
+            &["10.0.0.1", "-t", "3", "--", "0x1F"],
+            &["10.0.0.1", "-t", "3:u32", "--", "0b101"],
+            &["10.0.0.1", "-t", "3:f32", "--", "-1.5"],
+            &["10.0.0.1", "-m", "iec104", "-t", "bcr", "--", "0xFFFFFFFF"],
+            &["10.0.0.1", "-m", "iec104", "-t", "bcr", "--", "-12"],
+            &["10.0.0.1", "-m", "iec104", "-t", "siq", "--", "true"],
+        ];
+        for argv in good {
+            let mut args = parse(argv);
+            check_args(&mut args).unwrap();
+            assert_eq!(args.count, Some(1));
+        }
+    }
+
+    #[test]
+    fn iec_types_in_modbus_modes() {
+        for t in [
+            "siq", "diq", "nva", "sva", "r", "bcr", "st", "bo", "ep", "eps", "epo", "ps", "all",
+        ] {
+            for argv in [
+                &["10.0.0.1", "-t", t][..],
+                &["10.0.0.1", "-m", "udp", "-t", t],
+                &["/dev/pts/0", "-t", t],
+            ] {
+                assert!(check_args(&mut parse(argv)).is_err(), "{:?}", argv);
+            }
+            check_args(&mut parse(&["10.0.0.1", "-m", "iec104", "-t", t])).unwrap();
+        }
+    }
+}
@@ -1,690 +1,374 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches};
 use colored::Colorize;
 use dpoll::{
-    iec104_client::IEC104Client, Args, Device, DeviceList, DeviceType, Formats, Functions, Mode,
+    check_args,
+    codec::{self, Value},
+    config,
+    error::{self, Error},
+    iec101_client::{IEC101Client, IEC101Option},
+    iec104_client::{CommandOption, IEC104Client, Point, PointSpace},
+    iec104_link::LinkStats,
+    modbus_gateway, modbus_pdu,
+    modbus_proxy::{self, Action, Exchange, ProxyOption},
+    modbus_sniffer::{Sniffed, Sniffer},
+    modbus_tls::TlsOption,
+    parse_host,
+    record::{self, Iec104Replay, Record, Recorder},
+    rs485::{self, Rs485Option, RtsMode},
+    rtu_timing::TimingOption,
+    session::{serial_transport, Backoff, Data, ModbusSession, SerialOption, Session, Transport},
+    settings::{self, Source},
+    stats::{Outcome, PollStats},
+    Args, Command, ConfigCommand, Formats, Functions, Mode, ReplayArgs,
 };
-use lazy_static::lazy_static;
 use std::{
     fs::File,
-    io::BufReader,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc,
-    },
-    time::Duration,
+    io::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::time::{timeout_at, Instant};
-use tokio_modbus::{client::rtu_over_tcp, prelude::*};
-use tokio_serial::SerialStream;
-
-lazy_static! {
-    static ref TRANSMIT_COUNT: Arc<AtomicU32> = Arc::new(AtomicU32::new(0));
-    static ref RECEIVE_COUNT: Arc<AtomicU32> = Arc::new(AtomicU32::new(0));
-    static ref ERROR_COUNT: Arc<AtomicU32> = Arc::new(AtomicU32::new(0));
-}
+use tokio::{
+    net::TcpListener,
+    sync::Semaphore,
+    task::JoinSet,
+    time::{Instant, MissedTickBehavior},
+};
+use tokio_modbus::prelude::*;
+use tokio_serial::{SerialPortBuilder, SerialStream};
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let mut args = Args::parse();
-
-    check_args(&mut args)?;
+async fn main() {
+    if let Err(e) = start().await {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(error::exit_code(&e));
+    }
+}
 
-    if args.verbose.log_level().is_some() {
-        print_args(&args);
+async fn start() -> Result<()> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
+    args.reference_given = matches.value_source("reference") == Some(ValueSource::CommandLine);
+    for setting in settings::SETTINGS {
+        if matches.value_source(setting) == Some(ValueSource::CommandLine) {
+            args.sources.insert(setting, Source::CommandLine);
+        }
     }
+    let user_config = settings::read_user_config()?;
+    settings::apply(
+        &mut args,
+        user_config.as_ref().map(|(p, s)| (p.as_path(), s.as_str())),
+        |name| std::env::var(name).ok(),
+    )?;
 
     env_logger::Builder::new()
         .filter_level(args.verbose.log_level_filter())
         .init();
 
+    match &args.command {
+        Some(Command::Replay(replay_args)) => return replay(&args, replay_args).await,
+        Some(Command::Config(config_args)) => return config(&args, &config_args.command),
+        None => {}
+    }
+
+    if args.print_effective_config {
+        if !args.device.is_empty() {
+            check_args(&mut args)?;
+        }
+        settings::print(&args);
+        return Ok(());
+    }
+    if args.device.is_empty() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "the following required arguments were not provided:\n  <DEVICE|HOST|NAME>",
+            )
+            .exit();
+    }
+
+    check_args(&mut args)?;
+
+    if args.verbose.log_level().is_some() {
+        print_args(&args);
+    }
+
+    let stats = Arc::new(Mutex::new(PollStats::default()));
+    let link_stats = Arc::new(Mutex::new(LinkStats::default()));
     let argsc = args.clone();
+    let statsc = stats.clone();
+    let link_statsc = link_stats.clone();
     ctrlc::set_handler(move || {
-        if !argsc.once && argsc.writevalues.is_none() && argsc.mode.unwrap() != Mode::IEC104 {
-            let tc = TRANSMIT_COUNT.load(std::sync::atomic::Ordering::Relaxed);
-            let rc = RECEIVE_COUNT.load(std::sync::atomic::Ordering::Relaxed);
-            let ec = ERROR_COUNT.load(std::sync::atomic::Ordering::Relaxed);
-            println!(
-                "--- {} poll statistics --- \n
-{} frames transmitted, {} received, {} errors, {:.1}% frame loss\n",
-                argsc.device,
-                tc,
-                rc,
-                ec,
-                (tc as f32 - rc as f32) / tc as f32 * 100.0
-            );
-        }
+        print_stats(&argsc, &statsc, &link_statsc);
         println!("everything was closed.\nHave a nice day !");
         std::process::exit(0);
     })?;
 
-    match args.device_type() {
-        DeviceType::Device => match args.mode.unwrap() {
-            Mode::Rtu => rtu_client(args).await?,
-            Mode::Tcp => unreachable!(),
-            Mode::RtuInTcp => unreachable!(),
-            Mode::IEC104 => unreachable!(),
-        },
-        _ => match args.mode.unwrap() {
-            Mode::Tcp => tcp_client(args).await?,
-            Mode::Rtu => rtu_client(args).await?,
-            Mode::RtuInTcp => rtu_in_tcp_client(args).await?,
-            Mode::IEC104 => iec104_client(args).await?,
-        },
-    }
+    // check_args decided between serial port and host, the mode tells which
+    let rs = match args.mode.unwrap() {
+        Mode::Rtu | Mode::Ascii => rtu_client(args.clone(), stats.clone()).await,
+        Mode::IEC101 => iec101_client(args.clone()).await,
+        Mode::Sniff => sniff(args.clone(), stats.clone()).await,
+        Mode::Gateway => gateway(args.clone()).await,
+        Mode::Tcp | Mode::Tls => tcp_client(args.clone(), stats.clone()).await,
+        Mode::Udp => udp_client(args.clone(), stats.clone()).await,
+        Mode::RtuInTcp => rtu_in_tcp_client(args.clone(), stats.clone()).await,
+        Mode::Proxy => proxy(args.clone(), stats.clone()).await,
+        Mode::IEC104 => iec104_client(args.clone(), link_stats.clone()).await,
+    };
+    print_stats(&args, &stats, &link_stats);
+    rs
+}
 
-    Ok(())
+/// Request statistics per slave, or the IEC104 link statistics, printed on exit
+fn print_stats(args: &Args, stats: &Mutex<PollStats>, link_stats: &Mutex<LinkStats>) {
+    if args.writevalues.is_none()
+        && !matches!(
+            args.mode.unwrap(),
+            Mode::IEC104 | Mode::IEC101 | Mode::Gateway
+        )
+    {
+        let stats = stats.lock().unwrap();
+        for (unit, device) in stats.devices.iter() {
+            println!(
+                "--- {} slave {} poll statistics --- \n\n{}",
+                args.device, unit, device
+            );
+        }
+        if stats.connections > 1 || stats.connect_errors > 0 {
+            println!(
+                "{} connections, {} lost, {} connect errors\n",
+                stats.connections, stats.disconnects, stats.connect_errors
+            );
+        }
+    }
+    if args.mode.unwrap() == Mode::IEC104 {
+        println!(
+            "--- {} link statistics --- \n\n{}",
+            args.device,
+            link_stats.lock().unwrap()
+        );
+    }
 }
 
-async fn run<T: Writer + Reader>(mut ctx: T, args: Args) -> Result<()> {
-    loop {
-        let writevalues = args.writevalues.clone();
-        let function = args.r#type.clone().unwrap().function;
-        let format = args.r#type.clone().unwrap().format;
-        let slave = args.slave.clone();
-        let reference = args.reference.clone();
-        let count = args.count.unwrap();
-        let duration = args.timeout.unwrap();
-        let mut nregs = count;
-
-        if format == Formats::I32
-            || format == Formats::I32abcd
-            || format == Formats::I32badc
-            || format == Formats::I32cdab
-            || format == Formats::I32dcba
-            || format == Formats::U32
-            || format == Formats::U32abcd
-            || format == Formats::U32badc
-            || format == Formats::U32cdab
-            || format == Formats::U32dcba
-            || format == Formats::F32
-            || format == Formats::F32abcd
-            || format == Formats::F32badc
-            || format == Formats::F32cdab
-            || format == Formats::F32dcba
-            || format == Formats::Hex32
-            || format == Formats::Bin32
-        {
-            nregs *= 2;
+async fn write(session: &mut ModbusSession, args: &Args, writevalues: &[String]) -> Result<()> {
+    let function = args.r#type.clone().unwrap().function;
+    let format = args.r#type.clone().unwrap().format;
+    let data = write_data(args, &function, &format, writevalues)?;
+    match session
+        .write(args.slave[0], function.clone(), args.reference[0], data)
+        .await
+    {
+        Ok(()) => {
+            println!("Write {} references.", writevalues.len());
+            Ok(())
         }
+        Err(e) => {
+            println!("Write {:?} failed: {}", function, e);
+            Err(anyhow::Error::from(e).context(format!("Write {:?} failed", function)))
+        }
+    }
+}
 
-        // write
-        if writevalues.is_some() {
-            TRANSMIT_COUNT.fetch_add(1, Ordering::Relaxed);
-            let writevalues = writevalues.unwrap();
-            let rs;
-            match format {
-                Formats::Bin16
-                    if function == Functions::Coil || function == Functions::DiscreteInput =>
-                {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<bool>().unwrap())
-                        .collect::<Vec<bool>>();
-                    if wd.len() == 1 {
-                        rs = timeout_at(
-                            Instant::now() + duration,
-                            ctx.write_single_coil(reference[0], wd[0]),
-                        )
-                        .await;
-                    } else {
-                        rs = timeout_at(
-                            Instant::now() + duration,
-                            ctx.write_multiple_coils(reference[0], &wd),
-                        )
-                        .await;
-                    }
-                }
-                Formats::U16 | Formats::Bin16 | Formats::Hex16 => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| {
-                            if v.parse::<u16>().is_ok() {
-                                v.parse::<u16>().unwrap()
-                            } else if v.starts_with("0x")
-                                && u16::from_str_radix(v.trim_start_matches("0x"), 16).is_ok()
-                            {
-                                u16::from_str_radix(v.trim_start_matches("0x"), 16).unwrap()
-                            } else {
-                                u16::from_str_radix(v.trim_start_matches("0b"), 2).unwrap()
-                            }
-                        })
-                        .collect::<Vec<u16>>();
-                    if wd.len() == 1 {
-                        rs = timeout_at(
-                            Instant::now() + duration,
-                            ctx.write_single_register(reference[0], wd[0]),
-                        )
-                        .await;
-                    } else {
-                        rs = timeout_at(
-                            Instant::now() + duration,
-                            ctx.write_multiple_registers(reference[0], &wd),
-                        )
-                        .await;
-                    }
-                }
-                Formats::I16 => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<i16>().unwrap())
-                        .collect::<Vec<i16>>();
-                    if wd.len() == 1 {
-                        rs = timeout_at(
-                            Instant::now() + duration,
-                            ctx.write_single_register(reference[0], wd[0] as u16),
-                        )
-                        .await;
-                    } else {
-                        rs = timeout_at(
-                            Instant::now() + duration,
-                            ctx.write_multiple_registers(
-                                reference[0],
-                                &wd.iter().map(|v| *v as u16).collect::<Vec<u16>>(),
-                            ),
-                        )
-                        .await;
-                    }
-                }
-                Formats::I32 => {
-                    let wd = writevalues.iter().map(|v| v.parse::<i32>().unwrap()).fold(
-                        Vec::new(),
-                        |mut acc, v| {
-                            let data = v.to_be_bytes();
-                            if args.little_endian {
-                                acc.push(u16::from_be_bytes([data[2], data[3]]));
-                                acc.push(u16::from_be_bytes([data[0], data[1]]));
-                            } else {
-                                acc.push(u16::from_be_bytes([data[0], data[1]]));
-                                acc.push(u16::from_be_bytes([data[2], data[3]]));
-                            }
-                            acc
-                        },
-                    );
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::I32abcd => {
-                    let wd = writevalues.iter().map(|v| v.parse::<i32>().unwrap()).fold(
-                        Vec::new(),
-                        |mut acc, v| {
-                            let data = v.to_be_bytes();
-                            acc.push(u16::from_be_bytes([data[0], data[1]]));
-                            acc.push(u16::from_be_bytes([data[2], data[3]]));
-                            acc
-                        },
-                    );
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::I32cdab => {
-                    let wd = writevalues.iter().map(|v| v.parse::<i32>().unwrap()).fold(
-                        Vec::new(),
-                        |mut acc, v| {
-                            let data = v.to_be_bytes();
-                            acc.push(u16::from_be_bytes([data[2], data[3]]));
-                            acc.push(u16::from_be_bytes([data[0], data[1]]));
-                            acc
-                        },
-                    );
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::I32badc => {
-                    let wd = writevalues.iter().map(|v| v.parse::<i32>().unwrap()).fold(
-                        Vec::new(),
-                        |mut acc, v| {
-                            let data = v.to_be_bytes();
-                            acc.push(u16::from_be_bytes([data[1], data[0]]));
-                            acc.push(u16::from_be_bytes([data[3], data[2]]));
-                            acc
-                        },
-                    );
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::I32dcba => {
-                    let wd = writevalues.iter().map(|v| v.parse::<i32>().unwrap()).fold(
-                        Vec::new(),
-                        |mut acc, v| {
-                            let data = v.to_be_bytes();
-                            acc.push(u16::from_be_bytes([data[3], data[2]]));
-                            acc.push(u16::from_be_bytes([data[1], data[0]]));
-                            acc
-                        },
-                    );
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::U32 | Formats::Hex32 | Formats::Bin32 => {
-                    // check_args already checked Formats::I32
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| {
-                            if v.parse::<u32>().is_ok() {
-                                v.parse::<u32>().unwrap()
-                            } else if v.starts_with("0x")
-                                && u32::from_str_radix(v.trim_start_matches("0x"), 16).is_ok()
-                            {
-                                u32::from_str_radix(v.trim_start_matches("0x"), 16).unwrap()
-                            } else {
-                                u32::from_str_radix(v.trim_start_matches("0b"), 2).unwrap()
-                            }
-                        })
-                        .collect::<Vec<u32>>();
-
-                    let wd = wd.iter().fold(Vec::new(), |mut acc, v| {
-                        let data = v.to_be_bytes();
-                        if args.little_endian {
-                            acc.push(u16::from_be_bytes([data[2], data[3]]));
-                            acc.push(u16::from_be_bytes([data[0], data[1]]));
-                        } else {
-                            acc.push(u16::from_be_bytes([data[0], data[1]]));
-                            acc.push(u16::from_be_bytes([data[2], data[3]]));
-                        }
-                        acc
-                    });
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::U32abcd => {
-                    let wd = writevalues.iter().map(|v| v.parse::<u32>().unwrap()).fold(
-                        Vec::new(),
-                        |mut acc, v| {
-                            let data = v.to_be_bytes();
-                            acc.push(u16::from_be_bytes([data[0], data[1]]));
-                            acc.push(u16::from_be_bytes([data[2], data[3]]));
-                            acc
-                        },
-                    );
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::U32cdab => {
-                    let wd = writevalues.iter().map(|v| v.parse::<u32>().unwrap()).fold(
-                        Vec::new(),
-                        |mut acc, v| {
-                            let data = v.to_be_bytes();
-                            acc.push(u16::from_be_bytes([data[2], data[3]]));
-                            acc.push(u16::from_be_bytes([data[0], data[1]]));
-                            acc
-                        },
-                    );
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::U32badc => {
-                    let wd = writevalues.iter().map(|v| v.parse::<u32>().unwrap()).fold(
-                        Vec::new(),
-                        |mut acc, v| {
-                            let data = v.to_be_bytes();
-                            acc.push(u16::from_be_bytes([data[1], data[0]]));
-                            acc.push(u16::from_be_bytes([data[3], data[2]]));
-                            acc
-                        },
-                    );
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::U32dcba => {
-                    let wd = writevalues.iter().map(|v| v.parse::<u32>().unwrap()).fold(
-                        Vec::new(),
-                        |mut acc, v| {
-                            let data = v.to_be_bytes();
-                            acc.push(u16::from_be_bytes([data[3], data[2]]));
-                            acc.push(u16::from_be_bytes([data[1], data[0]]));
-                            acc
-                        },
-                    );
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::F32 => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<f32>().unwrap())
-                        .map(|v| v.to_bits())
-                        .collect::<Vec<u32>>();
-
-                    let wd = wd.iter().fold(Vec::new(), |mut acc, v| {
-                        let data = v.to_be_bytes();
-                        if args.little_endian {
-                            acc.push(u16::from_be_bytes([data[2], data[3]]));
-                            acc.push(u16::from_be_bytes([data[0], data[1]]));
-                        } else {
-                            acc.push(u16::from_be_bytes([data[0], data[1]]));
-                            acc.push(u16::from_be_bytes([data[2], data[3]]));
-                        }
-                        acc
-                    });
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::F32abcd => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<f32>().unwrap())
-                        .map(|v| v.to_bits())
-                        .collect::<Vec<u32>>();
-
-                    let wd = wd.iter().fold(Vec::new(), |mut acc, v| {
-                        let data = v.to_be_bytes();
-                        acc.push(u16::from_be_bytes([data[0], data[1]]));
-                        acc.push(u16::from_be_bytes([data[2], data[3]]));
-                        acc
-                    });
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::F32cdab => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<f32>().unwrap())
-                        .map(|v| v.to_bits())
-                        .collect::<Vec<u32>>();
-
-                    let wd = wd.iter().fold(Vec::new(), |mut acc, v| {
-                        let data = v.to_be_bytes();
-                        acc.push(u16::from_be_bytes([data[2], data[3]]));
-                        acc.push(u16::from_be_bytes([data[0], data[1]]));
-                        acc
-                    });
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::F32badc => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<f32>().unwrap())
-                        .map(|v| v.to_bits())
-                        .collect::<Vec<u32>>();
-
-                    let wd = wd.iter().fold(Vec::new(), |mut acc, v| {
-                        let data = v.to_be_bytes();
-                        acc.push(u16::from_be_bytes([data[1], data[0]]));
-                        acc.push(u16::from_be_bytes([data[3], data[2]]));
-                        acc
-                    });
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                Formats::F32dcba => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<f32>().unwrap())
-                        .map(|v| v.to_bits())
-                        .collect::<Vec<u32>>();
-
-                    let wd = wd.iter().fold(Vec::new(), |mut acc, v| {
-                        let data = v.to_be_bytes();
-                        acc.push(u16::from_be_bytes([data[3], data[2]]));
-                        acc.push(u16::from_be_bytes([data[1], data[0]]));
-                        acc
-                    });
-                    rs = timeout_at(
-                        Instant::now() + duration,
-                        ctx.write_multiple_registers(reference[0], &wd),
-                    )
-                    .await;
-                }
-                _ => {
-                    todo!()
-                }
-            }
-            match rs {
-                Ok(Ok(_)) => {
-                    RECEIVE_COUNT.fetch_add(1, Ordering::Relaxed);
-                    println!("Write {} references.", writevalues.len());
-                }
-                Ok(Err(e)) => {
-                    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                    println!("Write {:?} failed: {:?}", function, e);
-                }
-                Err(_) => {
-                    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                    println!("Write {:?} timeout", function);
+/// Poll every slave on its own timer, with as many requests in flight as there are sessions
+async fn poll(sessions: Vec<ModbusSession>, args: Args) -> Result<()> {
+    let in_flight = Arc::new(Semaphore::new(sessions.len()));
+    let sessions = Arc::new(Mutex::new(sessions));
+    let mut tasks = JoinSet::new();
+    for slave in args.slave.clone() {
+        let args = args.clone();
+        let in_flight = in_flight.clone();
+        let sessions = sessions.clone();
+        tasks.spawn(async move {
+            let mut timer = tokio::time::interval(Duration::from_millis(args.poll_rate.unwrap()));
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                timer.tick().await;
+                let permit = in_flight.acquire().await.unwrap();
+                let mut session = sessions.lock().unwrap().pop().unwrap();
+                let (out, failure) = poll_slave(&mut session, &args, slave).await;
+                sessions.lock().unwrap().push(session);
+                drop(permit);
+                print!("{}", out);
+                if args.once {
+                    return failure;
                 }
             }
-        } else {
-            // read
-            for slave in slave {
-                ctx.set_slave(Slave(slave));
-                print!("-- Polling slave {}...", slave);
-                if !args.once {
-                    println!(" Ctrl-C to stop");
-                } else {
-                    println!();
-                }
-                for &addr in reference.iter() {
-                    TRANSMIT_COUNT.fetch_add(1, Ordering::Relaxed);
-                    match function {
-                        Functions::Coil => {
-                            match timeout_at(Instant::now() + duration, ctx.read_coils(addr, nregs))
-                                .await
-                            {
-                                Ok(Ok(r)) => match r {
-                                    Ok(v) => {
-                                        let data = v
-                                            .iter()
-                                            .map(|c| if !(*c) { 0 } else { 1 })
-                                            .collect::<Vec<u16>>();
-                                        print_read_value(
-                                            addr,
-                                            count,
-                                            &format,
-                                            &function,
-                                            args.little_endian,
-                                            data,
-                                        );
-                                    }
-                                    Err(e) => {
-                                        ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                        println!("Read {:?} failed: {:?}", function, e);
-                                    }
-                                },
-                                Ok(Err(e)) => {
-                                    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                    println!("Read {:?} failed: {:?}", function, e);
-                                }
-                                Err(_) => {
-                                    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                    println!("Read {:?} timeout", function);
-                                }
-                            };
-                        }
-                        Functions::DiscreteInput => {
-                            match timeout_at(
-                                Instant::now() + duration,
-                                ctx.read_discrete_inputs(addr, nregs),
-                            )
-                            .await
-                            {
-                                Ok(Ok(r)) => match r {
-                                    Ok(v) => {
-                                        let data = v
-                                            .iter()
-                                            .map(|c| if !(*c) { 0 } else { 1 })
-                                            .collect::<Vec<u16>>();
-                                        print_read_value(
-                                            addr,
-                                            count,
-                                            &format,
-                                            &function,
-                                            args.little_endian,
-                                            data,
-                                        );
-                                    }
+        });
+    }
 
-                                    Err(e) => {
-                                        ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                        println!("Read {:?} failed: {:?}", function, e);
-                                    }
-                                },
-                                Ok(Err(e)) => {
-                                    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                    println!("Read {:?} failed: {:?}", function, e);
-                                }
-                                Err(_) => {
-                                    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                    println!("Read {:?} timeout", function);
-                                }
-                            };
-                        }
-                        Functions::HoldingRegister => {
-                            match timeout_at(
-                                Instant::now() + duration,
-                                ctx.read_holding_registers(addr, nregs),
-                            )
-                            .await
-                            {
-                                Ok(Ok(r)) => match r {
-                                    Ok(data) => {
-                                        print_read_value(
-                                            addr,
-                                            count,
-                                            &format,
-                                            &function,
-                                            args.little_endian,
-                                            data,
-                                        );
-                                    }
-                                    Err(e) => {
-                                        ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                        println!("Read {:?} failed: {:?}", function, e);
-                                    }
-                                },
-                                Ok(Err(e)) => {
-                                    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                    println!("Read {:?} failed: {:?}", function, e);
-                                }
-                                Err(_) => {
-                                    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                    println!("Read {:?} timeout", function);
-                                }
-                            };
-                        }
-                        Functions::InputRegister => {
-                            match timeout_at(
-                                Instant::now() + duration,
-                                ctx.read_input_registers(addr, nregs),
-                            )
-                            .await
-                            {
-                                Ok(Ok(r)) => match r {
-                                    Ok(data) => {
-                                        print_read_value(
-                                            addr,
-                                            count,
-                                            &format,
-                                            &function,
-                                            args.little_endian,
-                                            data,
-                                        );
-                                    }
-                                    Err(e) => {
-                                        ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                        println!("Read {:?} failed: {:?}", function, e);
-                                    }
-                                },
-                                Ok(Err(e)) => {
-                                    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                    println!("Read {:?} failed: {:?}", function, e);
-                                }
-                                Err(_) => {
-                                    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
-                                    println!("Read {:?} timeout", function);
-                                }
-                            };
-                        }
-                        _ => {
-                            unreachable!();
-                        }
-                    }
-                }
-                if !args.once {
-                    std::thread::sleep(Duration::from_millis(args.poll_rate.unwrap()));
-                }
+    // the last failed request decides the exit code of a single poll
+    let mut failure = None;
+    while let Some(rs) = tasks.join_next().await {
+        failure = rs?.or(failure);
+    }
+    failure.map_or(Ok(()), Err)
+}
+
+/// Read the references of `slave`, the output is returned to be printed in one piece
+async fn poll_slave(
+    session: &mut ModbusSession,
+    args: &Args,
+    slave: u8,
+) -> (String, Option<anyhow::Error>) {
+    let function = args.r#type.clone().unwrap().function;
+    let format = args.r#type.clone().unwrap().format;
+    let count = args.count.unwrap();
+
+    if !session.is_connected() {
+        println!(
+            "{}",
+            format!("-- Lost {}, reconnecting...", args.device).yellow()
+        );
+        if let Err(e) = connect(session, args).await {
+            return (String::new(), Some(e));
+        }
+        println!("{}", format!("-- Reconnected to {}", args.device).green());
+    }
+    let mut out = format!("-- Polling slave {}...", slave);
+    if !args.once {
+        out += " Ctrl-C to stop\n";
+    } else {
+        out += "\n";
+    }
+    let mut failure = None;
+    for &addr in args.reference.iter() {
+        match session
+            .read_values(
+                slave,
+                function.clone(),
+                addr,
+                count,
+                &format,
+                args.little_endian,
+            )
+            .await
+        {
+            Ok(values) => out += &codec::format_values(addr, &format, &values),
+            Err(e) => {
+                out += &format!("Read {:?} failed: {}\n", function, e);
+                failure =
+                    Some(anyhow::Error::from(e).context(format!("Read {:?} failed", function)));
             }
         }
+    }
+    (out, failure)
+}
 
-        if args.once {
-            break;
-        }
+/// Registers or coils of the values to write, in the format of `-t`
+fn write_data(
+    args: &Args,
+    function: &Functions,
+    format: &Formats,
+    writevalues: &[String],
+) -> Result<Data> {
+    if *function == Functions::Coil || *function == Functions::DiscreteInput {
+        let bits = writevalues
+            .iter()
+            .map(|v| {
+                v.parse::<bool>()
+                    .with_context(|| format!("Write value {} must be bool", v))
+            })
+            .collect::<Result<_>>()?;
+        return Ok(Data::Bits(bits));
     }
+    let mut words = Vec::new();
+    for v in writevalues {
+        let value = Value::parse(v, format)?;
+        words.extend(codec::encode(value, format, args.little_endian)?);
+    }
+    Ok(Data::Words(words))
+}
 
-    Ok(())
+/// First address of `host`, an IP address or a name looked up in DNS
+async fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    tokio::net::lookup_host((host, port))
+        .await
+        .map_err(Error::from)
+        .with_context(|| format!("Failed to resolve {}", host))?
+        .next()
+        .ok_or_else(|| {
+            anyhow::Error::from(Error::Transport(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no address found for {}", host),
+            )))
+        })
 }
 
-async fn tcp_client(args: Args) -> Result<()> {
-    let socket_addr = SocketAddr::new(
-        IpAddr::V4(args.device.parse::<Ipv4Addr>().unwrap()),
-        args.port.unwrap(),
-    );
+async fn tcp_client(args: Args, stats: Arc<Mutex<PollStats>>) -> Result<()> {
+    let socket_addr = resolve(&args.device, args.port.unwrap()).await?;
+    let transport = match args.mode.unwrap() {
+        Mode::Tls => Transport::Tls(
+            socket_addr,
+            TlsOption {
+                ca: args.ca.clone().unwrap_or_default(),
+                cert: args.cert.clone().unwrap_or_default(),
+                key: args.key.clone().unwrap_or_default(),
+                server_name: args.server_name.clone().unwrap_or(args.device.clone()),
+            }
+            .load()
+            .context("Invalid TLS configuration")?,
+        ),
+        _ => Transport::Tcp(socket_addr),
+    };
+    modbus_client(args, transport, stats).await
+}
+
+/// Connect and poll, the connection is opened again whenever it breaks
+async fn modbus_client(
+    args: Args,
+    transport: Transport,
+    stats: Arc<Mutex<PollStats>>,
+) -> Result<()> {
+    let recorder = match &args.record {
+        Some(path) => Some(Arc::new(Recorder::create(path)?)),
+        None => None,
+    };
+    // a connection for each request in flight
+    let mut sessions = Vec::new();
+    for _ in 0..args.parallel.unwrap().min(args.slave.len()) {
+        let mut session = ModbusSession::new(transport.clone(), args.timeout.unwrap());
+        session.set_stats(stats.clone());
+        if let Some(recorder) = &recorder {
+            session.set_recorder(recorder.clone());
+        }
+        connect(&mut session, &args).await?;
+        sessions.push(session);
+    }
+    if let Some((version, subject)) = sessions[0].tls_peer() {
+        if args.verbose.log_level() >= Some(log::Level::Warn) {
+            println!("TLS...................: {}, peer {}", version, subject);
+        }
+    }
+    match args.writevalues.clone() {
+        Some(writevalues) => write(&mut sessions[0], &args, &writevalues).await,
+        None => poll(sessions, args).await,
+    }
+}
 
+/// Retry until connected with a growing delay, unless polling once
+async fn connect(session: &mut ModbusSession, args: &Args) -> Result<()> {
+    let mut backoff = Backoff::default();
     loop {
-        match tcp::connect(socket_addr).await {
-            Ok(ctx) => {
-                run(ctx, args).await?;
-                break;
-            }
+        match session.connect().await {
+            Ok(()) => return Ok(()),
+            Err(e) if args.once => return Err(anyhow::Error::from(e).context("Connect error")),
             Err(e) => {
-                if args.once {
-                    return Err(anyhow::anyhow!("Connect error: {:?}", e));
-                }
-                println!("Connect error: {:?}", e);
+                let delay = backoff.next_delay();
+                println!(
+                    "Connect error: {}, retry in {:.1} s",
+                    e,
+                    delay.as_secs_f32()
+                );
+                tokio::time::sleep(delay).await;
             }
         }
     }
-    Ok(())
 }
 
-async fn rtu_client(args: Args) -> Result<()> {
-    let path = args.device.clone();
-    let slave = Slave(args.slave[0]);
-    let builder = tokio_serial::new(path, args.baudrate.unwrap())
+fn serial_builder(args: &Args) -> SerialPortBuilder {
+    tokio_serial::new(args.device.clone(), args.baudrate.unwrap())
         .data_bits(match args.databits.unwrap() {
             7 => tokio_serial::DataBits::Seven,
             8 => tokio_serial::DataBits::Eight,
@@ -702,724 +386,704 @@ async fn rtu_client(args: Args) -> Result<()> {
             _ => tokio_serial::StopBits::One,
         })
         // .flow_control(tokio_serial::FlowControl::None)
-        .timeout(args.timeout.unwrap());
+        .timeout(args.timeout.unwrap())
+}
 
-    loop {
-        match SerialStream::open(&builder) {
-            Ok(port) => {
-                let ctx = rtu::attach_slave(port, slave);
-                run(ctx, args).await?;
-                break;
-            }
-            Err(e) => {
-                if args.once {
-                    return Err(anyhow::anyhow!("Connect error: {:?}", e));
-                }
-                println!("Connect error: {:?}", e);
-            }
-        }
+/// Serial port settings of the arguments
+fn serial_option(args: &Args) -> SerialOption {
+    SerialOption {
+        builder: serial_builder(args),
+        ascii: args.mode.unwrap() == Mode::Ascii,
+        rs485: rs485_option(args),
+        rs485_kernel: args.rs485_kernel,
+        timing: timing_option(args),
     }
+}
 
-    Ok(())
+fn serial_char_time(args: &Args) -> Duration {
+    rs485::char_time(
+        args.baudrate.unwrap(),
+        args.databits.unwrap(),
+        args.parity.as_deref() != Some("none"),
+        args.stopbits.unwrap(),
+    )
 }
 
-async fn rtu_in_tcp_client(args: Args) -> Result<()> {
-    let socket_addr = SocketAddr::new(
-        IpAddr::V4(args.device.parse::<Ipv4Addr>().unwrap()),
-        args.port.unwrap(),
-    );
+/// Silent intervals from the baudrate unless given
+fn timing_option(args: &Args) -> TimingOption {
+    let char_time = serial_char_time(args);
+    let turnaround = Duration::from_millis(args.turnaround.unwrap());
+    let mut op = match args.mode.unwrap() {
+        Mode::Ascii => TimingOption::ascii(char_time, turnaround),
+        _ => TimingOption::rtu(args.baudrate.unwrap(), char_time, turnaround),
+    };
+    if let Some(ms) = args.request_delay {
+        op.request_delay = Duration::from_secs_f32(ms / 1000.0);
+    }
+    if let Some(ms) = args.char_timeout {
+        op.char_timeout = Duration::from_secs_f32(ms / 1000.0);
+    }
+    op
+}
 
-    let slave = Slave(args.slave[0]);
+/// RS-485 direction control from `-R` / `-F`
+fn rs485_option(args: &Args) -> Option<Rs485Option> {
+    let mode = if args.rts_on_send {
+        RtsMode::OnSend
+    } else if args.rts_after_send {
+        RtsMode::AfterSend
+    } else {
+        return None;
+    };
+    Some(Rs485Option {
+        mode,
+        delay_before: Duration::from_millis(args.rts_delay_before.unwrap()),
+        delay_after: Duration::from_millis(args.rts_delay_after.unwrap()),
+        char_time: serial_char_time(args),
+    })
+}
+
+async fn rtu_client(args: Args, stats: Arc<Mutex<PollStats>>) -> Result<()> {
+    let transport = Transport::Serial(serial_option(&args));
+    modbus_client(args, transport, stats).await
+}
 
-    let ctx = rtu_over_tcp::connect_slave(socket_addr, slave).await?;
-    run(ctx, args).await?;
+/// Modbus/TCP proxy between the clients and the device, every request is logged
+async fn proxy(args: Args, stats: Arc<Mutex<PollStats>>) -> Result<()> {
+    let upstream = resolve(&args.device, args.port.unwrap()).await?;
+    let op = ProxyOption {
+        upstream: Some(upstream),
+        rewrites: args.rewrite.clone(),
+        drops: args.drop.clone(),
+        timeout: args.timeout.unwrap(),
+        little_endian: args.little_endian,
+    };
+    let listen = args.listen.clone().unwrap();
+    let listener = TcpListener::bind(&listen).await?;
+    println!("-- Proxy {} -> {}... Ctrl-C to stop", listen, upstream);
+    let start = Instant::now();
+    modbus_proxy::serve(listener, op, move |exchange| {
+        print_exchange(&args, &stats, start.elapsed().as_secs_f32(), exchange)
+    })
+    .await?;
     Ok(())
 }
 
-async fn iec104_client(args: Args) -> Result<()> {
-    let writevalues = args.writevalues.clone();
-    let function = args.r#type.clone().unwrap().function;
-    let remote_addr = args.slave.clone()[0];
-    let reference = args.reference.clone();
-    let count = args.count.unwrap();
+fn print_exchange(args: &Args, stats: &Mutex<PollStats>, time: f32, exchange: Exchange) {
+    let function = match &exchange.request {
+        Ok(req) => modbus_pdu::function_code(req).unwrap_or_default(),
+        Err(pdu) => pdu.first().copied().unwrap_or_default(),
+    };
+    let outcome = match (&exchange.response, exchange.action) {
+        (_, Action::NoResponse) => Some(Outcome::Timeout),
+        (_, Action::Dropped) => None,
+        (Some(rsp), _) => Some(Outcome::of_response(rsp)),
+        (None, _) => Some(Outcome::Response),
+    };
+    {
+        let mut stats = stats.lock().unwrap();
+        stats.request(exchange.unit, function);
+        if let Some(outcome) = outcome {
+            stats.outcome(exchange.unit, function, outcome);
+        }
+    }
 
-    let socket_addr = SocketAddr::new(
-        IpAddr::V4(args.device.parse::<Ipv4Addr>().unwrap()),
-        args.port.unwrap(),
+    let action = match exchange.action {
+        Action::Forwarded => "".normal(),
+        Action::Rewritten => " [rewritten]".yellow(),
+        Action::Dropped => " [dropped]".red(),
+        Action::NoResponse => " [no response]".red(),
+    };
+    let request = match &exchange.request {
+        Ok(req) => format!("{:?}", req),
+        Err(pdu) => record::to_hex(pdu, " "),
+    };
+    println!(
+        "[{:.3}] {} unit {}: {}{}",
+        time, exchange.peer, exchange.unit, request, action
     );
-    let mut client = IEC104Client::new(socket_addr, remote_addr as u16);
-    if writevalues.is_some() {
-        client.start().await?;
-    } else {
-        client.start_interrogation().await?;
+    if let (Ok(req), Some(rsp), Action::Forwarded | Action::Rewritten) =
+        (&exchange.request, &exchange.response, exchange.action)
+    {
+        print_transaction(args, req, rsp);
     }
-    loop {
-        // write
-        if writevalues.is_some() {
-            std::thread::sleep(Duration::from_millis(args.poll_rate.unwrap()));
-            // TODO:
-            let mut addr = reference[0];
-            let writevalues = writevalues.clone().unwrap();
-            match function {
-                Functions::Siq => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<bool>().unwrap())
-                        .collect::<Vec<bool>>();
-                    for w in wd {
-                        if let Err(e) = client.write_siq(addr, w).await {
-                            println!("write siq err{e}");
-                            continue;
-                        }
-                        addr += 1;
-                    }
-                }
-                Functions::Diq => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<u8>().unwrap())
-                        .collect::<Vec<u8>>();
-                    for w in wd {
-                        if client.write_diq(addr, w).await.is_err() {
-                            continue;
-                        }
-                        addr += 1;
-                    }
-                }
-                Functions::Nva => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<i16>().unwrap())
-                        .collect::<Vec<i16>>();
-                    for w in wd {
-                        if client.write_nva(addr, w).await.is_err() {
-                            continue;
-                        }
-                        addr += 1;
-                    }
-                }
-                Functions::Sva => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<i16>().unwrap())
-                        .collect::<Vec<i16>>();
-                    for w in wd {
-                        if client.write_sva(addr, w).await.is_err() {
-                            continue;
-                        }
-                        addr += 1;
-                    }
-                }
-                Functions::R => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<f32>().unwrap())
-                        .collect::<Vec<f32>>();
-                    for w in wd {
-                        if client.write_r(addr, w).await.is_err() {
-                            continue;
-                        }
-                        addr += 1;
-                    }
-                }
-                Functions::Bcr => {
-                    let wd = writevalues
-                        .iter()
-                        .map(|v| v.parse::<i32>().unwrap())
-                        .collect::<Vec<i32>>();
-                    for w in wd {
-                        if client.write_bcr(addr, w).await.is_err() {
-                            continue;
-                        }
-                        addr += 1;
-                    }
-                }
-                _ => {}
-            }
-            println!("Write {} references.", writevalues.len());
-            std::thread::sleep(Duration::from_millis(args.poll_rate.unwrap()));
-        } else {
-            // read
-            print!("-- Polling remote addr {}...", remote_addr);
-            if !args.once {
-                println!(" Ctrl-C to stop");
-            } else {
-                println!();
-            }
-            match function {
-                Functions::Siq => {
-                    for &addr in reference.iter() {
-                        let mut ad = addr;
-                        for _ in 0..count {
-                            print!("[{}({:#04X})]: \t", ad, ad);
-                            if let Some(r) = client.read_siq(ad) {
-                                println!("{}", r);
-                            } else {
-                                println!("waiting for data...");
-                            }
-                            ad += 1;
-                        }
-                        if reference.len() > 1 {
-                            println!("================");
-                        }
-                    }
-                }
-                Functions::Diq => {
-                    for &addr in reference.iter() {
-                        let mut ad = addr;
-                        for _ in 0..count {
-                            print!("[{}({:#04X})]: \t", ad, ad);
-                            if let Some(r) = client.read_diq(ad) {
-                                println!("{}", r);
-                            } else {
-                                println!("waiting for data...");
-                            }
-                            ad += 1;
-                        }
-                        if reference.len() > 1 {
-                            println!("================");
-                        }
-                    }
-                }
-                Functions::Nva => {
-                    for &addr in reference.iter() {
-                        let mut ad = addr;
-                        for _ in 0..count {
-                            print!("[{}({:#04X})]: \t", ad, ad);
-                            if let Some(r) = client.read_nva(ad) {
-                                println!("{}", r);
-                            } else {
-                                println!("waiting for data...");
-                            }
-                            ad += 1;
-                        }
-                        if reference.len() > 1 {
-                            println!("================");
-                        }
-                    }
-                }
-                Functions::Sva => {
-                    for &addr in reference.iter() {
-                        let mut ad = addr;
-                        for _ in 0..count {
-                            print!("[{}({:#04X})]: \t", ad, ad);
-                            if let Some(r) = client.read_sva(ad) {
-                                println!("{}", r);
-                            } else {
-                                println!("waiting for data...");
-                            }
-                            ad += 1;
-                        }
-                        if reference.len() > 1 {
-                            println!("================");
-                        }
-                    }
-                }
-                Functions::R => {
-                    for &addr in reference.iter() {
-                        let mut ad = addr;
-                        for _ in 0..count {
-                            print!("[{}({:#04X})]: \t", ad, ad);
-                            if let Some(r) = client.read_r(ad) {
-                                println!("{}", r);
-                            } else {
-                                println!("waiting for data...");
-                            }
-                            ad += 1;
-                        }
-                        if reference.len() > 1 {
-                            println!("================");
-                        }
-                    }
-                }
-                Functions::Bcr => {
-                    for &addr in reference.iter() {
-                        let mut ad = addr;
-                        for _ in 0..count {
-                            print!("[{}({:#04X})]: \t", ad, ad);
-                            if let Some(r) = client.read_bcr(ad) {
-                                println!("{}", r);
-                            } else {
-                                println!("waiting for data...");
-                            }
-                            ad += 1;
-                        }
-                        if reference.len() > 1 {
-                            println!("================");
-                        }
-                    }
-                }
-                Functions::All => {
-                    let mut has_data = false;
-                    for (ad, v) in client.extract_all_siq() {
-                        has_data = true;
-                        print!("[siq] [{}({:#04X})]: \t", ad, ad);
-                        println!("{}", v);
-                    }
-
-                    for (ad, v) in client.extract_all_diq() {
-                        has_data = true;
-                        print!("[diq] [{}({:#04X})]: \t", ad, ad);
-                        println!("{}", v);
-                    }
-
-                    for (ad, v) in client.extract_all_nva() {
-                        has_data = true;
-                        print!("[nva] [{}({:#04X})]: \t", ad, ad);
-                        println!("{}", v);
-                    }
-
-                    for (ad, v) in client.extract_all_sva() {
-                        has_data = true;
-                        print!("[sva] [{}({:#04X})]: \t", ad, ad);
-                        println!("{}", v);
-                    }
+}
 
-                    for (ad, v) in client.extract_all_r() {
-                        has_data = true;
-                        print!("[r] [{}({:#04X})]: \t", ad, ad);
-                        println!("{}", v);
-                    }
+/// Modbus/TCP server forwarding to the slaves on the serial port
+async fn gateway(args: Args) -> Result<()> {
+    let op = serial_option(&args);
+    let transport = serial_transport(SerialStream::open(&op.builder)?, &op)?;
+    let listen = args.listen.clone().unwrap();
+    let listener = TcpListener::bind(&listen).await?;
+    println!("-- Gateway {} -> {}... Ctrl-C to stop", listen, args.device);
+    modbus_gateway::serve(listener, transport, args.timeout.unwrap()).await?;
+    Ok(())
+}
 
-                    for (ad, v) in client.extract_all_bcr() {
-                        has_data = true;
-                        print!("[bcr] [{}({:#04X})]: \t", ad, ad);
-                        println!("{}", v);
-                    }
+/// Listen on the bus without sending, frames are split at silent intervals and requests are
+/// paired with their responses
+async fn sniff(args: Args, stats: Arc<Mutex<PollStats>>) -> Result<()> {
+    // the modem lines are left as the driver sets them on open
+    let port = SerialStream::open(&serial_builder(&args))?;
 
-                    if !has_data {
-                        println!("waiting for data...");
-                    }
-                }
-                _ => unreachable!(),
-            }
+    let timing = timing_option(&args);
+    // USB adapters hand over characters in bursts of their latency timer
+    let gap = match args.request_delay {
+        Some(_) => timing.request_delay,
+        None => timing.request_delay.max(Duration::from_millis(20)),
+    };
+    let mut capture = match &args.capture {
+        Some(path) => Some(File::create(path)?),
+        None => None,
+    };
+    let mut sniffer = Sniffer::new(port, gap, args.timeout.unwrap());
+    let start = Instant::now();
 
-            if !args.once {
-                std::thread::sleep(Duration::from_millis(args.poll_rate.unwrap()));
+    println!("-- Sniffing {}... Ctrl-C to stop", args.device);
+    loop {
+        let (frames, sniffed) = sniffer.next().await?;
+        if let Some(file) = capture.as_mut() {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            for frame in frames {
+                writeln!(file, "{:.3} {}", now, record::to_hex(&frame, " "))?;
             }
         }
-        if args.once {
-            break;
+        for s in sniffed {
+            print_sniffed(&args, &stats, start.elapsed().as_secs_f32(), s);
         }
     }
-
-    Ok(())
 }
 
-fn print_read_value(
-    mut addr: u16,
-    count: u16,
-    format: &Formats,
-    function: &Functions,
-    little_endian: bool,
-    data: Vec<u16>,
-) {
-    RECEIVE_COUNT.fetch_add(1, Ordering::Relaxed);
-    for c in 0..count as usize {
-        // print!("{}", format!("[{}({:#04X})]: \t", addr, addr).green());
-        print!("[{}({:#04X})]: \t", addr, addr);
-        match format {
-            Formats::U16 => {
-                if (data[c] & 0x8000) != 0 {
-                    println!("{} ({})", data[c], data[c] as i16);
-                } else {
-                    println!("{}", data[c]);
-                }
-                addr += 1;
-            }
-            Formats::I16 => {
-                println!("{}", data[c] as i16);
-                addr += 1;
-            }
-            Formats::I32 => {
-                let v = extract_data(&data, 2 * c, little_endian);
-                println!("{}", v as i32);
-                addr += 2;
+/// Check the configuration file or list its devices
+fn config(args: &Args, command: &ConfigCommand) -> Result<()> {
+    let conf = args.conf.clone().unwrap();
+    let device_list = config::load(&conf)?;
+    match command {
+        ConfigCommand::Check => {
+            let problems = config::validate(&device_list);
+            for p in problems.iter() {
+                println!("{}", p);
             }
-            Formats::I32abcd => {
-                let v = extract_data(&data, 2 * c, false);
-                println!("{}", v as i32);
-                addr += 2;
-            }
-            Formats::I32cdab => {
-                let v = extract_data(&data, 2 * c, true);
-                println!("{}", v as i32);
-                addr += 2;
-            }
-            Formats::I32badc => {
-                let v = extract_data_32(&data, 2 * c, 1, 0, 3, 2);
-                println!("{}", v as i32);
-                addr += 2;
-            }
-            Formats::I32dcba => {
-                let v = extract_data_32(&data, 2 * c, 3, 2, 1, 0);
-                println!("{}", v as i32);
-                addr += 2;
-            }
-            Formats::U32 => {
-                let v = extract_data(&data, 2 * c, little_endian);
-                if v & 0x80000000 != 0 {
-                    println!("{} ({})", v, v as i32);
-                } else {
-                    println!("{}", v);
-                }
-                addr += 2;
-            }
-            Formats::U32abcd => {
-                let v = extract_data(&data, 2 * c, false);
-                if v & 0x80000000 != 0 {
-                    println!("{} ({})", v, v as i32);
-                } else {
-                    println!("{}", v);
-                }
-                addr += 2;
+            if !problems.is_empty() {
+                Err(anyhow::anyhow!("{} problems in {}", problems.len(), conf))?;
             }
-            Formats::U32cdab => {
-                let v = extract_data(&data, 2 * c, true);
-                if v & 0x80000000 != 0 {
-                    println!("{} ({})", v, v as i32);
-                } else {
-                    println!("{}", v);
-                }
-                addr += 2;
-            }
-            Formats::U32badc => {
-                let v = extract_data_32(&data, 2 * c, 1, 0, 3, 2);
-                if v & 0x80000000 != 0 {
-                    println!("{} ({})", v, v as i32);
-                } else {
-                    println!("{}", v);
-                }
-                addr += 2;
-            }
-            Formats::U32dcba => {
-                let v = extract_data_32(&data, 2 * c, 3, 2, 1, 0);
-                if v & 0x80000000 != 0 {
-                    println!("{} ({})", v, v as i32);
-                } else {
-                    println!("{}", v);
-                }
-                addr += 2;
-            }
-            Formats::F32 => {
-                let v = extract_data(&data, 2 * c, little_endian);
-                println!("{}", f32::from_bits(v));
-                addr += 2;
-            }
-            Formats::F32abcd => {
-                let v = extract_data(&data, 2 * c, false);
-                println!("{}", f32::from_bits(v));
-                addr += 2;
-            }
-            Formats::F32cdab => {
-                let v = extract_data(&data, 2 * c, true);
-                println!("{}", f32::from_bits(v));
-                addr += 2;
-            }
-            Formats::F32badc => {
-                let v = extract_data_32(&data, 2 * c, 1, 0, 3, 2);
-                println!("{}", f32::from_bits(v));
-                addr += 2;
-            }
-            Formats::F32dcba => {
-                let v = extract_data_32(&data, 2 * c, 3, 2, 1, 0);
-                println!("{}", f32::from_bits(v));
-                addr += 2;
-            }
-            Formats::Hex16 => {
-                println!("{:#04X}", data[c]);
-                addr += 1;
-            }
-            Formats::Hex32 => {
-                let v = extract_data(&data, 2 * c, little_endian);
-                println!("{:#010X}", v);
-                addr += 2;
-            }
-            Formats::Bin16
-                if *function == Functions::Coil || *function == Functions::DiscreteInput =>
-            {
-                println!("{:b}", data[c]);
-                addr += 1;
-            }
-            Formats::Bin16 => {
-                println!("{:016b}", data[c]);
-                addr += 1;
-            }
-            Formats::Bin32 => {
-                let v = extract_data(&data, 2 * c, little_endian);
-                println!("{:032b}", v);
-                addr += 2;
-            }
-            _ => {
-                // addr += 1;
-                todo!()
+            println!("{}: {} devices OK", conf, device_list.devices.len());
+        }
+        ConfigCommand::List => {
+            println!("NAME             PROTOCOL MODE       ENDPOINT                     ENABLED");
+            for d in device_list.devices.iter() {
+                let mode = match config::mode(&d.remote) {
+                    Some(mode) => format!("{:?}", mode).to_lowercase(),
+                    None => d.remote.mode.clone(),
+                };
+                println!(
+                    "{:<16} {:<8} {:<10} {:<28} {}",
+                    d.signature.name,
+                    d.remote.protocol.to_lowercase(),
+                    mode,
+                    config::endpoint(&d.remote),
+                    if d.enable { "yes".green() } else { "no".red() }
+                );
             }
         }
     }
+    Ok(())
 }
 
-fn check_args(args: &mut Args) -> Result<()> {
-    let writevalues = args.writevalues.clone();
-    let tp = args.r#type.clone().unwrap();
-    let func = tp.function;
-    let format = tp.format;
-    if writevalues.is_some() {
-        let writevalues = writevalues.unwrap();
-        if args.slave.len() > 1 {
-            Err(anyhow::anyhow!("Only one slave can write"))?;
-        }
+/// Print a session saved with `--record`, or answer Modbus requests as the recorded device
+async fn replay(args: &Args, replay: &ReplayArgs) -> Result<()> {
+    let records = record::read_records(&replay.file)?;
+    if let Some(listen) = &replay.serve {
+        let listener = TcpListener::bind(listen).await?;
+        record::serve(listener, &records)
+            .await
+            .with_context(|| format!("Can not serve {}", replay.file))?;
+        return Ok(());
+    }
 
-        match func {
-            Functions::DiscreteInput | Functions::InputRegister => {
-                Err(anyhow::anyhow!("Unable to write read-only element"))?;
-            }
-            Functions::Coil => {
-                for v in writevalues {
-                    if v.parse::<bool>().is_err() {
-                        Err(anyhow::anyhow!("Write value {} must be bool", v))?;
-                    }
-                }
-            }
-            Functions::HoldingRegister => match format {
-                Formats::U16 | Formats::Hex16 | Formats::Bin16 => {
-                    for v in writevalues {
-                        if v.parse::<u16>().is_err()
-                            && (v.starts_with("0x")
-                                && u16::from_str_radix(v.trim_start_matches("0x"), 16).is_err())
-                            && (v.starts_with("0b")
-                                && u16::from_str_radix(v.trim_start_matches("0b"), 2).is_err())
-                        {
-                            Err(anyhow::anyhow!("Write value {} must be u16/hex16/bin16", v))?;
-                        }
-                    }
-                }
-                Formats::I16 => {
-                    for v in writevalues {
-                        if v.parse::<i16>().is_err() {
-                            Err(anyhow::anyhow!("Write value {} must be int16", v))?;
-                        }
-                    }
-                }
-                Formats::U32
-                | Formats::U32abcd
-                | Formats::U32cdab
-                | Formats::U32badc
-                | Formats::U32dcba
-                | Formats::Hex32
-                | Formats::Bin32 => {
-                    for v in args.writevalues.clone().unwrap() {
-                        if v.parse::<u32>().is_err()
-                            && (v.starts_with("0x")
-                                && u32::from_str_radix(v.trim_start_matches("0x"), 16).is_err())
-                            && (v.starts_with("0b")
-                                && u32::from_str_radix(v.trim_start_matches("0b"), 2).is_err())
-                        {
-                            Err(anyhow::anyhow!("Write value {} must be u32/hex32/bin32", v))?;
-                        }
-                    }
-                }
-                Formats::I32
-                | Formats::I32abcd
-                | Formats::I32cdab
-                | Formats::I32badc
-                | Formats::I32dcba => {
-                    for v in args.writevalues.clone().unwrap() {
-                        if v.parse::<i32>().is_err() {
-                            Err(anyhow::anyhow!("Write value {} must be int32", v))?;
-                        }
+    let start = records.first().map(|r| r.time()).unwrap_or_default();
+    let points = Iec104Replay::default();
+    let mut iec104 = false;
+    for r in records {
+        let time = r.time() - start;
+        match r {
+            Record::Modbus {
+                slave,
+                request,
+                response,
+                error,
+                ..
+            } => {
+                let pdu = record::from_hex(&request)?;
+                let request = match modbus_pdu::request_from_pdu(&pdu) {
+                    Ok(req) => req,
+                    Err(e) => {
+                        println!(
+                            "[{:.3}] slave {}: {} ({})",
+                            time,
+                            slave,
+                            record::to_hex(&pdu, " "),
+                            e
+                        );
+                        continue;
                     }
-                }
-                Formats::F32
-                | Formats::F32abcd
-                | Formats::F32cdab
-                | Formats::F32badc
-                | Formats::F32dcba => {
-                    for v in args.writevalues.clone().unwrap() {
-                        if v.parse::<f32>().is_err() {
-                            Err(anyhow::anyhow!("Write value {} must be float", v))?;
+                };
+                println!("[{:.3}] slave {}: {:?}", time, slave, request);
+                match (response, error) {
+                    (Some(rsp), _) => {
+                        let rsp = record::from_hex(&rsp)?;
+                        match modbus_pdu::response_from_pdu(&request, &rsp) {
+                            Ok(rsp) => print_transaction(args, &request, &rsp),
+                            Err(e) => println!("{} ({})", record::to_hex(&rsp, " "), e),
                         }
                     }
-                }
-                Formats::String => {
-                    Err(anyhow::anyhow!("You can use string format only for output"))?
-                }
-                Formats::Unkonwn => Err(anyhow::anyhow!("Unknown format"))?,
-            },
-            Functions::Siq => {
-                for v in writevalues {
-                    if v.parse::<bool>().is_err() {
-                        Err(anyhow::anyhow!("Write value {} must be bool", v))?;
-                    }
-                }
-            }
-            Functions::Diq => {
-                for v in writevalues {
-                    if v.parse::<u8>().is_err() {
-                        Err(anyhow::anyhow!("Write value {} must be 0/1/2/3", v))?;
-                    }
-                    if v.parse::<u8>().unwrap() > 3 {
-                        Err(anyhow::anyhow!("Write value {} must be 0/1/2/3", v))?;
-                    }
-                }
-            }
-            Functions::Nva | Functions::Sva => {
-                for v in writevalues {
-                    if v.parse::<i16>().is_err() {
-                        Err(anyhow::anyhow!("Write value {} must be int16", v))?;
-                    }
-                }
-            }
-            Functions::R => {
-                for v in args.writevalues.clone().unwrap() {
-                    if v.parse::<f32>().is_err() {
-                        Err(anyhow::anyhow!("Write value {} must be float", v))?;
-                    }
+                    (None, Some(e)) => println!("{}", e.red()),
+                    (None, None) => {}
                 }
             }
-            Functions::Bcr => {
-                for v in args.writevalues.clone().unwrap() {
-                    if v.parse::<u32>().is_err()
-                        && (v.starts_with("0x")
-                            && u32::from_str_radix(v.trim_start_matches("0x"), 16).is_err())
-                        && (v.starts_with("0b")
-                            && u32::from_str_radix(v.trim_start_matches("0b"), 2).is_err())
-                    {
-                        Err(anyhow::anyhow!("Write value {} must be u32/hex32/bin32", v))?;
+            Record::Iec104 { sent, asdu, .. } => {
+                let asdu = record::from_hex(&asdu)?;
+                let dir = if sent { "sent" } else { "received" };
+                println!("[{:.3}] {}: {}", time, dir, record::to_hex(&asdu, " "));
+                if !sent {
+                    match points.feed(&asdu) {
+                        Ok(()) => iec104 = true,
+                        Err(e) => println!("{}", e.to_string().red()),
                     }
                 }
             }
-            Functions::All => {
-                if !args.writevalues.clone().unwrap().is_empty() {
-                    Err(anyhow::anyhow!("Write value not allowed"))?;
-                }
-            }
         }
+    }
 
-        args.once = true;
-        args.count = Some(args.writevalues.clone().unwrap().len() as u16);
+    if iec104 {
+        println!("-- Points at the end of the session");
+        print_iec104_all(&points);
     }
+    Ok(())
+}
 
-    match args.device_type() {
-        DeviceType::Device => {
-            args.mode = Some(Mode::Rtu);
+fn print_sniffed(args: &Args, stats: &Mutex<PollStats>, time: f32, sniffed: Sniffed) {
+    sniffed.count(&mut stats.lock().unwrap());
+    match sniffed {
+        Sniffed::Transaction {
+            slave,
+            request,
+            response,
+        } => {
+            println!("[{:.3}] slave {}: {:?}", time, slave, request);
+            print_transaction(args, &request, &response);
         }
-        DeviceType::Host => {
-            if args.mode.unwrap() == Mode::Rtu {
-                args.mode = Some(Mode::Tcp);
-            }
-            if args.device.parse::<SocketAddr>().is_ok() {
-                let sd = args.device.parse::<SocketAddr>().unwrap();
-                args.device = sd.ip().to_string();
-                args.port = Some(sd.port());
-            }
+        Sniffed::NoResponse { slave: 0, request } => {
+            println!("[{:.3}] broadcast: {:?}", time, request);
+        }
+        Sniffed::NoResponse { slave, request } => {
+            println!("[{:.3}] slave {}: {:?}", time, slave, request);
+            println!("{}", "No response".red());
+        }
+        Sniffed::Unknown { frame, reason } => {
+            println!(
+                "[{:.3}] slave {}: {} ({})",
+                time,
+                frame.slave,
+                record::to_hex(&frame.raw, " "),
+                reason
+            );
+        }
+        Sniffed::BadFrame { data, checksum } => {
+            println!(
+                "[{:.3}] {} {} ({})",
+                time,
+                "Bad frame:".red(),
+                record::to_hex(&data, " "),
+                if checksum { "wrong CRC" } else { "too short" }
+            );
+        }
+    }
+}
+
+/// Values of a request and its response, in the format of the `--format-range` they fall into
+fn print_transaction(
+    args: &Args,
+    request: &Request<'_>,
+    response: &std::result::Result<Response, ExceptionCode>,
+) {
+    let coils = |v: &[bool]| v.iter().map(|c| *c as u16).collect::<Vec<u16>>();
+    let rsp = match response {
+        Ok(rsp) => rsp,
+        Err(e) => {
+            println!("{}", Error::Exception(*e));
+            return;
         }
-        DeviceType::Name => {
-            let conf = File::open(args.clone().conf.unwrap())?;
-            let reader = BufReader::new(conf);
-            let device_list: DeviceList = serde_json::from_reader(reader)?;
-
-            let d = device_list
-                .devices
-                .iter()
-                .filter(|d| d.signature.name == args.device)
-                .collect::<Vec<&Device>>();
-
-            if d.is_empty() {
-                Err(anyhow::anyhow!("No device found"))?;
+    };
+    match (request, rsp) {
+        (Request::ReadCoils(addr, _), Response::ReadCoils(v)) => {
+            print_values(args, *addr, Functions::Coil, coils(v))
+        }
+        (Request::ReadDiscreteInputs(addr, _), Response::ReadDiscreteInputs(v)) => {
+            print_values(args, *addr, Functions::DiscreteInput, coils(v))
+        }
+        (Request::ReadHoldingRegisters(addr, _), Response::ReadHoldingRegisters(v))
+        | (
+            Request::ReadWriteMultipleRegisters(addr, ..),
+            Response::ReadWriteMultipleRegisters(v),
+        ) => print_values(args, *addr, Functions::HoldingRegister, v.clone()),
+        (Request::ReadInputRegisters(addr, _), Response::ReadInputRegisters(v)) => {
+            print_values(args, *addr, Functions::InputRegister, v.clone())
+        }
+        (Request::WriteMultipleRegisters(addr, v), _) => {
+            print_values(args, *addr, Functions::HoldingRegister, v.to_vec())
+        }
+        (Request::WriteMultipleCoils(addr, v), _) => {
+            print_values(args, *addr, Functions::Coil, coils(v))
+        }
+        (_, rsp) => println!("{:?}", rsp),
+    }
+}
+
+/// Print registers from `addr` on, split where the `--format-range` changes
+fn print_values(args: &Args, addr: u16, function: Functions, data: Vec<u16>) {
+    let default = match function {
+        Functions::Coil | Functions::DiscreteInput => Formats::Bin16,
+        _ => args.r#type.clone().unwrap().format,
+    };
+    let mut i = 0;
+    while i < data.len() {
+        let a = addr.wrapping_add(i as u16);
+        let range = args
+            .format_range
+            .iter()
+            .find(|r| r.r#type.function == function && (r.start..=r.end).contains(&a));
+        let (format, end) = match range {
+            Some(r) => (r.r#type.format.clone(), (r.end - addr) as usize + 1),
+            None => {
+                let next = args
+                    .format_range
+                    .iter()
+                    .filter(|r| r.r#type.function == function && r.start > a)
+                    .map(|r| (r.start - addr) as usize)
+                    .min();
+                (default.clone(), next.unwrap_or(data.len()))
             }
-            if d.len() > 1 {
-                Err(anyhow::anyhow!("Multiple devices found: {:?}", d))?;
+        };
+        let end = end.min(data.len());
+        // a single register left for a 32-bit format
+        let (format, words) = match (codec::word_count(&format) == 2, end - i) {
+            (true, 1) => (Formats::U16, 1),
+            (true, _) => (format, 2),
+            (false, _) => (format, 1),
+        };
+        let count = (end - i) / words;
+        let registers = &data[i..i + count * words];
+        let values = match function {
+            Functions::Coil | Functions::DiscreteInput => {
+                Ok(registers.iter().map(|v| Value::Bool(*v != 0)).collect())
             }
+            _ => codec::decode_all(registers, &format, args.little_endian),
+        };
+        match values {
+            Ok(values) => print!("{}", codec::format_values(a, &format, &values)),
+            Err(e) => println!("[{}({:#04X})]: \t{}", a, a, e),
+        }
+        i += count * words;
+    }
+}
 
-            let device = d[0];
-            if device.remote.protocol.to_lowercase() == "modbus" {
-                if device.remote.mode.to_lowercase() == "rtu" {
-                    args.mode = Some(Mode::Rtu);
-                    args.device = device.remote.device.clone().unwrap();
-                    if device.remote.slave_id.is_some() {
-                        args.slave.clear();
-                        args.slave.push(device.remote.slave_id.unwrap());
-                    }
-                    if device.remote.baud.is_some() {
-                        args.baudrate = Some(device.remote.baud.unwrap());
-                    }
-                    if device.remote.data_bit.is_some() {
-                        args.databits = Some(device.remote.data_bit.unwrap());
-                    }
-                    if device.remote.stop_bit.is_some() {
-                        args.stopbits = Some(device.remote.stop_bit.unwrap());
-                    }
-                    if device.remote.parity.is_some() {
-                        args.parity = Some(device.remote.parity.clone().unwrap());
-                    }
-                    if device.remote.timeout_ms.is_some() {
-                        args.timeout = Some(Duration::from_secs_f32(
-                            device.remote.timeout_ms.unwrap() as f32 / 1000.0,
-                        ));
-                    }
-                } else if device.remote.mode.to_lowercase() == "tcp" {
-                    args.mode = Some(Mode::Tcp);
-                    args.device = device.remote.host.clone().unwrap();
-                    if device.remote.slave_id.is_some() {
-                        args.slave.clear();
-                        args.slave.push(device.remote.slave_id.unwrap());
-                    }
-                    if device.remote.port.is_some() {
-                        args.port =
-                            Some(device.remote.port.clone().unwrap().parse::<u16>().unwrap());
-                    }
-                    if device.remote.timeout_ms.is_some() {
-                        args.timeout = Some(Duration::from_secs_f32(
-                            device.remote.timeout_ms.unwrap() as f32 / 1000.0,
-                        ));
-                    }
-                } else if device.remote.mode.to_lowercase() == "rtu_in_tcp" {
-                    args.mode = Some(Mode::RtuInTcp);
-                    args.device = device.remote.host.clone().unwrap();
-                    if device.remote.slave_id.is_some() {
-                        args.slave.clear();
-                        args.slave.push(device.remote.slave_id.unwrap());
-                    }
-                    if device.remote.port.is_some() {
-                        args.port =
-                            Some(device.remote.port.clone().unwrap().parse::<u16>().unwrap());
+async fn udp_client(args: Args, stats: Arc<Mutex<PollStats>>) -> Result<()> {
+    let addr = resolve(&args.device, args.port.unwrap()).await?;
+    let retries = args.retries.unwrap();
+    modbus_client(args, Transport::Udp { addr, retries }, stats).await
+}
+
+async fn rtu_in_tcp_client(args: Args, stats: Arc<Mutex<PollStats>>) -> Result<()> {
+    let socket_addr = resolve(&args.device, args.port.unwrap()).await?;
+    modbus_client(args, Transport::RtuInTcp(socket_addr), stats).await
+}
+
+async fn iec104_client(args: Args, link_stats: Arc<Mutex<LinkStats>>) -> Result<()> {
+    let function = args.r#type.clone().unwrap().function;
+    let remote_addr = args.slave.clone()[0];
+
+    let socket_addr = resolve(&args.device, args.port.unwrap()).await?;
+    let mut endpoints = vec![socket_addr];
+    for backup in args.backup.iter() {
+        let (host, port) = parse_host(backup).unwrap_or((backup.clone(), None));
+        endpoints.push(resolve(&host, port.unwrap_or(args.port.unwrap())).await?);
+    }
+    let mut client = IEC104Client::with_endpoints(&endpoints, remote_addr as u16)
+        .context("Failed to start the IEC104 link monitor")?;
+    client.set_link_stats(link_stats.clone())?;
+    if let Some(path) = &args.record {
+        client.set_recorder(Arc::new(Recorder::create(path)?))?;
+    }
+    client.set_command_option(CommandOption {
+        select: !args.direct,
+        qualifier: args.qu.unwrap(),
+        timeout: args.timeout.unwrap(),
+    });
+    // read the requested points on demand, interrogation is only needed for the whole station
+    client.set_read_on_demand(
+        args.writevalues.is_some() || (function != Functions::All && args.reference_given),
+    );
+    client.connect().await?;
+
+    if let Some(writevalues) = &args.writevalues {
+        tokio::time::sleep(Duration::from_millis(args.poll_rate.unwrap())).await;
+        let rs = write_commands(&mut client, &args, writevalues).await;
+        tokio::time::sleep(Duration::from_millis(args.poll_rate.unwrap())).await;
+        return rs;
+    }
+    let name = format!("remote addr {}", remote_addr);
+    poll_points(&mut client, &args, &name, Some(&link_stats)).await
+}
+
+async fn iec101_client(args: Args) -> Result<()> {
+    let link_addr = args.slave[0] as u16;
+
+    let mut client = IEC101Client::new(IEC101Option {
+        link_mode: args.link_mode.unwrap(),
+        link_addr,
+        link_addr_size: args.link_addr_size.unwrap(),
+        cot_size: args.cot_size.unwrap(),
+        ca_size: args.ca_size.unwrap(),
+        ioa_size: args.ioa_size.unwrap(),
+        common_addr: args.common_addr.unwrap_or(link_addr),
+        timeout: args.timeout.unwrap(),
+        poll_interval: Duration::from_millis(args.link_poll.unwrap()),
+    });
+    client.set_command_option(CommandOption {
+        select: !args.direct,
+        qualifier: args.qu.unwrap(),
+        timeout: args.timeout.unwrap(),
+    });
+    client.set_serial(serial_builder(&args));
+    client.connect().await.context("Connect error")?;
+
+    if let Some(writevalues) = &args.writevalues {
+        // the link has to be up first, a command is queued until then
+        return write_commands(&mut client, &args, writevalues).await;
+    }
+    let name = format!("link addr {}", link_addr);
+    poll_points(&mut client, &args, &name, None).await
+}
+
+/// Print the points of the references, or of the whole station for `all`
+async fn poll_points<S: Session + PointSpace>(
+    session: &mut S,
+    args: &Args,
+    name: &str,
+    link_stats: Option<&Mutex<LinkStats>>,
+) -> Result<()> {
+    let function = args.r#type.clone().unwrap().function;
+    let count = args.count.unwrap();
+    // the last failed read of a single poll decides the exit code
+    let mut failure = None;
+    loop {
+        print!("-- Polling {}...", name);
+        if !args.once {
+            println!(" Ctrl-C to stop");
+        } else {
+            println!();
+        }
+        match function {
+            Functions::All => print_iec104_all(session),
+            _ => {
+                for &addr in args.reference.iter() {
+                    match session
+                        .read(args.slave[0], function.clone(), addr, count)
+                        .await
+                    {
+                        Ok(data) => {
+                            for (i, point) in data.into_points().into_iter().enumerate() {
+                                let ad = addr + i as u16;
+                                match point {
+                                    Some(v) => println!("[{}({:#04X})]: \t{}", ad, ad, v),
+                                    None => {
+                                        println!("[{}({:#04X})]: \twaiting for data...", ad, ad)
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            println!("Read {:?} failed: {}", function, e);
+                            failure = Some(
+                                anyhow::Error::from(e)
+                                    .context(format!("Read {:?} failed", function)),
+                            );
+                        }
                     }
-                    if device.remote.timeout_ms.is_some() {
-                        args.timeout = Some(Duration::from_secs_f32(
-                            device.remote.timeout_ms.unwrap() as f32 / 1000.0,
-                        ));
+                    if args.reference.len() > 1 {
+                        println!("================");
                     }
-                } else {
-                    Err(anyhow::anyhow!("Unsupported mode:{}", device.remote.mode))?;
-                }
-            } else if device.remote.protocol.to_lowercase() == "iec104" {
-                args.mode = Some(Mode::IEC104);
-                args.device = device.remote.host.clone().unwrap();
-                if device.remote.slave_id.is_some() {
-                    args.slave.clear();
-                    args.slave.push(device.remote.slave_id.unwrap());
-                }
-                if device.remote.port.is_some() {
-                    args.port = Some(device.remote.port.clone().unwrap().parse::<u16>().unwrap());
-                }
-                if device.remote.timeout_ms.is_some() {
-                    args.timeout = Some(Duration::from_secs_f32(
-                        device.remote.timeout_ms.unwrap() as f32 / 1000.0,
-                    ));
                 }
-            } else {
-                Err(anyhow::anyhow!(
-                    "Unsupported protocol:{}",
-                    device.remote.protocol
-                ))?;
             }
         }
+
+        if let Some(link_stats) = link_stats.filter(|_| args.stats && !args.once) {
+            println!("--- link statistics ---\n{}", link_stats.lock().unwrap());
+        }
+        if args.once {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(args.poll_rate.unwrap())).await;
     }
 
-    Ok(())
+    failure.map_or(Ok(()), Err)
+}
+
+/// Send the write values as commands from the first reference on, one by one
+async fn write_commands(
+    session: &mut impl Session,
+    args: &Args,
+    writevalues: &[String],
+) -> Result<()> {
+    let function = args.r#type.clone().unwrap().function;
+    let reference = args.reference[0];
+    // the last failed command decides the exit code
+    let mut failure = None;
+    let mut written = 0;
+    for (i, w) in writevalues.iter().enumerate() {
+        let Some(addr) = reference.checked_add(i as u16) else {
+            Err(anyhow::anyhow!(
+                "Reference {} with {} values is beyond 65535",
+                reference,
+                writevalues.len()
+            ))?
+        };
+        let point = command_point(&function, w)?;
+        let data = Data::Points(vec![Some(point)]);
+        match session
+            .write(args.slave[0], function.clone(), addr, data)
+            .await
+        {
+            Ok(()) => {
+                written += 1;
+                println!("[{}({:#04X})]: \t{} written", addr, addr, w);
+            }
+            Err(e) => {
+                println!(
+                    "[{}({:#04X})]: \t{} write {:?} err {}",
+                    addr, addr, w, function, e
+                );
+                failure = Some(e);
+            }
+        }
+    }
+    println!("Write {} references.", written);
+    failure.map_or(Ok(()), |e| Err(e.into()))
+}
+
+/// Command value of a write value
+fn command_point(function: &Functions, w: &str) -> Result<Point> {
+    let invalid = || anyhow::anyhow!("Write value {} does not fit {:?}", w, function);
+    let point = match function {
+        Functions::Siq => Point::Siq(w.parse().map_err(|_| invalid())?),
+        Functions::Diq => Point::Diq(w.parse().map_err(|_| invalid())?),
+        Functions::Nva => Point::Nva(w.parse().map_err(|_| invalid())?),
+        Functions::Sva => Point::Sva(w.parse().map_err(|_| invalid())?),
+        Functions::R => Point::R(w.parse().map_err(|_| invalid())?),
+        Functions::Bcr => Point::Bcr(codec::parse_counter(w)?),
+        _ => Err(anyhow::anyhow!("No command for {:?}", function))?,
+    };
+    Ok(point)
+}
+
+fn print_iec104_all(client: &impl PointSpace) {
+    let mut has_data = false;
+    for (ad, v) in client.extract_all_siq() {
+        has_data = true;
+        print!("[siq] [{}({:#04X})]: \t", ad, ad);
+        println!("{}", v);
+    }
+
+    for (ad, v) in client.extract_all_diq() {
+        has_data = true;
+        print!("[diq] [{}({:#04X})]: \t", ad, ad);
+        println!("{}", v);
+    }
+
+    for (ad, v) in client.extract_all_nva() {
+        has_data = true;
+        print!("[nva] [{}({:#04X})]: \t", ad, ad);
+        println!("{}", v);
+    }
+
+    for (ad, v) in client.extract_all_sva() {
+        has_data = true;
+        print!("[sva] [{}({:#04X})]: \t", ad, ad);
+        println!("{}", v);
+    }
+
+    for (ad, v) in client.extract_all_r() {
+        has_data = true;
+        print!("[r] [{}({:#04X})]: \t", ad, ad);
+        println!("{}", v);
+    }
+
+    for (ad, v) in client.extract_all_bcr() {
+        has_data = true;
+        print!("[bcr] [{}({:#04X})]: \t", ad, ad);
+        println!("{}", v);
+    }
+
+    for (ad, _) in client.extract_all_st() {
+        has_data = true;
+        print!("[st] [{}({:#04X})]: \t", ad, ad);
+        print_iec104_value(client, &Functions::St, ad);
+    }
+
+    for (ad, _) in client.extract_all_bo() {
+        has_data = true;
+        print!("[bo] [{}({:#04X})]: \t", ad, ad);
+        print_iec104_value(client, &Functions::Bo, ad);
+    }
+
+    for (ad, _) in client.extract_all_ep() {
+        has_data = true;
+        print!("[ep] [{}({:#04X})]: \t", ad, ad);
+        print_iec104_value(client, &Functions::Ep, ad);
+    }
+
+    for (ad, _) in client.extract_all_eps() {
+        has_data = true;
+        print!("[eps] [{}({:#04X})]: \t", ad, ad);
+        print_iec104_value(client, &Functions::Eps, ad);
+    }
+
+    for (ad, _) in client.extract_all_epo() {
+        has_data = true;
+        print!("[epo] [{}({:#04X})]: \t", ad, ad);
+        print_iec104_value(client, &Functions::Epo, ad);
+    }
+
+    for (ad, _) in client.extract_all_ps() {
+        has_data = true;
+        print!("[ps] [{}({:#04X})]: \t", ad, ad);
+        print_iec104_value(client, &Functions::Ps, ad);
+    }
+
+    if !has_data {
+        println!("waiting for data...");
+    }
+}
+
+fn print_iec104_value(client: &impl PointSpace, function: &Functions, ad: u16) {
+    match client.point(function, ad) {
+        Some(v) => println!("{}", v),
+        None => println!("waiting for data..."),
+    }
 }
 
 fn print_args(args: &Args) {
+    if let Some(sig) = &args.signature {
+        let mut info = vec![sig.name.clone()];
+        info.extend(sig.r#type.clone());
+        info.extend(sig.model.clone());
+        info.extend(sig.comment.clone().filter(|c| !c.is_empty()));
+        println!("Device................: {}", info.join(", "));
+    }
     println!("Protocol configuration: {:?}", args.mode.unwrap());
     println!("Slave/Remote configuration...: address = {:?}", args.slave);
     println!(
@@ -1428,7 +1092,8 @@ fn print_args(args: &Args) {
         args.count.unwrap()
     );
     match args.mode {
-        Some(Mode::Rtu) => {
+        Some(Mode::Rtu) | Some(Mode::Ascii) | Some(Mode::Sniff) | Some(Mode::Gateway)
+        | Some(Mode::IEC101) => {
             println!(
                 "Communication.........: {}, {:?}-{:1?}-{}-{:?}
                                 t/o {:.2} s, poll rate {} ms",
@@ -1440,8 +1105,36 @@ fn print_args(args: &Args) {
                 args.timeout.unwrap().as_secs_f32(),
                 args.poll_rate.unwrap()
             );
+            if args.mode == Some(Mode::Gateway) {
+                println!(
+                    "                      : listen {}",
+                    args.listen.clone().unwrap()
+                );
+            }
+            if matches!(
+                args.mode,
+                Some(Mode::Rtu) | Some(Mode::Ascii) | Some(Mode::Gateway)
+            ) {
+                let op = timing_option(args);
+                println!(
+                    "                      : request delay {:.2} ms, char timeout {:.2} ms, turnaround {} ms",
+                    op.request_delay.as_secs_f32() * 1000.0,
+                    op.char_timeout.as_secs_f32() * 1000.0,
+                    op.turnaround.as_millis()
+                );
+            }
+            if let Some(op) = rs485_option(args) {
+                println!(
+                    "                      : RS-485 {:?}{}, delay before {} ms, after {} ms",
+                    op.mode,
+                    if args.rs485_kernel { " (kernel)" } else { "" },
+                    op.delay_before.as_millis(),
+                    op.delay_after.as_millis()
+                );
+            }
         }
-        Some(Mode::Tcp) | Some(Mode::RtuInTcp) | Some(Mode::IEC104) => {
+        Some(Mode::Tcp) | Some(Mode::Tls) | Some(Mode::Udp) | Some(Mode::RtuInTcp)
+        | Some(Mode::Proxy) | Some(Mode::IEC104) => {
             println!(
                 "Communication.........: {}, port {}, t/o {:.2} s, poll rate {} ms",
                 args.device.to_string().red(),
@@ -1449,6 +1142,25 @@ fn print_args(args: &Args) {
                 args.timeout.unwrap().as_secs_f32(),
                 args.poll_rate.unwrap()
             );
+            if !args.backup.is_empty() {
+                println!("                      : backup {:?}", args.backup);
+            }
+            if args.mode == Some(Mode::Proxy) {
+                println!(
+                    "                      : listen {}, rewrite {:?}, drop {:?}",
+                    args.listen.clone().unwrap(),
+                    args.rewrite,
+                    args.drop
+                );
+            }
+            if args.mode == Some(Mode::Tls) {
+                println!(
+                    "                      : ca {}, cert {}, key {}",
+                    args.ca.clone().unwrap_or_default(),
+                    args.cert.clone().unwrap_or_default(),
+                    args.key.clone().unwrap_or_default()
+                );
+            }
         }
         None => unreachable!(),
     }
@@ -1459,18 +1171,78 @@ fn print_args(args: &Args) {
     );
 }
 
-fn extract_data(data: &[u16], pos: usize, little_endian: bool) -> u32 {
-    if little_endian {
-        extract_data_32(data, pos, 2, 3, 0, 1)
-    } else {
-        extract_data_32(data, pos, 0, 1, 2, 3)
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Modbus/TCP slaves answering reads of holding registers with zeros, `slow` after `delay`
+    async fn serve(listener: TcpListener, slow: u8, delay: Duration, units: Arc<Mutex<Vec<u8>>>) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let units = units.clone();
+            tokio::spawn(async move {
+                let mut request = [0; 12];
+                while stream.read_exact(&mut request).await.is_ok() {
+                    let unit = request[6];
+                    units.lock().unwrap().push(unit);
+                    if unit == slow {
+                        tokio::time::sleep(delay).await;
+                    }
+                    let len = 2 * request[11];
+                    let mut response = vec![request[0], request[1], 0, 0, 0, len + 3, unit, 3, len];
+                    response.resize(9 + len as usize, 0);
+                    if stream.write_all(&response).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
     }
-}
 
-fn extract_data_32(data: &[u16], pos: usize, a: usize, b: usize, c: usize, d: usize) -> u32 {
-    let data = [data[pos].to_be_bytes(), data[pos + 1].to_be_bytes()].concat();
-    u32::from_be_bytes([data[a], data[b], data[c], data[d]])
-}
+    #[tokio::test]
+    async fn slow_slave_does_not_delay_the_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let units = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(serve(
+            listener,
+            2,
+            Duration::from_millis(800),
+            units.clone(),
+        ));
 
-#[cfg(test)]
-mod tests {}
+        let device = addr.to_string();
+        let mut args = Args::parse_from([
+            "dpoll",
+            &device,
+            "-a",
+            "1",
+            "-a",
+            "2",
+            "--parallel",
+            "2",
+            "-l",
+            "50",
+            "-o",
+            "2",
+        ]);
+        check_args(&mut args).unwrap();
+        let stats = Arc::new(Mutex::new(PollStats::default()));
+        let polling = modbus_client(args, Transport::Tcp(addr), stats);
+        assert!(tokio::time::timeout(Duration::from_millis(500), polling)
+            .await
+            .is_err());
+
+        // slave 1 keeps its interval while slave 2 is still answering the first request
+        let units = units.lock().unwrap();
+        assert_eq!(units.iter().filter(|&&u| u == 2).count(), 1);
+        assert!(
+            units.iter().filter(|&&u| u == 1).count() >= 5,
+            "{:?}",
+            units
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use std::{fmt, io};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_modbus::{
+    client::{Client, Context},
+    Error, Request, Response, Slave, SlaveContext,
+};
+
+use crate::{error::ChecksumError, modbus_pdu};
+
+const START: u8 = b':';
+const CRLF: &[u8] = b"\r\n";
+/// longest frame in characters, `:` and CRLF included
+const MAX_FRAME: usize = 513;
+
+/// Attach a Modbus ASCII client to a serial port
+pub fn attach_slave<T>(transport: T, slave: Slave) -> Context
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + fmt::Debug + 'static,
+{
+    let client: Box<dyn Client> = Box::new(AsciiClient { transport, slave });
+    Context::from(client)
+}
+
+/// Longitudinal redundancy check, two's complement of the sum of all bytes
+pub fn lrc(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg()
+}
+
+/// `:` + hex(slave, pdu, lrc) + CRLF
+pub fn encode_frame(slave: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut data = vec![slave];
+    data.extend_from_slice(pdu);
+    data.push(lrc(&data));
+
+    let mut frame = vec![START];
+    for b in data {
+        frame.extend_from_slice(format!("{:02X}", b).as_bytes());
+    }
+    frame.extend_from_slice(CRLF);
+    frame
+}
+
+/// Slave address and PDU of a frame without `:` and CRLF, checking the LRC
+pub fn decode_frame(hex: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if hex.len() < 6 || !hex.len().is_multiple_of(2) {
+        return Err(invalid("Invalid ASCII frame length"));
+    }
+    let data = hex
+        .chunks(2)
+        .map(|c| {
+            std::str::from_utf8(c)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+                .ok_or_else(|| invalid("Invalid hex character in ASCII frame"))
+        })
+        .collect::<io::Result<Vec<u8>>>()?;
+    let (&checksum, data) = data.split_last().unwrap();
+    if lrc(data) != checksum {
+        return Err(ChecksumError::Lrc {
+            expected: lrc(data),
+            actual: checksum,
+        }
+        .into());
+    }
+    Ok((data[0], data[1..].to_vec()))
+}
+
+#[derive(Debug)]
+struct AsciiClient<T> {
+    transport: T,
+    slave: Slave,
+}
+
+impl<T: AsyncRead + Unpin> AsciiClient<T> {
+    /// Read until the next complete frame, characters before `:` are skipped
+    async fn read_frame(&mut self) -> io::Result<Vec<u8>> {
+        let mut frame = Vec::new();
+        let mut started = false;
+        loop {
+            let b = self.transport.read_u8().await?;
+            if b == START {
+                // a new start character discards an incomplete frame
+                frame.clear();
+                started = true;
+            } else if started {
+                frame.push(b);
+                if frame.ends_with(CRLF) {
+                    frame.truncate(frame.len() - CRLF.len());
+                    return Ok(frame);
+                }
+                if frame.len() >= MAX_FRAME - 1 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("ASCII frame longer than {} characters", MAX_FRAME),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+impl<T> SlaveContext for AsciiClient<T> {
+    fn set_slave(&mut self, slave: Slave) {
+        self.slave = slave;
+    }
+}
+
+#[async_trait]
+impl<T> Client for AsciiClient<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + fmt::Debug,
+{
+    async fn call(&mut self, request: Request<'_>) -> tokio_modbus::Result<Response> {
+        let pdu = modbus_pdu::request_pdu(&request).map_err(Error::Transport)?;
+        let frame = encode_frame(self.slave.0, &pdu);
+        log::debug!("ASCII TX {}", String::from_utf8_lossy(&frame).trim_end());
+        self.transport
+            .write_all(&frame)
+            .await
+            .map_err(Error::Transport)?;
+        self.transport.flush().await.map_err(Error::Transport)?;
+
+        // broadcast is not answered
+        if self.slave.0 == 0 {
+            let rsp = match request {
+                Request::WriteSingleCoil(addr, coil) => Response::WriteSingleCoil(addr, coil),
+                Request::WriteSingleRegister(addr, word) => {
+                    Response::WriteSingleRegister(addr, word)
+                }
+                Request::WriteMultipleCoils(addr, ref coils) => {
+                    Response::WriteMultipleCoils(addr, coils.len() as u16)
+                }
+                Request::WriteMultipleRegisters(addr, ref words) => {
+                    Response::WriteMultipleRegisters(addr, words.len() as u16)
+                }
+                _ => {
+                    return Err(Error::Transport(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Broadcast is only allowed for writing",
+                    )))
+                }
+            };
+            return Ok(Ok(rsp));
+        }
+
+        loop {
+            let hex = self.read_frame().await.map_err(Error::Transport)?;
+            log::debug!("ASCII RX :{}", String::from_utf8_lossy(&hex));
+            let (slave, pdu) = decode_frame(&hex).map_err(Error::Transport)?;
+            if slave != self.slave.0 {
+                log::warn!(
+                    "Response from slave {} ignored, expected {}",
+                    slave,
+                    self.slave.0
+                );
+                continue;
+            }
+            return modbus_pdu::response_from_pdu(&request, &pdu).map_err(Error::Transport);
+        }
+    }
+
+    async fn disconnect(&mut self) -> io::Result<()> {
+        self.transport.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lrc_of_read_request() {
+        assert_eq!(lrc(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]), 0xF2);
+        assert_eq!(lrc(&[]), 0);
+        assert_eq!(lrc(&[0xFF, 0x01]), 0);
+    }
+
+    #[test]
+    fn encode_read_request() {
+        let frame = encode_frame(1, &[0x03, 0x00, 0x00, 0x00, 0x0A]);
+        assert_eq!(frame, b":01030000000AF2\r\n");
+    }
+
+    #[test]
+    fn decode_read_request() {
+        let (slave, pdu) = decode_frame(b"01030000000AF2").unwrap();
+        assert_eq!(slave, 1);
+        assert_eq!(pdu, [0x03, 0x00, 0x00, 0x00, 0x0A]);
+        // lower case hex digits are accepted
+        assert!(decode_frame(b"01030000000af2").is_ok());
+    }
+
+    #[test]
+    fn decode_rejects_bad_frames() {
+        let e = decode_frame(b"01030000000AF3").unwrap_err();
+        assert!(e.to_string().contains("LRC mismatch"));
+        assert!(ChecksumError::is_source_of(&e));
+        let e = decode_frame(b"01030000000AF").unwrap_err();
+        assert!(e.to_string().contains("length"));
+        assert!(!ChecksumError::is_source_of(&e));
+        let e = decode_frame(b"0103").unwrap_err();
+        assert!(e.to_string().contains("length"));
+        let e = decode_frame(b"01030000000GF2").unwrap_err();
+        assert!(e.to_string().contains("hex"));
+    }
+
+    fn client(data: &[u8]) -> AsciiClient<&[u8]> {
+        AsciiClient {
+            transport: data,
+            slave: Slave(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn read_frame_skips_noise() {
+        let mut c = client(b"\x00xx:0103:01030000000AF2\r\n");
+        assert_eq!(c.read_frame().await.unwrap(), b"01030000000AF2");
+    }
+
+    #[tokio::test]
+    async fn read_frame_is_capped() {
+        let mut data = vec![START];
+        data.extend(std::iter::repeat_n(b'0', MAX_FRAME));
+        data.extend_from_slice(CRLF);
+        let e = client(&data).read_frame().await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // the longest frame is accepted
+        let mut data = vec![START];
+        data.extend(std::iter::repeat_n(b'0', MAX_FRAME - 3));
+        data.extend_from_slice(CRLF);
+        assert_eq!(
+            client(&data).read_frame().await.unwrap().len(),
+            MAX_FRAME - 3
+        );
+    }
+
+    #[tokio::test]
+    async fn read_frame_eof() {
+        let e = client(b":0103").read_frame().await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
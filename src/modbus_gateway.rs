@@ -0,0 +1,318 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    time::{timeout_at, Instant},
+};
+
+use crate::modbus_pdu;
+
+/// Gateway target device failed to respond
+const GATEWAY_TARGET_DEVICE: u8 = 0x0B;
+
+struct Job {
+    unit: u8,
+    pdu: Vec<u8>,
+    /// response PDU, none for a broadcast
+    reply: oneshot::Sender<Option<Vec<u8>>>,
+}
+
+/// Forward Modbus/TCP requests of all clients to the serial line, one at a time in arrival order.
+///
+/// The unit id is the slave address on the line, unit 0 is a broadcast and not answered.
+pub async fn serve<T>(listener: TcpListener, serial: T, timeout: Duration) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(64);
+    let mut line = tokio::spawn(run_line(serial, rx, timeout));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                println!("Client {} connected", peer);
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = run_client(stream, tx).await {
+                        if e.kind() != io::ErrorKind::UnexpectedEof {
+                            log::warn!("Client {}: {}", peer, e);
+                        }
+                    }
+                    println!("Client {} disconnected", peer);
+                });
+            }
+            // the serial line only ends on an error
+            rs = &mut line => {
+                return rs.map_err(io::Error::other)?;
+            }
+        }
+    }
+}
+
+async fn run_client(mut stream: TcpStream, jobs: mpsc::Sender<Job>) -> io::Result<()> {
+    let peer = stream
+        .peer_addr()
+        .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0)));
+    loop {
+        let adu = read_mbap(&mut stream).await?;
+        let (tid, unit, pdu) = modbus_pdu::decode_mbap(&adu)?;
+        log::info!("{} tid {} unit {} request {:02X?}", peer, tid, unit, pdu);
+
+        let (reply, response) = oneshot::channel();
+        let job = Job {
+            unit,
+            pdu: pdu.to_vec(),
+            reply,
+        };
+        if jobs.send(job).await.is_err() {
+            return Err(io::Error::other("Serial line closed"));
+        }
+        let Ok(Some(rsp)) = response.await else {
+            continue;
+        };
+        log::info!("{} tid {} unit {} response {:02X?}", peer, tid, unit, rsp);
+        stream
+            .write_all(&modbus_pdu::encode_mbap(tid, unit, &rsp))
+            .await?;
+    }
+}
+
+/// Read one complete MBAP frame
+pub async fn read_mbap<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; 7];
+    stream.read_exact(&mut header).await?;
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if !(2..=254).contains(&len) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "MBAP length out of range",
+        ));
+    }
+    let mut adu = header.to_vec();
+    adu.resize(6 + len, 0);
+    stream.read_exact(&mut adu[7..]).await?;
+    Ok(adu)
+}
+
+/// Serve the jobs one at a time, a failed transaction is answered with an exception.
+///
+/// Only a closed port ends the line: end of file, or a request that can not be written.
+async fn run_line<T>(
+    mut serial: T,
+    mut jobs: mpsc::Receiver<Job>,
+    timeout: Duration,
+) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    while let Some(job) = jobs.recv().await {
+        serial
+            .write_all(&modbus_pdu::encode_rtu(job.unit, &job.pdu))
+            .await?;
+        serial.flush().await?;
+        let fc = job.pdu[0];
+        let rsp = match receive(&mut serial, job.unit, fc, timeout).await {
+            Ok(rsp) => rsp,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(e),
+            Err(e) => {
+                log::warn!("Slave {} function {:#04X}: {}", job.unit, fc, e);
+                Some(vec![fc | 0x80, GATEWAY_TARGET_DEVICE])
+            }
+        };
+        // the client may be gone already
+        let _ = job.reply.send(rsp);
+    }
+    Ok(())
+}
+
+/// Response PDU of `unit` to function `fc`, none for a broadcast
+async fn receive<T>(
+    serial: &mut T,
+    unit: u8,
+    fc: u8,
+    timeout: Duration,
+) -> io::Result<Option<Vec<u8>>>
+where
+    T: AsyncRead + Unpin,
+{
+    if unit == 0 {
+        return Ok(None);
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut rx = vec![];
+    let mut buf = [0u8; 256];
+    loop {
+        let n = match timeout_at(deadline, serial.read(&mut buf)).await {
+            Ok(rs) => rs?,
+            Err(_) => {
+                log::warn!("Slave {} did not respond to function {:#04X}", unit, fc);
+                return Ok(Some(vec![fc | 0x80, GATEWAY_TARGET_DEVICE]));
+            }
+        };
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Serial port closed",
+            ));
+        }
+        rx.extend_from_slice(&buf[..n]);
+        while let Some(len) = modbus_pdu::rtu_response_len(fc, &rx).filter(|len| *len <= rx.len()) {
+            let frame: Vec<u8> = rx.drain(..len).collect();
+            if frame[0] == unit && frame[1] & 0x7F == fc && modbus_pdu::rtu_crc_ok(&frame) {
+                return Ok(Some(frame[1..len - 2].to_vec()));
+            }
+            log::warn!(
+                "Frame {:02X?} ignored, waiting for slave {} function {:#04X}",
+                frame,
+                unit,
+                fc
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+    use crate::rtu_timing::{RtuTiming, TimingOption};
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    /// Gateway on a local port with `serial` as its line
+    async fn gateway<T>(serial: T) -> TcpStream
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, serial, TIMEOUT));
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    async fn request(client: &mut TcpStream, tid: u16, unit: u8, pdu: &[u8]) -> Vec<u8> {
+        let adu = modbus_pdu::encode_mbap(tid, unit, pdu);
+        client.write_all(&adu).await.unwrap();
+        let adu = read_mbap(client).await.unwrap();
+        let (rsp_tid, rsp_unit, rsp) = modbus_pdu::decode_mbap(&adu).unwrap();
+        assert_eq!((rsp_tid, rsp_unit), (tid, unit));
+        rsp.to_vec()
+    }
+
+    /// Read the RTU request `rtu` on the slave side
+    async fn expect(slave: &mut DuplexStream, rtu: &[u8]) {
+        let mut buf = vec![0; rtu.len()];
+        slave.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, rtu);
+    }
+
+    #[tokio::test]
+    async fn translates_mbap_and_rtu() {
+        let (line, mut slave) = duplex(256);
+        let mut client = gateway(line).await;
+
+        let pdu = [0x03, 0x00, 0x6B, 0x00, 0x02];
+        let slave_task = tokio::spawn(async move {
+            expect(&mut slave, &modbus_pdu::encode_rtu(17, &pdu)).await;
+            // a frame of another slave first, then the response in two bursts
+            let other = modbus_pdu::encode_rtu(5, &[0x03, 0x02, 0x00, 0x01]);
+            slave.write_all(&other).await.unwrap();
+            let rsp = modbus_pdu::encode_rtu(17, &[0x03, 0x04, 0x02, 0x2B, 0x00, 0x00]);
+            slave.write_all(&rsp[..3]).await.unwrap();
+            slave.write_all(&rsp[3..]).await.unwrap();
+
+            // a broadcast is written but not answered
+            expect(
+                &mut slave,
+                &modbus_pdu::encode_rtu(0, &[0x06, 0x00, 0x01, 0x00, 0x03]),
+            )
+            .await;
+            slave
+        });
+        assert_eq!(
+            request(&mut client, 0x1234, 17, &pdu).await,
+            [0x03, 0x04, 0x02, 0x2B, 0x00, 0x00]
+        );
+
+        let adu = modbus_pdu::encode_mbap(2, 0, &[0x06, 0x00, 0x01, 0x00, 0x03]);
+        client.write_all(&adu).await.unwrap();
+        slave_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exception_response() {
+        let (line, mut slave) = duplex(256);
+        let mut client = gateway(line).await;
+
+        let pdu = [0x06, 0x00, 0x01, 0x00, 0x03];
+        tokio::spawn(async move {
+            expect(&mut slave, &modbus_pdu::encode_rtu(1, &pdu)).await;
+            let rsp = modbus_pdu::encode_rtu(1, &[0x86, 0x02]);
+            slave.write_all(&rsp).await.unwrap();
+            slave
+        });
+        assert_eq!(request(&mut client, 1, 1, &pdu).await, [0x86, 0x02]);
+    }
+
+    #[tokio::test]
+    async fn failed_transactions_keep_serving() {
+        let (line, mut slave) = duplex(256);
+        let op = TimingOption {
+            request_delay: Duration::ZERO,
+            char_timeout: Duration::from_millis(20),
+            turnaround: Duration::ZERO,
+            char_time: Duration::ZERO,
+        };
+        let mut client = gateway(RtuTiming::new(line, op)).await;
+
+        let pdu = [0x03, 0x00, 0x00, 0x00, 0x01];
+        let rtu = modbus_pdu::encode_rtu(1, &pdu);
+        let rsp = modbus_pdu::encode_rtu(1, &[0x03, 0x02, 0x00, 0x2A]);
+        tokio::spawn(async move {
+            // no response
+            expect(&mut slave, &rtu).await;
+            // bad CRC
+            expect(&mut slave, &rtu).await;
+            let mut bad = rsp.clone();
+            bad[4] ^= 1;
+            slave.write_all(&bad).await.unwrap();
+            // gap within the response
+            expect(&mut slave, &rtu).await;
+            slave.write_all(&rsp[..2]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            slave.write_all(&rsp[2..]).await.unwrap();
+            // served again
+            expect(&mut slave, &rtu).await;
+            slave.write_all(&rsp).await.unwrap();
+            slave
+        });
+        for tid in 0..3 {
+            assert_eq!(
+                request(&mut client, tid, 1, &pdu).await,
+                [0x83, GATEWAY_TARGET_DEVICE]
+            );
+        }
+        assert_eq!(
+            request(&mut client, 3, 1, &pdu).await,
+            [0x03, 0x02, 0x00, 0x2A]
+        );
+    }
+
+    #[tokio::test]
+    async fn closed_line_ends_the_gateway() {
+        let (line, slave) = duplex(256);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gateway = tokio::spawn(serve(listener, line, TIMEOUT));
+        let mut client = TcpStream::connect(addr).await.unwrap();
+
+        drop(slave);
+        let adu = modbus_pdu::encode_mbap(1, 1, &[0x03, 0x00, 0x00, 0x00, 0x01]);
+        client.write_all(&adu).await.unwrap();
+        assert!(gateway.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,511 @@
+use std::io;
+
+use tokio_modbus::{ExceptionCode, Request, Response};
+
+use crate::error::ChecksumError;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn unsupported(req: &Request<'_>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Unsupported request {:?}", req),
+    )
+}
+
+pub fn function_code(req: &Request<'_>) -> io::Result<u8> {
+    let fc = match req {
+        Request::ReadCoils(..) => 0x01,
+        Request::ReadDiscreteInputs(..) => 0x02,
+        Request::ReadHoldingRegisters(..) => 0x03,
+        Request::ReadInputRegisters(..) => 0x04,
+        Request::WriteSingleCoil(..) => 0x05,
+        Request::WriteSingleRegister(..) => 0x06,
+        Request::WriteMultipleCoils(..) => 0x0F,
+        Request::WriteMultipleRegisters(..) => 0x10,
+        Request::MaskWriteRegister(..) => 0x16,
+        Request::ReadWriteMultipleRegisters(..) => 0x17,
+        _ => return Err(unsupported(req)),
+    };
+    Ok(fc)
+}
+
+pub fn exception_code(code: u8) -> ExceptionCode {
+    match code {
+        0x01 => ExceptionCode::IllegalFunction,
+        0x02 => ExceptionCode::IllegalDataAddress,
+        0x03 => ExceptionCode::IllegalDataValue,
+        0x04 => ExceptionCode::ServerDeviceFailure,
+        0x05 => ExceptionCode::Acknowledge,
+        0x06 => ExceptionCode::ServerDeviceBusy,
+        0x08 => ExceptionCode::MemoryParityError,
+        0x0A => ExceptionCode::GatewayPathUnavailable,
+        0x0B => ExceptionCode::GatewayTargetDevice,
+        _ => ExceptionCode::Custom(code),
+    }
+}
+
+fn pack_coils(coils: &[bool]) -> Vec<u8> {
+    let mut bytes = vec![0u8; coils.len().div_ceil(8)];
+    for (i, coil) in coils.iter().enumerate() {
+        if *coil {
+            bytes[i / 8] |= 1 << (i % 8);
+        }
+    }
+    bytes
+}
+
+fn unpack_coils(bytes: &[u8], count: u16) -> Vec<bool> {
+    (0..count as usize)
+        .map(|i| bytes.get(i / 8).is_some_and(|b| b & (1 << (i % 8)) != 0))
+        .collect()
+}
+
+fn push_words(pdu: &mut Vec<u8>, words: &[u16]) {
+    for w in words {
+        pdu.extend_from_slice(&w.to_be_bytes());
+    }
+}
+
+fn word(data: &[u8], pos: usize) -> io::Result<u16> {
+    data.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("Response too short"))
+}
+
+/// Byte counted payload of a read response, `data` starts after the function code
+fn payload(data: &[u8]) -> io::Result<&[u8]> {
+    let n = *data.first().ok_or_else(|| invalid("Response too short"))? as usize;
+    data.get(1..1 + n)
+        .ok_or_else(|| invalid("Response byte count mismatch"))
+}
+
+fn tail(data: &[u8], pos: usize) -> io::Result<&[u8]> {
+    data.get(pos..).ok_or_else(|| invalid("Request too short"))
+}
+
+fn words(data: &[u8]) -> io::Result<Vec<u16>> {
+    let data = payload(data)?;
+    (0..data.len() / 2).map(|i| word(data, i * 2)).collect()
+}
+
+pub fn request_pdu(req: &Request<'_>) -> io::Result<Vec<u8>> {
+    let mut pdu = vec![function_code(req)?];
+    match req {
+        Request::ReadCoils(addr, cnt)
+        | Request::ReadDiscreteInputs(addr, cnt)
+        | Request::ReadHoldingRegisters(addr, cnt)
+        | Request::ReadInputRegisters(addr, cnt) => push_words(&mut pdu, &[*addr, *cnt]),
+        Request::WriteSingleCoil(addr, coil) => {
+            push_words(&mut pdu, &[*addr, if *coil { 0xFF00 } else { 0x0000 }])
+        }
+        Request::WriteSingleRegister(addr, word) => push_words(&mut pdu, &[*addr, *word]),
+        Request::WriteMultipleCoils(addr, coils) => {
+            let bytes = pack_coils(coils);
+            push_words(&mut pdu, &[*addr, coils.len() as u16]);
+            pdu.push(bytes.len() as u8);
+            pdu.extend_from_slice(&bytes);
+        }
+        Request::WriteMultipleRegisters(addr, words) => {
+            push_words(&mut pdu, &[*addr, words.len() as u16]);
+            pdu.push((words.len() * 2) as u8);
+            push_words(&mut pdu, words);
+        }
+        Request::MaskWriteRegister(addr, and, or) => push_words(&mut pdu, &[*addr, *and, *or]),
+        Request::ReadWriteMultipleRegisters(read_addr, cnt, write_addr, words) => {
+            push_words(
+                &mut pdu,
+                &[*read_addr, *cnt, *write_addr, words.len() as u16],
+            );
+            pdu.push((words.len() * 2) as u8);
+            push_words(&mut pdu, words);
+        }
+        _ => return Err(unsupported(req)),
+    }
+    Ok(pdu)
+}
+
+/// Decode the response PDU to `req`, an exception response is returned as the inner error
+pub fn response_from_pdu(
+    req: &Request<'_>,
+    pdu: &[u8],
+) -> io::Result<Result<Response, ExceptionCode>> {
+    let fc = function_code(req)?;
+    let (&rsp_fc, data) = pdu.split_first().ok_or_else(|| invalid("Empty response"))?;
+    if rsp_fc == fc | 0x80 {
+        let code = *data.first().ok_or_else(|| invalid("Response too short"))?;
+        return Ok(Err(exception_code(code)));
+    }
+    if rsp_fc != fc {
+        return Err(invalid(&format!(
+            "Function code mismatch: expected {:#04X}, got {:#04X}",
+            fc, rsp_fc
+        )));
+    }
+
+    let rsp = match req {
+        Request::ReadCoils(_, cnt) => Response::ReadCoils(unpack_coils(payload(data)?, *cnt)),
+        Request::ReadDiscreteInputs(_, cnt) => {
+            Response::ReadDiscreteInputs(unpack_coils(payload(data)?, *cnt))
+        }
+        Request::ReadHoldingRegisters(..) => Response::ReadHoldingRegisters(words(data)?),
+        Request::ReadInputRegisters(..) => Response::ReadInputRegisters(words(data)?),
+        Request::WriteSingleCoil(..) => {
+            Response::WriteSingleCoil(word(data, 0)?, word(data, 2)? == 0xFF00)
+        }
+        Request::WriteSingleRegister(..) => {
+            Response::WriteSingleRegister(word(data, 0)?, word(data, 2)?)
+        }
+        Request::WriteMultipleCoils(..) => {
+            Response::WriteMultipleCoils(word(data, 0)?, word(data, 2)?)
+        }
+        Request::WriteMultipleRegisters(..) => {
+            Response::WriteMultipleRegisters(word(data, 0)?, word(data, 2)?)
+        }
+        Request::MaskWriteRegister(..) => {
+            Response::MaskWriteRegister(word(data, 0)?, word(data, 2)?, word(data, 4)?)
+        }
+        Request::ReadWriteMultipleRegisters(..) => {
+            Response::ReadWriteMultipleRegisters(words(data)?)
+        }
+        _ => return Err(unsupported(req)),
+    };
+    Ok(Ok(rsp))
+}
+
+/// MBAP header (transaction id, protocol 0, length, unit id) followed by the PDU
+pub fn encode_mbap(transaction_id: u16, unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut adu = Vec::with_capacity(7 + pdu.len());
+    adu.extend_from_slice(&transaction_id.to_be_bytes());
+    adu.extend_from_slice(&[0, 0]);
+    adu.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+    adu.push(unit);
+    adu.extend_from_slice(pdu);
+    adu
+}
+
+/// Transaction id, unit id and PDU of a complete MBAP frame
+pub fn decode_mbap(adu: &[u8]) -> io::Result<(u16, u8, &[u8])> {
+    if adu.len() < 8 {
+        return Err(invalid("MBAP frame too short"));
+    }
+    if word(adu, 2)? != 0 {
+        return Err(invalid("Not a Modbus protocol id"));
+    }
+    let len = word(adu, 4)? as usize;
+    if len < 2 || adu.len() != 6 + len {
+        return Err(invalid("MBAP length mismatch"));
+    }
+    Ok((word(adu, 0)?, adu[6], &adu[7..]))
+}
+
+/// Decode a request PDU, as seen by a slave
+pub fn request_from_pdu(pdu: &[u8]) -> io::Result<Request<'static>> {
+    let (&fc, data) = pdu.split_first().ok_or_else(|| invalid("Empty request"))?;
+    let req = match fc {
+        0x01 => Request::ReadCoils(word(data, 0)?, word(data, 2)?),
+        0x02 => Request::ReadDiscreteInputs(word(data, 0)?, word(data, 2)?),
+        0x03 => Request::ReadHoldingRegisters(word(data, 0)?, word(data, 2)?),
+        0x04 => Request::ReadInputRegisters(word(data, 0)?, word(data, 2)?),
+        0x05 => Request::WriteSingleCoil(word(data, 0)?, word(data, 2)? == 0xFF00),
+        0x06 => Request::WriteSingleRegister(word(data, 0)?, word(data, 2)?),
+        0x0F => {
+            let cnt = word(data, 2)?;
+            let coils = unpack_coils(payload(tail(data, 4)?)?, cnt);
+            Request::WriteMultipleCoils(word(data, 0)?, coils.into())
+        }
+        0x10 => Request::WriteMultipleRegisters(word(data, 0)?, words(tail(data, 4)?)?.into()),
+        0x16 => Request::MaskWriteRegister(word(data, 0)?, word(data, 2)?, word(data, 4)?),
+        0x17 => Request::ReadWriteMultipleRegisters(
+            word(data, 0)?,
+            word(data, 2)?,
+            word(data, 4)?,
+            words(tail(data, 8)?)?.into(),
+        ),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported function code {:#04X}", fc),
+            ))
+        }
+    };
+    Ok(req)
+}
+
+/// Modbus CRC-16 (polynomial 0xA001, initial 0xFFFF), sent low byte first
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for b in data {
+        crc ^= *b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Slave address, PDU and CRC
+pub fn encode_rtu(slave: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut adu = Vec::with_capacity(3 + pdu.len());
+    adu.push(slave);
+    adu.extend_from_slice(pdu);
+    adu.extend_from_slice(&crc16(&adu).to_le_bytes());
+    adu
+}
+
+/// Check the CRC in the last two bytes, a [`ChecksumError`] if it is wrong
+pub fn check_rtu_crc(adu: &[u8]) -> io::Result<()> {
+    if adu.len() < 4 {
+        return Err(invalid("RTU frame shorter than 4 bytes"));
+    }
+    let (data, crc) = adu.split_at(adu.len() - 2);
+    let expected = crc16(data);
+    let actual = u16::from_le_bytes([crc[0], crc[1]]);
+    if expected != actual {
+        return Err(ChecksumError::Crc { expected, actual }.into());
+    }
+    Ok(())
+}
+
+/// Whether the last two bytes are the CRC of the rest
+pub fn rtu_crc_ok(adu: &[u8]) -> bool {
+    check_rtu_crc(adu).is_ok()
+}
+
+/// Length of the RTU response to function `fc` starting with `buf`, once it can be told
+pub fn rtu_response_len(fc: u8, buf: &[u8]) -> Option<usize> {
+    let byte = |i: usize| buf.get(i).map(|b| *b as usize);
+    if *buf.get(1)? == fc | 0x80 {
+        return Some(5);
+    }
+    match fc {
+        0x01..=0x04 | 0x17 => byte(2).map(|n| 5 + n),
+        0x05 | 0x06 | 0x0F | 0x10 => Some(8),
+        0x16 => Some(10),
+        // unknown function code, shortest frame with a matching CRC
+        _ => (4..=buf.len()).find(|len| rtu_crc_ok(&buf[..*len])),
+    }
+}
+
+/// Encode a response PDU, as sent by a slave
+pub fn response_pdu(rsp: &Response) -> io::Result<Vec<u8>> {
+    let fc = match rsp {
+        Response::ReadCoils(..) => 0x01,
+        Response::ReadDiscreteInputs(..) => 0x02,
+        Response::ReadHoldingRegisters(..) => 0x03,
+        Response::ReadInputRegisters(..) => 0x04,
+        Response::WriteSingleCoil(..) => 0x05,
+        Response::WriteSingleRegister(..) => 0x06,
+        Response::WriteMultipleCoils(..) => 0x0F,
+        Response::WriteMultipleRegisters(..) => 0x10,
+        Response::MaskWriteRegister(..) => 0x16,
+        Response::ReadWriteMultipleRegisters(..) => 0x17,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported response {:?}", rsp),
+            ))
+        }
+    };
+    let mut pdu = vec![fc];
+    match rsp {
+        Response::ReadCoils(coils) | Response::ReadDiscreteInputs(coils) => {
+            let bytes = pack_coils(coils);
+            pdu.push(bytes.len() as u8);
+            pdu.extend_from_slice(&bytes);
+        }
+        Response::ReadHoldingRegisters(words)
+        | Response::ReadInputRegisters(words)
+        | Response::ReadWriteMultipleRegisters(words) => {
+            pdu.push((words.len() * 2) as u8);
+            push_words(&mut pdu, words);
+        }
+        Response::WriteSingleCoil(addr, coil) => {
+            push_words(&mut pdu, &[*addr, if *coil { 0xFF00 } else { 0x0000 }])
+        }
+        Response::WriteSingleRegister(addr, word) => push_words(&mut pdu, &[*addr, *word]),
+        Response::WriteMultipleCoils(addr, cnt) | Response::WriteMultipleRegisters(addr, cnt) => {
+            push_words(&mut pdu, &[*addr, *cnt])
+        }
+        Response::MaskWriteRegister(addr, and, or) => push_words(&mut pdu, &[*addr, *and, *or]),
+        _ => unreachable!(),
+    }
+    Ok(pdu)
+}
+
+/// Exception response PDU
+pub fn exception_pdu(fc: u8, code: ExceptionCode) -> Vec<u8> {
+    vec![fc | 0x80, exception_value(code)]
+}
+
+pub fn exception_value(code: ExceptionCode) -> u8 {
+    match code {
+        ExceptionCode::IllegalFunction => 0x01,
+        ExceptionCode::IllegalDataAddress => 0x02,
+        ExceptionCode::IllegalDataValue => 0x03,
+        ExceptionCode::ServerDeviceFailure => 0x04,
+        ExceptionCode::Acknowledge => 0x05,
+        ExceptionCode::ServerDeviceBusy => 0x06,
+        ExceptionCode::MemoryParityError => 0x08,
+        ExceptionCode::GatewayPathUnavailable => 0x0A,
+        ExceptionCode::GatewayTargetDevice => 0x0B,
+        ExceptionCode::Custom(code) => code,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x4B37);
+        assert_eq!(crc16(&[]), 0xFFFF);
+        let adu = encode_rtu(1, &[0x03, 0x00, 0x00, 0x00, 0x0A]);
+        assert_eq!(adu, [0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0xCD]);
+        assert!(rtu_crc_ok(&adu));
+        assert!(!rtu_crc_ok(&adu[..7]));
+        assert!(!rtu_crc_ok(&[0xFF, 0xFF]));
+
+        let e = check_rtu_crc(&adu[..7]).unwrap_err();
+        assert!(ChecksumError::is_source_of(&e));
+        let e = check_rtu_crc(&[0xFF, 0xFF]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(!ChecksumError::is_source_of(&e));
+    }
+
+    #[test]
+    fn read_request() {
+        let pdu = request_pdu(&Request::ReadHoldingRegisters(0x006B, 3)).unwrap();
+        assert_eq!(pdu, [0x03, 0x00, 0x6B, 0x00, 0x03]);
+    }
+
+    #[test]
+    fn write_requests() {
+        let pdu = request_pdu(&Request::WriteSingleCoil(0x00AC, true)).unwrap();
+        assert_eq!(pdu, [0x05, 0x00, 0xAC, 0xFF, 0x00]);
+
+        let coils = [
+            true, false, true, true, false, false, true, true, true, false,
+        ];
+        let pdu = request_pdu(&Request::WriteMultipleCoils(0x0013, coils[..].into())).unwrap();
+        assert_eq!(pdu, [0x0F, 0x00, 0x13, 0x00, 0x0A, 0x02, 0xCD, 0x01]);
+
+        let words = [0x000A, 0x0102];
+        let pdu = request_pdu(&Request::WriteMultipleRegisters(1, words[..].into())).unwrap();
+        assert_eq!(
+            pdu,
+            [0x10, 0x00, 0x01, 0x00, 0x02, 0x04, 0x00, 0x0A, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn read_responses() {
+        let req = Request::ReadHoldingRegisters(0x006B, 3);
+        let rsp = response_from_pdu(&req, &[0x03, 0x06, 0x02, 0x2B, 0x00, 0x00, 0x00, 0x64]);
+        assert_eq!(
+            rsp.unwrap(),
+            Ok(Response::ReadHoldingRegisters(vec![0x022B, 0, 0x64]))
+        );
+
+        let req = Request::ReadCoils(0x0013, 10);
+        let rsp = response_from_pdu(&req, &[0x01, 0x02, 0xCD, 0x01]).unwrap();
+        assert_eq!(
+            rsp,
+            Ok(Response::ReadCoils(vec![
+                true, false, true, true, false, false, true, true, true, false
+            ]))
+        );
+    }
+
+    #[test]
+    fn write_response() {
+        let req = Request::WriteSingleRegister(1, 3);
+        let rsp = response_from_pdu(&req, &[0x06, 0x00, 0x01, 0x00, 0x03]).unwrap();
+        assert_eq!(rsp, Ok(Response::WriteSingleRegister(1, 3)));
+    }
+
+    #[test]
+    fn encode_responses() {
+        let rsp = Response::ReadCoils(vec![
+            true, false, true, true, false, false, true, true, true, false,
+        ]);
+        assert_eq!(response_pdu(&rsp).unwrap(), [0x01, 0x02, 0xCD, 0x01]);
+        let rsp = Response::ReadInputRegisters(vec![0x000A]);
+        assert_eq!(response_pdu(&rsp).unwrap(), [0x04, 0x02, 0x00, 0x0A]);
+        let rsp = Response::WriteSingleCoil(0x00AC, true);
+        assert_eq!(response_pdu(&rsp).unwrap(), [0x05, 0x00, 0xAC, 0xFF, 0x00]);
+        let rsp = Response::WriteMultipleRegisters(1, 2);
+        assert_eq!(response_pdu(&rsp).unwrap(), [0x10, 0x00, 0x01, 0x00, 0x02]);
+        let rsp = Response::MaskWriteRegister(4, 0xF2, 0x25);
+        assert_eq!(
+            response_pdu(&rsp).unwrap(),
+            [0x16, 0x00, 0x04, 0x00, 0xF2, 0x00, 0x25]
+        );
+
+        // decoding gives the response back
+        let req = Request::ReadWriteMultipleRegisters(3, 2, 14, vec![0xFF].into());
+        let rsp = Response::ReadWriteMultipleRegisters(vec![0x00FE, 0x0ACD]);
+        let pdu = response_pdu(&rsp).unwrap();
+        assert_eq!(pdu, [0x17, 0x04, 0x00, 0xFE, 0x0A, 0xCD]);
+        assert_eq!(response_from_pdu(&req, &pdu).unwrap(), Ok(rsp));
+    }
+
+    #[test]
+    fn exception_response() {
+        let req = Request::ReadInputRegisters(0, 1);
+        let rsp = response_from_pdu(&req, &[0x84, 0x02]).unwrap();
+        assert_eq!(rsp, Err(ExceptionCode::IllegalDataAddress));
+    }
+
+    #[test]
+    fn mbap() {
+        let adu = encode_mbap(0x1234, 0x11, &[0x03, 0x00, 0x6B, 0x00, 0x03]);
+        assert_eq!(
+            adu,
+            [0x12, 0x34, 0x00, 0x00, 0x00, 0x06, 0x11, 0x03, 0x00, 0x6B, 0x00, 0x03]
+        );
+        let (tid, unit, pdu) = decode_mbap(&adu).unwrap();
+        assert_eq!((tid, unit), (0x1234, 0x11));
+        assert_eq!(pdu, [0x03, 0x00, 0x6B, 0x00, 0x03]);
+    }
+
+    #[test]
+    fn invalid_mbap() {
+        let adu = encode_mbap(1, 1, &[0x03, 0x00]);
+        assert!(decode_mbap(&adu[..7]).is_err());
+        assert!(decode_mbap(&adu[..adu.len() - 1]).is_err());
+        let mut other = adu.clone();
+        other[3] = 1;
+        assert!(decode_mbap(&other).is_err());
+    }
+
+    #[test]
+    fn invalid_responses() {
+        let req = Request::ReadHoldingRegisters(0, 2);
+        assert!(response_from_pdu(&req, &[]).is_err());
+        assert!(response_from_pdu(&req, &[0x04, 0x02, 0x00, 0x01]).is_err());
+        // byte count beyond the data
+        assert!(response_from_pdu(&req, &[0x03, 0x04, 0x00, 0x01]).is_err());
+        assert!(response_from_pdu(&req, &[0x83]).is_err());
+    }
+
+    #[test]
+    fn response_lengths() {
+        assert_eq!(rtu_response_len(0x03, &[1]), None);
+        assert_eq!(rtu_response_len(0x03, &[1, 0x03]), None);
+        assert_eq!(rtu_response_len(0x03, &[1, 0x03, 4]), Some(9));
+        assert_eq!(rtu_response_len(0x03, &[1, 0x83]), Some(5));
+        assert_eq!(rtu_response_len(0x10, &[1, 0x10]), Some(8));
+        assert_eq!(rtu_response_len(0x16, &[1, 0x16]), Some(10));
+        let custom = encode_rtu(1, &[0x41, 0x01, 0x02]);
+        assert_eq!(rtu_response_len(0x41, &custom[..5]), None);
+        assert_eq!(rtu_response_len(0x41, &custom), Some(custom.len()));
+    }
+}
@@ -0,0 +1,442 @@
+use std::{io, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    time::{timeout_at, Instant},
+};
+use tokio_modbus::{ExceptionCode, Request, Response};
+
+use crate::{
+    codec::{self, Value},
+    modbus_gateway::read_mbap,
+    modbus_pdu, parse_range, parse_reference, Functions, Type,
+};
+
+/// Only the Modbus tables `-t 1|2|3|4` can be rewritten or dropped
+fn parse_table(function: &Functions) -> anyhow::Result<Functions> {
+    match function {
+        Functions::Coil
+        | Functions::DiscreteInput
+        | Functions::HoldingRegister
+        | Functions::InputRegister => Ok(function.clone()),
+        _ => Err(anyhow::anyhow!(
+            "Table must be 1, 2, 3 or 4: {:?}",
+            function
+        )),
+    }
+}
+
+/// Reads of the registers at `addr` are answered with `value` in the format of the type,
+/// ADDR=TYPE=VALUE, for example 100=3=0x8000 or 200=4:f32=-1.5
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pub addr: u16,
+    pub r#type: Type,
+    pub value: Value,
+}
+
+impl FromStr for RewriteRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Rewrite must be ADDR=TYPE=VALUE");
+        let (addr, rest) = s.split_once('=').ok_or_else(invalid)?;
+        let (tp, value) = rest.rsplit_once('=').ok_or_else(invalid)?;
+        let r#type: Type = tp.parse()?;
+        parse_table(&r#type.function)?;
+        Ok(RewriteRule {
+            addr: parse_reference(addr)?,
+            value: Value::parse(value, &r#type.format)?,
+            r#type,
+        })
+    }
+}
+
+/// Requests touching the registers are neither forwarded nor answered, TABLE:START[-END]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DropRule {
+    pub function: Functions,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for DropRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (table, range) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Drop must be TABLE:START[-END]"))?;
+        let (start, end) = parse_range(range)?;
+        let r#type: Type = table.parse()?;
+        Ok(DropRule {
+            function: parse_table(&r#type.function)?,
+            start,
+            end,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProxyOption {
+    pub upstream: Option<SocketAddr>,
+    pub rewrites: Vec<RewriteRule>,
+    pub drops: Vec<DropRule>,
+    pub timeout: Duration,
+    /// word order of the rewritten 32-bit values (`-L`)
+    pub little_endian: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Forwarded,
+    /// response values replaced by rewrite rules
+    Rewritten,
+    /// request matched a drop rule
+    Dropped,
+    /// the device did not answer in time, the client got exception 0x0B
+    NoResponse,
+}
+
+/// One request passing the proxy
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub peer: SocketAddr,
+    pub unit: u8,
+    /// undecodable requests are passed on as they are
+    pub request: Result<Request<'static>, Vec<u8>>,
+    /// response as sent to the client
+    pub response: Option<Result<Response, ExceptionCode>>,
+    pub action: Action,
+}
+
+/// Table, first address and count a request reads or writes
+fn touched(req: &Request<'_>) -> Vec<(Functions, u16, u16)> {
+    match req {
+        Request::ReadCoils(addr, cnt) => vec![(Functions::Coil, *addr, *cnt)],
+        Request::ReadDiscreteInputs(addr, cnt) => vec![(Functions::DiscreteInput, *addr, *cnt)],
+        Request::ReadHoldingRegisters(addr, cnt) => {
+            vec![(Functions::HoldingRegister, *addr, *cnt)]
+        }
+        Request::ReadInputRegisters(addr, cnt) => vec![(Functions::InputRegister, *addr, *cnt)],
+        Request::WriteSingleCoil(addr, _) => vec![(Functions::Coil, *addr, 1)],
+        Request::WriteMultipleCoils(addr, coils) => {
+            vec![(Functions::Coil, *addr, coils.len() as u16)]
+        }
+        Request::WriteSingleRegister(addr, _) | Request::MaskWriteRegister(addr, ..) => {
+            vec![(Functions::HoldingRegister, *addr, 1)]
+        }
+        Request::WriteMultipleRegisters(addr, words) => {
+            vec![(Functions::HoldingRegister, *addr, words.len() as u16)]
+        }
+        Request::ReadWriteMultipleRegisters(read_addr, cnt, write_addr, words) => vec![
+            (Functions::HoldingRegister, *read_addr, *cnt),
+            (Functions::HoldingRegister, *write_addr, words.len() as u16),
+        ],
+        _ => vec![],
+    }
+}
+
+fn dropped(drops: &[DropRule], req: &Request<'_>) -> bool {
+    touched(req).iter().any(|(function, addr, cnt)| {
+        drops.iter().any(|d| {
+            d.function == *function
+                && (d.start as u32) < *addr as u32 + *cnt as u32
+                && *addr <= d.end
+        })
+    })
+}
+
+/// Apply the rewrite rules to the values of a read response, true if any value changed
+fn rewrite(op: &ProxyOption, req: &Request<'_>, rsp: &mut Response) -> io::Result<bool> {
+    let Some((function, start, _)) = touched(req).into_iter().next() else {
+        return Ok(false);
+    };
+    let mut changed = false;
+    for r in op.rewrites.iter().filter(|r| r.r#type.function == function) {
+        let words = codec::encode(r.value, &r.r#type.format, op.little_endian)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        for (k, word) in words.into_iter().enumerate() {
+            let addr = r.addr as usize + k;
+            let Some(i) = addr.checked_sub(start as usize) else {
+                continue;
+            };
+            match rsp {
+                Response::ReadCoils(v) | Response::ReadDiscreteInputs(v) if i < v.len() => {
+                    v[i] = word != 0;
+                    changed = true;
+                }
+                Response::ReadHoldingRegisters(v)
+                | Response::ReadInputRegisters(v)
+                | Response::ReadWriteMultipleRegisters(v)
+                    if i < v.len() =>
+                {
+                    v[i] = word;
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// Accept Modbus/TCP clients and pass their requests to the device, one device connection
+/// per client. Every request is reported to `log`.
+pub async fn serve<F>(listener: TcpListener, op: ProxyOption, on_exchange: F) -> io::Result<()>
+where
+    F: Fn(Exchange) + Send + Sync + 'static,
+{
+    let op = Arc::new(op);
+    let on_exchange = Arc::new(on_exchange);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        println!("Client {} connected", peer);
+        let op = op.clone();
+        let on_exchange = on_exchange.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_client(stream, peer, &op, on_exchange.as_ref()).await {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    log::warn!("Client {}: {}", peer, e);
+                }
+            }
+            println!("Client {} disconnected", peer);
+        });
+    }
+}
+
+async fn run_client<F: Fn(Exchange)>(
+    mut client: TcpStream,
+    peer: SocketAddr,
+    op: &ProxyOption,
+    on_exchange: &F,
+) -> io::Result<()> {
+    let mut device = TcpStream::connect(op.upstream.unwrap()).await?;
+    loop {
+        let adu = read_mbap(&mut client).await?;
+        let (tid, unit, pdu) = modbus_pdu::decode_mbap(&adu)?;
+        let request = modbus_pdu::request_from_pdu(pdu).map_err(|_| pdu.to_vec());
+        let mut exchange = Exchange {
+            peer,
+            unit,
+            request,
+            response: None,
+            action: Action::Forwarded,
+        };
+
+        if let Ok(req) = &exchange.request {
+            if dropped(&op.drops, req) {
+                exchange.action = Action::Dropped;
+                on_exchange(exchange);
+                continue;
+            }
+        }
+
+        device.write_all(&adu).await?;
+        let Some(rsp_adu) = response(&mut device, tid, op.timeout).await? else {
+            let code = ExceptionCode::GatewayTargetDevice;
+            let rsp = modbus_pdu::exception_pdu(pdu[0], code);
+            client
+                .write_all(&modbus_pdu::encode_mbap(tid, unit, &rsp))
+                .await?;
+            exchange.action = Action::NoResponse;
+            exchange.response = Some(Err(code));
+            on_exchange(exchange);
+            continue;
+        };
+        let (_, _, rsp_pdu) = modbus_pdu::decode_mbap(&rsp_adu)?;
+
+        let mut out = rsp_adu.clone();
+        if let Ok(req) = &exchange.request {
+            if let Ok(mut rsp) = modbus_pdu::response_from_pdu(req, rsp_pdu) {
+                if let Ok(values) = rsp.as_mut() {
+                    if rewrite(op, req, values)? {
+                        exchange.action = Action::Rewritten;
+                        out =
+                            modbus_pdu::encode_mbap(tid, unit, &modbus_pdu::response_pdu(values)?);
+                    }
+                }
+                exchange.response = Some(rsp);
+            }
+        }
+        client.write_all(&out).await?;
+        on_exchange(exchange);
+    }
+}
+
+/// Response with transaction id `tid`, late responses to earlier requests are skipped
+async fn response(device: &mut TcpStream, tid: u16, wait: Duration) -> io::Result<Option<Vec<u8>>> {
+    let deadline = Instant::now() + wait;
+    loop {
+        let Ok(adu) = timeout_at(deadline, read_mbap(device)).await else {
+            return Ok(None);
+        };
+        let adu = adu?;
+        let (rsp_tid, ..) = modbus_pdu::decode_mbap(&adu)?;
+        if rsp_tid == tid {
+            return Ok(Some(adu));
+        }
+        log::warn!("Late response with transaction id {} discarded", rsp_tid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, sync::Mutex};
+
+    use super::*;
+
+    fn option(rewrites: &[&str], drops: &[&str]) -> ProxyOption {
+        ProxyOption {
+            upstream: None,
+            rewrites: rewrites.iter().map(|r| r.parse().unwrap()).collect(),
+            drops: drops.iter().map(|d| d.parse().unwrap()).collect(),
+            timeout: Duration::from_millis(100),
+            little_endian: false,
+        }
+    }
+
+    #[test]
+    fn parse_rules() {
+        let r: RewriteRule = "0x10=4:f32=-1.5".parse().unwrap();
+        assert_eq!(r.addr, 0x10);
+        assert_eq!(r.r#type.function, Functions::InputRegister);
+        assert_eq!(r.value, Value::F32(-1.5));
+        let r: RewriteRule = "100=3=0x8000".parse().unwrap();
+        assert_eq!(r.value, Value::U16(0x8000));
+        assert!("100=3:i16=40000".parse::<RewriteRule>().is_err());
+        assert!("100=siq=1".parse::<RewriteRule>().is_err());
+        assert!("3:100".parse::<RewriteRule>().is_err());
+
+        let d: DropRule = "3:200-209".parse().unwrap();
+        assert_eq!(d.function, Functions::HoldingRegister);
+        assert_eq!((d.start, d.end), (200, 209));
+        assert!("5:1".parse::<DropRule>().is_err());
+        assert!("3".parse::<DropRule>().is_err());
+    }
+
+    #[test]
+    fn drop_matching() {
+        let op = option(&[], &["3:200-209", "1:5"]);
+        let drops = &op.drops;
+        assert!(dropped(drops, &Request::ReadHoldingRegisters(200, 1)));
+        assert!(dropped(drops, &Request::ReadHoldingRegisters(190, 11)));
+        assert!(!dropped(drops, &Request::ReadHoldingRegisters(190, 10)));
+        assert!(dropped(drops, &Request::ReadHoldingRegisters(209, 5)));
+        assert!(!dropped(drops, &Request::ReadHoldingRegisters(210, 5)));
+        // same address in another table
+        assert!(!dropped(drops, &Request::ReadInputRegisters(200, 1)));
+        assert!(dropped(
+            drops,
+            &Request::WriteMultipleRegisters(198, Cow::Owned(vec![0; 3]))
+        ));
+        assert!(dropped(drops, &Request::WriteSingleCoil(5, true)));
+        assert!(!dropped(drops, &Request::ReadCoils(6, 8)));
+        // the write part of a read/write request
+        assert!(dropped(
+            drops,
+            &Request::ReadWriteMultipleRegisters(0, 1, 205, Cow::Owned(vec![1]))
+        ));
+        assert!(!dropped(drops, &Request::ReadHoldingRegisters(0xFFFF, 1)));
+    }
+
+    #[test]
+    fn rewrite_matching() {
+        let op = option(&["100=3=0x8000", "102=3:f32=1.0", "7=1=1"], &[]);
+
+        let req = Request::ReadHoldingRegisters(100, 3);
+        let mut rsp = Response::ReadHoldingRegisters(vec![1, 2, 3]);
+        assert!(rewrite(&op, &req, &mut rsp).unwrap());
+        // the second register of the f32 lies beyond the response
+        assert_eq!(rsp, Response::ReadHoldingRegisters(vec![0x8000, 2, 0x3F80]));
+
+        let req = Request::ReadHoldingRegisters(103, 2);
+        let mut rsp = Response::ReadHoldingRegisters(vec![1, 2]);
+        assert!(rewrite(&op, &req, &mut rsp).unwrap());
+        assert_eq!(rsp, Response::ReadHoldingRegisters(vec![0, 2]));
+
+        let mut le = option(&["102=3:f32=1.0"], &[]);
+        le.little_endian = true;
+        let req = Request::ReadHoldingRegisters(102, 2);
+        let mut rsp = Response::ReadHoldingRegisters(vec![1, 2]);
+        assert!(rewrite(&le, &req, &mut rsp).unwrap());
+        assert_eq!(rsp, Response::ReadHoldingRegisters(vec![0, 0x3F80]));
+
+        // other table or outside the response
+        let req = Request::ReadInputRegisters(100, 2);
+        let mut rsp = Response::ReadInputRegisters(vec![1, 2]);
+        assert!(!rewrite(&op, &req, &mut rsp).unwrap());
+        let req = Request::ReadHoldingRegisters(104, 2);
+        let mut rsp = Response::ReadHoldingRegisters(vec![1, 2]);
+        assert!(!rewrite(&op, &req, &mut rsp).unwrap());
+
+        let req = Request::ReadCoils(0, 8);
+        let mut rsp = Response::ReadCoils(vec![false; 8]);
+        assert!(rewrite(&op, &req, &mut rsp).unwrap());
+        assert_eq!(
+            rsp,
+            Response::ReadCoils(vec![false, false, false, false, false, false, false, true])
+        );
+    }
+
+    #[tokio::test]
+    async fn device_timeout_keeps_client() {
+        let device = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut op = option(&[], &[]);
+        op.upstream = Some(device.local_addr().unwrap());
+        let read = [0x03, 0x00, 0x00, 0x00, 0x01];
+        tokio::spawn(async move {
+            let (mut stream, _) = device.accept().await.unwrap();
+            let adu = read_mbap(&mut stream).await.unwrap();
+            assert_eq!(modbus_pdu::decode_mbap(&adu).unwrap().0, 1);
+            let adu = read_mbap(&mut stream).await.unwrap();
+            assert_eq!(modbus_pdu::decode_mbap(&adu).unwrap().0, 2);
+            // the late response to the first request, then the second one
+            let rsp = [0x03, 0x02, 0x00, 0x01];
+            stream
+                .write_all(&modbus_pdu::encode_mbap(1, 1, &rsp))
+                .await
+                .unwrap();
+            let rsp = [0x03, 0x02, 0x00, 0x02];
+            stream
+                .write_all(&modbus_pdu::encode_mbap(2, 1, &rsp))
+                .await
+                .unwrap();
+            stream
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let actions = Arc::new(Mutex::new(vec![]));
+        let seen = actions.clone();
+        tokio::spawn(serve(listener, op, move |e: Exchange| {
+            seen.lock().unwrap().push(e.action)
+        }));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(&modbus_pdu::encode_mbap(1, 1, &read))
+            .await
+            .unwrap();
+        let adu = read_mbap(&mut client).await.unwrap();
+        assert_eq!(
+            modbus_pdu::decode_mbap(&adu).unwrap(),
+            (1, 1, &[0x83, 0x0B][..])
+        );
+
+        client
+            .write_all(&modbus_pdu::encode_mbap(2, 1, &read))
+            .await
+            .unwrap();
+        let adu = read_mbap(&mut client).await.unwrap();
+        assert_eq!(
+            modbus_pdu::decode_mbap(&adu).unwrap(),
+            (2, 1, &[0x03, 0x02, 0x00, 0x02][..])
+        );
+        assert_eq!(
+            *actions.lock().unwrap(),
+            [Action::NoResponse, Action::Forwarded]
+        );
+    }
+}
@@ -0,0 +1,426 @@
+use std::{io, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::{timeout, Instant},
+};
+use tokio_modbus::{ExceptionCode, Request, Response};
+
+use crate::{
+    error::ChecksumError,
+    modbus_pdu,
+    stats::{Outcome, PollStats},
+};
+
+/// One frame seen on the line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtuFrame {
+    pub slave: u8,
+    /// function code and data, without slave address and CRC
+    pub pdu: Vec<u8>,
+    pub raw: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sniffed {
+    /// request and its response
+    Transaction {
+        slave: u8,
+        request: Request<'static>,
+        response: Result<Response, ExceptionCode>,
+    },
+    /// request the slave did not answer before the next frame, broadcasts are never answered
+    NoResponse {
+        slave: u8,
+        request: Request<'static>,
+    },
+    /// frame that is neither a known request nor the response to the pending one
+    Unknown { frame: RtuFrame, reason: String },
+    /// data between silent intervals without a valid CRC, `checksum` unless it was too short to
+    /// have one
+    BadFrame { data: Vec<u8>, checksum: bool },
+}
+
+impl Sniffed {
+    /// Count a request and what became of it, frames that are no request only as errors
+    pub fn count(&self, stats: &mut PollStats) {
+        let fc = |req: &Request<'_>| modbus_pdu::function_code(req).unwrap_or_default();
+        match self {
+            Sniffed::Transaction {
+                slave,
+                request,
+                response,
+            } => {
+                stats.request(*slave, fc(request));
+                stats.outcome(*slave, fc(request), Outcome::of_response(response));
+            }
+            // broadcasts are never answered
+            Sniffed::NoResponse { slave: 0, request } => {
+                stats.request(0, fc(request));
+                stats.outcome(0, fc(request), Outcome::Response);
+            }
+            Sniffed::NoResponse { slave, request } => {
+                stats.request(*slave, fc(request));
+                stats.outcome(*slave, fc(request), Outcome::Timeout);
+            }
+            Sniffed::Unknown { frame, .. } => {
+                let function = frame.pdu.first().copied().unwrap_or_default();
+                stats.outcome(frame.slave, function, Outcome::Io);
+            }
+            Sniffed::BadFrame { data, checksum } => {
+                // slave address and function code as far as they can be told
+                let byte = |i: usize| data.get(i).copied().unwrap_or_default();
+                let outcome = match checksum {
+                    true => Outcome::Checksum,
+                    false => Outcome::Io,
+                };
+                stats.outcome(byte(0), byte(1), outcome);
+            }
+        }
+    }
+}
+
+impl RtuFrame {
+    /// Frame with a valid CRC
+    pub fn parse(raw: Vec<u8>) -> io::Result<Self> {
+        modbus_pdu::check_rtu_crc(&raw)?;
+        Ok(Self {
+            slave: raw[0],
+            pdu: raw[1..raw.len() - 2].to_vec(),
+            raw,
+        })
+    }
+}
+
+/// Splits the bytes received on the line into frames at the silent intervals between them
+#[derive(Debug)]
+pub struct FrameSplitter {
+    /// silence ending a frame
+    gap: Duration,
+    buf: Vec<u8>,
+    last_rx: Option<Instant>,
+}
+
+impl FrameSplitter {
+    pub fn new(gap: Duration) -> Self {
+        Self {
+            gap,
+            buf: vec![],
+            last_rx: None,
+        }
+    }
+
+    /// Characters received at `now`, returns the frame the silence before them ended
+    pub fn push(&mut self, data: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let ended = match self.last_rx {
+            Some(last_rx) if now.saturating_duration_since(last_rx) >= self.gap => self.flush(),
+            _ => None,
+        };
+        self.buf.extend_from_slice(data);
+        self.last_rx = Some(now);
+        ended
+    }
+
+    /// The line went silent, the frame is complete
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        self.last_rx = None;
+        if self.buf.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.buf))
+        }
+    }
+}
+
+/// Pairs requests with the responses following them
+#[derive(Debug, Default)]
+pub struct Pairing {
+    pending: Option<(u8, Request<'static>)>,
+}
+
+impl Pairing {
+    pub fn push(&mut self, frame: RtuFrame) -> Vec<Sniffed> {
+        let mut out = vec![];
+        if let Some((slave, request)) = self.pending.take() {
+            if slave == frame.slave && slave != 0 {
+                if let Ok(response) = modbus_pdu::response_from_pdu(&request, &frame.pdu) {
+                    out.push(Sniffed::Transaction {
+                        slave,
+                        request,
+                        response,
+                    });
+                    return out;
+                }
+            }
+            out.push(Sniffed::NoResponse { slave, request });
+        }
+        match modbus_pdu::request_from_pdu(&frame.pdu) {
+            Ok(request) => self.pending = Some((frame.slave, request)),
+            Err(e) => out.push(Sniffed::Unknown {
+                frame,
+                reason: e.to_string(),
+            }),
+        }
+        out
+    }
+
+    /// The request still waiting for its response, after a silence longer than any slave takes
+    pub fn expire(&mut self) -> Option<Sniffed> {
+        self.pending
+            .take()
+            .map(|(slave, request)| Sniffed::NoResponse { slave, request })
+    }
+}
+
+/// Passive listener, never writes to the line
+#[derive(Debug)]
+pub struct Sniffer<T> {
+    port: T,
+    /// silence after which a request is taken as unanswered
+    response_timeout: Duration,
+    splitter: FrameSplitter,
+    pairing: Pairing,
+}
+
+impl<T: AsyncRead + Unpin> Sniffer<T> {
+    /// A frame ends after `gap` of silence
+    pub fn new(port: T, gap: Duration, response_timeout: Duration) -> Self {
+        Self {
+            port,
+            response_timeout,
+            splitter: FrameSplitter::new(gap),
+            pairing: Pairing::default(),
+        }
+    }
+
+    fn decode(&mut self, raw: Vec<u8>) -> (Vec<Vec<u8>>, Vec<Sniffed>) {
+        let sniffed = match RtuFrame::parse(raw.clone()) {
+            Ok(frame) => self.pairing.push(frame),
+            Err(e) => vec![Sniffed::BadFrame {
+                data: raw.clone(),
+                checksum: ChecksumError::is_source_of(&e),
+            }],
+        };
+        (vec![raw], sniffed)
+    }
+
+    /// Wait for the next frame, returned raw for capturing and decoded
+    pub async fn next(&mut self) -> io::Result<(Vec<Vec<u8>>, Vec<Sniffed>)> {
+        let mut buf = [0u8; 256];
+        let mut silent = Duration::ZERO;
+        loop {
+            match timeout(self.splitter.gap, self.port.read(&mut buf)).await {
+                Ok(Ok(0)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Serial port closed",
+                    ))
+                }
+                Ok(Ok(n)) => {
+                    silent = Duration::ZERO;
+                    if let Some(raw) = self.splitter.push(&buf[..n], Instant::now()) {
+                        return Ok(self.decode(raw));
+                    }
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {
+                    if let Some(raw) = self.splitter.flush() {
+                        return Ok(self.decode(raw));
+                    }
+                    silent += self.splitter.gap;
+                    if silent >= self.response_timeout {
+                        if let Some(sniffed) = self.pairing.expire() {
+                            return Ok((vec![], vec![sniffed]));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    const GAP: Duration = Duration::from_millis(4);
+
+    fn frame(slave: u8, pdu: &[u8]) -> RtuFrame {
+        RtuFrame::parse(modbus_pdu::encode_rtu(slave, pdu)).unwrap()
+    }
+
+    fn request(slave: u8, req: &Request<'_>) -> RtuFrame {
+        frame(slave, &modbus_pdu::request_pdu(req).unwrap())
+    }
+
+    fn response(slave: u8, rsp: &Response) -> RtuFrame {
+        frame(slave, &modbus_pdu::response_pdu(rsp).unwrap())
+    }
+
+    #[test]
+    fn splits_at_silence() {
+        let mut splitter = FrameSplitter::new(GAP);
+        let req = modbus_pdu::encode_rtu(1, &[0x03, 0x00, 0x00, 0x00, 0x0A]);
+        let t0 = Instant::now();
+
+        // characters handed over in bursts shorter than the gap are one frame
+        assert_eq!(splitter.push(&req[..3], t0), None);
+        assert_eq!(splitter.push(&req[3..], t0 + GAP / 2), None);
+        assert_eq!(splitter.push(&[0x01, 0x03], t0 + GAP * 2), Some(req));
+        assert_eq!(splitter.flush(), Some(vec![0x01, 0x03]));
+        assert_eq!(splitter.flush(), None);
+
+        // no silence between two frames, they can not be told apart
+        let mut splitter = FrameSplitter::new(GAP);
+        let rsp = modbus_pdu::encode_rtu(1, &[0x06, 0x00, 0x01, 0x00, 0x03]);
+        assert_eq!(splitter.push(&rsp, t0), None);
+        assert_eq!(splitter.push(&rsp, t0), None);
+        let merged = splitter.flush().unwrap();
+        assert_eq!(merged.len(), 16);
+        assert!(RtuFrame::parse(merged).is_err());
+    }
+
+    #[test]
+    fn parse_checks_crc() {
+        let raw = modbus_pdu::encode_rtu(7, &[0x05, 0x00, 0x10, 0xFF, 0x00]);
+        let frame = RtuFrame::parse(raw.clone()).unwrap();
+        assert_eq!(frame.slave, 7);
+        assert_eq!(frame.pdu, [0x05, 0x00, 0x10, 0xFF, 0x00]);
+        assert_eq!(frame.raw, raw);
+
+        let mut bad = raw.clone();
+        bad[3] ^= 1;
+        assert!(ChecksumError::is_source_of(
+            &RtuFrame::parse(bad).unwrap_err()
+        ));
+        assert!(!ChecksumError::is_source_of(
+            &RtuFrame::parse(raw[..3].to_vec()).unwrap_err()
+        ));
+    }
+
+    #[test]
+    fn pairs_read_and_write_transactions() {
+        let mut pairing = Pairing::default();
+
+        let read = Request::ReadHoldingRegisters(100, 2);
+        assert_eq!(pairing.push(request(1, &read)), []);
+        let sniffed = pairing.push(response(1, &Response::ReadHoldingRegisters(vec![1, 2])));
+        assert_eq!(
+            sniffed,
+            [Sniffed::Transaction {
+                slave: 1,
+                request: read,
+                response: Ok(Response::ReadHoldingRegisters(vec![1, 2])),
+            }]
+        );
+
+        let write = Request::WriteMultipleRegisters(10, Cow::Owned(vec![5, 6, 7]));
+        assert_eq!(pairing.push(request(2, &write)), []);
+        let sniffed = pairing.push(frame(2, &[0x90, 0x02]));
+        assert_eq!(
+            sniffed,
+            [Sniffed::Transaction {
+                slave: 2,
+                request: write,
+                response: Err(ExceptionCode::IllegalDataAddress),
+            }]
+        );
+        assert_eq!(pairing.expire(), None);
+    }
+
+    #[test]
+    fn unanswered_requests() {
+        let mut pairing = Pairing::default();
+        let read = Request::ReadCoils(0, 8);
+        let write = Request::WriteSingleRegister(1, 3);
+
+        assert_eq!(pairing.push(request(1, &read)), []);
+        // the next request comes before the response
+        assert_eq!(
+            pairing.push(request(0, &write)),
+            [Sniffed::NoResponse {
+                slave: 1,
+                request: read.clone(),
+            }]
+        );
+        // a broadcast is never paired, even with a frame that looks like its echo
+        assert_eq!(
+            pairing.push(request(0, &write)),
+            [Sniffed::NoResponse {
+                slave: 0,
+                request: write.clone(),
+            }]
+        );
+        assert_eq!(
+            pairing.expire(),
+            Some(Sniffed::NoResponse {
+                slave: 0,
+                request: write,
+            })
+        );
+
+        // a response from another slave does not answer the request
+        assert_eq!(pairing.push(request(1, &read)), []);
+        let sniffed = pairing.push(frame(2, &[0x01, 0x01, 0x00]));
+        assert_eq!(
+            sniffed[0],
+            Sniffed::NoResponse {
+                slave: 1,
+                request: read,
+            }
+        );
+        assert!(matches!(sniffed[1], Sniffed::Unknown { .. }));
+    }
+
+    #[test]
+    fn counts_each_request_once() {
+        let mut stats = PollStats::default();
+        let sniffed = [
+            Sniffed::Transaction {
+                slave: 1,
+                request: Request::ReadInputRegisters(0, 2),
+                response: Ok(Response::ReadInputRegisters(vec![1, 2])),
+            },
+            Sniffed::Transaction {
+                slave: 1,
+                request: Request::WriteMultipleCoils(0, Cow::Owned(vec![true; 10])),
+                response: Ok(Response::WriteMultipleCoils(0, 10)),
+            },
+            Sniffed::Transaction {
+                slave: 1,
+                request: Request::WriteMultipleRegisters(0, Cow::Owned(vec![1, 2])),
+                response: Err(ExceptionCode::IllegalDataValue),
+            },
+            Sniffed::NoResponse {
+                slave: 1,
+                request: Request::ReadInputRegisters(0, 2),
+            },
+            Sniffed::BadFrame {
+                data: vec![1, 3, 0, 0],
+                checksum: true,
+            },
+            Sniffed::BadFrame {
+                data: vec![1],
+                checksum: false,
+            },
+        ];
+        for s in sniffed.iter() {
+            s.count(&mut stats);
+        }
+
+        let device = stats.device(1).unwrap();
+        assert_eq!(device.transmitted(), 4);
+        assert_eq!(device.received(), 3);
+        let read = &device.functions[&0x04];
+        assert_eq!((read.transmitted, read.received, read.timeouts), (2, 1, 1));
+        let coils = &device.functions[&0x0F];
+        assert_eq!((coils.transmitted, coils.received), (1, 1));
+        let registers = &device.functions[&0x10];
+        assert_eq!((registers.transmitted, registers.received), (1, 1));
+        assert_eq!(registers.exceptions[&0x03], 1);
+        assert_eq!(device.functions[&0x03].checksum_errors, 1);
+        assert_eq!(device.functions[&0x00].io_errors, 1);
+    }
+}
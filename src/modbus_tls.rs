@@ -0,0 +1,245 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Modbus/TCP Security, TLS with mutual certificate authentication
+#[derive(Debug, Clone, Default)]
+pub struct TlsOption {
+    /// CA certificates (PEM) the server certificate is verified with
+    pub ca: String,
+    /// client certificate chain (PEM)
+    pub cert: String,
+    /// client private key (PEM)
+    pub key: String,
+    /// name the server certificate has to be issued for, DNS name or IP address
+    pub server_name: String,
+}
+
+fn invalid<E: std::fmt::Display>(path: &str, e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e))
+}
+
+fn open(path: &str) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificate found"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)?
+        .ok_or_else(|| invalid(path, "no private key found"))
+}
+
+impl TlsOption {
+    /// Read the certificates and the key, once before connecting, so that a mistake is
+    /// reported at once instead of on every connect attempt
+    pub fn load(&self) -> io::Result<TlsConfig> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&self.ca)? {
+            roots.add(cert).map_err(|e| invalid(&self.ca, e))?;
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(load_certs(&self.cert)?, load_key(&self.key)?)
+            .map_err(|e| invalid(&self.key, e))?;
+        let server_name = ServerName::try_from(self.server_name.clone())
+            .map_err(|e| invalid(&self.server_name, e))?;
+        Ok(TlsConfig {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+}
+
+/// Client configuration of a [`TlsOption`] with the certificates loaded
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+pub async fn connect(
+    socket_addr: SocketAddr,
+    config: &TlsConfig,
+) -> io::Result<TlsStream<TcpStream>> {
+    let stream = TcpStream::connect(socket_addr).await?;
+    TlsConnector::from(config.config.clone())
+        .connect(config.server_name.clone(), stream)
+        .await
+}
+
+/// Negotiated protocol version and subject of the server certificate
+pub fn describe(stream: &TlsStream<TcpStream>) -> (String, String) {
+    let (_, conn) = stream.get_ref();
+    let version = conn
+        .protocol_version()
+        .map(|v| format!("{:?}", v))
+        .unwrap_or_else(|| "unknown".to_string());
+    let subject = conn
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| X509Certificate::from_der(cert.as_ref()).ok())
+        .map(|(_, cert)| cert.subject().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    (version, subject)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use tokio::net::TcpListener;
+    use tokio_rustls::{
+        rustls::{server::WebPkiClientVerifier, ServerConfig},
+        TlsAcceptor,
+    };
+
+    use super::*;
+
+    /// CA, server and client certificates in a directory of their own
+    struct Pki {
+        dir: PathBuf,
+        server: ServerConfig,
+    }
+
+    impl Pki {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("dpoll-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "dpoll CA");
+            let ca_key = KeyPair::generate().unwrap();
+            let ca = params.self_signed(&ca_key).unwrap();
+
+            let signed = |names: Vec<String>, cn: &str| {
+                let mut params = CertificateParams::new(names).unwrap();
+                params.distinguished_name.push(DnType::CommonName, cn);
+                let key = KeyPair::generate().unwrap();
+                let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+                (cert, key)
+            };
+            let (server_cert, server_key) = signed(vec!["localhost".to_string()], "dpoll server");
+            let (client_cert, client_key) = signed(vec![], "dpoll client");
+
+            fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+            fs::write(dir.join("cert.pem"), client_cert.pem()).unwrap();
+            fs::write(dir.join("key.pem"), client_key.serialize_pem()).unwrap();
+
+            let mut roots = RootCertStore::empty();
+            roots.add(ca.der().clone()).unwrap();
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .unwrap();
+            let server = ServerConfig::builder()
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(
+                    vec![server_cert.der().clone()],
+                    PrivateKeyDer::try_from(server_key.serialize_der()).unwrap(),
+                )
+                .unwrap();
+            Pki { dir, server }
+        }
+
+        fn path(&self, file: &str) -> String {
+            self.dir.join(file).display().to_string()
+        }
+
+        fn option(&self, server_name: &str) -> TlsOption {
+            TlsOption {
+                ca: self.path("ca.pem"),
+                cert: self.path("cert.pem"),
+                key: self.path("key.pem"),
+                server_name: server_name.to_string(),
+            }
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn load_fails_fast() {
+        let pki = Pki::new("tls-load");
+        assert!(pki.option("localhost").load().is_ok());
+
+        let mut op = pki.option("localhost");
+        op.ca = pki.path("missing.pem");
+        let e = op.load().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().contains("missing.pem"));
+
+        // a certificate where the key should be
+        let mut op = pki.option("localhost");
+        op.key = pki.path("ca.pem");
+        let e = op.load().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        fs::write(pki.path("empty.pem"), "no PEM here").unwrap();
+        let mut op = pki.option("localhost");
+        op.cert = pki.path("empty.pem");
+        assert_eq!(op.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let op = pki.option("");
+        assert_eq!(op.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn mutual_authentication() {
+        let pki = Pki::new("tls-connect");
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(pki.server.clone()));
+
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            acceptor.accept(stream).await
+        };
+        let config = pki.option("localhost").load().unwrap();
+        let (client, server) = tokio::join!(connect(addr, &config), server);
+        let (version, subject) = describe(&client.unwrap());
+        assert!(server.is_ok());
+        assert_eq!(version, "TLSv1_3");
+        assert_eq!(subject, "CN=dpoll server");
+
+        // the server certificate is not issued for this name
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            acceptor.accept(stream).await
+        };
+        let config = pki.option("plc.example.com").load().unwrap();
+        let (client, _) = tokio::join!(connect(addr, &config), server);
+        assert!(client.is_err());
+    }
+}
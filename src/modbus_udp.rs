@@ -0,0 +1,175 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+use tokio_modbus::{
+    client::{Client, Context},
+    Error, Request, Response, Slave, SlaveContext,
+};
+
+use crate::modbus_pdu;
+
+/// Modbus over UDP, each request is sent up to `retries + 1` times and waits `timeout` for the
+/// reply with the same transaction id
+pub async fn connect_slave(
+    socket_addr: SocketAddr,
+    slave: Slave,
+    timeout: Duration,
+    retries: usize,
+) -> io::Result<Context> {
+    let local: SocketAddr = if socket_addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(socket_addr).await?;
+    let client: Box<dyn Client> = Box::new(UdpClient {
+        socket,
+        slave,
+        transaction_id: 0,
+        timeout,
+        retries,
+    });
+    Ok(Context::from(client))
+}
+
+#[derive(Debug)]
+struct UdpClient {
+    socket: UdpSocket,
+    slave: Slave,
+    transaction_id: u16,
+    timeout: Duration,
+    retries: usize,
+}
+
+impl UdpClient {
+    /// Wait for the reply to `transaction_id`, replies to earlier requests are discarded
+    async fn receive(&self, transaction_id: u16) -> io::Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0u8; 260];
+        loop {
+            let n = match timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                // ICMP port unreachable of an earlier datagram, the device may not be up yet
+                Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    log::warn!("Transaction {}: {}", transaction_id, e);
+                    return Ok(None);
+                }
+                Ok(n) => n?,
+                Err(_) => return Ok(None),
+            };
+            log::debug!("UDP RX {:02X?}", &buf[..n]);
+            match modbus_pdu::decode_mbap(&buf[..n]) {
+                Ok((tid, unit, pdu)) if tid == transaction_id && unit == self.slave.0 => {
+                    return Ok(Some(pdu.to_vec()))
+                }
+                Ok((tid, unit, _)) => {
+                    log::warn!(
+                        "Late reply discarded: transaction {} unit {}, expected transaction {} unit {}",
+                        tid,
+                        unit,
+                        transaction_id,
+                        self.slave.0
+                    );
+                }
+                Err(e) => log::warn!("Invalid datagram discarded: {}", e),
+            }
+        }
+    }
+}
+
+impl SlaveContext for UdpClient {
+    fn set_slave(&mut self, slave: Slave) {
+        self.slave = slave;
+    }
+}
+
+#[async_trait]
+impl Client for UdpClient {
+    async fn call(&mut self, request: Request<'_>) -> tokio_modbus::Result<Response> {
+        let pdu = modbus_pdu::request_pdu(&request).map_err(Error::Transport)?;
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        let tid = self.transaction_id;
+        let adu = modbus_pdu::encode_mbap(tid, self.slave.0, &pdu);
+
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                log::warn!("Transaction {} timeout, retry {}", tid, attempt);
+            }
+            log::debug!("UDP TX {:02X?}", adu);
+            match self.socket.send(&adu).await {
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    log::warn!("Transaction {}: {}", tid, e);
+                    continue;
+                }
+                r => r.map_err(Error::Transport)?,
+            };
+            if let Some(pdu) = self.receive(tid).await.map_err(Error::Transport)? {
+                return modbus_pdu::response_from_pdu(&request, &pdu).map_err(Error::Transport);
+            }
+        }
+        Err(Error::Transport(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("No reply to transaction {}", tid),
+        )))
+    }
+
+    async fn disconnect(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_modbus::client::Reader;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    #[tokio::test]
+    async fn late_reply_is_discarded() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut ctx = connect_slave(addr, Slave(1), TIMEOUT, 0).await.unwrap();
+
+        let reply = async {
+            let mut buf = [0u8; 260];
+            let (n, peer) = server.recv_from(&mut buf).await.unwrap();
+            let (tid, unit, pdu) = modbus_pdu::decode_mbap(&buf[..n]).unwrap();
+            assert_eq!((unit, pdu), (1, &[0x03, 0x00, 0x00, 0x00, 0x01][..]));
+            // reply to an earlier request, then from another unit, then the right one
+            let old = modbus_pdu::encode_mbap(tid - 1, 1, &[0x03, 0x02, 0x00, 0x01]);
+            let other = modbus_pdu::encode_mbap(tid, 2, &[0x03, 0x02, 0x00, 0x02]);
+            let right = modbus_pdu::encode_mbap(tid, 1, &[0x03, 0x02, 0x00, 0x03]);
+            for adu in [old, other, right] {
+                server.send_to(&adu, peer).await.unwrap();
+            }
+        };
+        let (rsp, _) = tokio::join!(ctx.read_holding_registers(0, 1), reply);
+        assert_eq!(rsp.unwrap(), Ok(vec![3]));
+    }
+
+    #[tokio::test]
+    async fn refused_is_retried() {
+        // nothing listens on the port of a closed socket
+        let addr = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut ctx = connect_slave(addr, Slave(1), TIMEOUT, 2).await.unwrap();
+        let e = ctx.read_holding_registers(0, 1).await.unwrap_err();
+        let Error::Transport(e) = e else {
+            panic!("unexpected error {:?}", e);
+        };
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
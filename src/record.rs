@@ -0,0 +1,422 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, net::TcpListener};
+use tokio_iecp5::{asdu::Asdu, ClientHandler};
+use tokio_modbus::{
+    client::{Client, Context},
+    ExceptionCode, Request, Response, Slave, SlaveContext,
+};
+
+use crate::{
+    iec104_client::{IEC104ClientHandler, PointSpace},
+    modbus_gateway::read_mbap,
+    modbus_pdu,
+};
+
+/// One line of a record file, PDUs are hex strings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "protocol", rename_all = "lowercase")]
+pub enum Record {
+    Modbus {
+        /// unix time in seconds
+        time: f64,
+        slave: u8,
+        request: String,
+        /// response or exception PDU, none if the request failed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        response: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// ASDU of an I frame
+    Iec104 { time: f64, sent: bool, asdu: String },
+}
+
+impl Record {
+    pub fn time(&self) -> f64 {
+        match self {
+            Record::Modbus { time, .. } | Record::Iec104 { time, .. } => *time,
+        }
+    }
+}
+
+pub fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Upper case hex digits of `data`, two per byte with `sep` between the bytes
+pub fn to_hex(data: &[u8], sep: &str) -> String {
+    data.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+pub fn from_hex(s: &str) -> io::Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Odd number of hex digits",
+        ));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid hex digit"))
+        })
+        .collect()
+}
+
+/// Appends records to a file, one JSON object per line
+#[derive(Debug)]
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let file =
+            File::create(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+        Ok(Recorder {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(&self, record: &Record) {
+        let line = serde_json::to_string(record).unwrap();
+        if let Err(e) = writeln!(self.file.lock().unwrap(), "{}", line) {
+            log::warn!("Record error: {}", e);
+        }
+    }
+}
+
+pub fn read_records(path: &str) -> io::Result<Vec<Record>> {
+    let file =
+        File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path, i + 1, e),
+            )
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Record every request of `ctx` with its response, the PDUs are encoded again from the
+/// decoded request and response as the raw frames stay inside `ctx`
+pub fn recording(ctx: Context, slave: Slave, recorder: Arc<Recorder>) -> Context {
+    let client: Box<dyn Client> = Box::new(RecordingClient {
+        inner: ctx,
+        slave,
+        recorder,
+    });
+    Context::from(client)
+}
+
+#[derive(Debug)]
+struct RecordingClient {
+    inner: Context,
+    slave: Slave,
+    recorder: Arc<Recorder>,
+}
+
+impl SlaveContext for RecordingClient {
+    fn set_slave(&mut self, slave: Slave) {
+        self.slave = slave;
+        self.inner.set_slave(slave);
+    }
+}
+
+#[async_trait]
+impl Client for RecordingClient {
+    async fn call(&mut self, request: Request<'_>) -> tokio_modbus::Result<Response> {
+        let time = now();
+        let request_pdu = modbus_pdu::request_pdu(&request);
+        let rs = self.inner.call(request.clone()).await;
+        if let Ok(pdu) = request_pdu {
+            let (response, error) = match &rs {
+                Ok(Ok(rsp)) => (
+                    modbus_pdu::response_pdu(rsp).ok().map(|p| to_hex(&p, "")),
+                    None,
+                ),
+                Ok(Err(e)) => (
+                    Some(to_hex(&modbus_pdu::exception_pdu(pdu[0], *e), "")),
+                    None,
+                ),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            self.recorder.write(&Record::Modbus {
+                time,
+                slave: self.slave.0,
+                request: to_hex(&pdu, ""),
+                response,
+                error,
+            });
+        }
+        rs
+    }
+
+    async fn disconnect(&mut self) -> io::Result<()> {
+        self.inner.disconnect().await
+    }
+}
+
+type Responses = HashMap<(u8, Vec<u8>), (Vec<Vec<u8>>, usize)>;
+
+/// Recorded responses per slave and request PDU
+fn responses(records: &[Record]) -> io::Result<Responses> {
+    let mut responses = Responses::new();
+    for record in records {
+        if let Record::Modbus {
+            slave,
+            request,
+            response: Some(response),
+            ..
+        } = record
+        {
+            responses
+                .entry((*slave, from_hex(request)?))
+                .or_default()
+                .0
+                .push(from_hex(response)?);
+        }
+    }
+    if responses.is_empty() {
+        let msg = match records.iter().any(|r| matches!(r, Record::Iec104 { .. })) {
+            true => "Only Modbus sessions can be served, the file has IEC104 records only",
+            false => "No Modbus responses recorded",
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    Ok(responses)
+}
+
+/// Answer Modbus/TCP requests like the recorded device: the recorded responses to the same
+/// request are sent in turn, starting over after the last one.
+///
+/// Only Modbus sessions can be served, IEC104 records are skipped.
+pub async fn serve(listener: TcpListener, records: &[Record]) -> io::Result<()> {
+    let responses = responses(records)?;
+    println!(
+        "-- Replay of {} different requests on {}... Ctrl-C to stop",
+        responses.len(),
+        listener.local_addr()?
+    );
+    let responses = Arc::new(Mutex::new(responses));
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        println!("Client {} connected", peer);
+        let responses = responses.clone();
+        tokio::spawn(async move {
+            while let Ok(adu) = read_mbap(&mut stream).await {
+                let Ok((tid, unit, pdu)) = modbus_pdu::decode_mbap(&adu) else {
+                    break;
+                };
+                let rsp = next_response(&mut responses.lock().unwrap(), unit, pdu);
+                if stream
+                    .write_all(&modbus_pdu::encode_mbap(tid, unit, &rsp))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            println!("Client {} disconnected", peer);
+        });
+    }
+}
+
+fn next_response(responses: &mut Responses, unit: u8, pdu: &[u8]) -> Vec<u8> {
+    // TCP clients often use another unit id than the slave address recorded on a serial line
+    let key = match responses.contains_key(&(unit, pdu.to_vec())) {
+        true => Some((unit, pdu.to_vec())),
+        false => responses.keys().find(|(_, p)| p == pdu).cloned(),
+    };
+    match key.and_then(|k| responses.get_mut(&k)) {
+        Some((list, next)) => {
+            let rsp = list[*next % list.len()].clone();
+            *next += 1;
+            rsp
+        }
+        None => {
+            log::warn!("Request {:02X?} of unit {} was not recorded", pdu, unit);
+            modbus_pdu::exception_pdu(pdu[0], ExceptionCode::IllegalDataAddress)
+        }
+    }
+}
+
+/// Point image rebuilt from recorded IEC104 ASDUs
+#[derive(Debug, Default)]
+pub struct Iec104Replay {
+    handler: IEC104ClientHandler,
+}
+
+impl Iec104Replay {
+    pub fn feed(&self, asdu: &[u8]) -> io::Result<()> {
+        let asdu = Asdu::try_from(Bytes::from(asdu.to_vec()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        // the handler answers nothing but commands, which were answered when recording
+        self.handler
+            .call(asdu)
+            .into_inner()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(())
+    }
+}
+
+impl PointSpace for Iec104Replay {
+    fn handler(&self) -> &IEC104ClientHandler {
+        &self.handler
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn modbus(slave: u8, request: &str, response: &str) -> Record {
+        Record::Modbus {
+            time: 0.0,
+            slave,
+            request: request.to_string(),
+            response: Some(response.to_string()),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0x03, 0x00, 0xAB], ""), "0300AB");
+        assert_eq!(to_hex(&[0x03, 0x00, 0xAB], " "), "03 00 AB");
+        assert_eq!(to_hex(&[], " "), "");
+        assert_eq!(from_hex("0300ab").unwrap(), [0x03, 0x00, 0xAB]);
+        assert!(from_hex("").unwrap().is_empty());
+
+        let e = from_hex("030").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("Odd"));
+        let e = from_hex("03G0").unwrap_err();
+        assert!(e.to_string().contains("Invalid hex digit"));
+        // a multi-byte character is no hex digit either
+        assert!(from_hex("0é").is_err());
+    }
+
+    #[test]
+    fn read_record_file() {
+        let path = std::env::temp_dir().join(format!("dpoll-records-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let recorder = Recorder::create(path).unwrap();
+        let records = [
+            modbus(1, "0300000001", "0302002A"),
+            Record::Modbus {
+                time: 1.5,
+                slave: 2,
+                request: "0300000001".to_string(),
+                response: None,
+                error: Some("Timeout".to_string()),
+            },
+            Record::Iec104 {
+                time: 2.0,
+                sent: false,
+                asdu: "0D01030001000000".to_string(),
+            },
+        ];
+        for r in records.iter() {
+            recorder.write(r);
+        }
+        drop(recorder);
+        // blank lines are skipped
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        writeln!(file).unwrap();
+        drop(file);
+        assert_eq!(read_records(path).unwrap(), records);
+
+        fs::write(path, "\n{\"protocol\":\"modbus\"}\n").unwrap();
+        let e = read_records(path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().starts_with(&format!("{}:2:", path)));
+
+        fs::remove_file(path).unwrap();
+        let e = read_records(path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn responses_cycle() {
+        let records = [
+            modbus(1, "0300000001", "03020001"),
+            modbus(1, "0300000001", "03020002"),
+            modbus(2, "0300000001", "03020009"),
+        ];
+        let mut responses = responses(&records).unwrap();
+        let read = [0x03, 0x00, 0x00, 0x00, 0x01];
+        let next =
+            |responses: &mut Responses, unit| to_hex(&next_response(responses, unit, &read), "");
+
+        assert_eq!(next(&mut responses, 1), "03020001");
+        assert_eq!(next(&mut responses, 1), "03020002");
+        assert_eq!(next(&mut responses, 1), "03020001");
+        assert_eq!(next(&mut responses, 2), "03020009");
+        assert_eq!(next(&mut responses, 2), "03020009");
+
+        // not recorded
+        let write = [0x06, 0x00, 0x01, 0x00, 0x03];
+        assert_eq!(next_response(&mut responses, 1, &write), [0x86, 0x02]);
+    }
+
+    #[test]
+    fn responses_of_another_unit() {
+        let records = [modbus(7, "0300000001", "0302002A")];
+        let mut responses = responses(&records).unwrap();
+        let read = [0x03, 0x00, 0x00, 0x00, 0x01];
+        assert_eq!(
+            next_response(&mut responses, 255, &read),
+            [0x03, 0x02, 0x00, 0x2A]
+        );
+    }
+
+    #[test]
+    fn nothing_to_serve() {
+        let iec104 = Record::Iec104 {
+            time: 0.0,
+            sent: true,
+            asdu: "6401060001000000000014".to_string(),
+        };
+        let e = responses(&[iec104]).unwrap_err();
+        assert!(e.to_string().contains("IEC104"));
+        let failed = Record::Modbus {
+            time: 0.0,
+            slave: 1,
+            request: "0300000001".to_string(),
+            response: None,
+            error: Some("Timeout".to_string()),
+        };
+        assert!(responses(&[failed]).is_err());
+        assert!(responses(&[modbus(1, "0300000001", "030")]).is_err());
+    }
+}
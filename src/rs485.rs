@@ -0,0 +1,357 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Instant, Sleep},
+};
+use tokio_serial::SerialPort;
+
+/// Level of RTS (logical, /RTS pin is 0 when on) while the transmitter is driving the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtsMode {
+    /// RTS on when sending, off after sending (-F)
+    OnSend,
+    /// RTS off when sending, on after sending (-R)
+    AfterSend,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rs485Option {
+    pub mode: RtsMode,
+    /// delay between switching RTS to sending and the first character
+    pub delay_before: Duration,
+    /// delay between the last character on the line and switching RTS back
+    pub delay_after: Duration,
+    /// transmit time of one character on the line
+    pub char_time: Duration,
+}
+
+/// Transmit time of one character: start bit, data bits, parity bit and stop bits
+pub fn char_time(baudrate: u32, databits: u8, parity: bool, stopbits: u8) -> Duration {
+    let bits = 1 + databits as u32 + parity as u32 + stopbits as u32;
+    Duration::from_nanos(bits as u64 * 1_000_000_000 / baudrate.max(1) as u64)
+}
+
+/// Output line for the transmitter direction
+pub trait Rts {
+    fn write_rts(&mut self, level: bool) -> io::Result<()>;
+}
+
+impl<T: SerialPort> Rts for T {
+    fn write_rts(&mut self, level: bool) -> io::Result<()> {
+        Ok(self.write_request_to_send(level)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    DelayBefore,
+    Sending,
+    Draining,
+}
+
+/// Serial port with software RTS direction control for RS-485 adapters without auto-direction.
+///
+/// RTS is switched to sending before the first write of a frame. The frame ends with the flush,
+/// RTS is switched back once all written characters have left the line.
+#[derive(Debug)]
+pub struct Rs485<T> {
+    inner: T,
+    op: Rs485Option,
+    state: State,
+    written: usize,
+    timer: Pin<Box<Sleep>>,
+}
+
+impl<T: Rts> Rs485<T> {
+    pub fn new(mut inner: T, op: Rs485Option) -> io::Result<Self> {
+        inner.write_rts(op.mode == RtsMode::AfterSend)?;
+        Ok(Self {
+            inner,
+            op,
+            state: State::Idle,
+            written: 0,
+            timer: Box::pin(sleep(Duration::ZERO)),
+        })
+    }
+
+    fn set_rts(&mut self, sending: bool) -> io::Result<()> {
+        let level = sending == (self.op.mode == RtsMode::OnSend);
+        self.inner.write_rts(level)
+    }
+
+    /// Wait until the frame is on the line and switch RTS back to receiving
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.timer.as_mut().poll(cx));
+        self.set_rts(false)?;
+        self.written = 0;
+        self.state = State::Idle;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rs485<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Rts + Unpin> AsyncWrite for Rs485<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            match this.state {
+                State::Idle => {
+                    this.set_rts(true)?;
+                    if this.op.delay_before.is_zero() {
+                        this.state = State::Sending;
+                    } else {
+                        let deadline = Instant::now() + this.op.delay_before;
+                        this.timer.as_mut().reset(deadline);
+                        this.state = State::DelayBefore;
+                    }
+                }
+                State::DelayBefore => {
+                    ready!(this.timer.as_mut().poll(cx));
+                    this.state = State::Sending;
+                }
+                State::Sending => {
+                    let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+                    this.written += n;
+                    return Poll::Ready(Ok(n));
+                }
+                // the previous frame is still on the line
+                State::Draining => ready!(this.poll_drain(cx))?,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.state == State::Sending {
+            ready!(Pin::new(&mut this.inner).poll_flush(cx))?;
+            // the driver returns as soon as the data is queued, not when it is on the line
+            let deadline =
+                Instant::now() + this.op.char_time * this.written as u32 + this.op.delay_after;
+            this.timer.as_mut().reset(deadline);
+            this.state = State::Draining;
+        }
+        if this.state == State::Draining {
+            ready!(this.poll_drain(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// struct serial_rs485 of linux/serial.h
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct SerialRs485 {
+    flags: u32,
+    delay_rts_before_send: u32,
+    delay_rts_after_send: u32,
+    padding: [u32; 5],
+}
+
+#[cfg(all(
+    target_os = "linux",
+    not(any(target_arch = "sparc", target_arch = "sparc64"))
+))]
+use libc::TIOCSRS485;
+/// _IOWR('T', 0x42, struct serial_rs485), libc does not export it for sparc
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "sparc", target_arch = "sparc64")
+))]
+const TIOCSRS485: libc::c_ulong = 0xc020_5442;
+#[cfg(target_os = "linux")]
+const SER_RS485_ENABLED: u32 = 1 << 0;
+#[cfg(target_os = "linux")]
+const SER_RS485_RTS_ON_SEND: u32 = 1 << 1;
+#[cfg(target_os = "linux")]
+const SER_RS485_RTS_AFTER_SEND: u32 = 1 << 2;
+
+/// Let the kernel driver switch RTS (TIOCSRS485), delays are in whole milliseconds
+#[cfg(target_os = "linux")]
+pub fn enable_kernel_rs485<P: std::os::fd::AsRawFd>(port: &P, op: &Rs485Option) -> io::Result<()> {
+    let conf = SerialRs485 {
+        flags: SER_RS485_ENABLED
+            | match op.mode {
+                RtsMode::OnSend => SER_RS485_RTS_ON_SEND,
+                RtsMode::AfterSend => SER_RS485_RTS_AFTER_SEND,
+            },
+        delay_rts_before_send: op.delay_before.as_millis() as u32,
+        delay_rts_after_send: op.delay_after.as_millis() as u32,
+        ..Default::default()
+    };
+    // SAFETY: the fd is an open serial port and conf outlives the call
+    let ret = unsafe {
+        libc::ioctl(
+            port.as_raw_fd(),
+            TIOCSRS485 as _,
+            &conf as *const SerialRs485,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_kernel_rs485<P>(_port: &P, _op: &Rs485Option) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "kernel RS-485 mode is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio_modbus::{client::Reader, Slave};
+
+    use super::*;
+    use crate::modbus_ascii;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Event {
+        Rts(bool),
+        Write(usize),
+        Flush,
+        Read,
+    }
+
+    #[derive(Debug)]
+    struct Port {
+        events: Arc<Mutex<Vec<Event>>>,
+        rx: &'static [u8],
+    }
+
+    impl Port {
+        fn push(&self, event: Event) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    impl Rts for Port {
+        fn write_rts(&mut self, level: bool) -> io::Result<()> {
+            self.push(Event::Rts(level));
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Port {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            this.push(Event::Read);
+            let n = this.rx.len().min(buf.remaining());
+            buf.put_slice(&this.rx[..n]);
+            this.rx = &this.rx[n..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for Port {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.push(Event::Write(buf.len()));
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.push(Event::Flush);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Read one holding register with the ASCII client, return the events up to the first read
+    async fn ascii_read(mode: RtsMode) -> Vec<Event> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let port = Port {
+            events: events.clone(),
+            rx: b":010302002AD0\r\n",
+        };
+        let op = Rs485Option {
+            mode,
+            delay_before: Duration::from_millis(1),
+            delay_after: Duration::from_millis(1),
+            char_time: char_time(115200, 7, true, 1),
+        };
+        let mut ctx = modbus_ascii::attach_slave(Rs485::new(port, op).unwrap(), Slave(1));
+        let value = ctx.read_holding_registers(0, 1).await.unwrap().unwrap();
+        assert_eq!(value, [42]);
+        let events = events.lock().unwrap();
+        let first_read = events.iter().position(|e| *e == Event::Read).unwrap();
+        events[..first_read].to_vec()
+    }
+
+    #[tokio::test]
+    async fn ascii_rts_on_send() {
+        assert_eq!(
+            ascii_read(RtsMode::OnSend).await,
+            [
+                Event::Rts(false),
+                Event::Rts(true),
+                Event::Write(b":010300000001FB\r\n".len()),
+                Event::Flush,
+                Event::Rts(false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn ascii_rts_after_send() {
+        assert_eq!(
+            ascii_read(RtsMode::AfterSend).await,
+            [
+                Event::Rts(true),
+                Event::Rts(false),
+                Event::Write(b":010300000001FB\r\n".len()),
+                Event::Flush,
+                Event::Rts(true),
+            ]
+        );
+    }
+
+    #[test]
+    fn char_time_of_8n1() {
+        assert_eq!(
+            char_time(9600, 8, false, 1),
+            Duration::from_nanos(10 * 1_000_000_000 / 9600)
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{error::ChecksumError, modbus_pdu};
+
+/// Wrong CRC of the response to the last request
+pub type CrcError = Arc<Mutex<Option<ChecksumError>>>;
+
+/// Serial transport of an RTU client checking the CRC of every response.
+///
+/// The RTU decoder skips a frame with a wrong CRC and the request ends in a timeout, the
+/// checksum error is kept for the caller to tell the two apart.
+#[derive(Debug)]
+pub struct CrcCheck<T> {
+    inner: T,
+    /// function code of the request waiting for its response
+    function: Option<u8>,
+    sending: bool,
+    rx: Vec<u8>,
+    error: CrcError,
+}
+
+impl<T> CrcCheck<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            function: None,
+            sending: false,
+            rx: Vec::new(),
+            error: CrcError::default(),
+        }
+    }
+
+    /// Cleared when the next request is sent
+    pub fn error(&self) -> CrcError {
+        self.error.clone()
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CrcCheck<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.sending = false;
+        let Some(function) = this.function else {
+            return Poll::Ready(Ok(()));
+        };
+
+        this.rx.extend_from_slice(&buf.filled()[filled..]);
+        if let Some(len) = modbus_pdu::rtu_response_len(function, &this.rx) {
+            if len <= this.rx.len() {
+                let (data, crc) = this.rx[..len].split_at(len - 2);
+                let expected = modbus_pdu::crc16(data);
+                let actual = u16::from_le_bytes([crc[0], crc[1]]);
+                if expected != actual {
+                    *this.error.lock().unwrap() = Some(ChecksumError::Crc { expected, actual });
+                }
+                this.function = None;
+                this.rx.clear();
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CrcCheck<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.sending {
+            this.sending = true;
+            // nobody answers a broadcast
+            this.function = buf.get(1).copied().filter(|_| buf[0] != 0);
+            this.rx.clear();
+            *this.error.lock().unwrap() = None;
+        }
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_flush(cx))?;
+        this.sending = false;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
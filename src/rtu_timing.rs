@@ -0,0 +1,235 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Instant, Sleep},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimingOption {
+    /// silence between the end of the last frame on the line and the next request
+    pub request_delay: Duration,
+    /// longest silence between two characters of a response
+    pub char_timeout: Duration,
+    /// silence after a broadcast request, no slave answers it
+    pub turnaround: Duration,
+    /// transmit time of one character on the line
+    pub char_time: Duration,
+}
+
+impl TimingOption {
+    /// t3.5 and t1.5 of the Modbus serial line spec, fixed to 1.75 ms and 0.75 ms above 19200 baud
+    pub fn rtu(baudrate: u32, char_time: Duration, turnaround: Duration) -> Self {
+        let (request_delay, char_timeout) = if baudrate > 19200 {
+            (Duration::from_micros(1750), Duration::from_micros(750))
+        } else {
+            (char_time * 7 / 2, char_time * 3 / 2)
+        };
+        Self {
+            request_delay,
+            char_timeout,
+            turnaround,
+            char_time,
+        }
+    }
+
+    /// ASCII frames have no silent interval, characters may be up to 1 s apart
+    pub fn ascii(char_time: Duration, turnaround: Duration) -> Self {
+        Self {
+            request_delay: Duration::ZERO,
+            char_timeout: Duration::from_secs(1),
+            turnaround,
+            char_time,
+        }
+    }
+}
+
+/// Serial transport keeping the silent intervals between frames and checking the gaps
+/// between the characters of a response.
+///
+/// A frame is the data written up to the next flush or the first character received.
+#[derive(Debug)]
+pub struct RtuTiming<T> {
+    inner: T,
+    op: TimingOption,
+    /// the line is silent from here on, end of the last frame sent or last character received
+    idle_since: Instant,
+    broadcast: bool,
+    sending: bool,
+    waiting: bool,
+    written: usize,
+    last_rx: Option<Instant>,
+    timer: Pin<Box<Sleep>>,
+}
+
+impl<T> RtuTiming<T> {
+    pub fn new(inner: T, op: TimingOption) -> Self {
+        Self {
+            inner,
+            op,
+            idle_since: Instant::now(),
+            broadcast: false,
+            sending: false,
+            waiting: false,
+            written: 0,
+            last_rx: None,
+            timer: Box::pin(sleep(Duration::ZERO)),
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for RtuTiming<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let n = buf.filled().len() - filled;
+        if n == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let now = Instant::now();
+        // a response, the request ended even if it was never flushed
+        this.sending = false;
+        if let Some(last_rx) = this.last_rx {
+            // the driver may hand over several characters at once, they took their time on the line
+            let gap = now.saturating_duration_since(last_rx);
+            if gap > this.op.char_timeout + this.op.char_time * n as u32 {
+                this.last_rx = None;
+                this.idle_since = now;
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Inter-character timeout: {:.2} ms silence within the response, {:.2} ms allowed",
+                        (gap - this.op.char_time * n as u32).as_secs_f32() * 1000.0,
+                        this.op.char_timeout.as_secs_f32() * 1000.0
+                    ),
+                )));
+            }
+        }
+        this.last_rx = Some(now);
+        this.idle_since = now;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for RtuTiming<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.sending {
+            if !this.waiting {
+                let delay = if this.broadcast {
+                    this.op.turnaround
+                } else {
+                    this.op.request_delay
+                };
+                this.timer.as_mut().reset(this.idle_since + delay);
+                this.waiting = true;
+            }
+            ready!(this.timer.as_mut().poll(cx));
+            this.waiting = false;
+            this.sending = true;
+            this.written = 0;
+            this.last_rx = None;
+            this.broadcast = matches!(buf, [0, ..] | [b':', b'0', b'0', ..]);
+        }
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.written += n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_flush(cx))?;
+        if this.sending {
+            this.sending = false;
+            this.idle_since = Instant::now() + this.op.char_time * this.written as u32;
+            this.last_rx = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const OP: TimingOption = TimingOption {
+        request_delay: Duration::from_millis(30),
+        char_timeout: Duration::from_millis(20),
+        turnaround: Duration::from_millis(60),
+        char_time: Duration::ZERO,
+    };
+
+    #[tokio::test]
+    async fn frames_without_flush() {
+        let (port, mut peer) = duplex(64);
+        let mut port = RtuTiming::new(port, OP);
+        let mut buf = [0; 8];
+
+        port.write_all(b":0103\r\n").await.unwrap();
+        peer.read_exact(&mut buf[..7]).await.unwrap();
+        peer.write_all(b":0183\r\n").await.unwrap();
+        port.read_exact(&mut buf[..7]).await.unwrap();
+
+        // the request delay counts from the last character received
+        let start = Instant::now();
+        port.write_all(b":0103\r\n").await.unwrap();
+        assert!(start.elapsed() >= OP.request_delay - Duration::from_millis(1));
+
+        // silence before the response is not an inter-character gap
+        peer.read_exact(&mut buf[..7]).await.unwrap();
+        sleep(OP.char_timeout * 2).await;
+        peer.write_all(b":0183\r\n").await.unwrap();
+        port.read_exact(&mut buf[..7]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn inter_character_timeout() {
+        let (port, mut peer) = duplex(64);
+        let mut port = RtuTiming::new(port, OP);
+        let mut buf = [0; 8];
+
+        port.write_all(&[1, 3]).await.unwrap();
+        port.flush().await.unwrap();
+        peer.write_all(&[1]).await.unwrap();
+        port.read_exact(&mut buf[..1]).await.unwrap();
+        sleep(OP.char_timeout * 2).await;
+        peer.write_all(&[3]).await.unwrap();
+        let e = port.read_exact(&mut buf[..1]).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn turnaround_after_broadcast() {
+        let (port, _peer) = duplex(64);
+        let mut port = RtuTiming::new(port, OP);
+
+        port.write_all(&[0, 6, 0, 1, 0, 1]).await.unwrap();
+        port.flush().await.unwrap();
+        let start = Instant::now();
+        port.write_all(&[1, 3]).await.unwrap();
+        port.flush().await.unwrap();
+        assert!(start.elapsed() >= OP.turnaround - Duration::from_millis(1));
+    }
+}
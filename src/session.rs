@@ -0,0 +1,854 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_modbus::{
+    client::{rtu, rtu_over_tcp, tcp, Context, Reader, Writer},
+    Slave, SlaveContext,
+};
+use tokio_serial::{SerialPortBuilder, SerialStream};
+
+use crate::{
+    codec::{self, Value},
+    error::Error,
+    iec101_client::IEC101Client,
+    iec104_client::{CommandOutcome, IEC104Client, Point, PointSpace, ReadOutcome},
+    modbus_ascii,
+    modbus_tls::{self, TlsConfig},
+    modbus_udp,
+    record::{self, Recorder},
+    rs485::{self, Rs485, Rs485Option},
+    rtu_crc::{CrcCheck, CrcError},
+    rtu_timing::{RtuTiming, TimingOption},
+    stats::{Outcome, PollStats},
+    Formats, Functions,
+};
+
+/// Values of consecutive references
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
+    /// coils or discrete inputs
+    Bits(Vec<bool>),
+    /// holding or input registers
+    Words(Vec<u16>),
+    /// IEC104 information objects, none until received
+    Points(Vec<Option<Point>>),
+}
+
+impl Data {
+    /// Registers, bits as 0 and 1
+    pub fn into_words(self) -> Vec<u16> {
+        match self {
+            Data::Bits(v) => v.into_iter().map(u16::from).collect(),
+            Data::Words(v) => v,
+            Data::Points(_) => vec![],
+        }
+    }
+
+    /// Information objects, none for Modbus data
+    pub fn into_points(self) -> Vec<Option<Point>> {
+        match self {
+            Data::Points(v) => v,
+            _ => vec![],
+        }
+    }
+}
+
+/// Connection to a device, the same for every protocol, failed requests return an [`Error`]
+#[async_trait]
+pub trait Session: Send {
+    /// Open the connection, or open it again after it failed
+    async fn connect(&mut self) -> Result<(), Error>;
+
+    /// Read `count` values of the table `function` from `addr` on, `unit` is the Modbus slave
+    async fn read(
+        &mut self,
+        unit: u8,
+        function: Functions,
+        addr: u16,
+        count: u16,
+    ) -> Result<Data, Error>;
+
+    /// Write consecutive values from `addr` on
+    async fn write(
+        &mut self,
+        unit: u8,
+        function: Functions,
+        addr: u16,
+        data: Data,
+    ) -> Result<(), Error>;
+
+    /// Read `count` values of `format`, coils and discrete inputs as [`Value::Bool`]
+    async fn read_values(
+        &mut self,
+        unit: u8,
+        function: Functions,
+        addr: u16,
+        count: u16,
+        format: &Formats,
+        little_endian: bool,
+    ) -> Result<Vec<Value>, Error> {
+        let words = match function {
+            Functions::Coil | Functions::DiscreteInput => 1,
+            _ => codec::word_count(format) as u16,
+        };
+        let Some(n) = count.checked_mul(words) else {
+            return Err(Error::invalid_input(format!(
+                "{} values of {:?} are more than 65535 registers",
+                count, format
+            )));
+        };
+        match self.read(unit, function.clone(), addr, n).await? {
+            Data::Bits(v) => Ok(v.into_iter().map(Value::Bool).collect()),
+            Data::Words(v) => codec::decode_all(&v, format, little_endian).map_err(|e| {
+                Error::Transport(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            }),
+            Data::Points(_) => Err(Error::invalid_input(format!(
+                "{:?} has no register values",
+                function
+            ))),
+        }
+    }
+}
+
+/// Delays between connect attempts, doubled after every failed attempt up to `max`. Each delay
+/// is varied by up to a quarter at random, so that clients which lost the same device don't all
+/// reconnect at once.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        // std seeds the keys once per thread and bumps them for every RandomState, so each
+        // delay hashes to a different value, not a strong random one but enough for jitter
+        let random = RandomState::new().build_hasher().finish() % 1000;
+        delay.mul_f64(0.75 + random as f64 / 2000.0)
+    }
+
+    /// Start over with `initial`, e.g. after connecting
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(30))
+    }
+}
+
+pub trait SerialIo: AsyncRead + AsyncWrite + fmt::Debug + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + fmt::Debug + Unpin + Send> SerialIo for T {}
+
+#[derive(Debug, Clone)]
+pub struct SerialOption {
+    pub builder: SerialPortBuilder,
+    /// Modbus ASCII instead of RTU
+    pub ascii: bool,
+    pub rs485: Option<Rs485Option>,
+    /// let the driver switch RTS instead of [`Rs485`]
+    pub rs485_kernel: bool,
+    pub timing: TimingOption,
+}
+
+/// Serial port with the RS-485 direction control and silent intervals of `op`
+pub fn serial_transport(
+    port: SerialStream,
+    op: &SerialOption,
+) -> io::Result<RtuTiming<Box<dyn SerialIo>>> {
+    let port: Box<dyn SerialIo> = match op.rs485 {
+        Some(rs485) if op.rs485_kernel => {
+            rs485::enable_kernel_rs485(&port, &rs485)?;
+            Box::new(port)
+        }
+        Some(rs485) => Box::new(Rs485::new(port, rs485)?),
+        None => Box::new(port),
+    };
+    Ok(RtuTiming::new(port, op.timing))
+}
+
+#[derive(Debug, Clone)]
+pub enum Transport {
+    Tcp(SocketAddr),
+    Tls(SocketAddr, TlsConfig),
+    Udp { addr: SocketAddr, retries: usize },
+    RtuInTcp(SocketAddr),
+    Serial(SerialOption),
+}
+
+/// Modbus client over any of the transports
+#[derive(Debug)]
+pub struct ModbusSession {
+    transport: Transport,
+    timeout: Duration,
+    ctx: Option<Context>,
+    recorder: Option<Arc<Recorder>>,
+    tls_peer: Option<(String, String)>,
+    stats: Arc<Mutex<PollStats>>,
+    crc_error: Option<CrcError>,
+}
+
+impl ModbusSession {
+    /// `timeout` for each request, UDP waits that long for every retry
+    pub fn new(transport: Transport, timeout: Duration) -> Self {
+        ModbusSession {
+            transport,
+            timeout,
+            ctx: None,
+            recorder: None,
+            tls_peer: None,
+            stats: Arc::new(Mutex::new(PollStats::default())),
+            crc_error: None,
+        }
+    }
+
+    /// Shared request statistics, updated for as long as the session is used
+    pub fn stats(&self) -> Arc<Mutex<PollStats>> {
+        self.stats.clone()
+    }
+
+    /// Count the requests in `stats` instead
+    pub fn set_stats(&mut self, stats: Arc<Mutex<PollStats>>) {
+        self.stats = stats;
+    }
+
+    /// Record every request with its response, takes effect on the next connect
+    pub fn set_recorder(&mut self, recorder: Arc<Recorder>) {
+        self.recorder = Some(recorder);
+    }
+
+    /// TLS version and certificate subject of the device after connecting with TLS
+    pub fn tls_peer(&self) -> Option<&(String, String)> {
+        self.tls_peer.as_ref()
+    }
+
+    /// False before connecting and once the transport broke, requests fail until connect
+    pub fn is_connected(&self) -> bool {
+        self.ctx.is_some()
+    }
+
+    /// Drop the connection if `rs` failed because it broke
+    fn check<T>(&mut self, rs: Result<T, Error>) -> Result<T, Error> {
+        let broken = match &rs {
+            // wrong CRCs, garbled frames and rejected requests don't break the connection
+            Err(Error::Transport(e)) => !matches!(
+                e.kind(),
+                io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput
+            ),
+            _ => false,
+        };
+        if broken && self.ctx.take().is_some() {
+            self.stats.lock().unwrap().disconnects += 1;
+        }
+        rs
+    }
+
+    async fn open(&mut self, slave: Slave) -> io::Result<Context> {
+        let ctx = match &self.transport {
+            Transport::Tcp(addr) => tcp::connect(*addr).await?,
+            Transport::Tls(addr, op) => {
+                let stream = modbus_tls::connect(*addr, op).await?;
+                self.tls_peer = Some(modbus_tls::describe(&stream));
+                tcp::attach(stream)
+            }
+            Transport::Udp { addr, retries } => {
+                modbus_udp::connect_slave(*addr, slave, self.timeout, *retries).await?
+            }
+            Transport::RtuInTcp(addr) => rtu_over_tcp::connect_slave(*addr, slave).await?,
+            Transport::Serial(op) => {
+                let transport = serial_transport(SerialStream::open(&op.builder)?, op)?;
+                match op.ascii {
+                    true => modbus_ascii::attach_slave(transport, slave),
+                    false => self.attach_rtu(transport, slave),
+                }
+            }
+        };
+        Ok(ctx)
+    }
+
+    /// RTU client on `transport`, checking the CRC of the responses
+    fn attach_rtu(&mut self, transport: impl SerialIo + 'static, slave: Slave) -> Context {
+        let transport = CrcCheck::new(transport);
+        self.crc_error = Some(transport.error());
+        rtu::attach_slave(transport, slave)
+    }
+
+    /// Time a request may take with all of its retries
+    fn request_timeout(&self) -> Duration {
+        match self.transport {
+            Transport::Udp { retries, .. } => self.timeout * (retries as u32 + 1),
+            _ => self.timeout,
+        }
+    }
+}
+
+fn context(ctx: &mut Option<Context>, unit: u8) -> Result<&mut Context, Error> {
+    let ctx = ctx.as_mut().ok_or_else(|| {
+        Error::Transport(io::Error::new(io::ErrorKind::NotConnected, "Not connected"))
+    })?;
+    ctx.set_slave(Slave(unit));
+    Ok(ctx)
+}
+
+/// Requests to one unit, each counted in the statistics by its function code
+struct Counter<'a> {
+    stats: &'a Mutex<PollStats>,
+    unit: u8,
+    timeout: Duration,
+    crc_error: Option<&'a CrcError>,
+}
+
+impl Counter<'_> {
+    async fn call<T, F>(&self, function: u8, f: F) -> Result<T, Error>
+    where
+        F: Future<Output = tokio_modbus::Result<T>>,
+    {
+        self.stats.lock().unwrap().request(self.unit, function);
+        let rs = match tokio::time::timeout(self.timeout, f).await {
+            Ok(Ok(Ok(v))) => Ok(v),
+            Ok(Ok(Err(code))) => Err(Error::Exception(code)),
+            Ok(Err(e)) => Err(Error::from(e)),
+            // the RTU decoder skips a response with a wrong CRC, waiting for the right one
+            Err(_) => match self.crc_error.and_then(|e| e.lock().unwrap().take()) {
+                Some(e) => Err(Error::Transport(e.into())),
+                None => Err(Error::Timeout),
+            },
+        };
+        let outcome = match &rs {
+            Ok(_) => Outcome::Response,
+            Err(e) => Outcome::of_error(e),
+        };
+        self.stats
+            .lock()
+            .unwrap()
+            .outcome(self.unit, function, outcome);
+        rs
+    }
+}
+
+#[async_trait]
+impl Session for ModbusSession {
+    async fn connect(&mut self) -> Result<(), Error> {
+        // the slave is set again before every request
+        let slave = Slave(1);
+        self.ctx = None;
+        let ctx = match self.open(slave).await {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                self.stats.lock().unwrap().connect_errors += 1;
+                return Err(Error::Transport(e));
+            }
+        };
+        self.stats.lock().unwrap().connections += 1;
+        self.ctx = Some(match &self.recorder {
+            Some(recorder) => record::recording(ctx, slave, recorder.clone()),
+            None => ctx,
+        });
+        Ok(())
+    }
+
+    async fn read(
+        &mut self,
+        unit: u8,
+        function: Functions,
+        addr: u16,
+        count: u16,
+    ) -> Result<Data, Error> {
+        let rs = self.read_data(unit, function, addr, count).await;
+        self.check(rs)
+    }
+
+    async fn write(
+        &mut self,
+        unit: u8,
+        function: Functions,
+        addr: u16,
+        data: Data,
+    ) -> Result<(), Error> {
+        let rs = self.write_data(unit, function, addr, data).await;
+        self.check(rs)
+    }
+}
+
+impl ModbusSession {
+    async fn read_data(
+        &mut self,
+        unit: u8,
+        function: Functions,
+        addr: u16,
+        count: u16,
+    ) -> Result<Data, Error> {
+        let counter = Counter {
+            stats: &self.stats,
+            unit,
+            timeout: self.request_timeout(),
+            crc_error: self.crc_error.as_ref(),
+        };
+        let ctx = context(&mut self.ctx, unit)?;
+        let data = match function {
+            Functions::Coil => Data::Bits(counter.call(0x01, ctx.read_coils(addr, count)).await?),
+            Functions::DiscreteInput => Data::Bits(
+                counter
+                    .call(0x02, ctx.read_discrete_inputs(addr, count))
+                    .await?,
+            ),
+            Functions::HoldingRegister => Data::Words(
+                counter
+                    .call(0x03, ctx.read_holding_registers(addr, count))
+                    .await?,
+            ),
+            Functions::InputRegister => Data::Words(
+                counter
+                    .call(0x04, ctx.read_input_registers(addr, count))
+                    .await?,
+            ),
+            _ => {
+                return Err(Error::invalid_input(format!(
+                    "{:?} is not a Modbus table",
+                    function
+                )))
+            }
+        };
+        Ok(data)
+    }
+
+    async fn write_data(
+        &mut self,
+        unit: u8,
+        function: Functions,
+        addr: u16,
+        data: Data,
+    ) -> Result<(), Error> {
+        let counter = Counter {
+            stats: &self.stats,
+            unit,
+            timeout: self.request_timeout(),
+            crc_error: self.crc_error.as_ref(),
+        };
+        let ctx = context(&mut self.ctx, unit)?;
+        match data {
+            Data::Bits(v) if v.len() == 1 => {
+                counter.call(0x05, ctx.write_single_coil(addr, v[0])).await
+            }
+            Data::Bits(v) => counter.call(0x0F, ctx.write_multiple_coils(addr, &v)).await,
+            Data::Words(v) if v.len() == 1 => {
+                counter
+                    .call(0x06, ctx.write_single_register(addr, v[0]))
+                    .await
+            }
+            Data::Words(v) => {
+                counter
+                    .call(0x10, ctx.write_multiple_registers(addr, &v))
+                    .await
+            }
+            Data::Points(_) => Err(Error::invalid_input(format!(
+                "{:?} is not a Modbus table",
+                function
+            ))),
+        }
+    }
+}
+
+/// Points of type `function` from `addr` on, none until they were received
+fn read_points(
+    space: &impl PointSpace,
+    function: Functions,
+    addr: u16,
+    count: u16,
+) -> Result<Data, Error> {
+    if matches!(
+        function,
+        Functions::Coil
+            | Functions::DiscreteInput
+            | Functions::HoldingRegister
+            | Functions::InputRegister
+            | Functions::All
+    ) {
+        return Err(Error::invalid_input(format!(
+            "{:?} is not a type of IEC104 points",
+            function
+        )));
+    }
+    if addr as usize + count as usize > 0x10000 {
+        return Err(Error::invalid_input(format!(
+            "Address {} with {} points is beyond 65535",
+            addr, count
+        )));
+    }
+    let points = (0..count)
+        .map(|i| space.point(&function, addr + i))
+        .collect();
+    Ok(Data::Points(points))
+}
+
+/// Send `points` as commands from `addr` on, up to the first one that fails
+async fn write_points<F, Fut>(addr: u16, data: Data, write: F) -> Result<(), Error>
+where
+    F: Fn(u16, Point) -> Fut,
+    Fut: Future<Output = Result<CommandOutcome, Error>>,
+{
+    let Data::Points(points) = data else {
+        return Err(Error::invalid_input("IEC104 commands need points"));
+    };
+    if addr as usize + points.len() > 0x10000 {
+        return Err(Error::invalid_input(format!(
+            "Address {} with {} points is beyond 65535",
+            addr,
+            points.len()
+        )));
+    }
+    for (i, point) in points.into_iter().enumerate() {
+        let Some(point) = point else {
+            continue;
+        };
+        let addr = addr + i as u16;
+        let outcome = write(addr, point).await?;
+        match outcome.error(addr) {
+            Some(e) => {
+                log::warn!("Command {}: {}", addr, outcome);
+                return Err(e);
+            }
+            None if outcome == CommandOutcome::Confirmed => {
+                log::warn!("Command {}: {}", addr, outcome)
+            }
+            None => log::info!("Command {}: {}", addr, outcome),
+        }
+    }
+    Ok(())
+}
+
+/// The data image is filled by a general interrogation, reads return what was received so far.
+///
+/// With [`IEC104Client::set_read_on_demand`] every point is read with C_RD_NA_1 instead, until
+/// the outstation rejects a read or doesn't answer it.
+#[async_trait]
+impl Session for IEC104Client {
+    async fn connect(&mut self) -> Result<(), Error> {
+        self.stop().await;
+        match self.read_on_demand() {
+            true => self.start().await,
+            false => self.start_interrogation().await,
+        }
+        .map_err(Error::from)?;
+        Ok(())
+    }
+
+    async fn read(
+        &mut self,
+        _unit: u8,
+        function: Functions,
+        addr: u16,
+        count: u16,
+    ) -> Result<Data, Error> {
+        // checks the addresses before anything is sent
+        read_points(self, function.clone(), addr, count)?;
+        if self.read_on_demand() {
+            for ad in (0..count).map(|i| addr + i) {
+                match self.read_point(ad).await.map_err(Error::from)? {
+                    ReadOutcome::Replied => continue,
+                    ReadOutcome::Rejected => {
+                        log::warn!("Read {} rejected, fall back to interrogation", ad);
+                    }
+                    // the outstation may not support C_RD_NA_1 at all
+                    ReadOutcome::Timeout => {
+                        log::warn!("Read {} timeout, fall back to interrogation", ad);
+                        self.set_read_on_demand(false);
+                        self.connect().await?;
+                        return Err(Error::Timeout);
+                    }
+                }
+                self.set_read_on_demand(false);
+                self.connect().await?;
+                break;
+            }
+        }
+        read_points(self, function, addr, count)
+    }
+
+    async fn write(
+        &mut self,
+        _unit: u8,
+        _function: Functions,
+        addr: u16,
+        data: Data,
+    ) -> Result<(), Error> {
+        let client = &*self;
+        write_points(addr, data, |addr, point| client.write_point(addr, point)).await
+    }
+}
+
+/// Like [`IEC104Client`] with interrogation, the link layer keeps resetting the link by itself,
+/// so the serial port is opened on the first connect only
+#[async_trait]
+impl Session for IEC101Client {
+    async fn connect(&mut self) -> Result<(), Error> {
+        if self.is_started() {
+            return Ok(());
+        }
+        let Some(builder) = self.serial() else {
+            return Err(Error::invalid_input("No serial port to connect to"));
+        };
+        let port = SerialStream::open(builder).map_err(|e| Error::Transport(e.into()))?;
+        self.start(port);
+        Ok(())
+    }
+
+    async fn read(
+        &mut self,
+        _unit: u8,
+        function: Functions,
+        addr: u16,
+        count: u16,
+    ) -> Result<Data, Error> {
+        read_points(self, function, addr, count)
+    }
+
+    async fn write(
+        &mut self,
+        _unit: u8,
+        _function: Functions,
+        addr: u16,
+        data: Data,
+    ) -> Result<(), Error> {
+        let client = &*self;
+        write_points(addr, data, |addr, point| client.write_point(addr, point)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::iec104_client::IEC104ClientHandler;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        for expected in [1, 2, 4, 5, 5] {
+            let expected = Duration::from_secs(expected);
+            let delay = backoff.next_delay();
+            assert!(delay >= expected.mul_f64(0.75), "{:?}", delay);
+            assert!(delay <= expected.mul_f64(1.25), "{:?}", delay);
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(1250));
+    }
+
+    #[test]
+    fn data_conversions() {
+        assert_eq!(Data::Bits(vec![true, false]).into_words(), [1, 0]);
+        assert_eq!(Data::Words(vec![7, 8]).into_words(), [7, 8]);
+        assert!(Data::Points(vec![None]).into_words().is_empty());
+        assert_eq!(
+            Data::Points(vec![Some(Point::Nva(3)), None]).into_points(),
+            [Some(Point::Nva(3)), None]
+        );
+        assert!(Data::Words(vec![1]).into_points().is_empty());
+    }
+
+    #[tokio::test]
+    async fn broken_transport_drops_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut session = ModbusSession::new(Transport::Tcp(addr), Duration::from_secs(1));
+        session.connect().await.unwrap();
+        assert!(session.is_connected());
+
+        let garbled = io::Error::new(io::ErrorKind::InvalidData, "bad CRC");
+        for e in [
+            Error::Transport(garbled),
+            Error::invalid_input("Too many registers"),
+            Error::Timeout,
+            Error::Exception(tokio_modbus::ExceptionCode::IllegalDataAddress),
+        ] {
+            assert!(session.check::<()>(Err(e)).is_err());
+            assert!(session.is_connected());
+        }
+
+        for _ in 0..2 {
+            let broken = io::Error::new(io::ErrorKind::BrokenPipe, "closed");
+            assert!(session.check::<()>(Err(Error::Transport(broken))).is_err());
+            assert!(!session.is_connected());
+        }
+        let stats = session.stats();
+        let stats = stats.lock().unwrap();
+        assert_eq!((stats.connections, stats.disconnects), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn wrong_crc_of_rtu_responses() {
+        use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+        use crate::{error::ChecksumError, modbus_pdu};
+
+        let op = SerialOption {
+            builder: tokio_serial::new("/dev/null", 9600),
+            ascii: false,
+            rs485: None,
+            rs485_kernel: false,
+            timing: TimingOption::rtu(9600, Duration::ZERO, Duration::ZERO),
+        };
+        let mut session = ModbusSession::new(Transport::Serial(op), Duration::from_millis(200));
+        let (port, mut slave) = duplex(256);
+        session.ctx = Some(session.attach_rtu(port, Slave(1)));
+
+        let answer = tokio::spawn(async move {
+            let mut request = [0; 8];
+            for crc in [0x1234, 0] {
+                slave.read_exact(&mut request).await.unwrap();
+                let mut adu = modbus_pdu::encode_rtu(1, &[0x03, 0x02, 0x00, 0x2A]);
+                let len = adu.len();
+                adu[len - 2] ^= (crc >> 8) as u8;
+                adu[len - 1] ^= crc as u8;
+                slave.write_all(&adu).await.unwrap();
+            }
+        });
+
+        let e = session
+            .read(1, Functions::HoldingRegister, 0, 1)
+            .await
+            .unwrap_err();
+        let Error::Transport(e) = e else {
+            panic!("{:?}", e);
+        };
+        assert!(ChecksumError::is_source_of(&e), "{:?}", e);
+        // a garbled frame does not break the line
+        assert!(session.is_connected());
+
+        let data = session
+            .read(1, Functions::HoldingRegister, 0, 1)
+            .await
+            .unwrap();
+        assert_eq!(data, Data::Words(vec![42]));
+        answer.await.unwrap();
+
+        let stats = session.stats();
+        let stats = stats.lock().unwrap();
+        let function = &stats.device(1).unwrap().functions[&0x03];
+        assert_eq!(function.checksum_errors, 1);
+        assert_eq!(function.received, 1);
+        assert_eq!(stats.disconnects, 0);
+    }
+
+    /// Answers every read with `data`
+    struct Fixed(Data);
+
+    #[async_trait]
+    impl Session for Fixed {
+        async fn connect(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn read(&mut self, _: u8, _: Functions, _: u16, count: u16) -> Result<Data, Error> {
+            match &self.0 {
+                Data::Words(v) if v.len() != count as usize => Err(Error::Timeout),
+                data => Ok(data.clone()),
+            }
+        }
+
+        async fn write(&mut self, _: u8, _: Functions, _: u16, _: Data) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn read_typed_values() {
+        let mut session = Fixed(Data::Words(vec![0x3FC0, 0, 0xBFC0, 0]));
+        let values = session
+            .read_values(1, Functions::HoldingRegister, 0, 2, &Formats::F32, false)
+            .await
+            .unwrap();
+        assert_eq!(values, [Value::F32(1.5), Value::F32(-1.5)]);
+        let values = session
+            .read_values(1, Functions::InputRegister, 0, 4, &Formats::I16, false)
+            .await
+            .unwrap();
+        assert_eq!(values[2], Value::I16(-16448));
+        assert!(session
+            .read_values(
+                1,
+                Functions::HoldingRegister,
+                0,
+                40000,
+                &Formats::U32,
+                false
+            )
+            .await
+            .is_err());
+
+        let mut session = Fixed(Data::Bits(vec![true, false]));
+        let values = session
+            .read_values(1, Functions::Coil, 0, 2, &Formats::U32, false)
+            .await
+            .unwrap();
+        assert_eq!(values, [Value::Bool(true), Value::Bool(false)]);
+    }
+
+    struct Space(IEC104ClientHandler);
+
+    impl PointSpace for Space {
+        fn handler(&self) -> &IEC104ClientHandler {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn points_up_to_the_last_address() {
+        let space = Space(IEC104ClientHandler::new());
+        let data = read_points(&space, Functions::Nva, 65534, 2).unwrap();
+        assert_eq!(data, Data::Points(vec![None, None]));
+        assert!(read_points(&space, Functions::Nva, 65535, 2).is_err());
+        assert!(read_points(&space, Functions::HoldingRegister, 0, 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn commands_up_to_the_last_address() {
+        let sent = Mutex::new(vec![]);
+        let write = |addr, _| {
+            sent.lock().unwrap().push(addr);
+            async { Ok(CommandOutcome::Executed) }
+        };
+        let points = Data::Points(vec![Some(Point::Siq(true)), None, Some(Point::Siq(false))]);
+        write_points(65533, points.clone(), write).await.unwrap();
+        assert_eq!(*sent.lock().unwrap(), [65533, 65535]);
+
+        // nothing is sent when the last point is beyond 65535
+        assert!(write_points(65534, points, write).await.is_err());
+        assert_eq!(sent.lock().unwrap().len(), 2);
+
+        let rejected = |addr, _| async move {
+            Ok(match addr {
+                1 => CommandOutcome::Rejected(crate::iec104_client::CommandPhase::Select),
+                _ => CommandOutcome::Confirmed,
+            })
+        };
+        let points = Data::Points(vec![Some(Point::Nva(1)); 3]);
+        let e = write_points(0, points, rejected).await.unwrap_err();
+        assert!(matches!(e, Error::Rejected { addr: 1, .. }));
+    }
+}
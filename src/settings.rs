@@ -0,0 +1,309 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{parse_timeout, Args};
+
+/// Settings taken from the layers below the command line, by their `Args` field
+pub const SETTINGS: [&str; 9] = [
+    "conf",
+    "port",
+    "timeout",
+    "poll_rate",
+    "retries",
+    "baudrate",
+    "databits",
+    "stopbits",
+    "parity",
+];
+
+/// Layer a setting came from, a layer overrides the ones before it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    /// built into dpoll
+    #[default]
+    Default,
+    /// the user configuration file
+    File,
+    /// a `DPOLL_*` environment variable
+    Env,
+    /// the entry of NAME in the configuration file (--conf)
+    Device,
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File => write!(f, "config file"),
+            Source::Env => write!(f, "environment"),
+            Source::Device => write!(f, "device"),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// `$DPOLL_CONFIG`, otherwise `config.toml` in `$XDG_CONFIG_HOME/dpoll` or `~/.config/dpoll`
+pub fn user_config_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("DPOLL_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("dpoll").join("config.toml"))
+}
+
+/// Environment variable of `setting`, for example `DPOLL_POLL_RATE`
+pub fn env_name(setting: &str) -> String {
+    format!("DPOLL_{}", setting.to_uppercase())
+}
+
+fn set(args: &mut Args, setting: &str, value: &str) -> Result<()> {
+    match setting {
+        "conf" => args.conf = Some(value.to_string()),
+        "port" => args.port = Some(value.parse()?),
+        "timeout" => args.timeout = Some(parse_timeout(value)?),
+        "poll_rate" => args.poll_rate = Some(value.parse()?),
+        "retries" => args.retries = Some(value.parse()?),
+        "baudrate" => args.baudrate = Some(value.parse()?),
+        "databits" => args.databits = Some(value.parse()?),
+        "stopbits" => args.stopbits = Some(value.parse()?),
+        "parity" => args.parity = Some(value.to_string()),
+        _ => Err(anyhow::anyhow!("unknown setting {}", setting))?,
+    }
+    Ok(())
+}
+
+fn set_from(args: &mut Args, setting: &'static str, value: &str, source: Source) -> Result<()> {
+    if args.source(setting) <= source {
+        set(args, setting, value)?;
+        args.sources.insert(setting, source);
+    }
+    Ok(())
+}
+
+/// The user configuration file and its contents, `None` if there is none
+pub fn read_user_config() -> Result<Option<(PathBuf, String)>> {
+    let Some(path) = user_config_path() else {
+        return Ok(None);
+    };
+    match fs::read_to_string(&path) {
+        Ok(s) => Ok(Some((path, s))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Apply the user configuration file and the `DPOLL_*` environment variables looked up by `env`
+/// to the settings not given on the command line
+pub fn apply(
+    args: &mut Args,
+    file: Option<(&Path, &str)>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+    if let Some((path, s)) = file {
+        let file: BTreeMap<String, serde_json::Value> =
+            toml::from_str(s).with_context(|| format!("Invalid {}", path.display()))?;
+        for (key, value) in file.iter() {
+            let setting = SETTINGS
+                .iter()
+                .find(|s| **s == key)
+                .with_context(|| format!("Unknown setting {} in {}", key, path.display()))?;
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                v => v.to_string(),
+            };
+            set_from(args, setting, &value, Source::File)
+                .with_context(|| format!("Invalid {} in {}", key, path.display()))?;
+        }
+    }
+
+    for setting in SETTINGS {
+        if let Some(value) = env(&env_name(setting)) {
+            set_from(args, setting, &value, Source::Env)
+                .with_context(|| format!("Invalid {}", env_name(setting)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Take `value` of the device entry unless `setting` was given on the command line
+pub fn device<T>(
+    sources: &mut BTreeMap<&'static str, Source>,
+    setting: &'static str,
+    field: &mut Option<T>,
+    value: Option<T>,
+) {
+    if value.is_some() && sources.get(setting).copied().unwrap_or_default() <= Source::Device {
+        *field = value;
+        sources.insert(setting, Source::Device);
+    }
+}
+
+fn value(args: &Args, setting: &str) -> String {
+    match setting {
+        "conf" => format!("{:?}", args.conf.clone().unwrap_or_default()),
+        "port" => args.port.map(|v| v.to_string()).unwrap_or_default(),
+        "timeout" => args
+            .timeout
+            .map(|v| format!("{:.2}", v.as_secs_f32()))
+            .unwrap_or_default(),
+        "poll_rate" => args.poll_rate.map(|v| v.to_string()).unwrap_or_default(),
+        "retries" => args.retries.map(|v| v.to_string()).unwrap_or_default(),
+        "baudrate" => args.baudrate.map(|v| v.to_string()).unwrap_or_default(),
+        "databits" => args.databits.map(|v| v.to_string()).unwrap_or_default(),
+        "stopbits" => args.stopbits.map(|v| v.to_string()).unwrap_or_default(),
+        "parity" => format!("{:?}", args.parity.clone().unwrap_or_default()),
+        _ => String::new(),
+    }
+}
+
+/// Print every setting with its value and where it came from
+pub fn print(args: &Args) {
+    for setting in SETTINGS {
+        let source = args.source(setting);
+        let from = match source {
+            Source::File => user_config_path()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            Source::Env => env_name(setting),
+            Source::Device => args
+                .signature
+                .as_ref()
+                .map(|s| s.name.clone())
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        let line = format!(
+            "{:<10} = {:<48} # {} {}",
+            setting,
+            value(args, setting),
+            source,
+            from
+        );
+        println!("{}", line.trim_end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    const PATH: &str = "/home/user/.config/dpoll/config.toml";
+
+    fn parse(argv: &[&str]) -> Args {
+        Args::parse_from(["dpoll"].iter().chain(argv))
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: BTreeMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    fn apply_file(args: &mut Args, file: &str, vars: &[(&str, &str)]) -> Result<()> {
+        apply(args, Some((Path::new(PATH), file)), env(vars))
+    }
+
+    #[test]
+    fn defaults_without_file_and_env() {
+        let mut args = parse(&["10.0.0.1"]);
+        apply(&mut args, None, env(&[])).unwrap();
+        assert_eq!(args.poll_rate, Some(1000));
+        assert_eq!(args.source("poll_rate"), Source::Default);
+    }
+
+    #[test]
+    fn file_over_default() {
+        let mut args = parse(&["10.0.0.1"]);
+        apply_file(&mut args, "poll_rate = 500\nparity = \"even\"", &[]).unwrap();
+        assert_eq!(args.poll_rate, Some(500));
+        assert_eq!(args.parity.as_deref(), Some("even"));
+        assert_eq!(args.source("poll_rate"), Source::File);
+        assert_eq!(args.source("port"), Source::Default);
+    }
+
+    #[test]
+    fn env_over_file() {
+        let mut args = parse(&["10.0.0.1"]);
+        apply_file(
+            &mut args,
+            "poll_rate = 500\ntimeout = 2.5",
+            &[("DPOLL_POLL_RATE", "200")],
+        )
+        .unwrap();
+        assert_eq!(args.poll_rate, Some(200));
+        assert_eq!(args.source("poll_rate"), Source::Env);
+        assert_eq!(args.timeout, Some(std::time::Duration::from_millis(2500)));
+        assert_eq!(args.source("timeout"), Source::File);
+    }
+
+    #[test]
+    fn device_over_env() {
+        let mut args = parse(&["10.0.0.1"]);
+        apply_file(&mut args, "port = 1502", &[("DPOLL_PORT", "2502")]).unwrap();
+        device(&mut args.sources, "port", &mut args.port, Some(3502));
+        assert_eq!(args.port, Some(3502));
+        assert_eq!(args.source("port"), Source::Device);
+        // a setting the device entry lacks keeps its layer
+        device(&mut args.sources, "retries", &mut args.retries, None);
+        assert_eq!(args.source("retries"), Source::Default);
+    }
+
+    #[test]
+    fn command_line_over_all() {
+        let mut args = parse(&["10.0.0.1", "-l", "50"]);
+        args.sources.insert("poll_rate", Source::CommandLine);
+        apply_file(&mut args, "poll_rate = 500", &[("DPOLL_POLL_RATE", "200")]).unwrap();
+        device(
+            &mut args.sources,
+            "poll_rate",
+            &mut args.poll_rate,
+            Some(100),
+        );
+        assert_eq!(args.poll_rate, Some(50));
+        assert_eq!(args.source("poll_rate"), Source::CommandLine);
+    }
+
+    #[test]
+    fn unknown_and_invalid_settings() {
+        let e = apply_file(&mut parse(&["10.0.0.1"]), "pollrate = 500", &[]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("Unknown setting pollrate in {}", PATH)
+        );
+
+        let e = apply_file(&mut parse(&["10.0.0.1"]), "port = 70000", &[]).unwrap_err();
+        assert_eq!(e.to_string(), format!("Invalid port in {}", PATH));
+
+        let e = apply_file(&mut parse(&["10.0.0.1"]), "port = ", &[]).unwrap_err();
+        assert_eq!(e.to_string(), format!("Invalid {}", PATH));
+
+        let e = apply(
+            &mut parse(&["10.0.0.1"]),
+            None,
+            env(&[("DPOLL_TIMEOUT", "soon")]),
+        )
+        .unwrap_err();
+        assert_eq!(e.to_string(), "Invalid DPOLL_TIMEOUT");
+
+        // unrelated variables are not settings
+        apply(
+            &mut parse(&["10.0.0.1"]),
+            None,
+            env(&[("DPOLL_COLOR", "x")]),
+        )
+        .unwrap();
+    }
+}
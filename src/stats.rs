@@ -0,0 +1,250 @@
+use std::{collections::BTreeMap, fmt};
+
+use tokio_modbus::ExceptionCode;
+
+use crate::{
+    error::{self, ChecksumError, Error},
+    modbus_pdu,
+};
+
+/// What became of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Response,
+    /// exception response with this code
+    Exception(u8),
+    Timeout,
+    Io,
+    /// the response had a wrong CRC or LRC, see [`ChecksumError`]
+    Checksum,
+}
+
+impl Outcome {
+    pub fn of_response<T>(response: &Result<T, ExceptionCode>) -> Outcome {
+        match response {
+            Ok(_) => Outcome::Response,
+            Err(code) => Outcome::Exception(modbus_pdu::exception_value(*code)),
+        }
+    }
+
+    pub fn of_error(e: &Error) -> Outcome {
+        match e {
+            Error::Transport(e) if ChecksumError::is_source_of(e) => Outcome::Checksum,
+            Error::Transport(_) => Outcome::Io,
+            Error::Timeout => Outcome::Timeout,
+            Error::Exception(code) => Outcome::Exception(modbus_pdu::exception_value(*code)),
+            // a negative confirmation is an answer all the same
+            Error::Rejected { .. } => Outcome::Response,
+        }
+    }
+}
+
+/// Counters of one function code
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub transmitted: u64,
+    /// responses including exceptions
+    pub received: u64,
+    pub timeouts: u64,
+    /// exception responses per exception code
+    pub exceptions: BTreeMap<u8, u64>,
+    pub io_errors: u64,
+    pub checksum_errors: u64,
+}
+
+impl FunctionStats {
+    pub fn errors(&self) -> u64 {
+        self.timeouts
+            + self.exceptions.values().sum::<u64>()
+            + self.io_errors
+            + self.checksum_errors
+    }
+}
+
+/// Counters of one slave, per function code
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    pub functions: BTreeMap<u8, FunctionStats>,
+}
+
+impl DeviceStats {
+    pub fn transmitted(&self) -> u64 {
+        self.functions.values().map(|f| f.transmitted).sum()
+    }
+
+    pub fn received(&self) -> u64 {
+        self.functions.values().map(|f| f.received).sum()
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.functions.values().map(|f| f.errors()).sum()
+    }
+
+    /// Share of the requests without any response, in percent
+    pub fn loss(&self) -> f32 {
+        match self.transmitted() {
+            0 => 0.0,
+            tc => tc.saturating_sub(self.received()) as f32 / tc as f32 * 100.0,
+        }
+    }
+}
+
+impl fmt::Display for DeviceStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} frames transmitted, {} received, {} errors, {:.1}% frame loss",
+            self.transmitted(),
+            self.received(),
+            self.errors(),
+            self.loss()
+        )?;
+        for (fc, s) in self.functions.iter() {
+            write!(
+                f,
+                "  function {:#04X}: {} transmitted, {} received, {} timeouts, {} IO errors, {} CRC errors",
+                fc, s.transmitted, s.received, s.timeouts, s.io_errors, s.checksum_errors
+            )?;
+            for (code, n) in s.exceptions.iter() {
+                write!(
+                    f,
+                    ", {} {}",
+                    error::exception_name(modbus_pdu::exception_code(*code)),
+                    n
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Request counters of a session, per slave
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PollStats {
+    pub devices: BTreeMap<u8, DeviceStats>,
+    pub connections: u32,
+    pub connect_errors: u32,
+    /// the transport broke while connected
+    pub disconnects: u32,
+}
+
+impl PollStats {
+    pub fn device(&self, unit: u8) -> Option<&DeviceStats> {
+        self.devices.get(&unit)
+    }
+
+    fn function(&mut self, unit: u8, function: u8) -> &mut FunctionStats {
+        self.devices
+            .entry(unit)
+            .or_default()
+            .functions
+            .entry(function)
+            .or_default()
+    }
+
+    /// A request with `function` was sent to `unit`
+    pub fn request(&mut self, unit: u8, function: u8) {
+        self.function(unit, function).transmitted += 1;
+    }
+
+    pub fn outcome(&mut self, unit: u8, function: u8, outcome: Outcome) {
+        let s = self.function(unit, function);
+        match outcome {
+            Outcome::Response => s.received += 1,
+            Outcome::Exception(code) => {
+                s.received += 1;
+                *s.exceptions.entry(code).or_default() += 1;
+            }
+            Outcome::Timeout => s.timeouts += 1,
+            Outcome::Io => s.io_errors += 1,
+            Outcome::Checksum => s.checksum_errors += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn outcomes_per_function() {
+        let mut stats = PollStats::default();
+        for outcome in [
+            Outcome::Response,
+            Outcome::Exception(0x02),
+            Outcome::Exception(0x02),
+            Outcome::Timeout,
+            Outcome::Io,
+            Outcome::Checksum,
+        ] {
+            stats.request(1, 0x03);
+            stats.outcome(1, 0x03, outcome);
+        }
+        stats.request(2, 0x01);
+        stats.outcome(2, 0x01, Outcome::Response);
+
+        let read = &stats.device(1).unwrap().functions[&0x03];
+        assert_eq!((read.transmitted, read.received), (6, 3));
+        assert_eq!(read.exceptions[&0x02], 2);
+        assert_eq!(
+            (read.timeouts, read.io_errors, read.checksum_errors),
+            (1, 1, 1)
+        );
+        assert_eq!(read.errors(), 5);
+        assert_eq!(stats.device(2).unwrap().received(), 1);
+        assert!(stats.device(3).is_none());
+    }
+
+    #[test]
+    fn loss_counts_requests_without_response() {
+        let mut device = DeviceStats::default();
+        assert_eq!(device.loss(), 0.0);
+        device.functions.insert(
+            0x03,
+            FunctionStats {
+                transmitted: 3,
+                received: 2,
+                ..Default::default()
+            },
+        );
+        device.functions.insert(
+            0x10,
+            FunctionStats {
+                transmitted: 1,
+                received: 0,
+                ..Default::default()
+            },
+        );
+        assert_eq!(device.loss(), 50.0);
+        // a late response counted after a timeout never makes the loss negative
+        device.functions.get_mut(&0x10).unwrap().received = 3;
+        assert_eq!(device.loss(), 0.0);
+    }
+
+    #[test]
+    fn outcomes_of_errors() {
+        let checksum = ChecksumError::Lrc {
+            expected: 1,
+            actual: 2,
+        };
+        assert_eq!(
+            Outcome::of_error(&Error::Transport(checksum.into())),
+            Outcome::Checksum
+        );
+        // only the source tells a checksum error
+        let garbled = io::Error::new(io::ErrorKind::InvalidData, "CRC mismatch");
+        assert_eq!(Outcome::of_error(&Error::Transport(garbled)), Outcome::Io);
+        assert_eq!(Outcome::of_error(&Error::Timeout), Outcome::Timeout);
+        assert_eq!(
+            Outcome::of_error(&Error::Exception(ExceptionCode::IllegalFunction)),
+            Outcome::Exception(0x01)
+        );
+        assert_eq!(
+            Outcome::of_response::<()>(&Err(ExceptionCode::ServerDeviceBusy)),
+            Outcome::Exception(0x06)
+        );
+    }
+}